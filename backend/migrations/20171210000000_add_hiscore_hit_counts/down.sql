@@ -0,0 +1,9 @@
+ALTER TABLE hiscores
+  DROP COLUMN count300,
+  DROP COLUMN count100,
+  DROP COLUMN count50,
+  DROP COLUMN countmiss,
+  DROP COLUMN countkatu,
+  DROP COLUMN countgeki,
+  DROP COLUMN maxcombo,
+  DROP COLUMN perfect;
@@ -0,0 +1,9 @@
+ALTER TABLE hiscores
+  ADD COLUMN count300 INT NULL,
+  ADD COLUMN count100 INT NULL,
+  ADD COLUMN count50 INT NULL,
+  ADD COLUMN countmiss INT NULL,
+  ADD COLUMN countkatu INT NULL,
+  ADD COLUMN countgeki INT NULL,
+  ADD COLUMN maxcombo INT NULL,
+  ADD COLUMN perfect BOOLEAN NULL;
@@ -0,0 +1 @@
+ALTER TABLE updates ADD COLUMN source VARCHAR(16) NULL;
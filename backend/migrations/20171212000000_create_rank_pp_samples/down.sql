@@ -0,0 +1 @@
+DROP TABLE rank_pp_samples;
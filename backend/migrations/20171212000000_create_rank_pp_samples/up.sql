@@ -0,0 +1,10 @@
+CREATE TABLE rank_pp_samples (
+  id INT NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  mode SMALLINT NOT NULL,
+  rank_bucket INT NOT NULL,
+  median_pp FLOAT NOT NULL,
+  sample_count INT NOT NULL,
+  snapshot_date DATE NOT NULL
+);
+
+CREATE INDEX mode_snapshot ON rank_pp_samples (mode, snapshot_date);
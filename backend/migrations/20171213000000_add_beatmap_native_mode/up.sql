@@ -0,0 +1,3 @@
+ALTER TABLE beatmaps ADD COLUMN native_mode SMALLINT NOT NULL DEFAULT 0 AFTER mode;
+-- the native mode of already cached beatmaps isn't known, so assume that they were requested in their native mode
+UPDATE beatmaps SET native_mode = mode;
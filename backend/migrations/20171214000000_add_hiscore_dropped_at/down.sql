@@ -0,0 +1 @@
+ALTER TABLE hiscores DROP COLUMN dropped_at;
@@ -0,0 +1 @@
+ALTER TABLE hiscores ADD COLUMN dropped_at TIMESTAMP NULL DEFAULT NULL;
@@ -0,0 +1,2 @@
+UPDATE updates SET pp_country_rank = 0 WHERE pp_country_rank IS NULL;
+ALTER TABLE updates MODIFY pp_country_rank INT NOT NULL;
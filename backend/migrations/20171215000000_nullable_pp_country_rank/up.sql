@@ -0,0 +1,2 @@
+ALTER TABLE updates MODIFY pp_country_rank INT NULL;
+UPDATE updates SET pp_country_rank = NULL WHERE pp_country_rank = 0;
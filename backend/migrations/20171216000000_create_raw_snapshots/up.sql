@@ -0,0 +1,8 @@
+CREATE TABLE raw_snapshots (
+  user_id INT NOT NULL,
+  mode SMALLINT NOT NULL,
+  payload MEDIUMTEXT NOT NULL,
+  truncated BOOLEAN NOT NULL,
+  fetched_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id, mode)
+);
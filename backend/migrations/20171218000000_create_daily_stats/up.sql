@@ -0,0 +1,11 @@
+CREATE TABLE daily_stats (
+  user_id INT NOT NULL,
+  mode SMALLINT NOT NULL,
+  date DATE NOT NULL,
+  pp_rank INT NOT NULL,
+  pp_raw FLOAT NOT NULL,
+  accuracy FLOAT NOT NULL,
+  playcount INT NOT NULL,
+  playcount_delta INT NOT NULL,
+  PRIMARY KEY (user_id, mode, date)
+);
@@ -0,0 +1,7 @@
+CREATE TABLE user_flags (
+  user_id INT NOT NULL,
+  flag VARCHAR(32) NOT NULL,
+  note TEXT NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id, flag)
+);
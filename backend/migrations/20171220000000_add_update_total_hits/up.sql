@@ -0,0 +1,2 @@
+ALTER TABLE updates ADD COLUMN total_hits BIGINT NOT NULL DEFAULT 0;
+UPDATE updates SET total_hits = count300 + count100 + count50;
@@ -0,0 +1,3 @@
+-- only one mode of each beatmap can be kept, so keep the one with the lowest mode
+DELETE b FROM beatmaps b INNER JOIN beatmaps other ON other.beatmap_id = b.beatmap_id AND other.mode < b.mode;
+ALTER TABLE beatmaps DROP PRIMARY KEY, ADD PRIMARY KEY (beatmap_id);
@@ -0,0 +1 @@
+ALTER TABLE beatmaps DROP PRIMARY KEY, ADD PRIMARY KEY (beatmap_id, mode);
@@ -0,0 +1,333 @@
+//! Maintenance and monitoring endpoints for the operators of the osu!track instance.  All of them require the
+//! `X-Admin-Token` header to match the configured `ADMIN_TOKEN`.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::Duration;
+use diesel;
+use diesel::prelude::*;
+use rocket::Outcome;
+use rocket::State;
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::content::Content;
+use rocket_contrib::Json;
+
+use super::DbPool;
+use clock::Clock;
+use error::ApiError;
+use helpers::{debug, get_user_from_username, load_cached_beatmaps, merge_users, set_tracking_enabled, MergeReport};
+use helpers::api_usage::ApiUsageSummary;
+use helpers::daily_stats;
+use helpers::db_metrics::DbStatus;
+use helpers::orphans::{count_orphans, repair_orphans, OrphanReport, RepairedUser};
+use helpers::raw_snapshots::load_raw_snapshot;
+use helpers::user_flags::{load_user_flags, remove_user_flag, set_user_flag, UserFlagKind};
+use models::{CachedBeatmapRow, RawSnapshot, Update, User, UserFlag};
+use osu_api::ApiClient;
+use params::{
+    next_page_offset, DateRangeParams, JsonBody, PageParams, Query, Username, DEFAULT_CACHED_BEATMAPS,
+    MAX_CACHED_BEATMAPS,
+};
+use schema::updates::dsl as updates_dsl;
+use schema::users::dsl as users_dsl;
+use secret::ADMIN_TOKEN;
+
+/// Request guard that only succeeds if the request carries the admin token, responding with a 401 otherwise
+pub struct AdminToken;
+
+impl<'a, 'r> FromRequest<'a, 'r> for AdminToken {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        match request.headers().get_one("X-Admin-Token") {
+            Some(token) if token == ADMIN_TOKEN => Outcome::Success(AdminToken),
+            _ => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// The number of days of history that windowed admin reports cover by default
+const DEFAULT_REPORT_DAYS: i64 = 7;
+
+/// Query parameters for admin reports covering a window of recent history
+#[derive(FromForm)]
+pub struct WindowParams {
+    pub days: Option<u32>,
+}
+
+/// Returns the number of updates recorded from each source over the last `?days=` days (7 by default).  Updates that
+/// were recorded before sources were tracked are counted under "unknown".
+#[get("/admin/update_sources")]
+pub fn update_sources(
+    _admin: AdminToken, db_pool: State<DbPool>, clock: State<Arc<Clock>>, params: Query<WindowParams>
+) -> Result<Json<HashMap<String, i64>>, ApiError> {
+    use diesel::dsl::count_star;
+
+    let db_conn = &*db_pool.get_conn();
+    let days = params.days.map(|days| days as i64).unwrap_or(DEFAULT_REPORT_DAYS);
+    let window_start = clock.now() - Duration::days(days);
+
+    let counts: Vec<(Option<String>, i64)> = updates_dsl::updates
+        .filter(updates_dsl::update_time.gt(window_start))
+        .group_by(updates_dsl::source)
+        .select((updates_dsl::source, count_star()))
+        .load(db_conn)
+        .map_err(debug)?;
+
+    let counts = counts.into_iter()
+        .map(|(source, count)| (source.unwrap_or_else(|| String::from("unknown")), count))
+        .collect();
+
+    Ok(Json(counts))
+}
+
+/// Returns true if two updates hold exactly the same stats, ignoring their ids, times, and sources
+fn same_stats(a: &Update, b: &Update) -> bool {
+    a.user_id == b.user_id && a.mode == b.mode && a.count300 == b.count300 && a.count100 == b.count100 &&
+        a.count50 == b.count50 && a.playcount == b.playcount && a.ranked_score == b.ranked_score &&
+        a.total_score == b.total_score && a.pp_rank == b.pp_rank && a.level == b.level && a.pp_raw == b.pp_raw &&
+        a.accuracy == b.accuracy && a.count_rank_ss == b.count_rank_ss && a.count_rank_s == b.count_rank_s &&
+        a.count_rank_a == b.count_rank_a && a.pp_country_rank == b.pp_country_rank
+}
+
+/// Given a user's updates in chronological order, returns the ids of those that are redundant: every update in a run
+/// of consecutive updates with identical stats except for the first and last of the run.  The first and last are kept
+/// so that the time range over which the stats didn't change is preserved.
+fn redundant_update_ids(updates: &[Update]) -> Vec<i32> {
+    updates.windows(3)
+        .filter(|w| same_stats(&w[0], &w[1]) && same_stats(&w[1], &w[2]))
+        .map(|w| w[1].id)
+        .collect()
+}
+
+#[derive(Serialize)]
+pub struct DedupReport {
+    pub removed: usize,
+}
+
+/// Deletes the redundant updates of a user in a mode left behind by past bugs in change detection.  See
+/// `redundant_update_ids` for which updates are considered redundant.
+#[post("/admin/dedup_updates/<username>/<mode>")]
+pub fn dedup_updates(
+    _admin: AdminToken, db_pool: State<DbPool>, username: Result<Username, String>, mode: u8
+) -> Result<Json<DedupReport>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let db_conn = &*db_pool.get_conn();
+    let usr: User = get_user_from_username(db_conn, username.normalized())?
+        .ok_or_else(|| ApiError::NotFound(format!("User {} isn't tracked", username.as_str())))?;
+
+    let removed = db_conn.transaction::<_, diesel::result::Error, _>(|| {
+        let updates: Vec<Update> = updates_dsl::updates
+            .filter(updates_dsl::user_id.eq(usr.id))
+            .filter(updates_dsl::mode.eq(mode as i16))
+            .order((updates_dsl::update_time.asc(), updates_dsl::id.asc()))
+            .load(db_conn)?;
+
+        let redundant_ids = redundant_update_ids(&updates);
+        if redundant_ids.is_empty() {
+            return Ok(0);
+        }
+        diesel::delete(updates_dsl::updates.filter(updates_dsl::id.eq_any(redundant_ids)))
+            .execute(db_conn)
+    }).map_err(debug)?;
+
+    Ok(Json(DedupReport { removed: removed }))
+}
+
+/// Returns the number of requests made to the osu! API during the current minute and the last hour, in total and for
+/// each endpoint, along with the rate limit and the percentage of it used so far this minute
+#[get("/admin/api_usage")]
+pub fn api_usage(_admin: AdminToken, api_client: State<ApiClient>) -> Json<ApiUsageSummary> {
+    Json(api_client.usage().summary())
+}
+
+/// Returns the state of the database pool, the time spent waiting for connections since the backend started, and the
+/// routes with the highest mean database time over the last hour, slowest first.  A route's database time is the time
+/// that it holds connections for, which doesn't include any time spent waiting on the osu! API.
+#[get("/admin/status")]
+pub fn status(_admin: AdminToken, db_pool: State<DbPool>) -> Json<DbStatus> {
+    Json(db_pool.metrics().status(db_pool.state()))
+}
+
+/// Returns the state of the database pool, the time spent waiting for connections, and the time that each route has
+/// spent on the database in the Prometheus text format.  The slowest routes of the last hour are listed by
+/// `/admin/status`.
+#[get("/admin/metrics")]
+pub fn metrics(_admin: AdminToken, db_pool: State<DbPool>) -> Content<String> {
+    Content(ContentType::Plain, db_pool.metrics().render_prometheus(db_pool.state()))
+}
+
+/// Returns the body of the most recent `get_user` response received from the osu! API for a user in a mode, which is
+/// kept for debugging payloads that fail to parse.  Returns a 404 if no response has been stored for them.
+#[get("/admin/raw/<user_id>/<mode>")]
+pub fn raw_snapshot(
+    _admin: AdminToken, db_pool: State<DbPool>, user_id: i32, mode: u8
+) -> Result<Option<Json<RawSnapshot>>, ApiError> {
+    let db_conn = &*db_pool.get_conn();
+    Ok(load_raw_snapshot(db_conn, user_id, mode)?.map(Json))
+}
+
+/// Enables or disables the tracking of a user at their request.  Takes a bare JSON boolean as the body and returns the
+/// user's updated row, or a 404 if they aren't tracked.  The data already stored for the user is kept either way.
+#[post("/admin/user/<id>/tracking", data = "<enabled>")]
+pub fn set_tracking(
+    _admin: AdminToken, db_pool: State<DbPool>, id: i32, enabled: Result<JsonBody<bool>, String>
+) -> Result<Option<Json<User>>, ApiError> {
+    let enabled = enabled.map_err(ApiError::BadInput)?;
+    let db_conn = &*db_pool.get_conn();
+    Ok(set_tracking_enabled(db_conn, id, *enabled)?.map(Json))
+}
+
+/// Returns the flags set on a user, oldest first
+#[get("/admin/user/<id>/flags")]
+pub fn list_flags(_admin: AdminToken, db_pool: State<DbPool>, id: i32) -> Result<Json<Vec<UserFlag>>, ApiError> {
+    let db_conn = &*db_pool.get_conn();
+    Ok(Json(load_user_flags(db_conn, id)?))
+}
+
+/// Sets a flag on a user.  Takes a bare JSON string as the body with a note explaining why the flag was set, which
+/// replaces the note if the flag is already set.  Returns the stored flag, or a 404 if the user isn't tracked.
+#[post("/admin/user/<id>/flags/<flag>", data = "<note>")]
+pub fn add_flag(
+    _admin: AdminToken, db_pool: State<DbPool>, id: i32, flag: Result<UserFlagKind, String>,
+    note: Result<JsonBody<String>, String>,
+) -> Result<Option<Json<UserFlag>>, ApiError> {
+    let flag = flag.map_err(ApiError::BadInput)?;
+    let note = note.map_err(ApiError::BadInput)?;
+    let db_conn = &*db_pool.get_conn();
+
+    let tracked: Option<User> = users_dsl::users.find(id).first(db_conn).optional().map_err(debug)?;
+    if tracked.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(Json(set_user_flag(db_conn, id, flag, &*note)?)))
+}
+
+/// Removes a flag from a user, responding with a 404 if it wasn't set
+#[delete("/admin/user/<id>/flags/<flag>")]
+pub fn remove_flag(
+    _admin: AdminToken, db_pool: State<DbPool>, id: i32, flag: Result<UserFlagKind, String>
+) -> Result<Option<Json<bool>>, ApiError> {
+    let flag = flag.map_err(ApiError::BadInput)?;
+    let db_conn = &*db_pool.get_conn();
+    Ok(if remove_user_flag(db_conn, id, flag)? { Some(Json(true)) } else { None })
+}
+
+/// Returns the number of updates and hiscores that belong to users without a user row, along with the ids of those
+/// users
+#[get("/admin/orphans")]
+pub fn orphans(_admin: AdminToken, db_pool: State<DbPool>) -> Result<Json<OrphanReport>, ApiError> {
+    let db_conn = &*db_pool.get_conn();
+    Ok(Json(count_orphans(db_conn)?))
+}
+
+/// Creates placeholder rows for the users reported by `/admin/orphans`, named with their current username from the
+/// osu! API if it can be found there and `u_<id>` otherwise.  Returns the rows that were created, so running it
+/// again once everything has been repaired returns an empty list.
+#[post("/admin/orphans/repair")]
+pub fn repair_orphaned_users(
+    _admin: AdminToken, api_client: State<ApiClient>, db_pool: State<DbPool>
+) -> Result<Json<Vec<RepairedUser>>, ApiError> {
+    let db_conn = &*db_pool.get_conn();
+    let repaired = repair_orphans(db_conn, |user_id| match api_client.get_username(user_id) {
+        Ok(username) => username,
+        Err(err) => {
+            warn!("Error while looking up the username of orphaned user {}: {}", user_id, err);
+            None
+        },
+    })?;
+
+    Ok(Json(repaired))
+}
+
+/// Moves all of the updates and hiscores of the user `from_id` to the user `into_id` and deletes `from_id`, repairing
+/// histories that were split between two ids.  Returns the number of rows that were moved along with the number of
+/// hiscores that were already recorded for `into_id` and deleted instead, or a 404 if `into_id` isn't tracked.
+#[post("/admin/merge_users/<from_id>/<into_id>")]
+pub fn merge(
+    _admin: AdminToken, db_pool: State<DbPool>, from_id: i32, into_id: i32
+) -> Result<Option<Json<MergeReport>>, ApiError> {
+    if from_id == into_id {
+        return Err(ApiError::BadInput(format!("Can't merge user {} into itself", from_id)));
+    }
+
+    let db_conn = &*db_pool.get_conn();
+    Ok(merge_users(db_conn, from_id, into_id)?.map(Json))
+}
+
+/// The result of rebuilding the daily aggregates of every tracked user
+#[derive(Serialize)]
+pub struct DailyStatsBackfill {
+    /// The number of `(user, mode)` pairs whose aggregates were rebuilt
+    pub histories: usize,
+    pub days: usize,
+}
+
+/// Rebuilds the daily aggregates of every user in every mode they have updates in from their stored updates.  Needed
+/// once to populate the aggregates of history recorded before they existed; running it again is harmless.
+#[post("/admin/daily_stats/backfill")]
+pub fn backfill_daily_stats(_admin: AdminToken, db_pool: State<DbPool>) -> Result<Json<DailyStatsBackfill>, ApiError> {
+    let db_conn = &*db_pool.get_conn();
+    let histories: Vec<(i32, i16)> = updates_dsl::updates
+        .select((updates_dsl::user_id, updates_dsl::mode))
+        .distinct()
+        .load(db_conn)
+        .map_err(debug)?;
+
+    let mut days = 0;
+    for &(user_id, mode) in &histories {
+        days += daily_stats::backfill_daily_stats(db_conn, user_id, mode)?;
+    }
+
+    Ok(Json(DailyStatsBackfill { histories: histories.len(), days: days }))
+}
+
+/// A page of the beatmap cache returned by `/admin/beatmaps/cached`
+#[derive(Serialize)]
+pub struct CachedBeatmapsPage {
+    pub beatmaps: Vec<CachedBeatmapRow>,
+    /// The `?offset=` to request the next page with, or `null` if this is the last page
+    pub next_offset: Option<u32>,
+}
+
+/// Lists the beatmaps in the beatmap cache whose `last_update` is within the optional `?from=` and `?to=` unix
+/// timestamps, most recently updated first, so that operators can audit the cache's growth and staleness.  Only the
+/// ids, mode, title, and `last_update` of each beatmap are returned.  `?limit=` sets the size of the page (100 by
+/// default, at most 500) and `?offset=` skips that many beatmaps to fetch later pages.
+#[get("/admin/beatmaps/cached")]
+pub fn cached_beatmaps(
+    _admin: AdminToken, db_pool: State<DbPool>, range: Query<DateRangeParams>, page: Query<PageParams>
+) -> Result<Json<CachedBeatmapsPage>, ApiError> {
+    let (limit, offset) = (page.limit(DEFAULT_CACHED_BEATMAPS, MAX_CACHED_BEATMAPS), page.offset());
+    let db_conn = &*db_pool.get_conn();
+    let beatmaps = load_cached_beatmaps(
+        db_conn, range.from.0.map(|t| t.0), range.to.0.map(|t| t.0), limit as i64, offset as i64
+    )?;
+
+    let next_offset = next_page_offset(limit, offset, beatmaps.len());
+    Ok(Json(CachedBeatmapsPage { beatmaps: beatmaps, next_offset: next_offset }))
+}
+
+#[test]
+fn redundant_updates() {
+    use chrono::NaiveDateTime;
+    use test_support::test_stored_update;
+
+    let update = |id: i32, pp_raw: f32| Update {
+        id: id,
+        update_time: NaiveDateTime::from_timestamp(1500000000 + id as i64, 0),
+        ..test_stored_update(pp_raw)
+    };
+
+    // a run of four identical updates keeps its first and last, and runs of two are left alone
+    let updates = vec![
+        update(1, 100.), update(2, 200.), update(3, 200.), update(4, 200.), update(5, 200.), update(6, 300.),
+        update(7, 300.), update(8, 200.),
+    ];
+    assert_eq!(redundant_update_ids(&updates), vec![3, 4]);
+
+    assert!(redundant_update_ids(&updates[..2]).is_empty());
+    assert!(redundant_update_ids(&[]).is_empty());
+}
@@ -0,0 +1,114 @@
+//! Versioning of the API's paths.  Routes are mounted both at their legacy unprefixed paths and under `V1_PREFIX`, and
+//! responses to the legacy paths carry a `Deprecation` header so that integrations know to move to the versioned ones.
+
+use rocket::{Outcome, Route};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::Response;
+
+/// The prefix that the first version of the API is mounted under
+pub const V1_PREFIX: &'static str = "/v1";
+/// The prefix of routes whose behavior differs from their legacy and `/v1` counterparts.  These routes are declared
+/// with the prefix in their paths and aren't mounted under `V1_PREFIX`.
+pub const V2_PREFIX: &'static str = "/v2";
+
+/// The version of the API that a request was made to, determined by the prefix of its path
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ApiVersion {
+    /// The unprefixed paths that the API was originally served at
+    Legacy,
+    V1,
+    V2,
+}
+
+/// Returns `true` if `path` is `prefix` or starts with it followed by a slash
+fn has_prefix(path: &str, prefix: &str) -> bool {
+    path.starts_with(prefix) && (path.len() == prefix.len() || path[prefix.len()..].starts_with('/'))
+}
+
+impl ApiVersion {
+    /// Returns the version of the API that a request for `path` was made to
+    pub fn from_path(path: &str) -> ApiVersion {
+        if has_prefix(path, V1_PREFIX) {
+            ApiVersion::V1
+        } else if has_prefix(path, V2_PREFIX) {
+            ApiVersion::V2
+        } else {
+            ApiVersion::Legacy
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ApiVersion {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        Outcome::Success(ApiVersion::from_path(request.uri().path()))
+    }
+}
+
+/// Returns `path` without the `/v1` prefix, which is the path that the route is mounted at for legacy requests
+pub fn unversioned_path(path: &str) -> &str {
+    match ApiVersion::from_path(path) {
+        ApiVersion::V1 => &path[V1_PREFIX.len()..],
+        ApiVersion::Legacy | ApiVersion::V2 => path,
+    }
+}
+
+/// Returns the routes that are mounted under `V1_PREFIX`: all of `routes` except those declared under `V2_PREFIX`
+pub fn v1_routes(routes: Vec<Route>) -> Vec<Route> {
+    routes.into_iter().filter(|route| !has_prefix(route.uri.path(), V2_PREFIX)).collect()
+}
+
+/// Fairing that adds a `Deprecation` header to the responses of routes requested through their legacy paths
+pub struct DeprecateLegacyPaths;
+
+impl Fairing for DeprecateLegacyPaths {
+    fn info(&self) -> Info {
+        Info { name: "Legacy path deprecation", kind: Kind::Response }
+    }
+
+    fn on_response(&self, req: &Request, res: &mut Response) {
+        if req.route().is_some() && req.guard::<ApiVersion>().succeeded() == Some(ApiVersion::Legacy) {
+            res.set_raw_header("Deprecation", "true");
+        }
+    }
+}
+
+#[test]
+fn version_prefixes() {
+    assert_eq!(ApiVersion::from_path("/v1/stats/Ameo/0"), ApiVersion::V1);
+    assert_eq!(ApiVersion::from_path("/stats/Ameo/0"), ApiVersion::Legacy);
+    assert_eq!(ApiVersion::from_path("/v1stats/Ameo/0"), ApiVersion::Legacy);
+    assert_eq!(ApiVersion::from_path("/v2/livestats/Ameo/0"), ApiVersion::V2);
+    assert_eq!(unversioned_path("/v1/stats/<username>/<mode>"), "/stats/<username>/<mode>");
+    assert_eq!(unversioned_path("/stats/<username>/<mode>"), "/stats/<username>/<mode>");
+}
+
+/// Make sure that the same handler is served through both prefixes and that only legacy responses are deprecated
+#[test]
+fn versioned_mounting() {
+    use rocket::http::Status;
+    use rocket::local::Client;
+
+    let rocket = ::rocket::ignite()
+        .mount("/", routes![::routes::version])
+        .mount(V1_PREFIX, routes![::routes::version])
+        .attach(DeprecateLegacyPaths)
+        .manage(::routes::VersionInfo::current());
+    let client = Client::new(rocket).unwrap();
+
+    let mut legacy = client.get("/version").dispatch();
+    let mut v1 = client.get("/v1/version").dispatch();
+    assert_eq!((legacy.status(), v1.status()), (Status::Ok, Status::Ok));
+    assert_eq!(legacy.body_string(), v1.body_string());
+    assert_eq!(legacy.headers().get_one("Deprecation"), Some("true"));
+    assert_eq!(v1.headers().get_one("Deprecation"), None);
+}
+
+#[test]
+fn v2_routes_not_mounted_under_v1() {
+    let paths: Vec<String> = v1_routes(::mounted_routes()).iter().map(|route| String::from(route.uri.path())).collect();
+    assert!(paths.contains(&String::from("/livestats/<username>/<mode>")));
+    assert!(!paths.iter().any(|path| path.starts_with("/v2/")));
+}
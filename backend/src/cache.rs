@@ -0,0 +1,190 @@
+//! Responders that allow browsers and CDNs to cache API responses.  Responses are tagged with an `ETag` computed from
+//! their body and a `Cache-Control` header, and requests with a matching `If-None-Match` header receive a 304.  The
+//! `CacheControl` fairing sets `Cache-Control` on the responses of other routes according to their entries in
+//! `ENDPOINTS`.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Method, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket::response::content::Content;
+use serde::Serialize;
+use serde_json;
+
+use api_version::unversioned_path;
+
+/// Computes a strong ETag for the given response body
+pub fn compute_etag(body: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+/// Serializes the wrapped value as JSON like `Json` does but marks the response as cacheable for `max_age` seconds and
+/// tags it with an ETag.
+pub struct CachedJson<T> {
+    pub value: T,
+    pub max_age: u32,
+}
+
+impl<T> CachedJson<T> {
+    pub fn new(value: T, max_age: u32) -> CachedJson<T> {
+        CachedJson { value: value, max_age: max_age }
+    }
+}
+
+impl<'r, T: Serialize> Responder<'r> for CachedJson<T> {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        let body = serde_json::to_string(&self.value).map_err(|err| {
+            println!("Error while serializing cached JSON response: {:?}", err);
+            Status::InternalServerError
+        })?;
+        let etag = compute_etag(&body);
+        let cache_control = format!("public, max-age={}", self.max_age);
+
+        if req.headers().get_one("If-None-Match") == Some(etag.as_str()) {
+            return Response::build()
+                .status(Status::NotModified)
+                .raw_header("ETag", etag)
+                .raw_header("Cache-Control", cache_control)
+                .ok();
+        }
+
+        Response::build_from(Content(ContentType::JSON, body).respond_to(req)?)
+            .raw_header("ETag", etag)
+            .raw_header("Cache-Control", cache_control)
+            .ok()
+    }
+}
+
+/// How browsers and CDNs may cache the responses of a route
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CachePolicy {
+    /// Responses must never be cached, since they're either live data or the result of a change
+    NoStore,
+    /// Successful responses may be cached for this many seconds
+    MaxAge(u32),
+}
+
+impl CachePolicy {
+    /// Returns the value of the `Cache-Control` header for a response with the given status
+    pub fn header_value(&self, status: Status) -> String {
+        match *self {
+            CachePolicy::MaxAge(max_age) if status == Status::Ok => format!("public, max-age={}", max_age),
+            _ => String::from("no-store"),
+        }
+    }
+}
+
+/// Wraps a responder and sets the `Cache-Control` header of its response according to the policy, for routes whose
+/// responses shouldn't all be cached for as long as the route's entry in `ENDPOINTS` allows
+pub struct WithCachePolicy<R>(pub R, pub CachePolicy);
+
+impl<'r, R: Responder<'r>> Responder<'r> for WithCachePolicy<R> {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        let mut res = self.0.respond_to(req)?;
+        let cache_control = self.1.header_value(res.status());
+        res.set_raw_header("Cache-Control", cache_control);
+        Ok(res)
+    }
+}
+
+/// Returns the caching policy of a route given its method and path, or `None` if it doesn't have one.  Routes with
+/// methods other than GET change data and routes under `/admin/` are for operators, so neither are ever cached.  The
+/// policies of the other routes are set in `ENDPOINTS`.
+pub fn route_cache_policy(method: Method, path: &str) -> Option<CachePolicy> {
+    if method != Method::Get || path.starts_with("/admin/") {
+        return Some(CachePolicy::NoStore);
+    }
+
+    ::ENDPOINTS.iter()
+        .find(|endpoint| endpoint.method == "GET" && endpoint.path == path)
+        .and_then(|endpoint| endpoint.cache_policy)
+}
+
+/// Fairing that sets the `Cache-Control` header of responses according to `route_cache_policy`.  GET routes that set
+/// the header themselves, such as those responding with `CachedJson` or `WithCachePolicy`, are left alone, while
+/// responses to requests with other methods are always sent with `no-store`.
+pub struct CacheControl;
+
+impl Fairing for CacheControl {
+    fn info(&self) -> Info {
+        Info { name: "Cache-Control", kind: Kind::Response }
+    }
+
+    fn on_response(&self, req: &Request, res: &mut Response) {
+        let policy = match req.route() {
+            Some(route) => route_cache_policy(req.method(), unversioned_path(route.uri.path())),
+            None => None,
+        };
+        let policy = match policy {
+            Some(policy) => policy,
+            None => { return; },
+        };
+        if policy != CachePolicy::NoStore && res.headers().contains("Cache-Control") {
+            return;
+        }
+
+        res.set_raw_header("Cache-Control", policy.header_value(res.status()));
+    }
+}
+
+#[cfg(test)]
+#[get("/stats/<username>/<mode>")]
+fn test_stats(username: String, mode: u8) -> Option<String> {
+    if username == "missing" { None } else { Some(format!("{} {}", username, mode)) }
+}
+
+#[cfg(test)]
+#[get("/update/<username>/<mode>")]
+fn test_update(username: String, mode: u8) -> String {
+    format!("{} {}", username, mode)
+}
+
+#[cfg(test)]
+#[post("/stats/<username>/<mode>")]
+fn test_post_stats(username: String, mode: u8) -> CachedJson<String> {
+    CachedJson::new(format!("{} {}", username, mode), 60)
+}
+
+#[cfg(test)]
+#[get("/graph/<username>/<mode>")]
+fn test_graph(username: String, mode: u8) -> CachedJson<String> {
+    CachedJson::new(format!("{} {}", username, mode), 300)
+}
+
+#[cfg(test)]
+#[get("/sig/<username>/<file>")]
+fn test_sig(username: String, file: String) -> WithCachePolicy<String> {
+    let policy = if username == "error" { CachePolicy::NoStore } else { CachePolicy::MaxAge(60) };
+    WithCachePolicy(format!("{} {}", username, file), policy)
+}
+
+#[test]
+fn route_cache_control() {
+    use rocket::local::Client;
+    use secret::STATS_CACHE_MAX_AGE_SECS;
+
+    let routes = routes![test_stats, test_update, test_post_stats, test_graph, test_sig];
+    let client = Client::new(::rocket::ignite().mount("/", routes).attach(CacheControl)).unwrap();
+    let cache_control = |res: ::rocket::local::LocalResponse| res.headers().get_one("Cache-Control").map(String::from);
+
+    let stats = cache_control(client.get("/stats/Ameo/0").dispatch());
+    assert_eq!(stats, Some(format!("public, max-age={}", STATS_CACHE_MAX_AGE_SECS)));
+    assert_eq!(cache_control(client.get("/stats/missing/0").dispatch()), Some(String::from("no-store")));
+    assert_eq!(cache_control(client.get("/update/Ameo/0").dispatch()), Some(String::from("no-store")));
+    // mutating routes are never cached, even if the route says otherwise
+    assert_eq!(cache_control(client.post("/stats/Ameo/0").dispatch()), Some(String::from("no-store")));
+    // routes without a policy keep the header that they set themselves
+    assert_eq!(cache_control(client.get("/graph/Ameo/0").dispatch()), Some(String::from("public, max-age=300")));
+
+    // responses that set their own policy override the route's
+    assert_eq!(cache_control(client.get("/sig/Ameo/0.png").dispatch()), Some(String::from("public, max-age=60")));
+    assert_eq!(cache_control(client.get("/sig/error/0.png").dispatch()), Some(String::from("no-store")));
+
+    assert_eq!(route_cache_policy(Method::Get, "/admin/api_usage"), Some(CachePolicy::NoStore));
+    assert_eq!(route_cache_policy(Method::Get, "/version"), None);
+}
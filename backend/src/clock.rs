@@ -0,0 +1,75 @@
+//! Sources of the current time.  Time-dependent logic reads the time from a `Clock` rather than from the system
+//! directly so that tests can control it with a `MockClock`.
+
+use std::time::Instant;
+#[cfg(test)]
+use std::sync::Mutex;
+#[cfg(test)]
+use std::time::Duration as StdDuration;
+
+use chrono::{NaiveDateTime, Utc};
+#[cfg(test)]
+use chrono::Duration;
+
+pub trait Clock: Send + Sync {
+    /// Returns a monotonic instant, used for measuring how much time has passed such as for cache TTLs
+    fn instant(&self) -> Instant;
+    /// Returns the current time in UTC
+    fn now(&self) -> NaiveDateTime;
+}
+
+/// The clock used in production, which reads the system's time
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn now(&self) -> NaiveDateTime {
+        Utc::now().naive_utc()
+    }
+}
+
+/// A clock that starts at a fixed time and only moves forward when it's advanced
+#[cfg(test)]
+pub struct MockClock {
+    start_instant: Instant,
+    start: NaiveDateTime,
+    elapsed: Mutex<StdDuration>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new(start: NaiveDateTime) -> MockClock {
+        MockClock { start_instant: Instant::now(), start: start, elapsed: Mutex::new(StdDuration::from_secs(0)) }
+    }
+
+    pub fn advance(&self, by: StdDuration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn instant(&self) -> Instant {
+        self.start_instant + *self.elapsed.lock().unwrap()
+    }
+
+    fn now(&self) -> NaiveDateTime {
+        self.start + Duration::from_std(*self.elapsed.lock().unwrap()).unwrap()
+    }
+}
+
+#[test]
+fn mock_clock_advances() {
+    let clock = MockClock::new(NaiveDateTime::from_timestamp(1500000000, 0));
+    let start_instant = clock.instant();
+    assert_eq!(clock.now(), NaiveDateTime::from_timestamp(1500000000, 0));
+    assert_eq!(clock.instant(), start_instant);
+
+    clock.advance(StdDuration::from_secs(90));
+    assert_eq!(clock.now(), NaiveDateTime::from_timestamp(1500000090, 0));
+    assert_eq!(clock.instant().duration_since(start_instant), StdDuration::from_secs(90));
+}
@@ -0,0 +1,137 @@
+//! A responder wrapper that gzips large responses for clients that accept it.  Headers set by the wrapped responder,
+//! such as the `ETag` of `CachedJson` which is computed from the uncompressed body, are passed through unchanged.
+
+use std::io::{Cursor, Read};
+
+use flate2::Compression;
+use flate2::read::GzEncoder;
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Body, Responder};
+
+use secret::GZIP_MIN_BYTES;
+
+/// Returns `true` if the value of an `Accept-Encoding` header allows gzip-encoded responses
+pub fn accepts_gzip(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|coding| {
+        let mut parts = coding.split(';').map(str::trim);
+        let name = parts.next().unwrap_or("");
+        if !name.eq_ignore_ascii_case("gzip") && !name.eq_ignore_ascii_case("x-gzip") {
+            return false;
+        }
+
+        // a quality of zero means that the coding isn't acceptable
+        parts.filter(|param| param.starts_with("q="))
+            .all(|param| param[2..].parse::<f32>().map(|q| q > 0.).unwrap_or(false))
+    })
+}
+
+/// Wraps a responder so that its body is gzipped if the client sent `Accept-Encoding: gzip`.  Bodies of a known size
+/// that are smaller than `GZIP_MIN_BYTES` are sent as-is since compressing them saves little; streamed bodies are
+/// always compressed as they're written.
+pub struct Compressed<R>(pub R);
+
+impl<'r, R: Responder<'r>> Responder<'r> for Compressed<R> {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        let mut response = self.0.respond_to(req)?;
+        response.adjoin_raw_header("Vary", "Accept-Encoding");
+        let accepted = req.headers().get("Accept-Encoding").any(accepts_gzip);
+        if !accepted || response.headers().contains("Content-Encoding") {
+            return Ok(response);
+        }
+
+        match response.take_body() {
+            Some(Body::Sized(mut body, _)) => {
+                let mut plain = Vec::new();
+                body.read_to_end(&mut plain).map_err(|err| {
+                    println!("Error while reading response body to compress: {:?}", err);
+                    Status::InternalServerError
+                })?;
+                if plain.len() < GZIP_MIN_BYTES {
+                    response.set_sized_body(Cursor::new(plain));
+                    return Ok(response);
+                }
+
+                let mut compressed = Vec::new();
+                GzEncoder::new(&plain[..], Compression::default()).read_to_end(&mut compressed).map_err(|err| {
+                    println!("Error while compressing response body: {:?}", err);
+                    Status::InternalServerError
+                })?;
+                response.set_sized_body(Cursor::new(compressed));
+            },
+            Some(Body::Chunked(body, chunk_size)) => {
+                response.set_chunked_body(GzEncoder::new(body, Compression::default()), chunk_size);
+            },
+            None => { return Ok(response); },
+        }
+
+        response.set_raw_header("Content-Encoding", "gzip");
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+#[get("/sized/<len>")]
+fn sized_body(len: usize) -> Compressed<::cache::CachedJson<String>> {
+    Compressed(::cache::CachedJson::new("a".repeat(len), 60))
+}
+
+#[cfg(test)]
+#[get("/streamed")]
+fn streamed_body() -> Compressed<::rocket::response::Stream<Cursor<Vec<u8>>>> {
+    Compressed(::rocket::response::Stream::from(Cursor::new("streamed ".repeat(1000).into_bytes())))
+}
+
+#[test]
+fn accept_encoding_parsing() {
+    assert!(accepts_gzip("gzip"));
+    assert!(accepts_gzip("deflate, GZIP;q=0.5, br"));
+    assert!(!accepts_gzip("deflate, br"));
+    assert!(!accepts_gzip("gzip;q=0"));
+    assert!(!accepts_gzip("gzip;q=0.0, identity"));
+    assert!(!accepts_gzip(""));
+}
+
+/// Make sure that compressed responses decompress to the uncompressed ones and keep the uncompressed body's ETag
+#[test]
+fn gzip_responses() {
+    use flate2::read::GzDecoder;
+    use rocket::http::Header;
+    use rocket::local::Client;
+
+    let client = Client::new(::rocket::ignite().mount("/", routes![sized_body, streamed_body])).unwrap();
+    let get = |path: &str, gzip: bool| {
+        let mut req = client.get(path.to_owned());
+        if gzip {
+            req.add_header(Header::new("Accept-Encoding", "gzip, deflate"));
+        }
+        let mut res = req.dispatch();
+        let encoding = res.headers().get_one("Content-Encoding").map(String::from);
+        let vary = res.headers().get_one("Vary").map(String::from);
+        let etag = res.headers().get_one("ETag").map(String::from);
+        (res.body_bytes().unwrap(), encoding, vary, etag)
+    };
+    let gunzip = |bytes: &[u8]| {
+        let mut plain = Vec::new();
+        GzDecoder::new(bytes).read_to_end(&mut plain).unwrap();
+        plain
+    };
+
+    let large = format!("/sized/{}", GZIP_MIN_BYTES * 4);
+    let (plain, encoding, vary, plain_etag) = get(&large, false);
+    assert_eq!((encoding, vary), (None, Some(String::from("Accept-Encoding"))));
+    let (compressed, encoding, _, etag) = get(&large, true);
+    assert_eq!(encoding, Some(String::from("gzip")));
+    assert!(compressed.len() < plain.len());
+    assert_eq!(gunzip(&compressed), plain);
+    assert_eq!(etag, plain_etag);
+
+    // small bodies aren't worth compressing
+    let (small, encoding, _, _) = get("/sized/10", true);
+    assert_eq!((small, encoding), (Vec::from(&b"\"aaaaaaaaaa\""[..]), None));
+
+    let (plain, _, _, _) = get("/streamed", false);
+    let (compressed, encoding, _, _) = get("/streamed", true);
+    assert_eq!(encoding, Some(String::from("gzip")));
+    assert_eq!(gunzip(&compressed), plain);
+}
@@ -0,0 +1,61 @@
+//! Defines the error type returned by the API routes, mapping the different kinds of failures to HTTP status codes
+
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+
+use helpers::{INVALID_BEATMAP_ERR, NON_JSON_RESPONSE_ERR};
+
+/// An error that occured while handling an API request.  Responds with the error message as the body along with a
+/// status code that depends on the kind of error.
+#[derive(Debug)]
+pub enum ApiError {
+    /// The client supplied invalid input.  Responds with a 400.
+    BadInput(String),
+    /// The requested data doesn't exist.  Responds with a 404.
+    NotFound(String),
+    /// The request isn't allowed, such as recording an update for a user who opted out of tracking.  Responds with a
+    /// 403.
+    Forbidden(String),
+    /// Something went wrong while processing the request.  Responds with a 500.
+    Internal(String),
+    /// The osu! API responded with something that couldn't be used, such as an error page during an outage.  Responds
+    /// with a 502.
+    Upstream(String),
+}
+
+impl ApiError {
+    pub fn status(&self) -> Status {
+        match *self {
+            ApiError::BadInput(_) => Status::BadRequest,
+            ApiError::NotFound(_) => Status::NotFound,
+            ApiError::Forbidden(_) => Status::Forbidden,
+            ApiError::Internal(_) => Status::InternalServerError,
+            ApiError::Upstream(_) => Status::BadGateway,
+        }
+    }
+}
+
+impl From<String> for ApiError {
+    fn from(err: String) -> ApiError {
+        if err == NON_JSON_RESPONSE_ERR || err == INVALID_BEATMAP_ERR {
+            ApiError::Upstream(err)
+        } else {
+            ApiError::Internal(err)
+        }
+    }
+}
+
+impl<'r> Responder<'r> for ApiError {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        let status = self.status();
+        let msg = match self {
+            ApiError::BadInput(msg) | ApiError::NotFound(msg) | ApiError::Forbidden(msg) | ApiError::Internal(msg) |
+                ApiError::Upstream(msg) => msg,
+        };
+
+        Response::build_from(msg.respond_to(req)?)
+            .status(status)
+            .ok()
+    }
+}
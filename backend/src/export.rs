@@ -0,0 +1,250 @@
+//! Streaming exports of a user's stored data, either as a single JSON document or as CSV.  The data is loaded from the
+//! database a page at a time as the response is written, so memory usage stays flat no matter how large the user's
+//! history is.
+
+use std::cmp;
+use std::io::{self, Read};
+
+use diesel::mysql::MysqlConnection;
+use r2d2::PooledConnection;
+use r2d2_diesel::ConnectionManager;
+use serde::Serialize;
+use serde_json;
+
+use helpers::{debug, load_updates_page, load_hiscores_page, MYSQL_DATE_FORMAT};
+use models::{Update, User};
+use secret::MAX_EXPORT_BYTES;
+
+/// The number of rows loaded from the database at a time
+const EXPORT_PAGE_SIZE: i64 = 1000;
+
+#[derive(Clone, Copy, PartialEq)]
+enum ExportSection {
+    Updates,
+    Hiscores,
+    Done,
+}
+
+/// A reader that produces a JSON document of the form `{"user": {..}, "updates": [..], "hiscores": [..]}` containing
+/// all of a user's updates and hiscores across all modes.  Meant to be used with Rocket's `Stream` responder.
+pub struct ExportStream {
+    conn: PooledConnection<ConnectionManager<MysqlConnection>>,
+    user_id: i32,
+    section: ExportSection,
+    /// The id of the last row written in the current section, or 0 if none have been written yet
+    last_id: i32,
+    buf: Vec<u8>,
+    buf_pos: usize,
+    bytes_written: usize,
+}
+
+fn to_io_err(err: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+impl ExportStream {
+    pub fn new(conn: PooledConnection<ConnectionManager<MysqlConnection>>, user: &User) -> Result<ExportStream, String> {
+        let mut buf = Vec::from(&b"{\"user\":"[..]);
+        serde_json::to_writer(&mut buf, user).map_err(debug)?;
+        buf.extend_from_slice(b",\"updates\":[");
+
+        Ok(ExportStream {
+            conn: conn,
+            user_id: user.id,
+            section: ExportSection::Updates,
+            last_id: 0,
+            buf: buf,
+            buf_pos: 0,
+            bytes_written: 0,
+        })
+    }
+
+    /// Serializes `rows` into the buffer as JSON array elements
+    fn write_rows<T: Serialize>(&mut self, rows: &[T]) -> Result<(), String> {
+        for (i, row) in rows.iter().enumerate() {
+            if i != 0 || self.last_id != 0 {
+                self.buf.push(b',');
+            }
+            serde_json::to_writer(&mut self.buf, row).map_err(debug)?;
+        }
+
+        Ok(())
+    }
+
+    /// Loads the next page of rows from the database into the buffer, moving on to the next section once the current
+    /// one is exhausted.
+    fn fill_buf(&mut self) -> Result<(), String> {
+        self.buf.clear();
+        self.buf_pos = 0;
+
+        match self.section {
+            ExportSection::Updates => {
+                let page = load_updates_page(&*self.conn, self.user_id, None, self.last_id, EXPORT_PAGE_SIZE)?;
+                match page.last().map(|update| update.id) {
+                    Some(last_id) => {
+                        self.write_rows(&page)?;
+                        self.last_id = last_id;
+                    },
+                    None => {
+                        self.buf.extend_from_slice(b"],\"hiscores\":[");
+                        self.section = ExportSection::Hiscores;
+                        self.last_id = 0;
+                    },
+                }
+            },
+            ExportSection::Hiscores => {
+                let page = load_hiscores_page(&*self.conn, self.user_id, None, self.last_id, EXPORT_PAGE_SIZE)?;
+                match page.last().map(|hiscore| hiscore.id) {
+                    Some(last_id) => {
+                        self.write_rows(&page)?;
+                        self.last_id = last_id;
+                    },
+                    None => {
+                        self.buf.extend_from_slice(b"]}");
+                        self.section = ExportSection::Done;
+                    },
+                }
+            },
+            ExportSection::Done => (),
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for ExportStream {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.buf_pos >= self.buf.len() {
+            if self.section == ExportSection::Done {
+                return Ok(0);
+            }
+            self.fill_buf().map_err(to_io_err)?;
+        }
+
+        let count = cmp::min(out.len(), self.buf.len() - self.buf_pos);
+        out[..count].copy_from_slice(&self.buf[self.buf_pos..self.buf_pos + count]);
+        self.buf_pos += count;
+        self.bytes_written += count;
+
+        if self.bytes_written > MAX_EXPORT_BYTES {
+            println!("Aborting export for user {} after it exceeded {} bytes", self.user_id, MAX_EXPORT_BYTES);
+            return Err(to_io_err(String::from("Export exceeded the maximum allowed size")));
+        }
+
+        Ok(count)
+    }
+}
+
+/// The columns of the CSV export, in order
+const CSV_HEADER: &'static str = "id,mode,count300,count100,count50,playcount,ranked_score,total_score,pp_rank,level,\
+pp_raw,accuracy,count_rank_ss,count_rank_s,count_rank_a,pp_country_rank,update_time,source\n";
+
+/// Formats an update as a row of the CSV export
+fn update_csv_row(update: &Update) -> String {
+    // sources are only ever one of a few fixed identifiers, but quote them defensively
+    let source = match update.source {
+        Some(ref source) if source.contains(|c: char| c == ',' || c == '"' || c == '\n') => {
+            format!("\"{}\"", source.replace('"', "\"\""))
+        },
+        Some(ref source) => source.clone(),
+        None => String::new(),
+    };
+
+    format!(
+        "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}\n",
+        update.id, update.mode, update.count300, update.count100, update.count50, update.playcount,
+        update.ranked_score, update.total_score, update.pp_rank, update.level, update.pp_raw, update.accuracy,
+        update.count_rank_ss, update.count_rank_s, update.count_rank_a,
+        update.pp_country_rank.map(|rank| rank.to_string()).unwrap_or_else(String::new),
+        update.update_time.format(MYSQL_DATE_FORMAT), source
+    )
+}
+
+/// A reader that produces all of a user's updates in a mode as CSV, one row per update preceded by a header row.
+/// Meant to be used with Rocket's `Stream` responder.
+pub struct CsvExportStream {
+    conn: PooledConnection<ConnectionManager<MysqlConnection>>,
+    user_id: i32,
+    mode: u8,
+    /// The id of the last update written, or 0 if none have been written yet
+    last_id: i32,
+    done: bool,
+    buf: Vec<u8>,
+    buf_pos: usize,
+    bytes_written: usize,
+}
+
+impl CsvExportStream {
+    pub fn new(conn: PooledConnection<ConnectionManager<MysqlConnection>>, user_id: i32, mode: u8) -> CsvExportStream {
+        CsvExportStream {
+            conn: conn,
+            user_id: user_id,
+            mode: mode,
+            last_id: 0,
+            done: false,
+            buf: Vec::from(CSV_HEADER.as_bytes()),
+            buf_pos: 0,
+            bytes_written: 0,
+        }
+    }
+
+    /// Loads the next page of updates from the database into the buffer
+    fn fill_buf(&mut self) -> Result<(), String> {
+        self.buf.clear();
+        self.buf_pos = 0;
+
+        let page = load_updates_page(&*self.conn, self.user_id, Some(self.mode), self.last_id, EXPORT_PAGE_SIZE)?;
+        match page.last().map(|update| update.id) {
+            Some(last_id) => {
+                for update in &page {
+                    self.buf.extend_from_slice(update_csv_row(update).as_bytes());
+                }
+                self.last_id = last_id;
+            },
+            None => { self.done = true; },
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for CsvExportStream {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.buf_pos >= self.buf.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.fill_buf().map_err(to_io_err)?;
+        }
+
+        let count = cmp::min(out.len(), self.buf.len() - self.buf_pos);
+        out[..count].copy_from_slice(&self.buf[self.buf_pos..self.buf_pos + count]);
+        self.buf_pos += count;
+        self.bytes_written += count;
+
+        if self.bytes_written > MAX_EXPORT_BYTES {
+            println!("Aborting CSV export for user {} after it exceeded {} bytes", self.user_id, MAX_EXPORT_BYTES);
+            return Err(to_io_err(String::from("Export exceeded the maximum allowed size")));
+        }
+
+        Ok(count)
+    }
+}
+
+#[test]
+fn csv_rows() {
+    use test_support::test_stored_update;
+
+    let mut update = Update { source: Some(String::from("web")), ..test_stored_update(1000.25) };
+    assert_eq!(
+        update_csv_row(&update),
+        "1,0,1000,100,10,50,100000,200000,50000,30.5,1000.25,97.5,1,5,10,1000,2017-07-14 02:40:00,web\n"
+    );
+    // every row has as many columns as the header
+    assert_eq!(update_csv_row(&update).split(',').count(), CSV_HEADER.split(',').count());
+
+    update.source = Some(String::from("a,\"b\""));
+    assert!(update_csv_row(&update).ends_with(",\"a,\"\"b\"\"\"\n"));
+    update.source = None;
+    assert!(update_csv_row(&update).ends_with("2017-07-14 02:40:00,\n"));
+}
@@ -0,0 +1,3 @@
+//! Functions for computing the accuracy of individual plays from their hit counts
+
+pub use osutrack_types::accuracy::{compute, HitCounts};
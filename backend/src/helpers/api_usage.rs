@@ -0,0 +1,239 @@
+//! Counting of the requests made to the osu! API so that operators can see how close the backend is to the API's rate
+//! limit.  Requests made during the current minute are counted with atomics; the counts are moved into the per-minute
+//! history, which is behind a lock, once the minute is over.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use chrono::NaiveDateTime;
+
+use clock::Clock;
+
+/// The osu! API endpoints whose requests are counted separately.  Requests to any other endpoint are counted as
+/// `OTHER_ENDPOINT`.
+pub const API_ENDPOINTS: [&'static str; 4] = ["get_user", "get_user_best", "get_beatmaps", "get_scores"];
+pub const OTHER_ENDPOINT: &'static str = "other";
+/// The number of minutes that request counts are kept for
+const USAGE_WINDOW_MINUTES: i64 = 60;
+/// A warning is logged once the requests made during a minute reach this percentage of the rate limit
+const USAGE_WARNING_PERCENT: u32 = 80;
+
+/// Returns the name of the osu! API endpoint that `url` requests, such as `get_user`, or `OTHER_ENDPOINT` if it isn't
+/// one of the `API_ENDPOINTS`
+pub fn api_endpoint(url: &str) -> &'static str {
+    let path = url.split('?').next().unwrap_or("");
+    let name = path.rsplit('/').next().unwrap_or("");
+    API_ENDPOINTS.iter().find(|&&endpoint| endpoint == name).cloned().unwrap_or(OTHER_ENDPOINT)
+}
+
+/// The index of the counter of `endpoint`; `OTHER_ENDPOINT` is counted after the `API_ENDPOINTS`
+fn endpoint_slot(endpoint: &str) -> usize {
+    API_ENDPOINTS.iter().position(|&known| known == endpoint).unwrap_or(API_ENDPOINTS.len())
+}
+
+fn slot_name(slot: usize) -> &'static str {
+    API_ENDPOINTS.get(slot).cloned().unwrap_or(OTHER_ENDPOINT)
+}
+
+/// The number of requests made to the osu! API during one minute
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MinuteUsage {
+    /// The start of the minute
+    pub minute: NaiveDateTime,
+    pub requests: u32,
+}
+
+/// The number of requests made to one osu! API endpoint
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct EndpointUsage {
+    pub endpoint: &'static str,
+    pub current_minute: u32,
+    pub last_hour: u32,
+}
+
+/// The requests made to the osu! API over the last `USAGE_WINDOW_MINUTES` minutes
+#[derive(Debug, Serialize)]
+pub struct ApiUsageSummary {
+    pub rate_limit_per_minute: u32,
+    /// The number of requests made so far during the current minute
+    pub current_minute: u32,
+    /// `current_minute` as a percentage of the rate limit
+    pub current_minute_percent: f32,
+    pub last_hour: u32,
+    /// The number of requests made to each endpoint, including those with no requests
+    pub endpoints: Vec<EndpointUsage>,
+    /// The number of requests made in each minute, oldest first.  Minutes without any requests are left out.
+    pub minutes: Vec<MinuteUsage>,
+}
+
+/// The requests made during the minute that's currently being counted
+struct CurrentMinute {
+    /// Minutes since the epoch
+    minute: AtomicUsize,
+    /// The number of requests to each endpoint, indexed by `endpoint_slot`
+    counts: Vec<AtomicUsize>,
+}
+
+/// Counts of the requests made to the osu! API in each of the last `USAGE_WINDOW_MINUTES` minutes.  Cloning it produces
+/// a handle to the same counts.
+#[derive(Clone)]
+pub struct ApiUsage {
+    clock: Arc<Clock>,
+    rate_limit_per_minute: u32,
+    current: Arc<CurrentMinute>,
+    /// `(minutes since the epoch, request counts indexed by endpoint slot)` pairs of past minutes, oldest first
+    history: Arc<Mutex<VecDeque<(i64, Vec<u32>)>>>,
+}
+
+impl ApiUsage {
+    pub fn new(clock: Arc<Clock>, rate_limit_per_minute: u32) -> ApiUsage {
+        let minute = clock.now().timestamp() / 60;
+        let current = CurrentMinute {
+            minute: AtomicUsize::new(minute as usize),
+            counts: (0..API_ENDPOINTS.len() + 1).map(|_| AtomicUsize::new(0)).collect(),
+        };
+
+        ApiUsage {
+            clock: clock,
+            rate_limit_per_minute: rate_limit_per_minute,
+            current: Arc::new(current),
+            history: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    fn current_minute(&self) -> i64 {
+        self.clock.now().timestamp() / 60
+    }
+
+    /// Moves the counts of the minute being counted into the history if `minute` is a later one, dropping counts that
+    /// have left the window
+    fn roll_over(&self, minute: i64) {
+        let mut history = self.history.lock().unwrap();
+        let counted_minute = self.current.minute.load(Ordering::SeqCst) as i64;
+        if counted_minute >= minute {
+            return;
+        }
+
+        let counts: Vec<u32> = self.current.counts.iter().map(|count| count.swap(0, Ordering::SeqCst) as u32).collect();
+        if counts.iter().any(|&count| count > 0) {
+            history.push_back((counted_minute, counts));
+        }
+        self.current.minute.store(minute as usize, Ordering::SeqCst);
+
+        while history.front().map(|&(first, _)| first <= minute - USAGE_WINDOW_MINUTES).unwrap_or(false) {
+            history.pop_front();
+        }
+    }
+
+    /// Records a request made to `endpoint` during the current minute, logging a warning if the requests made during
+    /// the minute have reached `USAGE_WARNING_PERCENT` of the rate limit
+    pub fn record(&self, endpoint: &str) {
+        let minute = self.current_minute();
+        if self.current.minute.load(Ordering::Acquire) as i64 != minute {
+            self.roll_over(minute);
+        }
+        self.current.counts[endpoint_slot(endpoint)].fetch_add(1, Ordering::Relaxed);
+
+        let requests: usize = self.current.counts.iter().map(|count| count.load(Ordering::Relaxed)).sum();
+        let warning_threshold = (self.rate_limit_per_minute * USAGE_WARNING_PERCENT + 99) / 100;
+        if warning_threshold > 0 && requests == warning_threshold as usize {
+            warn!(
+                "{} osu! API requests have been made this minute, {}% of the rate limit of {}",
+                requests, USAGE_WARNING_PERCENT, self.rate_limit_per_minute
+            );
+        }
+    }
+
+    pub fn summary(&self) -> ApiUsageSummary {
+        let minute = self.current_minute();
+        self.roll_over(minute);
+
+        let history = self.history.lock().unwrap();
+        let current: Vec<u32> = self.current.counts.iter().map(|count| count.load(Ordering::Relaxed) as u32).collect();
+        let minutes: Vec<(i64, &Vec<u32>)> = history.iter()
+            .filter(|&&(recorded, _)| recorded > minute - USAGE_WINDOW_MINUTES)
+            .map(|&(recorded, ref counts)| (recorded, counts))
+            .chain(if current.iter().any(|&count| count > 0) { Some((minute, &current)) } else { None })
+            .collect();
+
+        let current_total: u32 = current.iter().sum();
+        let endpoints = (0..current.len())
+            .map(|slot| EndpointUsage {
+                endpoint: slot_name(slot),
+                current_minute: current[slot],
+                last_hour: minutes.iter().map(|&(_, counts)| counts[slot]).sum(),
+            })
+            .collect();
+
+        ApiUsageSummary {
+            rate_limit_per_minute: self.rate_limit_per_minute,
+            current_minute: current_total,
+            current_minute_percent: if self.rate_limit_per_minute == 0 {
+                0.
+            } else {
+                current_total as f32 * 100. / self.rate_limit_per_minute as f32
+            },
+            last_hour: minutes.iter().map(|&(_, counts)| counts.iter().sum::<u32>()).sum(),
+            endpoints: endpoints,
+            minutes: minutes.iter()
+                .map(|&(recorded, counts)| MinuteUsage {
+                    minute: NaiveDateTime::from_timestamp(recorded * 60, 0),
+                    requests: counts.iter().sum(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[test]
+fn api_usage_window() {
+    use std::time::Duration;
+    use clock::MockClock;
+
+    let clock = Arc::new(MockClock::new(NaiveDateTime::from_timestamp(1500000000, 0)));
+    let usage = ApiUsage::new(clock.clone(), 60);
+    usage.record("get_user");
+    usage.record("get_user_best");
+    clock.advance(Duration::from_secs(60));
+    usage.record("get_user");
+
+    let summary = usage.summary();
+    assert_eq!((summary.current_minute, summary.last_hour), (1, 3));
+    assert_eq!(summary.minutes.iter().map(|usage| usage.requests).collect::<Vec<_>>(), vec![2, 1]);
+    assert_eq!(summary.minutes[0].minute, NaiveDateTime::from_timestamp(1500000000 / 60 * 60, 0));
+    let get_user = summary.endpoints.iter().find(|usage| usage.endpoint == "get_user").unwrap();
+    assert_eq!((get_user.current_minute, get_user.last_hour), (1, 2));
+
+    // once an hour has passed, the first minute's requests leave the window
+    clock.advance(Duration::from_secs(59 * 60));
+    let summary = usage.summary();
+    assert_eq!((summary.current_minute, summary.last_hour), (0, 1));
+    usage.record("get_scores");
+    assert_eq!(usage.history.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn api_usage_per_endpoint() {
+    use clock::MockClock;
+
+    let clock = Arc::new(MockClock::new(NaiveDateTime::from_timestamp(1500000000, 0)));
+    let usage = ApiUsage::new(clock, 50);
+    for _ in 0..30 {
+        usage.record(api_endpoint("https://osu.ppy.sh/api/get_user?k=REDACTED&u=Ameo"));
+    }
+    for _ in 0..10 {
+        usage.record(api_endpoint("https://osu.ppy.sh/api/get_beatmaps?k=REDACTED&b=22538"));
+    }
+    usage.record(api_endpoint("https://osu.ppy.sh/api/get_match?k=REDACTED&mp=1"));
+
+    let summary = usage.summary();
+    assert_eq!(summary.current_minute, 41);
+    assert_eq!(summary.current_minute_percent, 82.);
+    let counts: Vec<(&str, u32)> = summary.endpoints.iter()
+        .map(|usage| (usage.endpoint, usage.current_minute))
+        .collect();
+    assert_eq!(counts, vec![
+        ("get_user", 30), ("get_user_best", 0), ("get_beatmaps", 10), ("get_scores", 0), ("other", 1),
+    ]);
+}
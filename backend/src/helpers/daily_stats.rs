@@ -0,0 +1,202 @@
+//! Daily aggregates of users' stats, which let long histories be graphed without reading every update.  The aggregate
+//! of the current day is updated whenever an update is recorded, and the aggregates of a user's whole history can be
+//! rebuilt from their updates with `backfill_daily_stats`.
+
+use chrono::{NaiveDate, NaiveDateTime};
+use diesel;
+use diesel::prelude::*;
+use diesel::mysql::MysqlConnection;
+
+use helpers::debug;
+use models::{DailyStats, NewUpdate};
+use schema::daily_stats::dsl as daily_dsl;
+use schema::updates::dsl as updates_dsl;
+
+/// The stats of an update that daily aggregates are made of: `(pp_rank, pp_raw, accuracy, playcount)`
+pub type DailyFields = (i32, f32, f32, i32);
+
+/// Returns the aggregate of `date` after an update with `stats` is recorded on it, given the day's aggregate before the
+/// update and that of the last earlier day with updates.  The stats of the day's last update are kept, and the plays
+/// made since the previous day are accumulated.  The first day of a user's history starts from a delta of zero.
+pub fn next_daily_stats(
+    user_id: i32, mode: i16, date: NaiveDate, stats: DailyFields, today: Option<&DailyStats>,
+    prev_day: Option<&DailyStats>,
+) -> DailyStats {
+    let (pp_rank, pp_raw, accuracy, playcount) = stats;
+    let playcount_delta = match (today, prev_day) {
+        (Some(today), _) => today.playcount_delta + (playcount - today.playcount),
+        (None, Some(prev_day)) => playcount - prev_day.playcount,
+        (None, None) => 0,
+    };
+
+    DailyStats {
+        user_id: user_id,
+        mode: mode,
+        date: date,
+        pp_rank: pp_rank,
+        pp_raw: pp_raw,
+        accuracy: accuracy,
+        playcount: playcount,
+        playcount_delta: playcount_delta,
+    }
+}
+
+/// Folds a user's updates in a mode, given as `(update_time, stats)` in chronological order, into one aggregate per
+/// day in the same way that recording them one at a time would
+pub fn aggregate_updates(user_id: i32, mode: i16, updates: &[(NaiveDateTime, DailyFields)]) -> Vec<DailyStats> {
+    let mut days: Vec<DailyStats> = Vec::new();
+    for &(update_time, stats) in updates {
+        let date = update_time.date();
+        let next = {
+            let (today, prev_day) = match days.last() {
+                Some(last) if last.date == date => (Some(last), days.len().checked_sub(2).map(|i| &days[i])),
+                last => (None, last),
+            };
+            next_daily_stats(user_id, mode, date, stats, today, prev_day)
+        };
+
+        if days.last().map(|last| last.date == date).unwrap_or(false) {
+            days.pop();
+        }
+        days.push(next);
+    }
+
+    days
+}
+
+/// Updates the aggregate of the day of `update_time` with a newly recorded update
+pub fn record_daily_stats(
+    conn: &MysqlConnection, update: &NewUpdate, update_time: NaiveDateTime
+) -> Result<DailyStats, String> {
+    let date = update_time.date();
+    let user_days = || daily_dsl::daily_stats
+        .filter(daily_dsl::user_id.eq(update.user_id))
+        .filter(daily_dsl::mode.eq(update.mode));
+
+    let today: Option<DailyStats> = user_days()
+        .filter(daily_dsl::date.eq(date))
+        .first(conn)
+        .optional()
+        .map_err(debug)?;
+    let prev_day: Option<DailyStats> = user_days()
+        .filter(daily_dsl::date.lt(date))
+        .order(daily_dsl::date.desc())
+        .first(conn)
+        .optional()
+        .map_err(debug)?;
+
+    let stats = (update.pp_rank, update.pp_raw, update.accuracy, update.playcount);
+    let next = next_daily_stats(update.user_id, update.mode, date, stats, today.as_ref(), prev_day.as_ref());
+    diesel::replace_into(daily_dsl::daily_stats).values(&next).execute(conn).map_err(debug)?;
+
+    Ok(next)
+}
+
+/// Rebuilds all of a user's daily aggregates in a mode from their updates, returning the number of days aggregated
+pub fn backfill_daily_stats(conn: &MysqlConnection, user_id: i32, mode: i16) -> Result<usize, String> {
+    conn.transaction::<_, diesel::result::Error, _>(|| rebuild_daily_stats(conn, user_id, mode)).map_err(debug)
+}
+
+/// Does the work of `backfill_daily_stats` without a transaction of its own, for callers that rebuild the aggregates
+/// as part of a larger transaction
+pub fn rebuild_daily_stats(conn: &MysqlConnection, user_id: i32, mode: i16) -> QueryResult<usize> {
+    let updates: Vec<(NaiveDateTime, i32, f32, f32, i32)> = updates_dsl::updates
+        .filter(updates_dsl::user_id.eq(user_id))
+        .filter(updates_dsl::mode.eq(mode))
+        .order((updates_dsl::update_time.asc(), updates_dsl::id.asc()))
+        .select((
+            updates_dsl::update_time, updates_dsl::pp_rank, updates_dsl::pp_raw, updates_dsl::accuracy,
+            updates_dsl::playcount,
+        ))
+        .load(conn)?;
+    let updates: Vec<(NaiveDateTime, DailyFields)> = updates.into_iter()
+        .map(|(update_time, pp_rank, pp_raw, accuracy, playcount)| {
+            (update_time, (pp_rank, pp_raw, accuracy, playcount))
+        })
+        .collect();
+    let days = aggregate_updates(user_id, mode, &updates);
+
+    diesel::delete(daily_dsl::daily_stats.filter(daily_dsl::user_id.eq(user_id)).filter(daily_dsl::mode.eq(mode)))
+        .execute(conn)?;
+    if !days.is_empty() {
+        diesel::insert_into(daily_dsl::daily_stats).values(&days).execute(conn)?;
+    }
+
+    Ok(days.len())
+}
+
+/// Loads a user's daily aggregates in a mode between `from` and `to` (inclusive), oldest first
+pub fn load_daily_stats(
+    conn: &MysqlConnection, user_id: i32, mode: u8, from: Option<NaiveDate>, to: Option<NaiveDate>
+) -> Result<Vec<DailyStats>, String> {
+    let mut query = daily_dsl::daily_stats
+        .filter(daily_dsl::user_id.eq(user_id))
+        .filter(daily_dsl::mode.eq(mode as i16))
+        .into_boxed();
+    if let Some(from) = from {
+        query = query.filter(daily_dsl::date.ge(from));
+    }
+    if let Some(to) = to {
+        query = query.filter(daily_dsl::date.le(to));
+    }
+
+    query.order(daily_dsl::date.asc()).load(conn).map_err(debug)
+}
+
+#[test]
+fn daily_aggregation() {
+    use chrono::Datelike;
+
+    let day = |d: u32, h: u32| NaiveDate::from_ymd(2017, 12, d).and_hms(h, 0, 0);
+    let updates = vec![
+        (day(10, 9), (5000, 1000., 97., 100)), (day(10, 23), (4900, 1010., 97.1, 110)),
+        // just past midnight starts a new day
+        (day(11, 0), (4800, 1020., 97.2, 115)), (day(11, 12), (4700, 1030., 97.3, 130)),
+        // days without updates are skipped and the delta covers the whole gap
+        (day(14, 8), (4600, 1040., 97.4, 160)),
+    ];
+    let days = aggregate_updates(-1, 0, &updates);
+
+    let summary: Vec<(u32, i32, f32, i32, i32)> = days.iter()
+        .map(|day| (day.date.day0() + 1, day.pp_rank, day.pp_raw, day.playcount, day.playcount_delta))
+        .collect();
+    assert_eq!(summary, vec![
+        (10, 4900, 1010., 110, 10), (11, 4700, 1030., 130, 20), (14, 4600, 1040., 160, 30),
+    ]);
+}
+
+/// Make sure that recording updates one at a time across midnight produces the same aggregates as a backfill
+#[test]
+fn daily_stats_recording() {
+    use helpers::create_db_pool;
+    use test_support::test_update;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    let update = |pp_rank: i32, pp_raw: f32, playcount: i32| NewUpdate {
+        user_id: -1, pp_rank: pp_rank, playcount: playcount, ..test_update(pp_raw)
+    };
+    let day = |d: u32, h: u32, m: u32| NaiveDate::from_ymd(2017, 12, d).and_hms(h, m, 0);
+    let recorded = vec![
+        (update(5000, 1000., 100), day(10, 20, 0)), (update(4900, 1010., 110), day(10, 23, 59)),
+        (update(4800, 1020., 115), day(11, 0, 1)), (update(4700, 1030., 130), day(11, 18, 0)),
+    ];
+    for &(ref update, time) in &recorded {
+        record_daily_stats(conn, update, time).unwrap();
+    }
+
+    let days = load_daily_stats(conn, -1, 0, None, None).unwrap();
+    let summary: Vec<(i32, i32, i32)> = days.iter()
+        .map(|day| (day.pp_rank, day.playcount, day.playcount_delta))
+        .collect();
+    assert_eq!(summary, vec![(4900, 110, 10), (4700, 130, 20)]);
+    assert_eq!(load_daily_stats(conn, -1, 0, Some(day(11, 0, 0).date()), None).unwrap(), days[1..].to_vec());
+    assert_eq!(load_daily_stats(conn, -1, 0, None, Some(day(10, 0, 0).date())).unwrap(), days[..1].to_vec());
+
+    let updates: Vec<(NaiveDateTime, DailyFields)> = recorded.iter()
+        .map(|&(ref update, time)| (time, (update.pp_rank, update.pp_raw, update.accuracy, update.playcount)))
+        .collect();
+    assert_eq!(aggregate_updates(-1, 0, &updates), days);
+}
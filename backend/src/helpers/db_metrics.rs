@@ -0,0 +1,344 @@
+//! Instrumentation of the database pool so that operators can tell pool starvation apart from slow queries.  The time
+//! spent waiting for connections is recorded whenever one is checked out, and routes record the total time that they
+//! hold connections with a `DbTimer`.  Route times are kept both as running totals and per minute so that the slowest
+//! routes of the last hour can be reported.
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use clock::Clock;
+
+/// A warning is logged whenever checking a connection out of the pool takes at least this many milliseconds
+const SLOW_CHECKOUT_WARNING_MS: u64 = 250;
+/// The number of minutes that per-minute route times are kept for
+const ROUTE_TIME_WINDOW_MINUTES: i64 = 60;
+/// The number of routes listed in `DbStatus::slowest_routes`
+const SLOWEST_ROUTE_COUNT: usize = 10;
+
+fn micros(duration: Duration) -> usize {
+    duration.as_secs() as usize * 1_000_000 + duration.subsec_nanos() as usize / 1_000
+}
+
+/// The number of times that a route was timed and the time that it spent on the database
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct RouteTotals {
+    requests: u32,
+    total_micros: u64,
+    max_micros: u64,
+}
+
+impl RouteTotals {
+    fn add(&mut self, elapsed_micros: u64) {
+        self.requests += 1;
+        self.total_micros += elapsed_micros;
+        self.max_micros = self.max_micros.max(elapsed_micros);
+    }
+
+    fn merge(&mut self, other: &RouteTotals) {
+        self.requests += other.requests;
+        self.total_micros += other.total_micros;
+        self.max_micros = self.max_micros.max(other.max_micros);
+    }
+}
+
+/// The number of connections in the pool, as reported by r2d2
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct PoolState {
+    pub max_size: u32,
+    pub connections: u32,
+    pub idle_connections: u32,
+    pub in_use_connections: u32,
+}
+
+/// The connections checked out of the pool since the backend started
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct CheckoutStats {
+    pub checkouts: u64,
+    pub total_wait_ms: f64,
+    pub max_wait_ms: f64,
+    /// The number of checkouts that took at least `SLOW_CHECKOUT_WARNING_MS`
+    pub slow_checkouts: u64,
+}
+
+/// The time that one route spent on the database during the last hour
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RouteDbTime {
+    pub route: &'static str,
+    pub requests: u32,
+    pub mean_ms: f64,
+    pub max_ms: f64,
+    pub total_ms: f64,
+}
+
+/// The state of the database pool along with the routes that have been spending the most time on the database
+#[derive(Debug, Serialize)]
+pub struct DbStatus {
+    pub pool: PoolState,
+    pub checkouts: CheckoutStats,
+    /// Up to `SLOWEST_ROUTE_COUNT` routes with the highest mean database time over the last hour, slowest first
+    pub slowest_routes: Vec<RouteDbTime>,
+}
+
+struct Counters {
+    checkouts: AtomicUsize,
+    checkout_wait_micros: AtomicUsize,
+    max_checkout_wait_micros: AtomicUsize,
+    slow_checkouts: AtomicUsize,
+    /// Totals of each route since the backend started
+    route_totals: Mutex<HashMap<&'static str, RouteTotals>>,
+    /// Totals of each route in each minute, keyed by `(minutes since the epoch, route)`
+    route_minutes: Mutex<BTreeMap<(i64, &'static str), RouteTotals>>,
+}
+
+/// Counters of the database pool's checkouts and of the time that routes spend on the database.  Cloning it produces a
+/// handle to the same counters.
+#[derive(Clone)]
+pub struct DbMetrics {
+    clock: Arc<Clock>,
+    counters: Arc<Counters>,
+}
+
+impl DbMetrics {
+    pub fn new(clock: Arc<Clock>) -> DbMetrics {
+        let counters = Counters {
+            checkouts: AtomicUsize::new(0),
+            checkout_wait_micros: AtomicUsize::new(0),
+            max_checkout_wait_micros: AtomicUsize::new(0),
+            slow_checkouts: AtomicUsize::new(0),
+            route_totals: Mutex::new(HashMap::new()),
+            route_minutes: Mutex::new(BTreeMap::new()),
+        };
+
+        DbMetrics { clock: clock, counters: Arc::new(counters) }
+    }
+
+    pub fn instant(&self) -> Instant {
+        self.clock.instant()
+    }
+
+    /// Records a connection checkout that had to wait `wait` for a connection, logging a warning if that took at least
+    /// `SLOW_CHECKOUT_WARNING_MS`
+    pub fn record_checkout(&self, wait: Duration, pool_state: PoolState) {
+        let wait_micros = micros(wait);
+        self.counters.checkouts.fetch_add(1, Ordering::Relaxed);
+        self.counters.checkout_wait_micros.fetch_add(wait_micros, Ordering::Relaxed);
+        let mut max = self.counters.max_checkout_wait_micros.load(Ordering::Relaxed);
+        while wait_micros > max {
+            match self.counters.max_checkout_wait_micros.compare_exchange_weak(
+                max, wait_micros, Ordering::Relaxed, Ordering::Relaxed
+            ) {
+                Ok(_) => break,
+                Err(cur) => { max = cur; },
+            }
+        }
+
+        if wait >= Duration::from_millis(SLOW_CHECKOUT_WARNING_MS) {
+            self.counters.slow_checkouts.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "Waited {} ms for a database connection; {} of {} connections are in use",
+                wait_micros / 1000, pool_state.in_use_connections, pool_state.max_size
+            );
+        }
+    }
+
+    /// Records that a request to `route` spent `elapsed` on the database
+    pub fn record_route_time(&self, route: &'static str, elapsed: Duration) {
+        let elapsed_micros = micros(elapsed) as u64;
+        let minute = self.clock.now().timestamp() / 60;
+
+        let mut route_totals = self.counters.route_totals.lock().unwrap();
+        route_totals.entry(route).or_insert_with(RouteTotals::default).add(elapsed_micros);
+        let mut route_minutes = self.counters.route_minutes.lock().unwrap();
+        route_minutes.entry((minute, route)).or_insert_with(RouteTotals::default).add(elapsed_micros);
+        // drop the minutes that have left the window
+        let kept = route_minutes.split_off(&(minute - ROUTE_TIME_WINDOW_MINUTES + 1, ""));
+        *route_minutes = kept;
+    }
+
+    /// Starts timing the database work of a request to `route`, which is recorded once the returned timer is dropped
+    pub fn time_route(&self, route: &'static str) -> DbTimer {
+        DbTimer { metrics: self.clone(), route: route, held: Cell::new(Duration::from_secs(0)) }
+    }
+
+    pub fn checkout_stats(&self) -> CheckoutStats {
+        let ms = |counter: &AtomicUsize| counter.load(Ordering::Relaxed) as f64 / 1000.;
+        CheckoutStats {
+            checkouts: self.counters.checkouts.load(Ordering::Relaxed) as u64,
+            total_wait_ms: ms(&self.counters.checkout_wait_micros),
+            max_wait_ms: ms(&self.counters.max_checkout_wait_micros),
+            slow_checkouts: self.counters.slow_checkouts.load(Ordering::Relaxed) as u64,
+        }
+    }
+
+    /// Returns the `SLOWEST_ROUTE_COUNT` routes with the highest mean database time over the last hour, slowest first
+    pub fn slowest_routes(&self) -> Vec<RouteDbTime> {
+        let minute = self.clock.now().timestamp() / 60;
+        let mut by_route: HashMap<&'static str, RouteTotals> = HashMap::new();
+        for (&(_, route), totals) in self.counters.route_minutes.lock().unwrap()
+            .range((minute - ROUTE_TIME_WINDOW_MINUTES + 1, "")..)
+        {
+            by_route.entry(route).or_insert_with(RouteTotals::default).merge(totals);
+        }
+
+        let mut routes: Vec<RouteDbTime> = by_route.into_iter()
+            .map(|(route, totals)| RouteDbTime {
+                route: route,
+                requests: totals.requests,
+                mean_ms: totals.total_micros as f64 / totals.requests as f64 / 1000.,
+                max_ms: totals.max_micros as f64 / 1000.,
+                total_ms: totals.total_micros as f64 / 1000.,
+            })
+            .collect();
+        routes.sort_by(|a, b| b.mean_ms.partial_cmp(&a.mean_ms).unwrap().then(a.route.cmp(b.route)));
+        routes.truncate(SLOWEST_ROUTE_COUNT);
+        routes
+    }
+
+    pub fn status(&self, pool_state: PoolState) -> DbStatus {
+        DbStatus { pool: pool_state, checkouts: self.checkout_stats(), slowest_routes: self.slowest_routes() }
+    }
+
+    /// Renders the pool's state and all of the counters in the Prometheus text exposition format
+    pub fn render_prometheus(&self, pool_state: PoolState) -> String {
+        let mut out = String::new();
+        {
+            let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, f64)]| {
+                let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+                for &(ref labels, value) in samples {
+                    let _ = writeln!(out, "{}{} {}", name, labels, value);
+                }
+            };
+            let unlabeled = |value: f64| vec![(String::new(), value)];
+
+            metric("osutrack_db_pool_max_size", "gauge", "The maximum number of connections in the pool",
+                &unlabeled(pool_state.max_size as f64));
+            metric("osutrack_db_pool_connections", "gauge", "The number of open connections in the pool",
+                &unlabeled(pool_state.connections as f64));
+            metric("osutrack_db_pool_idle_connections", "gauge", "The number of idle connections in the pool",
+                &unlabeled(pool_state.idle_connections as f64));
+            metric("osutrack_db_pool_in_use_connections", "gauge", "The number of connections checked out of the pool",
+                &unlabeled(pool_state.in_use_connections as f64));
+
+            let checkouts = self.checkout_stats();
+            metric("osutrack_db_checkouts_total", "counter", "The number of connections checked out of the pool",
+                &unlabeled(checkouts.checkouts as f64));
+            metric("osutrack_db_checkout_wait_seconds_total", "counter", "The time spent waiting for connections",
+                &unlabeled(checkouts.total_wait_ms / 1000.));
+            metric("osutrack_db_checkout_wait_seconds_max", "gauge", "The longest wait for a connection",
+                &unlabeled(checkouts.max_wait_ms / 1000.));
+            metric("osutrack_db_slow_checkouts_total", "counter", "The number of slow connection checkouts",
+                &unlabeled(checkouts.slow_checkouts as f64));
+
+            let mut route_totals: Vec<(&'static str, RouteTotals)> = self.counters.route_totals.lock().unwrap()
+                .iter()
+                .map(|(&route, &totals)| (route, totals))
+                .collect();
+            route_totals.sort_by_key(|&(route, _)| route);
+            let labeled = |value: &Fn(&RouteTotals) -> f64| -> Vec<(String, f64)> {
+                route_totals.iter()
+                    .map(|&(route, ref totals)| (format!("{{route=\"{}\"}}", route), value(totals)))
+                    .collect()
+            };
+            metric("osutrack_db_route_requests_total", "counter", "The number of timed requests to each route",
+                &labeled(&|totals| totals.requests as f64));
+            metric("osutrack_db_route_seconds_total", "counter", "The time that each route has spent on the database",
+                &labeled(&|totals| totals.total_micros as f64 / 1_000_000.));
+        }
+
+        out
+    }
+}
+
+/// Adds up the time that a request to a route holds connections checked out with `DbPool::get_timed_conn` and records
+/// it as the route's database time once it's dropped.  Routes that query the osu! API don't hold a connection while
+/// they wait on it, so that time isn't counted.
+pub struct DbTimer {
+    metrics: DbMetrics,
+    route: &'static str,
+    held: Cell<Duration>,
+}
+
+impl DbTimer {
+    /// Returns the time that a connection was checked out at, to be passed to `finish` once it's returned
+    pub fn start(&self) -> Instant {
+        self.metrics.instant()
+    }
+
+    /// Adds the time since `start` to the time that connections were held for
+    pub fn finish(&self, start: Instant) {
+        self.held.set(self.held.get() + self.metrics.instant().duration_since(start));
+    }
+}
+
+impl Drop for DbTimer {
+    fn drop(&mut self) {
+        self.metrics.record_route_time(self.route, self.held.get());
+    }
+}
+
+#[cfg(test)]
+fn test_pool_state() -> PoolState {
+    PoolState { max_size: 10, connections: 4, idle_connections: 1, in_use_connections: 3 }
+}
+
+#[test]
+fn route_time_window() {
+    use chrono::NaiveDateTime;
+    use clock::MockClock;
+
+    let clock = Arc::new(MockClock::new(NaiveDateTime::from_timestamp(1500000000, 0)));
+    let metrics = DbMetrics::new(clock.clone());
+    {
+        // only the time that connections are held for counts
+        let timer = metrics.time_route("get_updates");
+        let start = timer.start();
+        clock.advance(Duration::from_millis(20));
+        timer.finish(start);
+        clock.advance(Duration::from_secs(1));
+        let start = timer.start();
+        clock.advance(Duration::from_millis(10));
+        timer.finish(start);
+    }
+    metrics.record_route_time("get_stats", Duration::from_millis(5));
+    metrics.record_route_time("get_updates", Duration::from_millis(10));
+
+    let slowest = metrics.slowest_routes();
+    assert_eq!(slowest.iter().map(|route| (route.route, route.requests)).collect::<Vec<_>>(), vec![
+        ("get_updates", 2), ("get_stats", 1),
+    ]);
+    assert_eq!((slowest[0].mean_ms, slowest[0].max_ms, slowest[0].total_ms), (20., 30., 40.));
+
+    // once an hour has passed, the earlier requests leave the window but still count towards the totals
+    clock.advance(Duration::from_secs(60 * 60));
+    metrics.record_route_time("get_stats", Duration::from_millis(1));
+    let slowest = metrics.slowest_routes();
+    assert_eq!(slowest.iter().map(|route| (route.route, route.requests)).collect::<Vec<_>>(), vec![("get_stats", 1)]);
+    assert_eq!(metrics.counters.route_minutes.lock().unwrap().len(), 1);
+    let rendered = metrics.render_prometheus(test_pool_state());
+    assert!(rendered.contains("osutrack_db_route_requests_total{route=\"get_stats\"} 2\n"), "{}", rendered);
+    assert!(rendered.contains("osutrack_db_route_requests_total{route=\"get_updates\"} 2\n"), "{}", rendered);
+}
+
+#[test]
+fn checkout_accounting() {
+    use chrono::NaiveDateTime;
+    use clock::MockClock;
+
+    let metrics = DbMetrics::new(Arc::new(MockClock::new(NaiveDateTime::from_timestamp(1500000000, 0))));
+    metrics.record_checkout(Duration::from_millis(2), test_pool_state());
+    metrics.record_checkout(Duration::from_millis(SLOW_CHECKOUT_WARNING_MS + 50), test_pool_state());
+    metrics.record_checkout(Duration::from_millis(8), test_pool_state());
+
+    let stats = metrics.checkout_stats();
+    assert_eq!(stats, CheckoutStats {
+        checkouts: 3, total_wait_ms: (SLOW_CHECKOUT_WARNING_MS + 60) as f64,
+        max_wait_ms: (SLOW_CHECKOUT_WARNING_MS + 50) as f64, slow_checkouts: 1,
+    });
+    let rendered = metrics.render_prometheus(test_pool_state());
+    assert!(rendered.contains("# TYPE osutrack_db_checkouts_total counter\nosutrack_db_checkouts_total 3\n"));
+    assert!(rendered.contains("osutrack_db_pool_in_use_connections 3\n"));
+}
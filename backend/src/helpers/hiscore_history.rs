@@ -0,0 +1,139 @@
+//! Reconstruction of how the pp of a user's top plays has changed over time from the times that their hiscores were
+//! recorded
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use diesel::mysql::MysqlConnection;
+
+use helpers::debug;
+use schema::hiscores::dsl as hiscores_dsl;
+use schema::updates::dsl as updates_dsl;
+
+/// The positions in a user's top plays whose pp is tracked, 1-indexed
+pub const TRACKED_POSITIONS: [usize; 3] = [1, 50, 100];
+
+/// The pp of a user's top plays at one point in time: `[unix_timestamp, best_pp, 50th_pp, 100th_pp]`.  Positions that
+/// the user didn't have a play in yet are `null`.
+pub type HiscoreHistoryPoint = (i64, Option<f32>, Option<f32>, Option<f32>);
+
+/// For each of the update times, finds the pp of the plays at the `TRACKED_POSITIONS` among the hiscores that had been
+/// recorded at or before that time.  Only the best play on each beatmap counts, so a play that improves on an earlier
+/// one on the same beatmap replaces it.  `update_times` and `hiscores` (`(time_recorded, beatmap_id, pp)` triples) must
+/// both be sorted in ascending order of time.
+pub fn reconstruct_hiscore_history(
+    update_times: &[NaiveDateTime], hiscores: &[(NaiveDateTime, i32, f32)]
+) -> Vec<HiscoreHistoryPoint> {
+    let max_position = TRACKED_POSITIONS[TRACKED_POSITIONS.len() - 1];
+    // the pp of the best play recorded so far on each beatmap
+    let mut best_pp: HashMap<i32, f32> = HashMap::new();
+    // the pp of the best `max_position` of those plays, in descending order
+    let mut top_pp: Vec<f32> = Vec::with_capacity(max_position);
+    let mut remaining = hiscores.iter().peekable();
+
+    update_times.iter().map(|&update_time| {
+        let mut changed = false;
+        while let Some(&&(time_recorded, beatmap_id, pp)) = remaining.peek() {
+            if time_recorded > update_time {
+                break;
+            }
+            remaining.next();
+
+            let best = best_pp.entry(beatmap_id).or_insert(pp);
+            if pp >= *best {
+                *best = pp;
+                changed = true;
+            }
+        }
+
+        if changed {
+            top_pp = best_pp.values().cloned().collect();
+            top_pp.sort_by(|a, b| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+            top_pp.truncate(max_position);
+        }
+
+        let at = |position: usize| top_pp.get(position - 1).cloned();
+        (update_time.timestamp(), at(TRACKED_POSITIONS[0]), at(TRACKED_POSITIONS[1]), at(TRACKED_POSITIONS[2]))
+    }).collect()
+}
+
+/// Loads the data needed to reconstruct a user's hiscore history in a mode: the times of their updates and the
+/// `(time_recorded, beatmap_id, pp)` triples of their hiscores, both in ascending order of time
+pub fn load_hiscore_history_data(
+    conn: &MysqlConnection, user_id: i32, mode: u8
+) -> Result<(Vec<NaiveDateTime>, Vec<(NaiveDateTime, i32, f32)>), String> {
+    let update_times = updates_dsl::updates
+        .filter(updates_dsl::user_id.eq(user_id))
+        .filter(updates_dsl::mode.eq(mode as i16))
+        .order(updates_dsl::update_time.asc())
+        .select(updates_dsl::update_time)
+        .load(conn)
+        .map_err(debug)?;
+    let hiscores = hiscores_dsl::hiscores
+        .filter(hiscores_dsl::user_id.eq(user_id))
+        .filter(hiscores_dsl::mode.eq(mode as i16))
+        .order(hiscores_dsl::time_recorded.asc())
+        .select((hiscores_dsl::time_recorded, hiscores_dsl::beatmap_id, hiscores_dsl::pp))
+        .load(conn)
+        .map_err(debug)?;
+
+    Ok((update_times, hiscores))
+}
+
+#[cfg(test)]
+fn time(secs: i64) -> NaiveDateTime {
+    NaiveDateTime::from_timestamp(1500000000 + secs, 0)
+}
+
+#[test]
+fn hiscore_history_reconstruction() {
+    // 120 plays recorded at the first update, with pp 1 through 120
+    let mut hiscores: Vec<(NaiveDateTime, i32, f32)> = (1..121).map(|pp| (time(0), pp, pp as f32)).collect();
+    // a new best play and one that only makes it into the top 100 before the second update
+    hiscores.push((time(50), 500, 500.));
+    hiscores.push((time(60), 501, 30.5));
+    // recorded after the last update, so never counted
+    hiscores.push((time(500), 502, 1000.));
+
+    let history = reconstruct_hiscore_history(&[time(0), time(100)], &hiscores);
+    assert_eq!(history, vec![
+        (time(0).timestamp(), Some(120.), Some(71.), Some(21.)),
+        (time(100).timestamp(), Some(500.), Some(72.), Some(23.)),
+    ]);
+}
+
+#[test]
+fn sparse_hiscore_history() {
+    let hiscores = vec![(time(10), 1, 100.), (time(20), 2, 200.)];
+    let history = reconstruct_hiscore_history(&[time(0), time(10), time(30)], &hiscores);
+    assert_eq!(history, vec![
+        (time(0).timestamp(), None, None, None),
+        (time(10).timestamp(), Some(100.), None, None),
+        (time(30).timestamp(), Some(200.), None, None),
+    ]);
+
+    assert_eq!(reconstruct_hiscore_history(&[], &hiscores), Vec::new());
+}
+
+/// A better play on a beatmap replaces the earlier one instead of being counted alongside it, while a worse one is
+/// ignored
+#[test]
+fn replaced_hiscore_history() {
+    // 49 plays, so there's no 50th play until one of them is counted twice
+    let mut hiscores: Vec<(NaiveDateTime, i32, f32)> = (100..147)
+        .map(|beatmap_id| (time(0), beatmap_id, 50.))
+        .collect();
+    hiscores.push((time(0), 1, 20.));
+    hiscores.push((time(0), 2, 150.));
+    hiscores.push((time(10), 1, 200.));
+    hiscores.push((time(20), 2, 120.));
+
+    let history = reconstruct_hiscore_history(&[time(0), time(10), time(20)], &hiscores);
+    assert_eq!(history, vec![
+        (time(0).timestamp(), Some(150.), None, None),
+        (time(10).timestamp(), Some(200.), None, None),
+        (time(20).timestamp(), Some(200.), None, None),
+    ]);
+}
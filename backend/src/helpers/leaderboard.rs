@@ -0,0 +1,115 @@
+//! The leaderboard of tracked users ranked by how much pp they have gained over a window of recent days
+
+use chrono::{Duration, NaiveDateTime};
+use diesel;
+use diesel::prelude::*;
+use diesel::mysql::MysqlConnection;
+use diesel::types::{BigInt, Float, Integer, SmallInt, Timestamp};
+
+use helpers::debug;
+
+/// The number of days that pp gain is measured over if no window is supplied
+pub const DEFAULT_PP_GAIN_DAYS: u16 = 7;
+/// The longest window that pp gain can be measured over
+pub const MAX_PP_GAIN_DAYS: u16 = 365;
+
+/// Selects `(user_id, gain)` for every user with at least two updates in a mode since a time, where `gain` is the
+/// difference between the pp of their last and first updates since then.  Binds the mode and then the time.
+const PP_GAINS_QUERY: &'static str = "SELECT w.user_id AS user_id, l.pp_raw - f.pp_raw AS gain FROM (
+        SELECT user_id, MIN(id) AS first_id, MAX(id) AS last_id FROM updates
+        WHERE mode = ? AND update_time >= ? GROUP BY user_id HAVING COUNT(*) >= 2
+    ) w
+    INNER JOIN updates f ON f.id = w.first_id
+    INNER JOIN updates l ON l.id = w.last_id";
+
+/// A user's position on the pp gain leaderboard
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PpGainRank {
+    /// 1 plus the number of users who gained more pp than the user
+    pub rank: i64,
+    /// The number of users with enough updates in the window to be on the leaderboard
+    pub total_users: i64,
+    pub gain: f32,
+}
+
+#[derive(QueryableByName)]
+struct RankRow {
+    #[sql_type = "Float"]
+    gain: f32,
+    #[sql_type = "BigInt"]
+    total_users: i64,
+    #[sql_type = "BigInt"]
+    above: i64,
+}
+
+/// Returns the time that a window of `days` days ending at `now` starts at
+pub fn window_start(now: NaiveDateTime, days: u16) -> NaiveDateTime {
+    now - Duration::days(days as i64)
+}
+
+/// Returns a user's position on the leaderboard of pp gained in a mode since `since`, or `None` if they have fewer than
+/// two updates since then.  Users who gained the same amount of pp share a rank.
+pub fn get_pp_gain_rank(
+    conn: &MysqlConnection, user_id: i32, mode: u8, since: NaiveDateTime
+) -> Result<Option<PpGainRank>, String> {
+    // the user's gain is compared against the other gains within the query so that it's never rounded on the way out
+    let rows: Vec<RankRow> = diesel::sql_query(format!(
+        "SELECT u.gain AS gain, COUNT(*) AS total_users, CAST(COALESCE(SUM(g.gain > u.gain), 0) AS SIGNED) AS above
+        FROM ({}) g INNER JOIN ({}) u ON u.user_id = ? GROUP BY u.user_id, u.gain",
+        PP_GAINS_QUERY, PP_GAINS_QUERY
+    )).bind::<SmallInt, _>(mode as i16)
+        .bind::<Timestamp, _>(since)
+        .bind::<SmallInt, _>(mode as i16)
+        .bind::<Timestamp, _>(since)
+        .bind::<Integer, _>(user_id)
+        .load(conn)
+        .map_err(debug)?;
+
+    Ok(rows.into_iter().next().map(|row| {
+        PpGainRank { rank: row.above + 1, total_users: row.total_users, gain: row.gain }
+    }))
+}
+
+/// Make sure that users are ranked by the pp gained between their first and last updates in the window, that ties share
+/// a rank, and that users without enough updates are left off
+#[test]
+fn pp_gain_rank() {
+    use clock::{Clock, SystemClock};
+    use helpers::{create_db_pool, ensure_user};
+    use models::NewUpdate;
+    use schema::updates::dsl as updates_dsl;
+    use test_support::test_update;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    let history = [
+        (-1, vec![1000., 1040., 1100.]), (-2, vec![1000., 1050.]), (-3, vec![1000., 1200.]), (-4, vec![900.]),
+        (-5, vec![1000., 1200.]), (-6, vec![0.1, 1000000.3]),
+    ];
+    for &(user_id, ref pps) in history.iter() {
+        ensure_user(conn, user_id, &format!("Gainer {}", -user_id)).unwrap();
+        for &pp in pps {
+            let update = NewUpdate { user_id: user_id, ..test_update(pp) };
+            diesel::insert_into(updates_dsl::updates).values(&update).execute(conn).unwrap();
+        }
+    }
+
+    let since = window_start(SystemClock.now(), DEFAULT_PP_GAIN_DAYS);
+    let ranks: Vec<PpGainRank> = [-3, -1, -2].iter()
+        .map(|&user_id| get_pp_gain_rank(conn, user_id, 0, since).unwrap().unwrap())
+        .collect();
+    assert_eq!(ranks.iter().map(|rank| rank.gain).collect::<Vec<_>>(), vec![200., 100., 50.]);
+    // other tracked users may have gained pp in the window as well, so only the relative ranks are known
+    assert!(ranks[0].rank < ranks[1].rank && ranks[1].rank < ranks[2].rank);
+    assert!(ranks.iter().all(|rank| rank.total_users == ranks[0].total_users && rank.total_users >= 3));
+
+    // users with the same gain share a rank
+    assert_eq!(get_pp_gain_rank(conn, -5, 0, since).unwrap().unwrap().rank, ranks[0].rank);
+    // a gain that isn't exactly representable as an `f32` isn't counted as more than itself
+    assert_eq!(get_pp_gain_rank(conn, -6, 0, since).unwrap().unwrap().rank, 1);
+
+    assert_eq!(get_pp_gain_rank(conn, -4, 0, since).unwrap(), None);
+    assert_eq!(get_pp_gain_rank(conn, -1, 1, since).unwrap(), None);
+}
@@ -0,0 +1,204 @@
+//! A small least-recently-used cache with a time-to-live, used to avoid repeating identical requests to the osu! API
+//! within a short period of time.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use clock::{Clock, SystemClock};
+
+struct Entry<V> {
+    value: V,
+    inserted: Instant,
+    /// The value of the cache's access counter when this entry was last read or written
+    last_used: u64,
+}
+
+/// The number of lookups that a cache has served since it was created
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub entries: usize,
+    pub max_age_secs: u64,
+}
+
+/// Holds up to `capacity` values, each of which expires `ttl` after being inserted.  Once the cache is full, inserting
+/// a new key evicts the least recently used entry.
+pub struct LruCache<K, V> {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<K, Entry<V>>,
+    access_counter: u64,
+    clock: Arc<Clock>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> LruCache<K, V> {
+    pub fn new(capacity: usize, ttl: Duration) -> LruCache<K, V> {
+        LruCache::with_clock(capacity, ttl, Arc::new(SystemClock))
+    }
+
+    /// Creates a cache whose entries expire according to `clock` rather than the system's time
+    pub fn with_clock(capacity: usize, ttl: Duration, clock: Arc<Clock>) -> LruCache<K, V> {
+        LruCache {
+            capacity: capacity,
+            ttl: ttl,
+            entries: HashMap::new(),
+            access_counter: 0,
+            clock: clock,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns a copy of the value stored for `key` if there is one and it hasn't expired yet
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        self.access_counter += 1;
+        let ttl = self.ttl;
+        let now = self.clock.instant();
+        match self.entries.get_mut(key) {
+            Some(entry) => if now.duration_since(entry.inserted) < ttl {
+                entry.last_used = self.access_counter;
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Some(entry.value.clone());
+            },
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            },
+        }
+
+        // the entry has expired
+        self.entries.remove(key);
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        self.access_counter += 1;
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            let lru_key = self.entries.iter()
+                .min_by_key(|&(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(lru_key) = lru_key {
+                self.entries.remove(&lru_key);
+            }
+        }
+
+        let entry = Entry { value: value, inserted: self.clock.instant(), last_used: self.access_counter };
+        self.entries.insert(key, entry);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns the number of hits and misses of the cache's lookups so far.  Expired entries count as misses.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.entries.len(),
+            max_age_secs: self.ttl.as_secs(),
+        }
+    }
+}
+
+/// Returns the cached value for `key` if there is a fresh one, otherwise calls `fetch` and caches its result if it
+/// succeeds.  If `force` is set, the cache is never read but the fetched value is still stored.  The lock isn't held
+/// while fetching, so concurrent misses for the same key may each call `fetch`.
+pub fn get_or_fetch<K, V, E, F>(cache: &Mutex<LruCache<K, V>>, key: K, force: bool, fetch: F) -> Result<V, E>
+    where K: Eq + Hash + Clone, V: Clone, F: FnOnce() -> Result<V, E>
+{
+    if !force {
+        if let Some(value) = cache.lock().unwrap().get(&key) {
+            return Ok(value);
+        }
+    }
+
+    let value = fetch()?;
+    cache.lock().unwrap().insert(key, value.clone());
+    Ok(value)
+}
+
+#[test]
+fn lru_eviction() {
+    let mut cache = LruCache::new(2, Duration::from_secs(60));
+    cache.insert("a", 1);
+    cache.insert("b", 2);
+    assert_eq!(cache.get(&"a"), Some(1));
+
+    // "b" is now the least recently used entry
+    cache.insert("c", 3);
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.get(&"b"), None);
+    assert_eq!(cache.get(&"a"), Some(1));
+    assert_eq!(cache.get(&"c"), Some(3));
+
+    // overwriting an existing key doesn't evict anything
+    cache.insert("c", 4);
+    assert_eq!(cache.get(&"a"), Some(1));
+    assert_eq!(cache.get(&"c"), Some(4));
+}
+
+#[test]
+fn lru_expiry() {
+    use chrono::NaiveDateTime;
+    use clock::MockClock;
+
+    let clock = Arc::new(MockClock::new(NaiveDateTime::from_timestamp(1500000000, 0)));
+    let mut cache = LruCache::with_clock(2, Duration::from_secs(30), clock.clone());
+    cache.insert("a", 1);
+    clock.advance(Duration::from_secs(29));
+    assert_eq!(cache.get(&"a"), Some(1));
+
+    // entries expire based on when they were inserted, not when they were last read
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(cache.get(&"a"), None);
+    assert_eq!(cache.len(), 0);
+}
+
+#[test]
+fn lru_hit_miss_stats() {
+    use chrono::NaiveDateTime;
+    use clock::MockClock;
+
+    let clock = Arc::new(MockClock::new(NaiveDateTime::from_timestamp(1500000000, 0)));
+    let mut cache = LruCache::with_clock(2, Duration::from_secs(30), clock.clone());
+    assert_eq!(cache.get(&"a"), None);
+    cache.insert("a", 1);
+    assert_eq!(cache.get(&"a"), Some(1));
+    assert_eq!(cache.get(&"a"), Some(1));
+
+    // expired entries are counted as misses
+    clock.advance(Duration::from_secs(30));
+    assert_eq!(cache.get(&"a"), None);
+    assert_eq!(cache.stats(), CacheStats { hits: 2, misses: 2, entries: 0, max_age_secs: 30 });
+}
+
+/// Make sure that forcing a fetch always calls the fetch function, even when there's a fresh value in the cache
+#[test]
+fn forced_fetch_bypasses_cache() {
+    use std::cell::Cell;
+
+    let cache = Mutex::new(LruCache::new(10, Duration::from_secs(60)));
+    let fetches = Cell::new(0);
+    let fetch = || -> Result<u32, ()> {
+        fetches.set(fetches.get() + 1);
+        Ok(fetches.get())
+    };
+
+    assert_eq!(get_or_fetch(&cache, "ameo", false, &fetch), Ok(1));
+    assert_eq!(get_or_fetch(&cache, "ameo", false, &fetch), Ok(1));
+    assert_eq!(fetches.get(), 1);
+
+    assert_eq!(get_or_fetch(&cache, "ameo", true, &fetch), Ok(2));
+    assert_eq!(fetches.get(), 2);
+    // the forced result replaces the cached one
+    assert_eq!(get_or_fetch(&cache, "ameo", false, &fetch), Ok(2));
+    assert_eq!(fetches.get(), 2);
+}
@@ -1,34 +1,116 @@
+pub mod accuracy;
+pub mod api_usage;
+pub mod daily_stats;
+pub mod db_metrics;
+pub mod hiscore_history;
+pub mod leaderboard;
+pub mod lru;
 pub mod modes;
+pub mod online_users;
+pub mod orphans;
+pub mod parse_alerts;
+pub mod pp_weighting;
+pub mod raw_snapshots;
+pub mod rank_pp;
+pub mod retry_queue;
+pub mod sampling;
+pub mod stats_math;
+pub mod user_flags;
 
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
 use std::fmt::Debug;
+use std::io::Read;
 
+use chrono::{DateTime, Duration, NaiveDateTime};
+use diesel;
 use diesel::prelude::*;
 use diesel::mysql::MysqlConnection;
 use diesel::result::Error;
+use diesel::types::{BigInt, Double, Integer, SmallInt};
 use reqwest::{self, Response, StatusCode};
+use reqwest::header::{ContentLength, Location};
 use r2d2::Pool;
 use r2d2_diesel::ConnectionManager;
 
-use secret::DB_CREDENTIALS;
-use models::{User, Update};
+use clock::Clock;
+use secret::{DB_CREDENTIALS, MAX_RESPONSE_BYTES, MIN_UPDATE_INTERVAL_SECS};
+use models::{Beatmap, CachedBeatmapRow, User, NewUser, Update, NewUpdate, Hiscore};
+#[cfg(test)]
+use test_support::{mock_response, serve_once, test_beatmap, test_stored_update, test_update};
 
-/// Utility function for making sure that a response is a 200 and then reading it into a String
-pub fn process_response(mut res: Response) -> Result<String, String> {
-    let _ = match res.status() {
-        StatusCode::NotFound => Err(String::from("Received error of 404 Not Found")),
-        StatusCode::InternalServerError => {
-            Err(String::from("Received error of 500 internal server error"))
+/// The error returned when the osu! API rejects our API key
+pub const INVALID_API_KEY_ERR: &'static str = "The osu! API rejected the configured API key";
+/// The error returned when the osu! API responds with something other than JSON, such as an HTML error page
+pub const NON_JSON_RESPONSE_ERR: &'static str = "osu! API returned non-JSON response";
+/// The error returned when the osu! API returns a beatmap that's missing fields or has dates that can't be parsed
+pub const INVALID_BEATMAP_ERR: &'static str = "osu! API returned a beatmap that couldn't be parsed";
+/// The number of bytes of non-JSON responses from the osu! API that are logged
+const NON_JSON_SNIPPET_BYTES: usize = 200;
+
+/// Returns `true` if `body` could be a JSON response from the osu! API, all of which are arrays or objects.  This
+/// catches the HTML error pages that are sometimes served with a 200 during outages without parsing the whole body.
+fn looks_like_json(body: &str) -> bool {
+    match body.trim_left().chars().next() {
+        Some('[') | Some('{') => true,
+        _ => false,
+    }
+}
+
+/// Utility function for making sure that a response is a 200 and then reading it into a String.  Bodies larger than
+/// `MAX_RESPONSE_BYTES` are rejected rather than being read into memory in full.
+pub fn process_response(res: Response) -> Result<String, String> {
+    let status = res.status();
+    match status {
+        StatusCode::Ok => (),
+        StatusCode::NotFound => { return Err(String::from("Received error of 404 Not Found")); },
+        StatusCode::InternalServerError => { return Err(String::from("Received error of 500 internal server error")); },
+        StatusCode::Unauthorized | StatusCode::Forbidden => {
+            println!(
+                "!!! Received {} from the osu! API; the configured API key is invalid or has been revoked !!!", status
+            );
+            return Err(String::from(INVALID_API_KEY_ERR));
+        },
+        StatusCode::ServiceUnavailable => {
+            return Err(String::from("The osu! API is currently unavailable (503 Service Unavailable)"));
+        },
+        // redirects are followed by the client, so this only happens if it gave up on following them
+        _ if status.is_redirection() => {
+            let location = res.headers().get::<Location>().map(|loc| String::from(&**loc));
+            return Err(format!("Received unfollowed redirect {} to {:?}", status, location));
         },
-        StatusCode::Ok => Ok(()),
-        _ => Err(format!("Received unknown error type: {:?}", res.status())),
-    }?;
+        _ => { return Err(format!("Received unknown error type: {:?}", status)); },
+    }
+
+    if let Some(&ContentLength(len)) = res.headers().get::<ContentLength>() {
+        if len > MAX_RESPONSE_BYTES as u64 {
+            return Err(format!("Response body of {} bytes exceeds the limit of {} bytes", len, MAX_RESPONSE_BYTES));
+        }
+    }
+
+    // the content length may be missing or wrong, so the limit is enforced while reading as well
+    let mut body = Vec::new();
+    res.take(MAX_RESPONSE_BYTES as u64 + 1).read_to_end(&mut body).map_err(debug)?;
+    if body.len() > MAX_RESPONSE_BYTES {
+        return Err(format!("Response body exceeds the limit of {} bytes", MAX_RESPONSE_BYTES));
+    }
 
-    res.text().map_err(debug)
+    let body = String::from_utf8(body).map_err(|_| String::from(NON_JSON_RESPONSE_ERR))?;
+    if !looks_like_json(&body) {
+        let (snippet, _) = raw_snapshots::truncate_payload(&body, NON_JSON_SNIPPET_BYTES);
+        warn!("The osu! API returned a non-JSON response: {:?}", snippet);
+        return Err(String::from(NON_JSON_RESPONSE_ERR));
+    }
+
+    Ok(body)
 }
 
-pub fn get_url(url: &str) -> Result<String, String> {
+/// Makes a GET request to the given URL with `client`, following up to 10 redirects, and returns the body of the
+/// response.
+pub fn get_url(client: &reqwest::Client, url: &str) -> Result<String, String> {
     process_response(
-        reqwest::get(url).map_err(|err| format!("Error while sending request to osu! API: {:?}", err))?
+        client.get(url).send().map_err(|err| format!("Error while sending request to osu! API: {:?}", err))?
     )
 }
 
@@ -47,21 +129,108 @@ pub fn parse_pair<T>(v: &str) -> T where T : ::std::str::FromStr {
 }
 
 pub const MYSQL_DATE_FORMAT: &'static str = "%Y-%m-%d %H:%M:%S";
+/// Formats with a UTC offset that dates from the osu! API are occasionally sent in instead of `MYSQL_DATE_FORMAT`
+const OFFSET_DATE_FORMATS: &'static [&'static str] = &[
+    "%Y-%m-%d %H:%M:%S%:z", "%Y-%m-%d %H:%M:%S %:z", "%Y-%m-%d %H:%M:%S%z", "%Y-%m-%d %H:%M:%S %z",
+];
+/// Formats without a UTC offset that dates from the osu! API are occasionally sent in instead of `MYSQL_DATE_FORMAT`
+const NAIVE_DATE_FORMATS: &'static [&'static str] = &["%Y-%m-%dT%H:%M:%S", "%Y/%m/%d %H:%M:%S"];
+
+/// Parses a date from the osu! API.  Dates are normally in `MYSQL_DATE_FORMAT`, but RFC 3339 dates and dates with a
+/// UTC offset or different separators are accepted as well.  Dates with an offset are converted to UTC.
+pub fn parse_osu_date(raw: &str) -> Result<NaiveDateTime, String> {
+    let raw = raw.trim();
+    if let Ok(date) = NaiveDateTime::parse_from_str(raw, MYSQL_DATE_FORMAT) {
+        return Ok(date);
+    }
+    if let Ok(date) = DateTime::parse_from_rfc3339(raw) {
+        return Ok(date.naive_utc());
+    }
+    for format in OFFSET_DATE_FORMATS {
+        if let Ok(date) = DateTime::parse_from_str(raw, format) {
+            return Ok(date.naive_utc());
+        }
+    }
+    for format in NAIVE_DATE_FORMATS {
+        if let Ok(date) = NaiveDateTime::parse_from_str(raw, format) {
+            return Ok(date);
+        }
+    }
+
+    Err(format!("Unrecognized date format: {:?}", raw))
+}
+
+/// The environment variable that tests read the URL of their database from.  Tests never use `DB_CREDENTIALS`, so that
+/// they can't write to a database holding real data.
+pub const TEST_DATABASE_URL_VAR: &'static str = "TEST_DATABASE_URL";
+
+/// Returns the URL of the database to connect to.  Under test, it's read from `TEST_DATABASE_URL_VAR` instead, which
+/// must be set.
+pub fn database_url() -> String {
+    if cfg!(test) {
+        return env::var(TEST_DATABASE_URL_VAR).unwrap_or_else(|_| panic!(
+            "The {} environment variable must be set to the URL of a database for the tests to use",
+            TEST_DATABASE_URL_VAR
+        ));
+    }
+
+    String::from(DB_CREDENTIALS)
+}
 
 pub fn create_db_pool() -> Pool<ConnectionManager<MysqlConnection>> {
-    let manager = ConnectionManager::<MysqlConnection>::new(format!("{}", DB_CREDENTIALS));
+    let manager = ConnectionManager::<MysqlConnection>::new(database_url());
     Pool::builder().build(manager).expect("Failed to create pool.")
 }
 
-/// Given a username, attempts to retrieve the stored `User` struct that goes along with it from the database.
+/// Given a username, attempts to retrieve the stored `User` struct that goes along with it from the database.  osu!
+/// frees up usernames when users rename themselves, so several stored users can share a username; in that case the one
+/// with the most recent update is the one that's currently using it.
 pub fn get_user_from_username(connection: &MysqlConnection, username: &str) -> Result<Option<User>, String> {
+    use schema::updates::dsl as updates_dsl;
     use schema::users::dsl as users_dsl;
-    match users_dsl::users.filter(users_dsl::username.eq(username)).first(connection) {
-        Ok(usr) => Ok(Some(usr)),
-        Err(err) => match err {
-            Error::NotFound => { return Ok(None); },
-            _ => { return Err(format!("Error while getting user row from database: {:?}", err)); },
-        }
+
+    let mut users: Vec<User> = users_dsl::users
+        .filter(users_dsl::username.eq(username))
+        .order(users_dsl::last_update.desc())
+        .load(connection)
+        .map_err(|err| format!("Error while getting user row from database: {:?}", err))?;
+    if users.len() <= 1 {
+        return Ok(users.pop());
+    }
+
+    let user_ids: Vec<i32> = users.iter().map(|usr| usr.id).collect();
+    let active_id: Option<i32> = updates_dsl::updates
+        .filter(updates_dsl::user_id.eq_any(user_ids))
+        .order(updates_dsl::update_time.desc())
+        .select(updates_dsl::user_id)
+        .first(connection)
+        .optional()
+        .map_err(debug)?;
+    // users without any updates fall back to the most recently renamed one
+    let active_ix = active_id.and_then(|id| users.iter().position(|usr| usr.id == id)).unwrap_or(0);
+
+    Ok(Some(users.swap_remove(active_ix)))
+}
+
+/// Makes sure that a row exists in the `users` table for the user, creating one if there isn't one yet and updating
+/// the stored username if the user has changed it.  Must be called before inserting updates for a user so that every
+/// update belongs to a user row.
+pub fn ensure_user(connection: &MysqlConnection, user_id: i32, username: &str) -> Result<(), String> {
+    use schema::users::dsl as users_dsl;
+
+    match users_dsl::users.find(user_id).first::<User>(connection) {
+        Ok(ref usr) if usr.username == username => Ok(()),
+        Ok(_) => diesel::update(users_dsl::users.find(user_id))
+            .set(users_dsl::username.eq(username))
+            .execute(connection)
+            .map(|_| ())
+            .map_err(|err| format!("Error while updating username: {:?}", err)),
+        Err(Error::NotFound) => diesel::insert_into(users_dsl::users)
+            .values(&NewUser { id: user_id, username: String::from(username) })
+            .execute(connection)
+            .map(|_| ())
+            .map_err(|err| format!("Unable to insert new user row into database: {:?}", err)),
+        Err(err) => Err(format!("Error while getting user row from database: {:?}", err)),
     }
 }
 
@@ -79,3 +248,1289 @@ pub fn get_last_update(user_id: i32, mode: u8, connection: &MysqlConnection) ->
 
     if updates.len() == 0 { Ok(None) } else { Ok(Some(updates.drain(..).next().unwrap())) }
 }
+
+/// Returns the most recent update of a user in a mode where their pp was different than `pp`
+pub fn find_last_update_with_different_pp(
+    connection: &MysqlConnection, user_id: i32, mode: u8, pp: f32
+) -> Result<Option<Update>, String> {
+    use schema::updates::dsl as updates_dsl;
+
+    updates_dsl::updates
+        .filter(updates_dsl::user_id.eq(user_id))
+        .filter(updates_dsl::mode.eq(mode as i16))
+        .filter(updates_dsl::pp_raw.ne(pp))
+        .order(updates_dsl::id.desc())
+        .first::<Update>(connection)
+        .optional()
+        .map_err(debug)
+}
+
+/// Returns the first update of a user in a mode that was recorded after the update with id `after_id`
+pub fn find_first_update_after(
+    connection: &MysqlConnection, user_id: i32, mode: u8, after_id: i32
+) -> Result<Option<Update>, String> {
+    use schema::updates::dsl as updates_dsl;
+
+    updates_dsl::updates
+        .filter(updates_dsl::user_id.eq(user_id))
+        .filter(updates_dsl::mode.eq(mode as i16))
+        .filter(updates_dsl::id.gt(after_id))
+        .order(updates_dsl::id.asc())
+        .first::<Update>(connection)
+        .optional()
+        .map_err(debug)
+}
+
+/// Returns the modes that a user has any updates in, in ascending order
+pub fn get_tracked_modes(connection: &MysqlConnection, user_id: i32) -> Result<Vec<u8>, String> {
+    use schema::updates::dsl as updates_dsl;
+
+    let modes: Vec<i16> = updates_dsl::updates
+        .filter(updates_dsl::user_id.eq(user_id))
+        .select(updates_dsl::mode)
+        .distinct()
+        .order(updates_dsl::mode.asc())
+        .load(connection)
+        .map_err(debug)?;
+
+    Ok(modes.into_iter().map(|mode| mode as u8).collect())
+}
+
+/// The id of the latest update of a user
+#[derive(QueryableByName)]
+struct LatestUpdateIdRow {
+    #[sql_type = "Integer"]
+    id: i32,
+}
+
+/// Loads the latest update in `mode` of each of the users with the given ids using a constant number of queries.  Users
+/// without any updates in the mode are left out.
+pub fn get_latest_updates(connection: &MysqlConnection, user_ids: &[i32], mode: u8) -> Result<Vec<Update>, String> {
+    use schema::updates::dsl as updates_dsl;
+
+    if user_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // the ids are integers, so they can be safely formatted into the query
+    let id_list = user_ids.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(",");
+    let latest_ids: Vec<LatestUpdateIdRow> = diesel::sql_query(format!(
+        "SELECT MAX(id) AS id FROM updates WHERE mode = ? AND user_id IN ({}) GROUP BY user_id", id_list
+    )).bind::<SmallInt, _>(mode as i16)
+        .load(connection)
+        .map_err(debug)?;
+    let latest_ids: Vec<i32> = latest_ids.into_iter().map(|row| row.id).collect();
+
+    updates_dsl::updates
+        .filter(updates_dsl::id.eq_any(latest_ids))
+        .load(connection)
+        .map_err(debug)
+}
+
+/// Loads a user's `(update_time, pp_rank)` history in a mode, optionally bounded to updates recorded between `from`
+/// and `to` (inclusive).
+pub fn load_rank_history(
+    connection: &MysqlConnection, user_id: i32, mode: u8, from: Option<NaiveDateTime>, to: Option<NaiveDateTime>
+) -> Result<Vec<(NaiveDateTime, i32)>, String> {
+    use schema::updates::dsl as updates_dsl;
+
+    let mut query = updates_dsl::updates
+        .filter(updates_dsl::user_id.eq(user_id))
+        .filter(updates_dsl::mode.eq(mode as i16))
+        .into_boxed();
+    if let Some(from) = from {
+        query = query.filter(updates_dsl::update_time.ge(from));
+    }
+    if let Some(to) = to {
+        query = query.filter(updates_dsl::update_time.le(to));
+    }
+
+    query.order(updates_dsl::update_time.asc())
+        .select((updates_dsl::update_time, updates_dsl::pp_rank))
+        .load(connection)
+        .map_err(debug)
+}
+
+/// Loads a user's updates in a mode ordered by time, newest first if `descending` is set.  If `limit` is supplied, only
+/// that many updates from the start of the ordering are read from the database.
+pub fn load_updates(
+    connection: &MysqlConnection, user_id: i32, mode: u8, descending: bool, limit: Option<i64>
+) -> Result<Vec<Update>, String> {
+    use schema::updates::dsl as updates_dsl;
+
+    let mut query = updates_dsl::updates
+        .filter(updates_dsl::user_id.eq(user_id))
+        .filter(updates_dsl::mode.eq(mode as i16))
+        .into_boxed();
+    query = if descending {
+        query.order((updates_dsl::update_time.desc(), updates_dsl::id.desc()))
+    } else {
+        query.order((updates_dsl::update_time.asc(), updates_dsl::id.asc()))
+    };
+    if let Some(limit) = limit {
+        query = query.limit(limit);
+    }
+
+    query.load(connection).map_err(debug)
+}
+
+/// Loads up to `n` of a user's updates in a mode recorded at or before `time` and up to `n` recorded after it, ordered
+/// by time.  Only the updates that are returned are read from the database.
+pub fn load_updates_around(
+    connection: &MysqlConnection, user_id: i32, mode: u8, time: NaiveDateTime, n: i64
+) -> Result<Vec<Update>, String> {
+    use schema::updates::dsl as updates_dsl;
+
+    let user_updates = || updates_dsl::updates
+        .filter(updates_dsl::user_id.eq(user_id))
+        .filter(updates_dsl::mode.eq(mode as i16));
+
+    let mut updates: Vec<Update> = user_updates()
+        .filter(updates_dsl::update_time.le(time))
+        .order((updates_dsl::update_time.desc(), updates_dsl::id.desc()))
+        .limit(n)
+        .load(connection)
+        .map_err(debug)?;
+    updates.reverse();
+    let after: Vec<Update> = user_updates()
+        .filter(updates_dsl::update_time.gt(time))
+        .order((updates_dsl::update_time.asc(), updates_dsl::id.asc()))
+        .limit(n)
+        .load(connection)
+        .map_err(debug)?;
+    updates.extend(after);
+
+    Ok(updates)
+}
+
+/// Which of a user's updates a timestamp resolves to when no update was recorded at exactly that time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UpdateBias {
+    /// The last update recorded at or before the timestamp
+    Before,
+    /// The first update recorded at or after the timestamp
+    After,
+    /// Whichever of those two is closer to the timestamp, preferring the earlier one on ties
+    Nearest,
+}
+
+/// Picks the update that `time` resolves to with `bias` given the last update at or before it and the first one at or
+/// after it
+pub fn pick_update_near(
+    time: NaiveDateTime, before: Option<Update>, after: Option<Update>, bias: UpdateBias
+) -> Option<Update> {
+    match (bias, before, after) {
+        (UpdateBias::Before, before, _) => before,
+        (UpdateBias::After, _, after) => after,
+        (UpdateBias::Nearest, Some(before), Some(after)) => {
+            if after.update_time - time < time - before.update_time { Some(after) } else { Some(before) }
+        },
+        (UpdateBias::Nearest, before, after) => before.or(after),
+    }
+}
+
+/// Finds the update of a user in a mode that `time` resolves to with `bias`, or `None` if there isn't one on the side of
+/// `time` that `bias` requires
+pub fn find_update_near(
+    connection: &MysqlConnection, user_id: i32, mode: u8, time: NaiveDateTime, bias: UpdateBias
+) -> Result<Option<Update>, String> {
+    use schema::updates::dsl as updates_dsl;
+
+    let user_updates = || updates_dsl::updates
+        .filter(updates_dsl::user_id.eq(user_id))
+        .filter(updates_dsl::mode.eq(mode as i16));
+
+    let before: Option<Update> = if bias == UpdateBias::After { None } else {
+        user_updates()
+            .filter(updates_dsl::update_time.le(time))
+            .order((updates_dsl::update_time.desc(), updates_dsl::id.desc()))
+            .first(connection)
+            .optional()
+            .map_err(debug)?
+    };
+    let after: Option<Update> = if bias == UpdateBias::Before { None } else {
+        user_updates()
+            .filter(updates_dsl::update_time.ge(time))
+            .order((updates_dsl::update_time.asc(), updates_dsl::id.asc()))
+            .first(connection)
+            .optional()
+            .map_err(debug)?
+    };
+
+    Ok(pick_update_near(time, before, after, bias))
+}
+
+/// Loads a user's hiscores in a mode as they were stored at `time`: those recorded at or before it, with hiscores that
+/// were only dropped from the user's top plays after `time` treated as not dropped yet
+pub fn load_hiscores_as_of(
+    connection: &MysqlConnection, user_id: i32, mode: u8, time: NaiveDateTime
+) -> Result<Vec<Hiscore>, String> {
+    use schema::hiscores::dsl as hiscores_dsl;
+
+    let hiscores: Vec<Hiscore> = hiscores_dsl::hiscores
+        .filter(hiscores_dsl::user_id.eq(user_id))
+        .filter(hiscores_dsl::mode.eq(mode as i16))
+        .filter(hiscores_dsl::time_recorded.le(time))
+        .load(connection)
+        .map_err(debug)?;
+
+    Ok(hiscores.into_iter()
+        .map(|mut hs| {
+            if hs.dropped_at.map(|dropped_at| dropped_at > time).unwrap_or(false) {
+                hs.dropped_at = None;
+            }
+            hs
+        })
+        .collect())
+}
+
+/// Loads up to `limit` of a user's stored updates with ids greater than `after_id`, ordered by id.  If `mode` is supplied,
+/// only updates in that mode are returned.  Used to page through large histories without loading them all at once.
+pub fn load_updates_page(
+    connection: &MysqlConnection, user_id: i32, mode: Option<u8>, after_id: i32, limit: i64
+) -> Result<Vec<Update>, String> {
+    use schema::updates::dsl as updates_dsl;
+
+    let mut query = updates_dsl::updates
+        .filter(updates_dsl::user_id.eq(user_id))
+        .filter(updates_dsl::id.gt(after_id))
+        .into_boxed();
+    if let Some(mode) = mode {
+        query = query.filter(updates_dsl::mode.eq(mode as i16));
+    }
+
+    query.order(updates_dsl::id.asc())
+        .limit(limit)
+        .load::<Update>(connection)
+        .map_err(debug)
+}
+
+/// Loads up to `limit` of a user's stored hiscores with ids greater than `after_id`, ordered by id.  If `mode` is
+/// supplied, only hiscores in that mode are returned.
+pub fn load_hiscores_page(
+    connection: &MysqlConnection, user_id: i32, mode: Option<u8>, after_id: i32, limit: i64
+) -> Result<Vec<Hiscore>, String> {
+    use schema::hiscores::dsl as hiscores_dsl;
+
+    let mut query = hiscores_dsl::hiscores
+        .filter(hiscores_dsl::user_id.eq(user_id))
+        .filter(hiscores_dsl::id.gt(after_id))
+        .into_boxed();
+    if let Some(mode) = mode {
+        query = query.filter(hiscores_dsl::mode.eq(mode as i16));
+    }
+
+    query.order(hiscores_dsl::id.asc())
+        .limit(limit)
+        .load::<Hiscore>(connection)
+        .map_err(debug)
+}
+
+/// A bucket of a user's hiscores grouped by the star rating of their beatmaps, as returned by the database.  `bucket` is
+/// the star rating multiplied by two and rounded down, so each bucket covers half a star.
+#[derive(QueryableByName)]
+pub struct DifficultyBucketRow {
+    #[sql_type = "BigInt"]
+    pub bucket: i64,
+    #[sql_type = "BigInt"]
+    pub plays: i64,
+    #[sql_type = "Double"]
+    pub avg_pp: f64,
+}
+
+/// A bucket of a user's hiscores covering beatmaps with star ratings in `[min_stars, max_stars)`
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DifficultyBucket {
+    pub min_stars: f32,
+    pub max_stars: f32,
+    pub plays: i64,
+    pub avg_pp: f64,
+}
+
+impl From<DifficultyBucketRow> for DifficultyBucket {
+    fn from(row: DifficultyBucketRow) -> DifficultyBucket {
+        DifficultyBucket {
+            min_stars: row.bucket as f32 / 2.,
+            max_stars: (row.bucket + 1) as f32 / 2.,
+            plays: row.plays,
+            avg_pp: row.avg_pp,
+        }
+    }
+}
+
+/// Groups a user's stored hiscores in a mode into half-star buckets by the star rating of their beatmaps, counting the
+/// plays and averaging their pp in each bucket.  Hiscores on beatmaps that aren't in the beatmap cache in the mode are
+/// excluded.  Beatmaps made for a mode other than standard can't be converted, so their star rating is always for their
+/// native mode.
+pub fn get_difficulty_buckets(
+    connection: &MysqlConnection, user_id: i32, mode: u8
+) -> Result<Vec<DifficultyBucket>, String> {
+    let rows: Vec<DifficultyBucketRow> = diesel::sql_query(
+        "SELECT CAST(FLOOR(b.difficulty * 2) AS SIGNED) AS bucket, COUNT(*) AS plays, AVG(h.pp) AS avg_pp
+        FROM hiscores h INNER JOIN beatmaps b ON b.beatmap_id = h.beatmap_id AND b.mode = h.mode
+        WHERE h.user_id = ? AND h.mode = ?
+        GROUP BY bucket
+        ORDER BY bucket ASC"
+    ).bind::<Integer, _>(user_id)
+        .bind::<SmallInt, _>(mode as i16)
+        .load(connection)
+        .map_err(debug)?;
+
+    Ok(rows.into_iter().map(DifficultyBucket::from).collect())
+}
+
+/// Sets `dropped_at` to the current time on the hiscores with ids in `dropped_ids` and clears it on those with ids in
+/// `reappeared_ids`
+pub fn mark_dropped_hiscores(
+    connection: &MysqlConnection, clock: &Clock, dropped_ids: &[i32], reappeared_ids: &[i32]
+) -> Result<(), String> {
+    use schema::hiscores::dsl as hiscores_dsl;
+
+    if !dropped_ids.is_empty() {
+        diesel::update(hiscores_dsl::hiscores.filter(hiscores_dsl::id.eq_any(dropped_ids)))
+            .set(hiscores_dsl::dropped_at.eq(Some(clock.now())))
+            .execute(connection)
+            .map_err(debug)?;
+    }
+    if !reappeared_ids.is_empty() {
+        diesel::update(hiscores_dsl::hiscores.filter(hiscores_dsl::id.eq_any(reappeared_ids)))
+            .set(hiscores_dsl::dropped_at.eq(None::<NaiveDateTime>))
+            .execute(connection)
+            .map_err(debug)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the ids of the beatmaps of a user's stored hiscores in a mode that aren't in the beatmap cache in that mode
+pub fn get_uncached_hiscore_beatmaps(
+    connection: &MysqlConnection, user_id: i32, mode: u8
+) -> Result<Vec<i32>, String> {
+    use schema::beatmaps::dsl as beatmaps_dsl;
+    use schema::hiscores::dsl as hiscores_dsl;
+
+    hiscores_dsl::hiscores
+        .left_join(beatmaps_dsl::beatmaps.on(
+            beatmaps_dsl::beatmap_id.eq(hiscores_dsl::beatmap_id).and(beatmaps_dsl::mode.eq(hiscores_dsl::mode))
+        ))
+        .filter(hiscores_dsl::user_id.eq(user_id))
+        .filter(hiscores_dsl::mode.eq(mode as i16))
+        .filter(beatmaps_dsl::beatmap_id.nullable().is_null())
+        .select(hiscores_dsl::beatmap_id)
+        .distinct()
+        .load(connection)
+        .map_err(debug)
+}
+
+/// Returns the `limit` most recently recorded hiscores of all tracked users after skipping the first `offset` of them,
+/// along with the usernames of the users that set them.  Only hiscores set in `mode` are returned if it's supplied.
+pub fn get_recent_hiscores(
+    connection: &MysqlConnection, mode: Option<u8>, limit: i64, offset: i64
+) -> Result<Vec<(Hiscore, String)>, String> {
+    use schema::hiscores;
+    use schema::hiscores::dsl as hiscores_dsl;
+    use schema::users::dsl as users_dsl;
+
+    let mut query = hiscores_dsl::hiscores
+        .inner_join(users_dsl::users)
+        .select((hiscores::all_columns, users_dsl::username))
+        .into_boxed();
+    if let Some(mode) = mode {
+        query = query.filter(hiscores_dsl::mode.eq(mode as i16));
+    }
+
+    query.order((hiscores_dsl::time_recorded.desc(), hiscores_dsl::id.desc()))
+        .limit(limit)
+        .offset(offset)
+        .load(connection)
+        .map_err(debug)
+}
+
+/// Returns the user's `limit` best current hiscores by pp in each mode that they have any in, keyed by mode.  Each
+/// mode is loaded with its own limited query so that the rest of the user's hiscores are never loaded.
+pub fn get_top_hiscores_by_mode(
+    connection: &MysqlConnection, user_id: i32, limit: i64
+) -> Result<BTreeMap<u8, Vec<Hiscore>>, String> {
+    use schema::hiscores::dsl as hiscores_dsl;
+
+    let current = || hiscores_dsl::hiscores
+        .filter(hiscores_dsl::user_id.eq(user_id))
+        .filter(hiscores_dsl::dropped_at.is_null());
+    let modes: Vec<i16> = current()
+        .select(hiscores_dsl::mode)
+        .distinct()
+        .load(connection)
+        .map_err(debug)?;
+
+    let mut top_hiscores = BTreeMap::new();
+    for mode in modes {
+        let hiscores = current()
+            .filter(hiscores_dsl::mode.eq(mode))
+            .order((hiscores_dsl::pp.desc(), hiscores_dsl::id.asc()))
+            .limit(limit)
+            .load(connection)
+            .map_err(debug)?;
+        top_hiscores.insert(mode as u8, hiscores);
+    }
+
+    Ok(top_hiscores)
+}
+
+/// Loads the beatmaps with the given ids that are in the beatmap cache in `mode`, keyed by id
+pub fn get_cached_beatmaps(
+    connection: &MysqlConnection, ids: &[i32], mode: u8
+) -> Result<HashMap<i32, Beatmap>, String> {
+    use schema::beatmaps::dsl as beatmaps_dsl;
+
+    if ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let beatmaps: Vec<Beatmap> = beatmaps_dsl::beatmaps
+        .filter(beatmaps_dsl::beatmap_id.eq_any(ids.to_vec()))
+        .filter(beatmaps_dsl::mode.eq(mode as i16))
+        .load(connection)
+        .map_err(debug)?;
+
+    Ok(beatmaps.into_iter().map(|beatmap| (beatmap.beatmap_id, beatmap)).collect())
+}
+
+/// Returns up to `limit` of the beatmaps in the beatmap cache whose `last_update` is within the optional bounds after
+/// skipping the first `offset` of them, most recently updated first
+pub fn load_cached_beatmaps(
+    connection: &MysqlConnection, from: Option<NaiveDateTime>, to: Option<NaiveDateTime>, limit: i64, offset: i64
+) -> Result<Vec<CachedBeatmapRow>, String> {
+    use schema::beatmaps::dsl as beatmaps_dsl;
+
+    let mut query = beatmaps_dsl::beatmaps
+        .select((
+            beatmaps_dsl::beatmap_id, beatmaps_dsl::beatmapset_id, beatmaps_dsl::mode, beatmaps_dsl::title,
+            beatmaps_dsl::last_update,
+        ))
+        .into_boxed();
+    if let Some(from) = from {
+        query = query.filter(beatmaps_dsl::last_update.ge(from));
+    }
+    if let Some(to) = to {
+        query = query.filter(beatmaps_dsl::last_update.le(to));
+    }
+
+    query.order((beatmaps_dsl::last_update.desc(), beatmaps_dsl::beatmap_id.desc(), beatmaps_dsl::mode.asc()))
+        .limit(limit)
+        .offset(offset)
+        .load(connection)
+        .map_err(debug)
+}
+
+/// Determines whether or not `cur` is worth storing given the last update recorded for the user in the same mode.  An
+/// update is only recorded if something meaningful changed and the last update is older than the minimum interval.
+pub fn should_record_update(clock: &Clock, last_update: Option<&Update>, cur: &NewUpdate) -> bool {
+    match last_update {
+        Some(last) => {
+            let changed = last.pp_rank != cur.pp_rank ||
+                last.playcount != cur.playcount ||
+                last.pp_country_rank != cur.pp_country_rank;
+            let elapsed = clock.now().signed_duration_since(last.update_time);
+
+            changed && elapsed >= Duration::seconds(MIN_UPDATE_INTERVAL_SECS)
+        },
+        None => true,
+    }
+}
+
+/// Returns `false` if the user has opted out of tracking.  Users that don't have a row yet are tracked.
+pub fn is_tracking_enabled(connection: &MysqlConnection, user_id: i32) -> Result<bool, String> {
+    use schema::users::dsl as users_dsl;
+
+    let enabled: Option<bool> = users_dsl::users
+        .find(user_id)
+        .select(users_dsl::enabled)
+        .first(connection)
+        .optional()
+        .map_err(debug)?;
+    Ok(enabled.unwrap_or(true))
+}
+
+/// Enables or disables the tracking of a user, returning their updated row or `None` if they aren't tracked
+pub fn set_tracking_enabled(connection: &MysqlConnection, user_id: i32, enabled: bool) -> Result<Option<User>, String> {
+    use schema::users::dsl as users_dsl;
+
+    diesel::update(users_dsl::users.find(user_id))
+        .set(users_dsl::enabled.eq(enabled))
+        .execute(connection)
+        .map_err(debug)?;
+    users_dsl::users.find(user_id).first(connection).optional().map_err(debug)
+}
+
+/// The number of rows moved from one user to another by `merge_users`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MergeReport {
+    pub updates_moved: usize,
+    pub hiscores_moved: usize,
+    /// Hiscores of the merged user that were deleted instead of moved since the same play was already recorded for the
+    /// user that they were merged into
+    pub duplicate_hiscores_deleted: usize,
+}
+
+/// Moves all of the updates, hiscores, and flags of the user `from_id` to the user `into_id` and deletes the row and
+/// raw snapshots of `from_id`, all in one transaction.  Hiscores and flags set on both users are only kept once.
+/// `into_id`'s `first_update` and `last_update` are widened to cover the moved history, its daily aggregates are
+/// rebuilt to include it, and it's opted out of tracking if `from_id` was.  Returns `None` without changing anything if
+/// `into_id` isn't tracked.
+pub fn merge_users(connection: &MysqlConnection, from_id: i32, into_id: i32) -> Result<Option<MergeReport>, String> {
+    use schema::daily_stats::dsl as daily_dsl;
+    use schema::hiscores::dsl as hiscores_dsl;
+    use schema::raw_snapshots::dsl as snapshots_dsl;
+    use schema::updates::dsl as updates_dsl;
+    use schema::user_flags::dsl as flags_dsl;
+    use schema::users::dsl as users_dsl;
+
+    if from_id == into_id {
+        return Err(format!("Can't merge user {} into itself", from_id));
+    }
+
+    connection.transaction::<_, Error, _>(|| {
+        let into: User = match users_dsl::users.find(into_id).first(connection).optional()? {
+            Some(into) => into,
+            None => { return Ok(None); },
+        };
+        let from: Option<User> = users_dsl::users.find(from_id).first(connection).optional()?;
+
+        // the same play recorded under both ids would otherwise be counted twice once they're merged
+        let duplicate_hiscores_deleted = diesel::sql_query(format!(
+            "DELETE f FROM hiscores f INNER JOIN hiscores i ON i.user_id = {} AND i.beatmap_id = f.beatmap_id AND \
+            i.mode = f.mode AND i.score = f.score AND i.score_time = f.score_time WHERE f.user_id = {}",
+            into_id, from_id
+        )).execute(connection)?;
+        let moved_modes: Vec<i16> = updates_dsl::updates
+            .filter(updates_dsl::user_id.eq(from_id))
+            .select(updates_dsl::mode)
+            .distinct()
+            .load(connection)?;
+        let updates_moved = diesel::update(updates_dsl::updates.filter(updates_dsl::user_id.eq(from_id)))
+            .set(updates_dsl::user_id.eq(into_id))
+            .execute(connection)?;
+        diesel::delete(daily_dsl::daily_stats.filter(daily_dsl::user_id.eq(from_id))).execute(connection)?;
+        for &mode in &moved_modes {
+            daily_stats::rebuild_daily_stats(connection, into_id, mode)?;
+        }
+        let hiscores_moved = diesel::update(hiscores_dsl::hiscores.filter(hiscores_dsl::user_id.eq(from_id)))
+            .set(hiscores_dsl::user_id.eq(into_id))
+            .execute(connection)?;
+
+        if let Some(from) = from {
+            diesel::update(users_dsl::users.find(into_id))
+                .set((
+                    users_dsl::first_update.eq(cmp::min(from.first_update, into.first_update)),
+                    users_dsl::last_update.eq(cmp::max(from.last_update, into.last_update)),
+                    users_dsl::enabled.eq(from.enabled && into.enabled),
+                ))
+                .execute(connection)?;
+            diesel::delete(users_dsl::users.find(from_id)).execute(connection)?;
+        }
+        diesel::delete(snapshots_dsl::raw_snapshots.filter(snapshots_dsl::user_id.eq(from_id))).execute(connection)?;
+
+        // flags already set on `into_id` keep their own note
+        diesel::sql_query(format!(
+            "INSERT IGNORE INTO user_flags (user_id, flag, note, created_at) \
+            SELECT {}, flag, note, created_at FROM user_flags WHERE user_id = {}",
+            into_id, from_id
+        )).execute(connection)?;
+        diesel::delete(flags_dsl::user_flags.filter(flags_dsl::user_id.eq(from_id))).execute(connection)?;
+
+        Ok(Some(MergeReport {
+            updates_moved: updates_moved,
+            hiscores_moved: hiscores_moved,
+            duplicate_hiscores_deleted: duplicate_hiscores_deleted,
+        }))
+    }).map_err(debug)
+}
+
+/// Writes the update to the database if it differs from the last recorded update and enough time has passed since that
+/// one was recorded, setting the `last_update` of the user's row to the time of the new update.  Updates of users who
+/// have opted out of tracking are never recorded.  Returns the id of the inserted row, or `None` if the update wasn't
+/// recorded.
+pub fn record_update(
+    connection: &MysqlConnection, clock: &Clock, update: &NewUpdate, last_update: Option<&Update>
+) -> Result<Option<i32>, String> {
+    use schema::updates::dsl as updates_dsl;
+    use schema::users::dsl as users_dsl;
+
+    if !should_record_update(clock, last_update, update) || !is_tracking_enabled(connection, update.user_id)? {
+        return Ok(None);
+    }
+
+    diesel::insert_into(updates_dsl::updates)
+        .values(update)
+        .execute(connection)
+        .map_err(debug)?;
+    let update_id = last_insert_id(connection)?;
+
+    let update_time: NaiveDateTime = updates_dsl::updates
+        .find(update_id)
+        .select(updates_dsl::update_time)
+        .first(connection)
+        .map_err(debug)?;
+    diesel::update(users_dsl::users.find(update.user_id))
+        .set(users_dsl::last_update.eq(update_time))
+        .execute(connection)
+        .map_err(debug)?;
+    daily_stats::record_daily_stats(connection, update, update_time)?;
+
+    Ok(Some(update_id))
+}
+
+/// Returns the user's row, which is expected to exist
+pub fn get_user(connection: &MysqlConnection, user_id: i32) -> Result<User, String> {
+    use schema::users::dsl as users_dsl;
+
+    users_dsl::users.find(user_id).first(connection).map_err(debug)
+}
+
+/// Returns the id of the row most recently inserted into an `AUTO_INCREMENT` table using the connection
+pub fn last_insert_id(connection: &MysqlConnection) -> Result<i32, String> {
+    diesel::expression::dsl::sql::<::diesel::types::Bigint>("SELECT LAST_INSERT_ID()")
+        .get_result::<i64>(connection)
+        .map(|id| id as i32)
+        .map_err(debug)
+}
+
+#[test]
+fn osu_date_parsing() {
+    let expected = NaiveDateTime::parse_from_str("2017-07-14 02:40:00", MYSQL_DATE_FORMAT).unwrap();
+    for raw in &[
+        "2017-07-14 02:40:00", " 2017-07-14 02:40:00\n", "2017-07-14 02:40:00+00:00", "2017-07-14 02:40:00 +00:00",
+        "2017-07-14 02:40:00+0000", "2017-07-14T02:40:00+00:00", "2017-07-14T02:40:00Z", "2017-07-14T02:40:00",
+        "2017/07/14 02:40:00", "2017-07-14 10:40:00+08:00",
+    ] {
+        assert_eq!(parse_osu_date(raw), Ok(expected), "{:?}", raw);
+    }
+
+    assert!(parse_osu_date("14/07/2017").is_err());
+    assert!(parse_osu_date("").is_err());
+}
+
+#[test]
+fn process_response_statuses() {
+    let get = |status_line: &str, extra_headers: &str| {
+        get_url(&reqwest::Client::new(), &serve_once(mock_response(status_line, extra_headers, "[]")))
+    };
+
+    assert_eq!(get("200 OK", ""), Ok(String::from("[]")));
+    assert_eq!(get("401 Unauthorized", ""), Err(String::from(INVALID_API_KEY_ERR)));
+    assert_eq!(get("403 Forbidden", ""), Err(String::from(INVALID_API_KEY_ERR)));
+    assert!(get("503 Service Unavailable", "").unwrap_err().contains("unavailable"));
+    assert!(get("404 Not Found", "").unwrap_err().contains("404"));
+    assert!(get("500 Internal Server Error", "").unwrap_err().contains("500"));
+    // a redirect without a location can't be followed
+    assert!(get("302 Found", "").unwrap_err().contains("redirect"));
+}
+
+/// Make sure that HTML error pages served with a 200 are reported as upstream errors rather than parse errors
+#[test]
+fn process_response_html_body() {
+    use error::ApiError;
+    use rocket::http::Status;
+
+    let html = "<!DOCTYPE html>\n<html><head><title>osu! is down</title></head><body>Maintenance</body></html>";
+    let res = get_url(&reqwest::Client::new(), &serve_once(mock_response("200 OK", "", html)));
+    assert_eq!(res, Err(String::from(NON_JSON_RESPONSE_ERR)));
+    assert_eq!(ApiError::from(res.unwrap_err()).status(), Status::BadGateway);
+
+    let json = "\n  [{\"user_id\":\"2\"}]";
+    let res = get_url(&reqwest::Client::new(), &serve_once(mock_response("200 OK", "", json)));
+    assert_eq!(res, Ok(String::from(json)));
+    assert!(!looks_like_json(""));
+}
+
+#[test]
+fn process_response_follows_redirects() {
+    let target = serve_once(mock_response("200 OK", "", "[1]"));
+    let redirect = serve_once(mock_response("301 Moved Permanently", &format!("Location: {}\r\n", target), ""));
+    assert_eq!(get_url(&reqwest::Client::new(), &redirect), Ok(String::from("[1]")));
+}
+
+#[test]
+fn process_response_body_limit() {
+    let oversized = "a".repeat(MAX_RESPONSE_BYTES + 1);
+    let res = get_url(&reqwest::Client::new(), &serve_once(mock_response("200 OK", "", &oversized)));
+    assert!(res.unwrap_err().contains("exceeds the limit"));
+
+    // the limit is enforced while reading bodies that don't have a content length as well
+    let response = format!("HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n{}", oversized);
+    let res = get_url(&reqwest::Client::new(), &serve_once(response));
+    assert!(res.unwrap_err().contains("exceeds the limit"));
+}
+
+/// Make sure that the minimum update interval is measured against the clock rather than the system time
+#[test]
+fn update_interval_policy() {
+    use std::time::Duration as StdDuration;
+    use clock::MockClock;
+
+    let recorded_at = NaiveDateTime::from_timestamp(1500000000, 0);
+    let clock = MockClock::new(recorded_at);
+    let last = Update { update_time: recorded_at, ..test_stored_update(1000.) };
+    let cur = NewUpdate { playcount: 51, pp_rank: 49990, ..test_update(1000.) };
+
+    assert!(should_record_update(&clock, None, &cur));
+    clock.advance(StdDuration::from_secs(MIN_UPDATE_INTERVAL_SECS as u64 - 1));
+    assert!(!should_record_update(&clock, Some(&last), &cur));
+    clock.advance(StdDuration::from_secs(1));
+    assert!(should_record_update(&clock, Some(&last), &cur));
+
+    // unchanged stats are never recorded no matter how much time has passed
+    let mut unchanged = cur.clone();
+    unchanged.playcount = last.playcount;
+    unchanged.pp_rank = last.pp_rank;
+    assert!(!should_record_update(&clock, Some(&last), &unchanged));
+}
+
+/// Make sure that two updates recorded within `MIN_UPDATE_INTERVAL_SECS` of each other only produce one row
+#[test]
+fn update_interval_limit() {
+    use schema::updates::dsl as updates_dsl;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+    let clock = ::clock::SystemClock;
+
+    let mut update = NewUpdate { user_id: -1, ..test_update(1000.) };
+    assert!(record_update(conn, &clock, &update, None).unwrap().is_some());
+
+    // the stats changed, but not enough time has passed since the last recorded update
+    update.playcount += 1;
+    update.pp_rank -= 10;
+    let last_update = get_last_update(update.user_id, 0, conn).unwrap();
+    assert!(last_update.is_some());
+    assert_eq!(record_update(conn, &clock, &update, last_update.as_ref()).unwrap(), None);
+
+    let count: i64 = updates_dsl::updates
+        .filter(updates_dsl::user_id.eq(update.user_id))
+        .count()
+        .get_result(conn)
+        .unwrap();
+    assert_eq!(count, 1);
+}
+
+/// Make sure that the ids of recorded updates are returned and that skipped updates don't return one
+#[test]
+fn recorded_update_ids() {
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+    let clock = ::clock::SystemClock;
+
+    let mut update = NewUpdate { user_id: -1, ..test_update(1000.) };
+    let first_id = record_update(conn, &clock, &update, None).unwrap().unwrap();
+
+    // pretend that the first update was recorded long enough ago for another one to be recorded
+    let mut last_update = get_last_update(update.user_id, 0, conn).unwrap().unwrap();
+    assert_eq!(last_update.id, first_id);
+    last_update.update_time = last_update.update_time - Duration::days(1);
+    update.playcount += 1;
+    let second_id = record_update(conn, &clock, &update, Some(&last_update)).unwrap().unwrap();
+    assert!(second_id > first_id);
+
+    // nothing changed since the second update, so it's skipped
+    let last_update = get_last_update(update.user_id, 0, conn).unwrap();
+    assert_eq!(record_update(conn, &clock, &update, last_update.as_ref()).unwrap(), None);
+}
+
+/// Inserts updates for user -1 in mode 0 with each of the given pp values, in order, returning their ids
+#[cfg(test)]
+fn insert_pp_history(conn: &MysqlConnection, pps: &[f32]) -> Vec<i32> {
+    use schema::updates::dsl as updates_dsl;
+
+    ensure_user(conn, -1, "PP History").unwrap();
+    pps.iter().map(|&pp| {
+        let update = NewUpdate { user_id: -1, ..test_update(pp) };
+        diesel::insert_into(updates_dsl::updates).values(&update).execute(conn).unwrap();
+        last_insert_id(conn).unwrap()
+    }).collect()
+}
+
+/// Make sure that recording an update moves the user's `last_update` to the time of the new update
+#[test]
+fn recorded_update_touches_user() {
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+    let clock = ::clock::SystemClock;
+
+    let ids = insert_pp_history(conn, &[1000.]);
+    let last_update = get_last_update(-1, 0, conn).unwrap();
+    let update = NewUpdate { user_id: -1, playcount: 51, ..test_update(1010.) };
+    let mut last_update = last_update.unwrap();
+    last_update.update_time = last_update.update_time - Duration::days(1);
+    let update_id = record_update(conn, &clock, &update, Some(&last_update)).unwrap().unwrap();
+    assert!(update_id > ids[0]);
+
+    let user = get_user(conn, -1).unwrap();
+    let update = get_last_update(-1, 0, conn).unwrap().unwrap();
+    assert_eq!(update.id, update_id);
+    assert_eq!(user.last_update, update.update_time);
+    assert!(user.first_update <= user.last_update);
+}
+
+/// Make sure that nothing is found for a user whose pp has never changed
+#[test]
+fn last_pp_diff_constant_pp() {
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    let ids = insert_pp_history(conn, &[1000., 1000., 1000.]);
+    assert!(find_last_update_with_different_pp(conn, -1, 0, 1000.).unwrap().is_none());
+    assert_eq!(find_first_update_after(conn, -1, 0, ids[0]).unwrap().map(|u| u.id), Some(ids[1]));
+    assert!(find_first_update_after(conn, -1, 0, ids[2]).unwrap().is_none());
+    // other modes aren't considered
+    assert!(find_last_update_with_different_pp(conn, -1, 1, 500.).unwrap().is_none());
+}
+
+/// Make sure that the update before a single pp change is found along with the first update after it
+#[test]
+fn last_pp_diff_single_change() {
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    let ids = insert_pp_history(conn, &[1000., 1000., 1100., 1100.]);
+    let different = find_last_update_with_different_pp(conn, -1, 0, 1100.).unwrap().unwrap();
+    assert_eq!(different.id, ids[1]);
+    assert_eq!(find_first_update_after(conn, -1, 0, different.id).unwrap().map(|u| u.id), Some(ids[2]));
+}
+
+/// Make sure that only the most recent pp change counts when a user's pp has gone back and forth
+#[test]
+fn last_pp_diff_oscillations() {
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    let ids = insert_pp_history(conn, &[1000., 1100., 1000., 1100., 1100.]);
+    let different = find_last_update_with_different_pp(conn, -1, 0, 1100.).unwrap().unwrap();
+    assert_eq!(different.id, ids[2]);
+    assert_eq!(find_first_update_after(conn, -1, 0, different.id).unwrap().map(|u| u.id), Some(ids[3]));
+
+    let different = find_last_update_with_different_pp(conn, -1, 0, 1000.).unwrap().unwrap();
+    assert_eq!(different.id, ids[4]);
+    assert!(find_first_update_after(conn, -1, 0, different.id).unwrap().is_none());
+}
+
+/// Make sure that the modes a user has updates in are each listed once
+#[test]
+fn tracked_modes() {
+    use schema::updates::dsl as updates_dsl;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    let update = |mode: i16| NewUpdate { user_id: -1, mode: mode, ..test_update(1000.) };
+    ensure_user(conn, -1, "Mode Hopper").unwrap();
+    assert_eq!(get_tracked_modes(conn, -1).unwrap(), Vec::<u8>::new());
+
+    diesel::insert_into(updates_dsl::updates).values(&vec![update(3), update(0), update(3)]).execute(conn).unwrap();
+    assert_eq!(get_tracked_modes(conn, -1).unwrap(), vec![0, 3]);
+}
+
+/// Make sure that the user with the most recent update is chosen when several users share a username
+#[test]
+fn duplicate_username_lookup() {
+    use schema::updates::dsl as updates_dsl;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    let update = |user_id: i32| NewUpdate { user_id: user_id, ..test_update(1000.) };
+    let set_update_time = |user_id: i32, timestamp: i64| {
+        diesel::update(updates_dsl::updates.filter(updates_dsl::user_id.eq(user_id)))
+            .set(updates_dsl::update_time.eq(NaiveDateTime::from_timestamp(timestamp, 0)))
+            .execute(conn)
+            .unwrap();
+    };
+
+    // the name was used by user -3 before they renamed themselves and user -4 took it
+    ensure_user(conn, -3, "Shared Name").unwrap();
+    ensure_user(conn, -4, "Shared Name").unwrap();
+    diesel::insert_into(updates_dsl::updates).values(&vec![update(-3), update(-4)]).execute(conn).unwrap();
+    set_update_time(-3, 1400000000);
+    set_update_time(-4, 1500000000);
+    assert_eq!(get_user_from_username(conn, "Shared Name").unwrap().unwrap().id, -4);
+
+    set_update_time(-3, 1600000000);
+    assert_eq!(get_user_from_username(conn, "shared name").unwrap().unwrap().id, -3);
+    assert!(get_user_from_username(conn, "Unused Name").unwrap().is_none());
+}
+
+/// Make sure that every update recorded after `ensure_user` belongs to a user row and that renames are picked up
+#[test]
+fn ensure_user_integrity() {
+    use schema::updates::dsl as updates_dsl;
+    use schema::users::dsl as users_dsl;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+    let clock = ::clock::SystemClock;
+
+    let update = NewUpdate { user_id: -2, ..test_update(1000.) };
+    ensure_user(conn, update.user_id, "Old Name").unwrap();
+    record_update(conn, &clock, &update, None).unwrap().unwrap();
+    ensure_user(conn, update.user_id, "New Name").unwrap();
+    ensure_user(conn, update.user_id, "New Name").unwrap();
+
+    let usr: User = users_dsl::users.find(update.user_id).first(conn).unwrap();
+    assert_eq!(usr.username, "New Name");
+
+    let update_count: i64 = updates_dsl::updates
+        .filter(updates_dsl::user_id.eq(update.user_id))
+        .count()
+        .get_result(conn)
+        .unwrap();
+    let owned_update_count: i64 = updates_dsl::updates
+        .inner_join(users_dsl::users)
+        .filter(updates_dsl::user_id.eq(update.user_id))
+        .count()
+        .get_result(conn)
+        .unwrap();
+    assert_eq!(update_count, 1);
+    assert_eq!(owned_update_count, update_count);
+}
+
+/// Make sure that hiscores are bucketed by the star ratings of their beatmaps and that uncached beatmaps are reported
+#[test]
+fn hiscore_difficulty_buckets() {
+    use models::{Beatmap, NewHiscore};
+    use schema::beatmaps::dsl as beatmaps_dsl;
+    use schema::hiscores::dsl as hiscores_dsl;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    let time = NaiveDateTime::from_timestamp(1500000000, 0);
+    let beatmaps: Vec<Beatmap> = [(-1, 4.2), (-2, 4.4), (-3, 5.7)].iter()
+        .map(|&(beatmap_id, difficulty)| Beatmap { difficulty: difficulty, ..test_beatmap(-1, beatmap_id) })
+        .collect();
+    diesel::insert_into(beatmaps_dsl::beatmaps).values(&beatmaps).execute(conn).unwrap();
+    // the same beatmap cached in another mode is left out of the buckets and the cached beatmaps of this mode
+    let taiko = Beatmap { mode: 1, difficulty: 9., ..beatmaps[0].clone() };
+    diesel::insert_into(beatmaps_dsl::beatmaps).values(&taiko).execute(conn).unwrap();
+    assert_eq!(get_cached_beatmaps(conn, &[-1], 0).unwrap()[&-1].difficulty, 4.2);
+    assert_eq!(get_cached_beatmaps(conn, &[-1], 1).unwrap()[&-1].difficulty, 9.);
+
+    let hiscores: Vec<NewHiscore> = [(-1, 100.), (-2, 200.), (-3, 300.), (-4, 400.)].iter().map(|&(beatmap_id, pp)| {
+        NewHiscore {
+            user_id: -1, mode: 0, beatmap_id: beatmap_id, score: 1000000, pp: pp, enabled_mods: 0,
+            rank: String::from("S"), score_time: time, count300: None, count100: None, count50: None,
+            countmiss: None, countkatu: None, countgeki: None, maxcombo: None, perfect: None,
+        }
+    }).collect();
+    diesel::insert_into(hiscores_dsl::hiscores).values(&hiscores).execute(conn).unwrap();
+
+    let buckets = get_difficulty_buckets(conn, -1, 0).unwrap();
+    assert_eq!(buckets, vec![
+        DifficultyBucket { min_stars: 4., max_stars: 4.5, plays: 2, avg_pp: 150. },
+        DifficultyBucket { min_stars: 5.5, max_stars: 6., plays: 1, avg_pp: 300. },
+    ]);
+    assert_eq!(get_uncached_hiscore_beatmaps(conn, -1, 0).unwrap(), vec![-4]);
+}
+
+/// Make sure that cached beatmaps are filtered by when they were last updated and come most recently updated first
+#[test]
+fn cached_beatmaps_in_range() {
+    use schema::beatmaps::dsl as beatmaps_dsl;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    // long enough ago that no real beatmap in the cache was updated around then
+    let base = NaiveDateTime::from_timestamp(631152000, 0);
+    let beatmaps: Vec<Beatmap> = [(-1, 0), (-2, 10), (-3, 20), (-4, 30)].iter()
+        .map(|&(beatmap_id, minutes)| Beatmap {
+            last_update: base + Duration::minutes(minutes),
+            title: format!("title {}", beatmap_id),
+            ..test_beatmap(-1, beatmap_id)
+        })
+        .collect();
+    diesel::insert_into(beatmaps_dsl::beatmaps).values(&beatmaps).execute(conn).unwrap();
+
+    let load = |from: i64, to: i64, limit: i64, offset: i64| -> Vec<i32> {
+        let range = (Some(base + Duration::minutes(from)), Some(base + Duration::minutes(to)));
+        load_cached_beatmaps(conn, range.0, range.1, limit, offset).unwrap()
+            .into_iter()
+            .map(|row| row.beatmap_id)
+            .collect()
+    };
+
+    assert_eq!(load(5, 30, 10, 0), vec![-4, -3, -2]);
+    assert_eq!(load(0, 15, 10, 0), vec![-2, -1]);
+    assert_eq!(load(0, 30, 2, 0), vec![-4, -3]);
+    assert_eq!(load(0, 30, 2, 2), vec![-2, -1]);
+    assert!(load(31, 60, 10, 0).is_empty());
+    let row = &load_cached_beatmaps(conn, Some(base), Some(base), 1, 0).unwrap()[0];
+    assert_eq!((row.beatmapset_id, row.title.as_str(), row.last_update), (-1, "title -1", base));
+}
+
+/// Make sure that the most recently recorded hiscores come first, can be paged through, and can be limited to a mode
+#[test]
+fn recent_hiscores() {
+    use models::NewHiscore;
+    use schema::hiscores::dsl as hiscores_dsl;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    ensure_user(conn, -1, "Recent Player").unwrap();
+    let time = NaiveDateTime::from_timestamp(1500000000, 0);
+    let hiscores: Vec<NewHiscore> = [(0, 100.), (0, 200.), (1, 300.), (0, 400.)].iter().map(|&(mode, pp)| {
+        NewHiscore {
+            user_id: -1, mode: mode, beatmap_id: -1, score: 1000000, pp: pp, enabled_mods: 0,
+            rank: String::from("S"), score_time: time, count300: None, count100: None, count50: None,
+            countmiss: None, countkatu: None, countgeki: None, maxcombo: None, perfect: None,
+        }
+    }).collect();
+    // inserted one at a time so that they're recorded in order
+    for hiscore in &hiscores {
+        diesel::insert_into(hiscores_dsl::hiscores).values(hiscore).execute(conn).unwrap();
+    }
+
+    let pps = |recent: Vec<(Hiscore, String)>| -> Vec<f32> { recent.into_iter().map(|(hs, _)| hs.pp).collect() };
+    let first_page = get_recent_hiscores(conn, None, 2, 0).unwrap();
+    assert_eq!(first_page[0].1, "Recent Player");
+    assert_eq!(pps(first_page), vec![400., 300.]);
+    assert_eq!(pps(get_recent_hiscores(conn, None, 2, 2).unwrap()), vec![200., 100.]);
+    assert_eq!(pps(get_recent_hiscores(conn, Some(0), 3, 0).unwrap()), vec![400., 200., 100.]);
+}
+
+/// Make sure that the best current hiscores of each mode are returned and that modes without hiscores are left out
+#[test]
+fn top_hiscores_by_mode() {
+    use models::NewHiscore;
+    use schema::hiscores::dsl as hiscores_dsl;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    // tracked in mode 1 as well, but without any hiscores there
+    insert_pp_history(conn, &[1000.]);
+    let time = NaiveDateTime::from_timestamp(1500000000, 0);
+    let hiscores: Vec<NewHiscore> = [(0, 100.), (0, 300.), (3, 50.), (0, 200.), (3, 80.)].iter()
+        .map(|&(mode, pp)| NewHiscore {
+            user_id: -1, mode: mode, beatmap_id: -1, score: 1000000, pp: pp, enabled_mods: 0,
+            rank: String::from("S"), score_time: time, count300: None, count100: None, count50: None,
+            countmiss: None, countkatu: None, countgeki: None, maxcombo: None, perfect: None,
+        })
+        .collect();
+    diesel::insert_into(hiscores_dsl::hiscores).values(&hiscores).execute(conn).unwrap();
+
+    let top = get_top_hiscores_by_mode(conn, -1, 2).unwrap();
+    let pps: Vec<(u8, Vec<f32>)> = top.into_iter()
+        .map(|(mode, hiscores)| (mode, hiscores.into_iter().map(|hs| hs.pp).collect()))
+        .collect();
+    assert_eq!(pps, vec![(0, vec![300., 200.]), (3, vec![80., 50.])]);
+    assert!(get_cached_beatmaps(conn, &[], 0).unwrap().is_empty());
+}
+
+/// Make sure that no updates are recorded for users who have opted out of tracking and that recording resumes once
+/// they opt back in
+#[test]
+fn disabled_user_not_recorded() {
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+    let clock = ::clock::SystemClock;
+
+    insert_pp_history(conn, &[1000.]);
+    // old enough that a new update would be recorded if the user were tracked
+    let mut last_update = get_last_update(-1, 0, conn).unwrap().unwrap();
+    last_update.update_time = last_update.update_time - Duration::days(1);
+    let update = NewUpdate { user_id: -1, playcount: 60, pp_rank: 40000, ..test_update(1100.) };
+
+    assert!(!set_tracking_enabled(conn, -1, false).unwrap().unwrap().enabled);
+    assert!(!is_tracking_enabled(conn, -1).unwrap());
+    assert_eq!(record_update(conn, &clock, &update, Some(&last_update)).unwrap(), None);
+    assert_eq!(get_last_update(-1, 0, conn).unwrap().unwrap().id, last_update.id);
+
+    assert!(set_tracking_enabled(conn, -1, true).unwrap().unwrap().enabled);
+    assert!(record_update(conn, &clock, &update, Some(&last_update)).unwrap().is_some());
+
+    assert!(is_tracking_enabled(conn, -2).unwrap());
+    assert!(set_tracking_enabled(conn, -2, false).unwrap().is_none());
+}
+
+#[test]
+fn updates_around_time() {
+    use schema::updates::dsl as updates_dsl;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    let ids = insert_pp_history(conn, &[1000., 1010., 1020., 1030., 1040.]);
+    // spread the updates an hour apart so that they can be told apart by time
+    let base = NaiveDateTime::from_timestamp(1500000000, 0);
+    for (i, &id) in ids.iter().enumerate() {
+        diesel::update(updates_dsl::updates.find(id))
+            .set(updates_dsl::update_time.eq(base + Duration::hours(i as i64)))
+            .execute(conn)
+            .unwrap();
+    }
+    let around = |time: NaiveDateTime, n: i64| -> Vec<i32> {
+        load_updates_around(conn, -1, 0, time, n).unwrap().iter().map(|update| update.id).collect()
+    };
+
+    // an update at exactly the given time counts as being before it
+    assert_eq!(around(base + Duration::hours(2), 1), vec![ids[1], ids[2], ids[3]]);
+    assert_eq!(around(base + Duration::minutes(90), 2), vec![ids[0], ids[1], ids[2], ids[3]]);
+    assert_eq!(around(base - Duration::hours(1), 2), vec![ids[0], ids[1]]);
+    assert_eq!(around(base + Duration::hours(10), 10), ids);
+    assert!(load_updates_around(conn, -1, 1, base, 10).unwrap().is_empty());
+}
+
+#[test]
+fn update_ordering() {
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    let ids = insert_pp_history(conn, &[1000., 1010., 1020., 1030.]);
+    let load = |descending: bool, limit: Option<i64>| -> Vec<i32> {
+        load_updates(conn, -1, 0, descending, limit).unwrap().iter().map(|update| update.id).collect()
+    };
+
+    // the updates are inserted within the same second, so ties in time are broken by id
+    assert_eq!(load(false, None), ids);
+    assert_eq!(load(true, None), ids.iter().rev().cloned().collect::<Vec<_>>());
+    assert_eq!(load(true, Some(2)), vec![ids[3], ids[2]]);
+    assert_eq!(load(false, Some(1)), vec![ids[0]]);
+}
+
+#[test]
+fn update_near_time() {
+    use schema::updates::dsl as updates_dsl;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    // three updates at 0h, 10h, and 12h
+    let ids = insert_pp_history(conn, &[1000., 1010., 1020.]);
+    let base = NaiveDateTime::from_timestamp(1500000000, 0);
+    for (&id, &hours) in ids.iter().zip([0, 10, 12].iter()) {
+        diesel::update(updates_dsl::updates.find(id))
+            .set(updates_dsl::update_time.eq(base + Duration::hours(hours)))
+            .execute(conn)
+            .unwrap();
+    }
+    let near = |hours: i64, bias: UpdateBias| -> Option<i32> {
+        find_update_near(conn, -1, 0, base + Duration::hours(hours), bias).unwrap().map(|update| update.id)
+    };
+
+    assert_eq!(near(4, UpdateBias::Nearest), Some(ids[0]));
+    assert_eq!(near(6, UpdateBias::Nearest), Some(ids[1]));
+    assert_eq!(near(11, UpdateBias::Nearest), Some(ids[1]));
+    assert_eq!(near(6, UpdateBias::Before), Some(ids[0]));
+    assert_eq!(near(6, UpdateBias::After), Some(ids[1]));
+    // updates at exactly the timestamp are picked regardless of the bias
+    assert_eq!(near(10, UpdateBias::Before), Some(ids[1]));
+    assert_eq!(near(10, UpdateBias::After), Some(ids[1]));
+    // only the side that the bias allows is considered at the edges of the history
+    assert_eq!(near(-1, UpdateBias::Before), None);
+    assert_eq!(near(-1, UpdateBias::Nearest), Some(ids[0]));
+    assert_eq!(near(20, UpdateBias::After), None);
+    assert_eq!(near(20, UpdateBias::Nearest), Some(ids[2]));
+    assert!(find_update_near(conn, -1, 1, base, UpdateBias::Nearest).unwrap().is_none());
+}
+
+/// Make sure that merging users moves all of their rows, deletes the merged user, and leaves other users alone
+#[test]
+fn user_merging() {
+    use models::NewHiscore;
+    use schema::hiscores::dsl as hiscores_dsl;
+    use schema::users::dsl as users_dsl;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    let ids = insert_pp_history(conn, &[1000., 1010., 1020.]);
+    daily_stats::backfill_daily_stats(conn, -1, 0).unwrap();
+    let hiscore = NewHiscore {
+        user_id: -1, mode: 0, beatmap_id: -1, score: 1000000, pp: 100., enabled_mods: 0, rank: String::from("S"),
+        score_time: NaiveDateTime::from_timestamp(1500000000, 0), count300: None, count100: None, count50: None,
+        countmiss: None, countkatu: None, countgeki: None, maxcombo: None, perfect: None,
+    };
+    diesel::insert_into(hiscores_dsl::hiscores).values(&hiscore).execute(conn).unwrap();
+    ensure_user(conn, -2, "Merged Into").unwrap();
+
+    assert!(merge_users(conn, -2, -2).is_err());
+    assert_eq!(merge_users(conn, -1, -3).unwrap(), None);
+    assert_eq!(get_last_update(-1, 0, conn).unwrap().map(|update| update.id), ids.last().cloned());
+
+    let report = merge_users(conn, -1, -2).unwrap().unwrap();
+    assert_eq!(report, MergeReport { updates_moved: 3, hiscores_moved: 1, duplicate_hiscores_deleted: 0 });
+    assert_eq!(get_last_update(-2, 0, conn).unwrap().map(|update| update.id), ids.last().cloned());
+    assert!(get_last_update(-1, 0, conn).unwrap().is_none());
+    assert!(users_dsl::users.find(-1).first::<User>(conn).optional().unwrap().is_none());
+    // the daily aggregates follow the updates
+    assert!(daily_stats::load_daily_stats(conn, -1, 0, None, None).unwrap().is_empty());
+    let days = daily_stats::load_daily_stats(conn, -2, 0, None, None).unwrap();
+    assert_eq!(days.iter().map(|day| day.pp_raw).collect::<Vec<f32>>(), vec![1020.]);
+
+    // merging again finds nothing left to move
+    let report = merge_users(conn, -1, -2).unwrap().unwrap();
+    assert_eq!(report, MergeReport { updates_moved: 0, hiscores_moved: 0, duplicate_hiscores_deleted: 0 });
+}
+
+/// Make sure that a play or flag recorded for both merged users is only kept once, that the merged user's flags are
+/// moved, and that an opted out user stays opted out
+#[test]
+fn user_merging_overlap() {
+    use helpers::user_flags::{load_user_flags, set_user_flag, UserFlagKind};
+    use models::NewHiscore;
+    use schema::hiscores::dsl as hiscores_dsl;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    ensure_user(conn, -1, "Merged From").unwrap();
+    ensure_user(conn, -2, "Merged Into").unwrap();
+    set_tracking_enabled(conn, -1, false).unwrap();
+    let hiscore = |user_id: i32, beatmap_id: i32| NewHiscore {
+        user_id: user_id, mode: 0, beatmap_id: beatmap_id, score: 1000000, pp: 100., enabled_mods: 0,
+        rank: String::from("S"), score_time: NaiveDateTime::from_timestamp(1500000000, 0), count300: None,
+        count100: None, count50: None, countmiss: None, countkatu: None, countgeki: None, maxcombo: None,
+        perfect: None,
+    };
+    let hiscores = vec![hiscore(-1, -1), hiscore(-1, -2), hiscore(-2, -1)];
+    diesel::insert_into(hiscores_dsl::hiscores).values(&hiscores).execute(conn).unwrap();
+    set_user_flag(conn, -1, UserFlagKind::DoNotAutoupdate, "known multi-account").unwrap();
+    set_user_flag(conn, -1, UserFlagKind::AllowResets, "data reset by user request").unwrap();
+    set_user_flag(conn, -2, UserFlagKind::AllowResets, "reset twice").unwrap();
+
+    let report = merge_users(conn, -1, -2).unwrap().unwrap();
+    assert_eq!(report, MergeReport { updates_moved: 0, hiscores_moved: 1, duplicate_hiscores_deleted: 1 });
+    let beatmap_ids: Vec<i32> = hiscores_dsl::hiscores
+        .filter(hiscores_dsl::user_id.eq(-2))
+        .select(hiscores_dsl::beatmap_id)
+        .order(hiscores_dsl::beatmap_id.asc())
+        .load(conn)
+        .unwrap();
+    assert_eq!(beatmap_ids, vec![-2, -1]);
+    assert!(!is_tracking_enabled(conn, -2).unwrap());
+    let flags: Vec<(String, String)> = load_user_flags(conn, -2).unwrap().into_iter()
+        .map(|flag| (flag.flag, flag.note))
+        .collect();
+    assert!(flags.contains(&(String::from("do_not_autoupdate"), String::from("known multi-account"))));
+    assert!(flags.contains(&(String::from("allow_resets"), String::from("reset twice"))));
+    assert_eq!(flags.len(), 2);
+    assert!(load_user_flags(conn, -1).unwrap().is_empty());
+}
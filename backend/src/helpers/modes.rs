@@ -1,6 +1,69 @@
 //! Definitions of the different mode types
 
-pub const STANDARD: u8 = 0;
-pub const TAIKO: u8 = 1;
-pub const CTB: u8 = 2;
-pub const MANIA: u8 = 3;
+pub use osutrack_types::mode::{GameMode, STANDARD, TAIKO, CTB, MANIA};
+
+/// Mode-appropriate names of the `count300`, `count100`, and `count50` stats of users, which are stored in the same
+/// columns for every mode but count different things outside of standard.  Responses include each alias alongside the
+/// raw field.  In taiko the counts are greats and goods and `count50` is always zero, while in catch they are caught
+/// fruits, drops, and droplets.  In mania they are the judgements that they're named after; the osu! API doesn't report
+/// totals of MAX (geki) and 200 (katu) judgements for users, so there is nothing more to expose for it.
+pub const COUNT_ALIASES: &'static [(u8, &'static [(&'static str, &'static str)])] = &[
+    (STANDARD, &[]),
+    (TAIKO, &[("count300", "count_great"), ("count100", "count_good")]),
+    (CTB, &[("count300", "fruits"), ("count100", "drops"), ("count50", "droplets")]),
+    (MANIA, &[]),
+];
+
+/// Returns the `(raw field, alias)` pairs of the count stats in `mode`; see `COUNT_ALIASES`
+pub fn count_aliases(mode: u8) -> &'static [(&'static str, &'static str)] {
+    COUNT_ALIASES.iter()
+        .find(|&&(aliased_mode, _)| aliased_mode == mode)
+        .map(|&(_, aliases)| aliases)
+        .unwrap_or(&[])
+}
+
+/// Makes sure that `mode` exists and is one of the `enabled` modes, returning an error message for clients otherwise
+pub fn check_mode(mode: u8, enabled: &[u8]) -> Result<u8, String> {
+    match GameMode::from_u8(mode) {
+        None => Err(format!("Invalid mode: {}; modes are numbered from {} to {}", mode, STANDARD, MANIA)),
+        Some(game_mode) if !enabled.contains(&mode) => {
+            Err(format!("Mode {} ({}) is not enabled on this instance", mode, game_mode))
+        },
+        Some(_) => Ok(mode),
+    }
+}
+
+#[test]
+fn count_alias_table() {
+    use std::collections::HashSet;
+
+    assert_eq!(COUNT_ALIASES.iter().map(|&(mode, _)| mode).collect::<Vec<_>>(), vec![STANDARD, TAIKO, CTB, MANIA]);
+    assert!(count_aliases(STANDARD).is_empty());
+    assert_eq!(count_aliases(CTB), &[("count300", "fruits"), ("count100", "drops"), ("count50", "droplets")]);
+    assert!(count_aliases(7).is_empty());
+
+    // aliases only ever rename the raw count fields and never clash with each other or with other stats
+    for &(_, aliases) in COUNT_ALIASES {
+        for &(raw, alias) in aliases {
+            assert!(["count300", "count100", "count50"].contains(&raw), "{}", raw);
+            assert!(!alias.starts_with("count_rank") && !alias.starts_with("pp_"), "{}", alias);
+        }
+        let names: HashSet<&str> = aliases.iter().map(|&(_, alias)| alias).collect();
+        assert_eq!(names.len(), aliases.len());
+    }
+}
+
+#[test]
+fn enabled_modes() {
+    let all = [STANDARD, TAIKO, CTB, MANIA];
+    for &mode in &all {
+        assert_eq!(check_mode(mode, &all), Ok(mode));
+    }
+    assert_eq!(check_mode(4, &all), Err(String::from("Invalid mode: 4; modes are numbered from 0 to 3")));
+
+    // a standard-only deployment rejects the other modes
+    assert_eq!(check_mode(STANDARD, &[STANDARD]), Ok(STANDARD));
+    let disabled = check_mode(MANIA, &[STANDARD]);
+    assert_eq!(disabled, Err(String::from("Mode 3 (osu!mania) is not enabled on this instance")));
+    assert!(check_mode(4, &[STANDARD]).unwrap_err().starts_with("Invalid mode"));
+}
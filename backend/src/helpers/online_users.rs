@@ -0,0 +1,162 @@
+//! Recording of the number of users in osu!'s IRC channel over time.  The count is read from a pluggable
+//! `OnlineUsersSource`; in production that's the `NAMES` reply of the channel on Bancho's IRC server.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+use diesel;
+use diesel::prelude::*;
+use diesel::mysql::MysqlConnection;
+
+use helpers::debug;
+use models::{NewOnlineUsers, OnlineUsers};
+use schema::online_users::dsl as online_users_dsl;
+
+/// The IRC server that the online users are counted on
+pub const IRC_SERVER: &'static str = "irc.ppy.sh:6667";
+/// The channel whose users are counted
+pub const IRC_CHANNEL: &'static str = "#osu";
+/// How long to wait for the IRC server to respond before giving up on a poll
+const IRC_TIMEOUT_SECS: u64 = 30;
+
+/// Something that can report the number of users that are currently online
+pub trait OnlineUsersSource {
+    fn fetch(&self) -> Result<NewOnlineUsers, String>;
+}
+
+/// Counts the users in an IRC channel by connecting to the server and requesting the channel's `NAMES`.  Operators are
+/// prefixed with `@` and voiced users with `+`; all of them are included in the total number of users.
+pub struct IrcNamesSource {
+    pub server: &'static str,
+    pub channel: &'static str,
+    pub username: &'static str,
+    pub password: &'static str,
+}
+
+/// Splits a line received from an IRC server into its command and the rest of the message, dropping the prefix
+fn split_irc_line(line: &str) -> (&str, &str) {
+    let line = if line.starts_with(':') { line.splitn(2, ' ').nth(1).unwrap_or("") } else { line };
+    let mut parts = line.splitn(2, ' ');
+    (parts.next().unwrap_or(""), parts.next().unwrap_or(""))
+}
+
+/// Adds the names in the trailing parameter of a `353` (`RPL_NAMREPLY`) message to `counts`
+fn count_names(params: &str, counts: &mut NewOnlineUsers) {
+    let names = params.splitn(2, " :").nth(1).unwrap_or("");
+    for name in names.split_whitespace() {
+        counts.users += 1;
+        if name.starts_with('@') {
+            counts.operators += 1;
+        } else if name.starts_with('+') {
+            counts.voiced += 1;
+        }
+    }
+}
+
+impl OnlineUsersSource for IrcNamesSource {
+    fn fetch(&self) -> Result<NewOnlineUsers, String> {
+        let stream = TcpStream::connect(self.server).map_err(debug)?;
+        stream.set_read_timeout(Some(Duration::from_secs(IRC_TIMEOUT_SECS))).map_err(debug)?;
+        let mut writer = stream.try_clone().map_err(debug)?;
+        write!(
+            writer, "PASS {}\r\nNICK {}\r\nUSER {} 0 * :{}\r\n", self.password, self.username, self.username,
+            self.username
+        ).map_err(debug)?;
+
+        let mut counts = NewOnlineUsers { users: 0, operators: 0, voiced: 0 };
+        for line in BufReader::new(stream).lines() {
+            let line = line.map_err(debug)?;
+            match split_irc_line(line.trim_right()) {
+                ("PING", params) => { write!(writer, "PONG {}\r\n", params).map_err(debug)?; },
+                // only request the names once the server has accepted the login
+                ("001", _) => { write!(writer, "NAMES {}\r\n", self.channel).map_err(debug)?; },
+                ("464", _) => { return Err(String::from("The IRC server rejected the configured password")); },
+                ("353", params) => count_names(params, &mut counts),
+                ("366", _) => {
+                    let _ = write!(writer, "QUIT\r\n");
+                    return Ok(counts);
+                },
+                _ => (),
+            }
+        }
+
+        Err(String::from("The IRC server closed the connection before listing the channel's users"))
+    }
+}
+
+/// Fetches the current number of online users from `source` and stores them in the database
+pub fn record_online_users<S: OnlineUsersSource>(conn: &MysqlConnection, source: &S) -> Result<(), String> {
+    let counts = source.fetch()?;
+    diesel::insert_into(online_users_dsl::online_users)
+        .values(&counts)
+        .execute(conn)
+        .map(|_| ())
+        .map_err(debug)
+}
+
+/// Loads the recorded online user counts between `from` and `to` (inclusive), ordered by time
+pub fn load_online_users(
+    conn: &MysqlConnection, from: Option<NaiveDateTime>, to: Option<NaiveDateTime>
+) -> Result<Vec<OnlineUsers>, String> {
+    let mut query = online_users_dsl::online_users.into_boxed();
+    if let Some(from) = from {
+        query = query.filter(online_users_dsl::time_recorded.ge(from));
+    }
+    if let Some(to) = to {
+        query = query.filter(online_users_dsl::time_recorded.le(to));
+    }
+
+    query.order(online_users_dsl::time_recorded.asc())
+        .load(conn)
+        .map_err(debug)
+}
+
+#[test]
+fn names_reply_counting() {
+    let mut counts = NewOnlineUsers { users: 0, operators: 0, voiced: 0 };
+    let lines = [
+        ":cho.ppy.sh 001 osutrack :Welcome to the osu!Bancho.",
+        ":cho.ppy.sh 353 osutrack = #osu :@Tillerino +BanchoBot Cookiezi [_Frost_]",
+        ":cho.ppy.sh 353 osutrack = #osu :@peppy Ameo",
+        ":cho.ppy.sh 366 osutrack #osu :End of /NAMES list.",
+    ];
+    for line in lines.iter() {
+        if let ("353", params) = split_irc_line(line) {
+            count_names(params, &mut counts);
+        }
+    }
+
+    assert_eq!(counts, NewOnlineUsers { users: 6, operators: 2, voiced: 1 });
+    assert_eq!(split_irc_line("PING :cho.ppy.sh"), ("PING", ":cho.ppy.sh"));
+}
+
+#[cfg(test)]
+struct FakeSource(NewOnlineUsers);
+
+#[cfg(test)]
+impl OnlineUsersSource for FakeSource {
+    fn fetch(&self) -> Result<NewOnlineUsers, String> {
+        Ok(self.0.clone())
+    }
+}
+
+/// Make sure that the counts reported by the source are stored
+#[test]
+fn online_users_recording() {
+    use helpers::create_db_pool;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    let source = FakeSource(NewOnlineUsers { users: 1234, operators: 12, voiced: 34 });
+    record_online_users(conn, &source).unwrap();
+
+    let latest: OnlineUsers = online_users_dsl::online_users
+        .order(online_users_dsl::time_recorded.desc())
+        .first(conn)
+        .unwrap();
+    assert_eq!((latest.users, latest.operators, latest.voiced), (1234, 12, 34));
+}
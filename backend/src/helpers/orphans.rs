@@ -0,0 +1,179 @@
+//! Detection and repair of updates and hiscores whose user doesn't have a row in `users`.  Races in old versions of the
+//! backend recorded stats before the user's row was created, which hides them from queries that go through the user.
+
+use diesel;
+use diesel::prelude::*;
+use diesel::mysql::MysqlConnection;
+use diesel::types::{BigInt, Integer};
+
+use helpers::debug;
+use models::NewUser;
+use schema::users::dsl as users_dsl;
+
+/// Selects the distinct ids of users that have updates but no user row
+const ORPHANED_UPDATE_USERS_QUERY: &'static str = "SELECT DISTINCT u.user_id AS user_id FROM updates u
+    WHERE NOT EXISTS (SELECT 1 FROM users WHERE users.id = u.user_id)";
+/// Selects the distinct ids of users that have hiscores but no user row
+const ORPHANED_HISCORE_USERS_QUERY: &'static str = "SELECT DISTINCT h.user_id AS user_id FROM hiscores h
+    WHERE NOT EXISTS (SELECT 1 FROM users WHERE users.id = h.user_id)";
+
+/// The number of rows that reference users without a user row
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct OrphanReport {
+    pub orphaned_updates: i64,
+    pub orphaned_hiscores: i64,
+    /// The ids of the users that are referenced but don't have a row, in ascending order
+    pub user_ids: Vec<i32>,
+}
+
+/// A placeholder user row created by `repair_orphans`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RepairedUser {
+    pub id: i32,
+    pub username: String,
+    /// Set if the username was found using the osu! API rather than made up
+    pub found: bool,
+}
+
+#[derive(QueryableByName)]
+struct UserIdRow {
+    #[sql_type = "Integer"]
+    user_id: i32,
+}
+
+#[derive(QueryableByName)]
+struct CountRow {
+    #[sql_type = "BigInt"]
+    count: i64,
+}
+
+/// Returns the username given to placeholder rows of users whose name couldn't be found.  Kept short enough that any
+/// user id fits into the 15 character username column.
+pub fn placeholder_username(user_id: i32) -> String {
+    format!("u_{}", user_id)
+}
+
+/// Returns the ids of all users that have updates or hiscores but no user row, in ascending order
+pub fn find_orphaned_user_ids(conn: &MysqlConnection) -> Result<Vec<i32>, String> {
+    let rows: Vec<UserIdRow> = diesel::sql_query(format!(
+        "SELECT o.user_id FROM ({} UNION {}) o ORDER BY o.user_id ASC",
+        ORPHANED_UPDATE_USERS_QUERY, ORPHANED_HISCORE_USERS_QUERY
+    )).load(conn)
+        .map_err(debug)?;
+
+    Ok(rows.into_iter().map(|row| row.user_id).collect())
+}
+
+/// Counts the updates and hiscores that reference users without a user row
+pub fn count_orphans(conn: &MysqlConnection) -> Result<OrphanReport, String> {
+    let count = |table: &str| -> Result<i64, String> {
+        let row: CountRow = diesel::sql_query(format!(
+            "SELECT COUNT(*) AS count FROM {} t WHERE NOT EXISTS (SELECT 1 FROM users WHERE users.id = t.user_id)",
+            table
+        )).get_result(conn)
+            .map_err(debug)?;
+        Ok(row.count)
+    };
+
+    Ok(OrphanReport {
+        orphaned_updates: count("updates")?,
+        orphaned_hiscores: count("hiscores")?,
+        user_ids: find_orphaned_user_ids(conn)?,
+    })
+}
+
+/// Creates a user row for every user that has updates or hiscores but no row.  `lookup_username` is called with the id
+/// of each of them and should return their current username, or `None` if it can't be found, in which case the user
+/// is named by `placeholder_username`.  The lookups are made before any rows are written, and the rows are inserted in
+/// a single transaction that skips users whose row was created in the meantime, so running this again is harmless.
+/// Only the users whose rows were actually inserted are returned.
+pub fn repair_orphans<F>(conn: &MysqlConnection, lookup_username: F) -> Result<Vec<RepairedUser>, String>
+    where F: Fn(i32) -> Option<String>
+{
+    let candidates: Vec<RepairedUser> = find_orphaned_user_ids(conn)?
+        .into_iter()
+        .map(|user_id| match lookup_username(user_id) {
+            Some(username) => RepairedUser { id: user_id, username: username, found: true },
+            None => RepairedUser { id: user_id, username: placeholder_username(user_id), found: false },
+        })
+        .collect();
+    if candidates.is_empty() {
+        return Ok(candidates);
+    }
+
+    // rows are inserted one at a time so that the ones that were skipped can be told apart by their affected counts
+    let inserted: Vec<bool> = conn.transaction::<_, diesel::result::Error, _>(|| {
+        candidates.iter()
+            .map(|usr| {
+                let row = NewUser { id: usr.id, username: usr.username.clone() };
+                diesel::insert_or_ignore_into(users_dsl::users).values(&row).execute(conn).map(|count| count > 0)
+            })
+            .collect()
+    }).map_err(debug)?;
+
+    Ok(candidates.into_iter()
+        .zip(inserted)
+        .filter(|&(_, inserted)| inserted)
+        .map(|(usr, _)| usr)
+        .collect())
+}
+
+/// Make sure that updates and hiscores without user rows are reported and that repairing them creates placeholder rows
+/// exactly once, only reporting the rows that it created
+#[test]
+fn orphan_repair() {
+    use chrono::NaiveDateTime;
+    use helpers::{create_db_pool, ensure_user};
+    use models::{NewHiscore, NewUpdate, User};
+    use schema::hiscores::dsl as hiscores_dsl;
+    use schema::updates::dsl as updates_dsl;
+    use test_support::test_update;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    let update = NewUpdate { user_id: -7, ..test_update(1000.) };
+    diesel::insert_into(updates_dsl::updates).values(&vec![update.clone(), update]).execute(conn).unwrap();
+    let hiscore = NewHiscore {
+        user_id: -8, mode: 0, beatmap_id: -1, score: 1000000, pp: 100., enabled_mods: 0, rank: String::from("S"),
+        score_time: NaiveDateTime::from_timestamp(1500000000, 0), count300: None, count100: None, count50: None,
+        countmiss: None, countkatu: None, countgeki: None, maxcombo: None, perfect: None,
+    };
+    diesel::insert_into(hiscores_dsl::hiscores).values(&hiscore).execute(conn).unwrap();
+
+    // other orphans may already exist in the database, so only the seeded ones are known
+    let before = count_orphans(conn).unwrap();
+    assert!(before.orphaned_updates >= 2 && before.orphaned_hiscores >= 1);
+    assert!(before.user_ids.contains(&-7) && before.user_ids.contains(&-8));
+
+    // the row of the orphan -9 is created by someone else while its username is being looked up
+    let orphan = NewUpdate { user_id: -9, ..test_update(1000.) };
+    diesel::insert_into(updates_dsl::updates).values(&orphan).execute(conn).unwrap();
+    let lookup = |user_id: i32| match user_id {
+        -7 => Some(String::from("Found Orphan")),
+        -9 => {
+            ensure_user(conn, -9, "Raced Orphan").unwrap();
+            Some(String::from("Raced Orphan"))
+        },
+        _ => None,
+    };
+    let repaired = repair_orphans(conn, &lookup).unwrap();
+    assert!(repaired.contains(&RepairedUser { id: -7, username: String::from("Found Orphan"), found: true }));
+    assert!(repaired.contains(&RepairedUser { id: -8, username: String::from("u_-8"), found: false }));
+    assert!(repaired.iter().all(|usr| usr.id != -9));
+    let usr: User = users_dsl::users.find(-8).first(conn).unwrap();
+    assert_eq!(usr.username, "u_-8");
+
+    let after = count_orphans(conn).unwrap();
+    assert_eq!(after, OrphanReport { orphaned_updates: 0, orphaned_hiscores: 0, user_ids: Vec::new() });
+    assert!(repair_orphans(conn, &lookup).unwrap().is_empty());
+}
+
+#[test]
+fn placeholder_username_length() {
+    use std::i32;
+
+    assert_eq!(placeholder_username(12345678), "u_12345678");
+    assert!(placeholder_username(i32::MIN).len() <= 15);
+}
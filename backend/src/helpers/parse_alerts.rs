@@ -0,0 +1,132 @@
+//! Detection of spikes in the rate at which osu! API responses fail to parse.  When the format of the API changes,
+//! every update starts failing with a parse error, so operators are alerted once the failure rate over a recent window
+//! passes a threshold rather than finding out from users.
+
+use std::collections::VecDeque;
+
+use chrono::{Duration, NaiveDateTime};
+
+use helpers::raw_snapshots::truncate_payload;
+
+/// The maximum number of bytes of the failing payload included in alerts
+const ALERT_SNIPPET_BYTES: usize = 512;
+
+/// An alert about parse failures of osu! API responses, sent to the ops webhook as JSON
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ParseAlert {
+    pub time: NaiveDateTime,
+    /// The number of responses that failed to parse during the window
+    pub failures: usize,
+    /// The number of responses that were parsed during the window, including those that failed
+    pub attempts: usize,
+    pub window_secs: i64,
+    /// The error of the most recent failure
+    pub last_error: String,
+    /// The start of the payload that caused the most recent failure
+    pub payload_snippet: String,
+}
+
+/// Keeps track of the outcomes of parsing osu! API responses over a sliding window.  An alert is raised once at least
+/// `min_failures` responses have failed to parse during the window and they make up at least `failure_rate` of all
+/// parsed responses.  No further alerts are raised until `cooldown` has passed since the last one.
+pub struct ParseFailureTracker {
+    min_failures: usize,
+    failure_rate: f32,
+    window: Duration,
+    cooldown: Duration,
+    /// The time of each parse attempt during the window and whether it failed, oldest first
+    attempts: VecDeque<(NaiveDateTime, bool)>,
+    failures: usize,
+    last_alert: Option<NaiveDateTime>,
+}
+
+impl ParseFailureTracker {
+    pub fn new(min_failures: usize, failure_rate: f32, window: Duration, cooldown: Duration) -> ParseFailureTracker {
+        ParseFailureTracker {
+            min_failures: min_failures,
+            failure_rate: failure_rate,
+            window: window,
+            cooldown: cooldown,
+            attempts: VecDeque::new(),
+            failures: 0,
+            last_alert: None,
+        }
+    }
+
+    /// Forgets the attempts made before the start of the window ending at `now`
+    fn expire(&mut self, now: NaiveDateTime) {
+        let window_start = now - self.window;
+        while self.attempts.front().map(|&(time, _)| time < window_start).unwrap_or(false) {
+            if let Some((_, true)) = self.attempts.pop_front() {
+                self.failures -= 1;
+            }
+        }
+    }
+
+    /// Records a response that was parsed successfully
+    pub fn record_success(&mut self, now: NaiveDateTime) {
+        self.expire(now);
+        self.attempts.push_back((now, false));
+    }
+
+    /// Records a response that failed to parse with `error`, returning an alert if the failure rate has passed the
+    /// threshold and no alert has been raised during the cooldown
+    pub fn record_failure(&mut self, now: NaiveDateTime, error: &str, payload: &str) -> Option<ParseAlert> {
+        self.expire(now);
+        self.attempts.push_back((now, true));
+        self.failures += 1;
+
+        let rate = self.failures as f32 / self.attempts.len() as f32;
+        if self.failures < self.min_failures || rate < self.failure_rate {
+            return None;
+        }
+        if let Some(last_alert) = self.last_alert {
+            if now - last_alert < self.cooldown {
+                return None;
+            }
+        }
+
+        self.last_alert = Some(now);
+        Some(ParseAlert {
+            time: now,
+            failures: self.failures,
+            attempts: self.attempts.len(),
+            window_secs: self.window.num_seconds(),
+            last_error: String::from(error),
+            payload_snippet: truncate_payload(payload, ALERT_SNIPPET_BYTES).0,
+        })
+    }
+}
+
+#[test]
+fn parse_failure_alerting() {
+    let start = NaiveDateTime::from_timestamp(1500000000, 0);
+    let at = |secs: i64| start + Duration::seconds(secs);
+    let mut tracker = ParseFailureTracker::new(3, 0.5, Duration::minutes(5), Duration::hours(1));
+
+    // failures below the minimum count or rate don't raise alerts
+    for i in 0..4 {
+        tracker.record_success(at(i));
+    }
+    assert_eq!(tracker.record_failure(at(10), "bad", "{}"), None);
+    assert_eq!(tracker.record_failure(at(11), "bad", "{}"), None);
+    assert_eq!(tracker.record_failure(at(12), "bad", "{}"), None);
+    let alert = tracker.record_failure(at(13), "missing field `pp_raw`", &"x".repeat(2000)).unwrap();
+    assert_eq!((alert.failures, alert.attempts, alert.window_secs), (4, 8, 300));
+    assert_eq!(alert.last_error, "missing field `pp_raw`");
+    assert_eq!(alert.payload_snippet.len(), ALERT_SNIPPET_BYTES);
+
+    // further failures are suppressed during the cooldown
+    assert_eq!(tracker.record_failure(at(20), "bad", "{}"), None);
+
+    // old attempts fall out of the window, so failures after a long period of successes don't alert right away
+    for i in 0..10 {
+        tracker.record_success(at(2 * 3600 + i));
+    }
+    assert_eq!(tracker.record_failure(at(2 * 3600 + 20), "bad", "{}"), None);
+    for i in 0..10 {
+        tracker.record_failure(at(2 * 3600 + 30 + i), "bad", "{}");
+    }
+    // the cooldown has passed by now, so the tenth failure in the window alerts again
+    assert_eq!(tracker.last_alert, Some(at(2 * 3600 + 38)));
+}
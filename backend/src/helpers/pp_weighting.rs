@@ -0,0 +1,80 @@
+//! The weighting that osu! applies to a user's top plays when adding them up into their total pp.  The play with the
+//! most pp counts in full and each one after it counts for 95% of the one before it.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+
+use models::Hiscore;
+
+/// The factor that the weight of each top play is multiplied by relative to the play before it
+pub const PP_WEIGHT_DECAY: f64 = 0.95;
+
+/// Details about a hiscore derived from the user's other top plays and the current time
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct HiscoreEnrichment {
+    /// The number of whole days since the play was set
+    pub age_days: i64,
+    /// The 1-based position of the play among the user's current top plays ordered by pp, or `null` if it has been
+    /// pushed out of them
+    pub position: Option<u32>,
+    /// The pp that the play contributes to the user's total, or `null` if it has been pushed out of their top plays
+    pub weighted_pp: Option<f32>,
+}
+
+/// Returns the fraction of its pp that the play at the 1-based `position` of a user's top plays contributes
+pub fn pp_weight(position: u32) -> f64 {
+    PP_WEIGHT_DECAY.powi(position as i32 - 1)
+}
+
+/// Maps the ids of a user's current top plays, given as `(id, pp)` pairs, to their 1-based positions ordered by pp.
+/// Plays with the same pp are ordered by id.
+pub fn current_positions(current: &[(i32, f32)]) -> HashMap<i32, u32> {
+    let mut by_pp: Vec<&(i32, f32)> = current.iter().collect();
+    by_pp.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal).then(a.0.cmp(&b.0)));
+
+    by_pp.into_iter()
+        .enumerate()
+        .map(|(i, &(id, _))| (id, i as u32 + 1))
+        .collect()
+}
+
+/// Computes the enrichment of a hiscore as of `now` given the positions returned by `current_positions`
+pub fn enrich_hiscore(hiscore: &Hiscore, positions: &HashMap<i32, u32>, now: NaiveDateTime) -> HiscoreEnrichment {
+    let position = positions.get(&hiscore.id).cloned();
+    HiscoreEnrichment {
+        age_days: (now - hiscore.score_time).num_days(),
+        position: position,
+        weighted_pp: position.map(|position| (hiscore.pp as f64 * pp_weight(position)) as f32),
+    }
+}
+
+#[test]
+fn pp_weighting() {
+    use chrono::Duration;
+
+    assert_eq!(pp_weight(1), 1.);
+    assert!((pp_weight(2) - 0.95).abs() < 1e-9);
+    assert!((pp_weight(11) - 0.95f64.powi(10)).abs() < 1e-9);
+
+    let positions = current_positions(&[(1, 100.), (2, 300.), (3, 200.), (4, 200.)]);
+    assert_eq!(positions.get(&2), Some(&1));
+    assert_eq!((positions.get(&3), positions.get(&4)), (Some(&2), Some(&3)));
+    assert_eq!(positions.get(&1), Some(&4));
+
+    let now = NaiveDateTime::from_timestamp(1500000000, 0);
+    let hiscore = |id: i32, pp: f32, days_ago: i64| Hiscore {
+        id: id, user_id: 1, mode: 0, beatmap_id: 1, score: 1000000, pp: pp, enabled_mods: 0, rank: String::from("S"),
+        score_time: now - Duration::days(days_ago) - Duration::hours(1), time_recorded: now, count300: None,
+        count100: None, count50: None, countmiss: None, countkatu: None, countgeki: None, maxcombo: None,
+        perfect: None, dropped_at: None,
+    };
+
+    let enriched = enrich_hiscore(&hiscore(3, 200., 730), &positions, now);
+    assert_eq!((enriched.age_days, enriched.position), (730, Some(2)));
+    assert!((enriched.weighted_pp.unwrap() - 190.).abs() < 1e-3);
+    // plays that aren't among the current top plays don't contribute anything
+    let dropped = enrich_hiscore(&hiscore(5, 400., 0), &positions, now);
+    assert_eq!(dropped, HiscoreEnrichment { age_days: 0, position: None, weighted_pp: None });
+}
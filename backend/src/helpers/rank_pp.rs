@@ -0,0 +1,305 @@
+//! Estimation of the pp needed to reach a given rank and vice versa.  A snapshot of the latest stats of every tracked
+//! user is taken nightly and reduced to buckets of (median rank, median pp) points, which are then interpolated between.
+
+use chrono::NaiveDate;
+use diesel;
+use diesel::prelude::*;
+use diesel::mysql::MysqlConnection;
+use diesel::types::{Float, Integer, SmallInt};
+
+use helpers::debug;
+use secret::ENABLED_MODES;
+use models::{NewRankPpSample, RankPpSample};
+use schema::rank_pp_samples::dsl as samples_dsl;
+
+/// The minimum number of users that must be sampled into a bucket.  Buckets with fewer samples are widened until they
+/// have enough.
+pub const MIN_BUCKET_SAMPLES: usize = 10;
+
+/// A point relating a rank to the pp of users at that rank
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct RankPpPoint {
+    pub rank: i32,
+    pub pp: f32,
+    pub sample_count: i32,
+}
+
+impl<'a> From<&'a RankPpSample> for RankPpPoint {
+    fn from(sample: &'a RankPpSample) -> RankPpPoint {
+        RankPpPoint { rank: sample.rank_bucket, pp: sample.median_pp, sample_count: sample.sample_count }
+    }
+}
+
+/// Returns the bucket edge after `edge`, following the sequence 10, 25, 50, 100, 250, 500, 1000, ...
+fn next_edge(edge: i64) -> i64 {
+    let mut magnitude = 1;
+    while magnitude * 10 <= edge {
+        magnitude *= 10;
+    }
+
+    match edge / magnitude {
+        1 => magnitude * 5 / 2,
+        2 => magnitude * 5,
+        _ => magnitude * 10,
+    }
+}
+
+fn median<T: Copy + PartialOrd>(vals: &mut [T]) -> T {
+    vals.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    vals[vals.len() / 2]
+}
+
+/// Groups `(rank, pp)` samples into buckets of increasing width, merging each bucket with the next until it contains at
+/// least `min_samples` samples, and reduces each bucket to its median rank and pp.  The returned points are sorted by
+/// rank.
+pub fn bucket_samples(mut samples: Vec<(i32, f32)>, min_samples: usize) -> Vec<RankPpPoint> {
+    samples.retain(|&(rank, _)| rank > 0);
+    samples.sort_by_key(|&(rank, _)| rank);
+
+    let mut buckets: Vec<Vec<(i32, f32)>> = Vec::new();
+    let mut cur_bucket = Vec::new();
+    let mut edge = 10;
+    for sample in samples {
+        while sample.0 as i64 >= edge {
+            if cur_bucket.len() >= min_samples {
+                buckets.push(cur_bucket);
+                cur_bucket = Vec::new();
+            }
+            edge = next_edge(edge);
+        }
+        cur_bucket.push(sample);
+    }
+
+    // a sparse final bucket is merged into the one before it
+    if !cur_bucket.is_empty() {
+        match buckets.last_mut() {
+            Some(last) if cur_bucket.len() < min_samples => last.extend(cur_bucket),
+            _ => buckets.push(cur_bucket),
+        }
+    }
+
+    buckets.into_iter()
+        .map(|bucket| {
+            let mut ranks: Vec<i32> = bucket.iter().map(|&(rank, _)| rank).collect();
+            let mut pps: Vec<f32> = bucket.iter().map(|&(_, pp)| pp).collect();
+            RankPpPoint { rank: median(&mut ranks), pp: median(&mut pps), sample_count: bucket.len() as i32 }
+        })
+        .collect()
+}
+
+/// Returns how far `rank` lies between the ranks `lo` and `hi` on a logarithmic scale, from 0 at `lo` to 1 at `hi`
+fn log_rank_position(lo: i32, hi: i32, rank: i32) -> f64 {
+    if hi == lo {
+        return 0.;
+    }
+
+    ((rank as f64).ln() - (lo as f64).ln()) / ((hi as f64).ln() - (lo as f64).ln())
+}
+
+/// Estimates the pp needed to reach `rank` by interpolating between `points` (sorted by rank) on a logarithmic rank
+/// scale.  Ranks outside of the range covered by the points are clamped to it.  Returns `None` if there are no points.
+pub fn pp_for_rank(points: &[RankPpPoint], rank: i32) -> Option<f32> {
+    let (first, last) = (points.first()?, points.last()?);
+    if rank <= first.rank {
+        return Some(first.pp);
+    } else if rank >= last.rank {
+        return Some(last.pp);
+    }
+
+    let segment = points.windows(2).find(|w| rank >= w[0].rank && rank <= w[1].rank)?;
+    let (lo, hi) = (segment[0], segment[1]);
+    let t = log_rank_position(lo.rank, hi.rank, rank);
+    Some((lo.pp as f64 + t * (hi.pp - lo.pp) as f64) as f32)
+}
+
+/// Estimates the percentage of tracked users that a user at `rank` is ranked at or above by interpolating the number of
+/// users ranked ahead of them between `points` (sorted by rank) on a logarithmic rank scale.  Half of each bucket's
+/// users are assumed to be ranked ahead of its median rank.  Returns `None` if there are no points or if `rank` isn't a
+/// valid rank.
+pub fn rank_percentile(points: &[RankPpPoint], rank: i32) -> Option<f32> {
+    let total: i32 = points.iter().map(|point| point.sample_count).sum();
+    if rank < 1 || total == 0 {
+        return None;
+    }
+
+    // the number of users ranked ahead of the median of each bucket, starting with nobody being ahead of rank 1
+    let mut anchors: Vec<(i32, f64)> = vec![(1, 0.)];
+    let mut ahead = 0.;
+    for point in points {
+        anchors.push((point.rank, ahead + point.sample_count as f64 / 2.));
+        ahead += point.sample_count as f64;
+    }
+
+    // ranks past the last bucket's median are clamped to it
+    let ahead = match anchors.windows(2).find(|w| rank >= w[0].0 && rank <= w[1].0) {
+        Some(w) => w[0].1 + log_rank_position(w[0].0, w[1].0, rank) * (w[1].1 - w[0].1),
+        None => anchors[anchors.len() - 1].1,
+    };
+    Some((100. * (1. - ahead / total as f64)) as f32)
+}
+
+/// Estimates the rank of a user with `pp` pp by interpolating between `points` (sorted by rank) on a logarithmic rank
+/// scale.  Amounts of pp outside of the range covered by the points are clamped to it.  Returns `None` if there are no
+/// points.
+pub fn rank_for_pp(points: &[RankPpPoint], pp: f32) -> Option<i32> {
+    let (first, last) = (points.first()?, points.last()?);
+    if pp >= first.pp {
+        return Some(first.rank);
+    } else if pp <= last.pp {
+        return Some(last.rank);
+    }
+
+    // medians aren't guaranteed to be strictly decreasing, so use the first segment that spans the pp
+    let segment = points.windows(2).find(|w| (pp <= w[0].pp && pp >= w[1].pp) || (pp >= w[0].pp && pp <= w[1].pp))?;
+    let (lo, hi) = (segment[0], segment[1]);
+    let t = if hi.pp == lo.pp { 0. } else { (pp - lo.pp) as f64 / (hi.pp - lo.pp) as f64 };
+    let ln_rank = (lo.rank as f64).ln() + t * ((hi.rank as f64).ln() - (lo.rank as f64).ln());
+    Some(ln_rank.exp().round() as i32)
+}
+
+/// The rank and pp of a tracked user as of their most recent update
+#[derive(QueryableByName)]
+struct LatestRankRow {
+    #[sql_type = "Integer"]
+    pp_rank: i32,
+    #[sql_type = "Float"]
+    pp_raw: f32,
+}
+
+/// Returns the `(rank, pp)` of every tracked user with a rank in a mode as of their most recent update
+fn load_latest_ranks(connection: &MysqlConnection, mode: u8) -> Result<Vec<(i32, f32)>, diesel::result::Error> {
+    let rows: Vec<LatestRankRow> = diesel::sql_query(
+        "SELECT u.pp_rank, u.pp_raw FROM updates u
+        INNER JOIN (SELECT MAX(id) AS id FROM updates WHERE mode = ? GROUP BY user_id) latest ON latest.id = u.id
+        WHERE u.pp_rank > 0"
+    ).bind::<SmallInt, _>(mode as i16)
+        .load(connection)?;
+
+    Ok(rows.into_iter().map(|row| (row.pp_rank, row.pp_raw)).collect())
+}
+
+/// Records a snapshot of the rank/pp distribution of all tracked users in every enabled mode for `date`, replacing any
+/// snapshot that was already recorded for that date.
+pub fn record_snapshot(connection: &MysqlConnection, date: NaiveDate) -> Result<(), String> {
+    connection.transaction::<_, diesel::result::Error, _>(|| {
+        for &mode in ENABLED_MODES {
+            let points = bucket_samples(load_latest_ranks(connection, mode)?, MIN_BUCKET_SAMPLES);
+            let samples: Vec<NewRankPpSample> = points.into_iter()
+                .map(|point| NewRankPpSample {
+                    mode: mode as i16,
+                    rank_bucket: point.rank,
+                    median_pp: point.pp,
+                    sample_count: point.sample_count,
+                    snapshot_date: date,
+                })
+                .collect();
+
+            diesel::delete(
+                samples_dsl::rank_pp_samples
+                    .filter(samples_dsl::mode.eq(mode as i16))
+                    .filter(samples_dsl::snapshot_date.eq(date))
+            ).execute(connection)?;
+            diesel::insert_into(samples_dsl::rank_pp_samples)
+                .values(&samples)
+                .execute(connection)?;
+        }
+
+        Ok(())
+    }).map_err(debug)
+}
+
+/// Returns the date and points of the most recent snapshot recorded for a mode, or `None` if there aren't any
+pub fn load_latest_snapshot(
+    connection: &MysqlConnection, mode: u8
+) -> Result<Option<(NaiveDate, Vec<RankPpPoint>)>, String> {
+    let latest_date: Option<NaiveDate> = samples_dsl::rank_pp_samples
+        .filter(samples_dsl::mode.eq(mode as i16))
+        .select(diesel::expression::dsl::max(samples_dsl::snapshot_date))
+        .first(connection)
+        .map_err(debug)?;
+    let latest_date = match latest_date {
+        Some(date) => date,
+        None => { return Ok(None); },
+    };
+
+    let samples: Vec<RankPpSample> = samples_dsl::rank_pp_samples
+        .filter(samples_dsl::mode.eq(mode as i16))
+        .filter(samples_dsl::snapshot_date.eq(latest_date))
+        .order(samples_dsl::rank_bucket.asc())
+        .load(connection)
+        .map_err(debug)?;
+
+    Ok(Some((latest_date, samples.iter().map(RankPpPoint::from).collect())))
+}
+
+#[cfg(test)]
+fn point(rank: i32, pp: f32) -> RankPpPoint {
+    RankPpPoint { rank: rank, pp: pp, sample_count: MIN_BUCKET_SAMPLES as i32 }
+}
+
+#[test]
+fn bucket_edges() {
+    let mut edges = vec![10];
+    while edges.len() < 8 {
+        let next = next_edge(*edges.last().unwrap());
+        edges.push(next);
+    }
+    assert_eq!(edges, vec![10, 25, 50, 100, 250, 500, 1000, 2500]);
+}
+
+/// Make sure that sparse buckets are widened until they contain enough samples
+#[test]
+fn sparse_buckets_widen() {
+    // 20 users ranked 1-20, then only a handful spread across the next few buckets, then 30 users in 1000-2500
+    let mut samples: Vec<(i32, f32)> = (1..21).map(|rank| (rank, 10000. - rank as f32)).collect();
+    samples.extend(vec![(30, 8000.), (60, 7000.), (120, 6000.), (300, 5000.), (600, 4000.)]);
+    samples.extend((0..30).map(|i| (1000 + i * 50, 3000. - i as f32)));
+
+    let points = bucket_samples(samples, 5);
+    let counts: Vec<i32> = points.iter().map(|p| p.sample_count).collect();
+    // 1-9, 10-24, 25-999 (widened from 25-49 until it had enough samples), then 1000-2499
+    assert_eq!(counts, vec![9, 11, 5, 30]);
+    assert_eq!(points[0], RankPpPoint { rank: 5, pp: 9995., sample_count: 9 });
+    assert_eq!(points[2], RankPpPoint { rank: 120, pp: 6000., sample_count: 5 });
+
+    // a sparse tail is merged into the last bucket
+    let points = bucket_samples(vec![(1, 100.), (2, 90.), (3, 80.), (50, 10.)], 3);
+    assert_eq!(points.len(), 1);
+    assert_eq!(points[0].sample_count, 4);
+
+    assert_eq!(bucket_samples(vec![(0, 100.)], 3), Vec::new());
+}
+
+#[test]
+fn rank_pp_interpolation() {
+    let points = vec![point(10, 10000.), point(100, 8000.), point(1000, 6000.), point(10000, 4000.)];
+
+    assert_eq!(pp_for_rank(&points, 100), Some(8000.));
+    // interpolation happens on a logarithmic rank scale, so rank 316 is about halfway between 100 and 1000
+    assert!((pp_for_rank(&points, 316).unwrap() - 7000.).abs() < 1.);
+    assert_eq!(pp_for_rank(&points, 1), Some(10000.));
+    assert_eq!(pp_for_rank(&points, 5000000), Some(4000.));
+
+    assert_eq!(rank_for_pp(&points, 8000.), Some(100));
+    assert_eq!(rank_for_pp(&points, 7000.), Some(316));
+    assert_eq!(rank_for_pp(&points, 20000.), Some(10));
+    assert_eq!(rank_for_pp(&points, 0.), Some(10000));
+
+    assert_eq!(pp_for_rank(&[], 100), None);
+    assert_eq!(rank_for_pp(&[], 100.), None);
+}
+
+#[test]
+fn rank_percentiles() {
+    let points = vec![point(10, 10000.), point(100, 8000.), point(1000, 6000.), point(10000, 4000.)];
+
+    assert_eq!(rank_percentile(&points, 1), Some(100.));
+    // all of the first bucket and half of the second are ahead of the second bucket's median
+    assert_eq!(rank_percentile(&points, 100), Some(62.5));
+    // interpolation happens on a logarithmic rank scale like it does for pp
+    assert!((rank_percentile(&points, 316).unwrap() - 50.).abs() < 0.1);
+    assert_eq!(rank_percentile(&points, 5000000), Some(12.5));
+
+    assert_eq!(rank_percentile(&points, 0), None);
+    assert_eq!(rank_percentile(&[], 100), None);
+}
@@ -0,0 +1,102 @@
+//! Storage of the raw `get_user` responses received from the osu! API.  Only the most recent response for each user and
+//! mode is kept so that payloads which fail to parse can be inspected without the table growing over time.
+
+use diesel;
+use diesel::prelude::*;
+use diesel::mysql::MysqlConnection;
+use serde_json::{self, Value};
+
+use helpers::debug;
+use models::{NewRawSnapshot, RawSnapshot};
+use schema::raw_snapshots::dsl as snapshots_dsl;
+
+/// Cuts `payload` down to at most `max_bytes` bytes without splitting a character, returning the result along with
+/// whether anything was cut off
+pub fn truncate_payload(payload: &str, max_bytes: usize) -> (String, bool) {
+    if payload.len() <= max_bytes {
+        return (String::from(payload), false);
+    }
+
+    let mut end = max_bytes;
+    while !payload.is_char_boundary(end) {
+        end -= 1;
+    }
+    (String::from(&payload[..end]), true)
+}
+
+/// Returns the id of the user in a raw `get_user` response without parsing the rest of it, or `None` if it doesn't
+/// contain one
+pub fn raw_user_id(payload: &str) -> Option<i32> {
+    let raw: Value = match serde_json::from_str(payload) {
+        Ok(raw) => raw,
+        Err(_) => { return None; },
+    };
+
+    match raw.get(0).and_then(|user| user.get("user_id")) {
+        Some(&Value::String(ref id)) => id.parse().ok(),
+        Some(id) => id.as_i64().map(|id| id as i32),
+        None => None,
+    }
+}
+
+/// Stores `snapshot`, replacing the snapshot previously stored for its user and mode
+pub fn store_raw_snapshot(conn: &MysqlConnection, snapshot: &NewRawSnapshot) -> Result<(), String> {
+    diesel::replace_into(snapshots_dsl::raw_snapshots)
+        .values(snapshot)
+        .execute(conn)
+        .map(|_| ())
+        .map_err(debug)
+}
+
+/// Returns the most recent raw `get_user` response stored for a user in a mode
+pub fn load_raw_snapshot(conn: &MysqlConnection, user_id: i32, mode: u8) -> Result<Option<RawSnapshot>, String> {
+    snapshots_dsl::raw_snapshots
+        .find((user_id, mode as i16))
+        .first(conn)
+        .optional()
+        .map_err(debug)
+}
+
+#[test]
+fn payload_truncation() {
+    assert_eq!(truncate_payload("[{}]", 4), (String::from("[{}]"), false));
+    assert_eq!(truncate_payload("[{\"a\":1}]", 4), (String::from("[{\"a"), true));
+    // multi-byte characters aren't split
+    assert_eq!(truncate_payload("\"ö\"", 2), (String::from("\""), true));
+
+    assert_eq!(raw_user_id("[{\"user_id\":\"2\",\"username\":\"peppy\"}]"), Some(2));
+    assert_eq!(raw_user_id("[{\"user_id\":2}]"), Some(2));
+    assert_eq!(raw_user_id("[]"), None);
+    assert_eq!(raw_user_id("<html>"), None);
+}
+
+/// Make sure that fetching a user's stats again overwrites their stored snapshot rather than adding another one
+#[test]
+fn raw_snapshot_overwrite() {
+    use chrono::NaiveDateTime;
+    use helpers::create_db_pool;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    let snapshot = |payload: &str, secs: i64| NewRawSnapshot {
+        user_id: -1, mode: 0, payload: String::from(payload), truncated: false,
+        fetched_at: NaiveDateTime::from_timestamp(1500000000 + secs, 0),
+    };
+    assert!(load_raw_snapshot(conn, -1, 0).unwrap().is_none());
+
+    store_raw_snapshot(conn, &snapshot("[{\"pp_raw\":\"1000\"}]", 0)).unwrap();
+    store_raw_snapshot(conn, &snapshot("[{\"pp_raw\":null}]", 60)).unwrap();
+    let stored = load_raw_snapshot(conn, -1, 0).unwrap().unwrap();
+    assert_eq!(stored.payload, "[{\"pp_raw\":null}]");
+    assert_eq!(stored.fetched_at, NaiveDateTime::from_timestamp(1500000060, 0));
+
+    let count: i64 = snapshots_dsl::raw_snapshots
+        .filter(snapshots_dsl::user_id.eq(-1))
+        .count()
+        .get_result(conn)
+        .unwrap();
+    assert_eq!(count, 1);
+    assert!(load_raw_snapshot(conn, -1, 1).unwrap().is_none());
+}
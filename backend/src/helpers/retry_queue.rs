@@ -0,0 +1,100 @@
+//! A queue of failed background database inserts that are attempted again a few times with increasing delays so that
+//! transient database errors don't cause the data to be lost.
+
+#[cfg(test)]
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+/// An insert to attempt again, which returns an error if it fails
+pub type RetryJob = Box<FnMut() -> Result<(), String> + Send>;
+
+/// Calls `job` up to `max_attempts` times until it succeeds, waiting `backoff` before the second attempt and twice as
+/// long before each attempt after that.  Returns the error of the last attempt if none of them succeed.
+pub fn retry_with_backoff<S: FnMut(Duration)>(
+    job: &mut RetryJob, max_attempts: u32, backoff: Duration, mut sleep: S
+) -> Result<(), String> {
+    let mut delay = backoff;
+    let mut attempt = 1;
+    loop {
+        match job() {
+            Ok(()) => { return Ok(()); },
+            Err(err) => if attempt >= max_attempts {
+                return Err(err);
+            },
+        }
+
+        sleep(delay);
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+/// A handle to a worker thread that retries failed inserts one at a time.  Cloning it produces a handle to the same
+/// worker.
+#[derive(Clone)]
+pub struct RetryQueue {
+    sender: SyncSender<(String, RetryJob)>,
+}
+
+impl RetryQueue {
+    /// Starts a worker that holds up to `depth` queued inserts and attempts each of them up to `max_attempts` times
+    pub fn start(depth: usize, max_attempts: u32, backoff: Duration) -> RetryQueue {
+        let (sender, receiver) = sync_channel::<(String, RetryJob)>(depth);
+        thread::spawn(move || {
+            for (description, mut job) in receiver {
+                if let Err(err) = retry_with_backoff(&mut job, max_attempts, backoff, thread::sleep) {
+                    error!("Giving up on {} after {} attempts: {}", description, max_attempts, err);
+                }
+            }
+        });
+
+        RetryQueue { sender: sender }
+    }
+
+    /// Creates a queue without a worker whose queued jobs can be inspected through the returned receiver
+    #[cfg(test)]
+    pub fn capture() -> (RetryQueue, Receiver<(String, RetryJob)>) {
+        let (sender, receiver) = sync_channel(100);
+        (RetryQueue { sender: sender }, receiver)
+    }
+
+    /// Queues `job` to be retried, described by `description` in the logs.  If the queue is full, the insert is dropped
+    /// and an error is logged rather than blocking the caller.
+    pub fn push(&self, description: String, job: RetryJob) {
+        match self.sender.try_send((description, job)) {
+            Ok(()) => (),
+            Err(TrySendError::Full((description, _))) => error!("Retry queue is full; dropping {}", description),
+            Err(TrySendError::Disconnected((description, _))) => {
+                error!("Retry queue worker has stopped; dropping {}", description)
+            },
+        }
+    }
+}
+
+#[test]
+fn retry_backoff() {
+    use std::sync::{Arc, Mutex};
+
+    let attempts = Arc::new(Mutex::new(0));
+    let job_attempts = attempts.clone();
+    // fails twice before succeeding
+    let mut job: RetryJob = Box::new(move || {
+        let mut attempts = job_attempts.lock().unwrap();
+        *attempts += 1;
+        if *attempts < 3 { Err(format!("attempt {} failed", *attempts)) } else { Ok(()) }
+    });
+
+    let mut sleeps = Vec::new();
+    assert_eq!(retry_with_backoff(&mut job, 5, Duration::from_millis(100), |delay| sleeps.push(delay)), Ok(()));
+    assert_eq!(*attempts.lock().unwrap(), 3);
+    assert_eq!(sleeps, vec![Duration::from_millis(100), Duration::from_millis(200)]);
+
+    // gives up with the last error once it runs out of attempts
+    *attempts.lock().unwrap() = -10;
+    let mut sleeps = Vec::new();
+    let res = retry_with_backoff(&mut job, 3, Duration::from_millis(100), |delay| sleeps.push(delay));
+    assert_eq!(res, Err(String::from("attempt -7 failed")));
+    assert_eq!(sleeps.len(), 2);
+}
@@ -0,0 +1,27 @@
+//! Functions for reducing the number of points in a series before sending it to clients for graphing
+
+/// Picks at most `max_points` evenly spaced items out of `items`, always keeping the first and last items so that the
+/// full range of the series is preserved.  Returns all of the items if there are already few enough of them.
+pub fn downsample<T: Clone>(items: &[T], max_points: usize) -> Vec<T> {
+    if items.len() <= max_points {
+        return items.to_vec();
+    } else if max_points < 2 {
+        return items.iter().take(max_points).cloned().collect();
+    }
+
+    let step = (items.len() - 1) as f64 / (max_points - 1) as f64;
+    (0..max_points)
+        .map(|i| items[(i as f64 * step).round() as usize].clone())
+        .collect()
+}
+
+#[test]
+fn downsample_series() {
+    let items: Vec<usize> = (0..1000).collect();
+    let sampled = downsample(&items, 11);
+    assert_eq!(sampled, vec![0, 100, 200, 300, 400, 500, 599, 699, 799, 899, 999]);
+
+    assert_eq!(downsample(&items[..5], 10), vec![0, 1, 2, 3, 4]);
+    assert_eq!(downsample(&items, 2), vec![0, 999]);
+    assert_eq!(downsample(&items, 0), Vec::<usize>::new());
+}
@@ -0,0 +1,98 @@
+//! Ratios and trends derived from a user's stored updates, as returned by `/efficiency`
+
+use models::Update;
+
+/// Divides `numerator` by `playcount`, returning `None` if the user has no plays
+pub fn per_play(numerator: f64, playcount: i32) -> Option<f64> {
+    if playcount <= 0 { None } else { Some(numerator / playcount as f64) }
+}
+
+/// Returns the pp gained per play between two of a user's updates, or `None` if they didn't play in between.  Plays
+/// that lost pp count as a negative gain.
+pub fn pp_per_play_between(start: &Update, end: &Update) -> Option<f64> {
+    per_play((end.pp_raw - start.pp_raw) as f64, end.playcount - start.playcount)
+}
+
+/// Returns the slope of the least squares line through `points`, given as `(x, y)` pairs.  Returns `None` if there are
+/// fewer than two points or if all of them have the same `x`, in which case there is no single line.
+pub fn linear_regression_slope(points: &[(f64, f64)]) -> Option<f64> {
+    if points.len() < 2 {
+        return None;
+    }
+
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|&(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|&(_, y)| y).sum::<f64>() / n;
+    let (covariance, variance) = points.iter().fold((0., 0.), |(cov, var), &(x, y)| {
+        (cov + (x - mean_x) * (y - mean_y), var + (x - mean_x) * (x - mean_x))
+    });
+
+    if variance == 0. { None } else { Some(covariance / variance) }
+}
+
+/// Returns the change in accuracy per day over `updates` as the slope of a linear regression of their accuracies
+/// against their update times, or `None` if there aren't enough updates to fit a line to
+pub fn accuracy_trend(updates: &[Update]) -> Option<f64> {
+    let first_time = match updates.iter().map(|update| update.update_time).min() {
+        Some(time) => time,
+        None => { return None; },
+    };
+    let points: Vec<(f64, f64)> = updates.iter()
+        .map(|update| {
+            let days = (update.update_time - first_time).num_seconds() as f64 / 86400.;
+            (days, update.accuracy as f64)
+        })
+        .collect();
+
+    linear_regression_slope(&points)
+}
+
+#[cfg(test)]
+fn test_update(day: i64, playcount: i32, pp_raw: f32, accuracy: f32) -> Update {
+    use chrono::NaiveDateTime;
+    use test_support::test_stored_update;
+
+    Update {
+        id: day as i32, playcount: playcount, accuracy: accuracy,
+        update_time: NaiveDateTime::from_timestamp(1500000000 + day * 86400, 0), ..test_stored_update(pp_raw)
+    }
+}
+
+#[test]
+fn per_play_ratios() {
+    assert_eq!(per_play(1000., 50), Some(20.));
+    assert_eq!(per_play(1000., 0), None);
+
+    let (start, end) = (test_update(0, 100, 1000., 98.), test_update(30, 150, 1100., 98.));
+    assert_eq!(pp_per_play_between(&start, &end), Some(2.));
+    assert_eq!(pp_per_play_between(&end, &end), None);
+}
+
+#[test]
+fn regression_slope() {
+    let line: Vec<(f64, f64)> = (0..10).map(|x| (x as f64, 3. * x as f64 + 1.)).collect();
+    assert!((linear_regression_slope(&line).unwrap() - 3.).abs() < 1e-9);
+    // noise on either side of the line doesn't change its slope
+    let noisy = vec![(0., 1.), (1., 3.), (2., 3.), (3., 5.)];
+    assert!((linear_regression_slope(&noisy).unwrap() - 1.2).abs() < 1e-9);
+
+    let constant = vec![(0., 5.), (1., 5.), (2., 5.)];
+    assert_eq!(linear_regression_slope(&constant), Some(0.));
+    assert_eq!(linear_regression_slope(&[]), None);
+    assert_eq!(linear_regression_slope(&[(1., 2.)]), None);
+    assert_eq!(linear_regression_slope(&[(1., 2.), (1., 3.)]), None);
+}
+
+#[test]
+fn accuracy_trends() {
+    let improving: Vec<Update> = (0..5).map(|day| test_update(day, 100, 1000., 97. + 0.25 * day as f32)).collect();
+    assert!((accuracy_trend(&improving).unwrap() - 0.25).abs() < 1e-6);
+    // the order that the updates are passed in doesn't matter
+    let reversed: Vec<Update> = improving.iter().rev().cloned().collect();
+    assert!((accuracy_trend(&reversed).unwrap() - 0.25).abs() < 1e-6);
+
+    let constant: Vec<Update> = (0..5).map(|day| test_update(day, 100, 1000., 98.)).collect();
+    assert_eq!(accuracy_trend(&constant), Some(0.));
+    assert_eq!(accuracy_trend(&[]), None);
+    assert_eq!(accuracy_trend(&improving[..1]), None);
+}
@@ -0,0 +1,106 @@
+//! Flags that the operators of the instance set on users to have the backend treat them specially, such as users whose
+//! data was reset at their request or who shouldn't be updated automatically.
+
+use std::str::FromStr;
+
+use diesel;
+use diesel::prelude::*;
+use diesel::mysql::MysqlConnection;
+
+use helpers::debug;
+use models::{NewUserFlag, UserFlag};
+use schema::user_flags::dsl as flags_dsl;
+
+/// The kinds of flags that can be set on a user
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UserFlagKind {
+    /// The user's stats are expected to go backwards, for example because their data was reset at their request, so
+    /// decreases in them aren't reported as a possible score reset in `UpdateDiff::unexpected_decreases`
+    AllowResets,
+    /// Updates requested by the scheduler are skipped for the user; updates requested by anyone else still go through
+    DoNotAutoupdate,
+}
+
+impl UserFlagKind {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            UserFlagKind::AllowResets => "allow_resets",
+            UserFlagKind::DoNotAutoupdate => "do_not_autoupdate",
+        }
+    }
+}
+
+impl FromStr for UserFlagKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "allow_resets" => Ok(UserFlagKind::AllowResets),
+            "do_not_autoupdate" => Ok(UserFlagKind::DoNotAutoupdate),
+            _ => Err(format!("Unknown user flag {:?}; expected `allow_resets` or `do_not_autoupdate`", s)),
+        }
+    }
+}
+
+/// Returns all of the flags set on a user, oldest first
+pub fn load_user_flags(conn: &MysqlConnection, user_id: i32) -> Result<Vec<UserFlag>, String> {
+    flags_dsl::user_flags
+        .filter(flags_dsl::user_id.eq(user_id))
+        .order((flags_dsl::created_at.asc(), flags_dsl::flag.asc()))
+        .load(conn)
+        .map_err(debug)
+}
+
+/// Returns `true` if `flag` is set on the user
+pub fn has_user_flag(conn: &MysqlConnection, user_id: i32, flag: UserFlagKind) -> Result<bool, String> {
+    let found: Option<String> = flags_dsl::user_flags
+        .find((user_id, flag.as_str()))
+        .select(flags_dsl::flag)
+        .first(conn)
+        .optional()
+        .map_err(debug)?;
+    Ok(found.is_some())
+}
+
+/// Sets `flag` on the user with `note` explaining why, replacing the note if the flag was already set.  Returns the
+/// stored flag.
+pub fn set_user_flag(
+    conn: &MysqlConnection, user_id: i32, flag: UserFlagKind, note: &str
+) -> Result<UserFlag, String> {
+    let new_flag = NewUserFlag { user_id: user_id, flag: String::from(flag.as_str()), note: String::from(note) };
+    diesel::replace_into(flags_dsl::user_flags).values(&new_flag).execute(conn).map_err(debug)?;
+    flags_dsl::user_flags.find((user_id, flag.as_str())).first(conn).map_err(debug)
+}
+
+/// Removes `flag` from the user, returning `false` if it wasn't set
+pub fn remove_user_flag(conn: &MysqlConnection, user_id: i32, flag: UserFlagKind) -> Result<bool, String> {
+    let deleted = diesel::delete(flags_dsl::user_flags.find((user_id, flag.as_str())))
+        .execute(conn)
+        .map_err(debug)?;
+    Ok(deleted > 0)
+}
+
+#[test]
+fn user_flag_storage() {
+    use helpers::create_db_pool;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    assert_eq!("do_not_autoupdate".parse(), Ok(UserFlagKind::DoNotAutoupdate));
+    assert!("autoupdate".parse::<UserFlagKind>().is_err());
+
+    set_user_flag(conn, -1, UserFlagKind::AllowResets, "data reset by user request").unwrap();
+    assert!(has_user_flag(conn, -1, UserFlagKind::AllowResets).unwrap());
+    assert!(!has_user_flag(conn, -1, UserFlagKind::DoNotAutoupdate).unwrap());
+
+    // setting a flag again replaces its note
+    let flag = set_user_flag(conn, -1, UserFlagKind::AllowResets, "reset twice").unwrap();
+    assert_eq!((flag.flag.as_str(), flag.note.as_str()), ("allow_resets", "reset twice"));
+    assert_eq!(load_user_flags(conn, -1).unwrap(), vec![flag]);
+
+    assert!(remove_user_flag(conn, -1, UserFlagKind::AllowResets).unwrap());
+    assert!(!remove_user_flag(conn, -1, UserFlagKind::AllowResets).unwrap());
+    assert!(load_user_flags(conn, -1).unwrap().is_empty());
+}
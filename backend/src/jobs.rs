@@ -0,0 +1,104 @@
+//! Background jobs that run periodically alongside the webserver
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration as StdDuration;
+
+use chrono::{Duration, NaiveDateTime};
+use diesel::mysql::MysqlConnection;
+use r2d2::Pool;
+use r2d2_diesel::ConnectionManager;
+
+use clock::Clock;
+use helpers::online_users::{record_online_users, IrcNamesSource, IRC_CHANNEL, IRC_SERVER};
+use helpers::rank_pp::record_snapshot;
+use secret::{IRC_PASSWORD, IRC_USERNAME, ONLINE_USERS_POLL_SECS};
+
+/// Returns the amount of time between `now` and the next midnight (UTC)
+fn until_next_midnight(now: NaiveDateTime) -> Duration {
+    let next_midnight = (now.date() + Duration::days(1)).and_hms(0, 0, 0);
+    next_midnight - now
+}
+
+/// Spawns a thread that runs `job` every night at midnight (UTC) according to `clock` with a connection from `pool`.
+/// Errors are logged and don't stop the job from running again the next night.
+pub fn spawn_nightly<F>(name: &'static str, pool: Pool<ConnectionManager<MysqlConnection>>, clock: Arc<Clock>, job: F)
+    where F: Fn(&MysqlConnection) -> Result<(), String> + Send + 'static
+{
+    thread::spawn(move || loop {
+        let wait = until_next_midnight(clock.now());
+        thread::sleep(wait.to_std().unwrap_or(StdDuration::from_secs(0)));
+
+        println!("Running nightly job {}", name);
+        let res = match pool.get() {
+            Ok(conn) => job(&*conn),
+            Err(err) => Err(format!("Unable to get connection from pool: {:?}", err)),
+        };
+        if let Err(err) = res {
+            println!("Error while running nightly job {}: {}", name, err);
+        }
+
+        // make sure that the job doesn't run twice if it finished within the same second that it started
+        thread::sleep(StdDuration::from_secs(1));
+    });
+}
+
+/// Spawns a thread that runs `job` with a connection from `pool` once every `interval`, starting immediately.  Errors are
+/// logged and don't stop the job from running again.
+pub fn spawn_interval<F>(
+    name: &'static str, pool: Pool<ConnectionManager<MysqlConnection>>, interval: StdDuration, job: F
+) where F: Fn(&MysqlConnection) -> Result<(), String> + Send + 'static {
+    thread::spawn(move || loop {
+        let res = match pool.get() {
+            Ok(conn) => job(&*conn),
+            Err(err) => Err(format!("Unable to get connection from pool: {:?}", err)),
+        };
+        if let Err(err) = res {
+            println!("Error while running job {}: {}", name, err);
+        }
+
+        thread::sleep(interval);
+    });
+}
+
+/// Starts all of the background jobs
+pub fn start(pool: Pool<ConnectionManager<MysqlConnection>>, clock: Arc<Clock>) {
+    let snapshot_clock = clock.clone();
+    spawn_nightly("rank_pp_snapshot", pool.clone(), clock, move |conn| {
+        record_snapshot(conn, snapshot_clock.now().date())
+    });
+
+    let irc_source = IrcNamesSource {
+        server: IRC_SERVER,
+        channel: IRC_CHANNEL,
+        username: IRC_USERNAME,
+        password: IRC_PASSWORD,
+    };
+    spawn_interval(
+        "online_users", pool, StdDuration::from_secs(ONLINE_USERS_POLL_SECS),
+        move |conn| record_online_users(conn, &irc_source)
+    );
+}
+
+#[test]
+fn next_midnight() {
+    use chrono::NaiveDate;
+
+    let now = NaiveDate::from_ymd(2017, 12, 31).and_hms(23, 30, 0);
+    assert_eq!(until_next_midnight(now), Duration::minutes(30));
+    let now = NaiveDate::from_ymd(2017, 12, 12).and_hms(0, 0, 0);
+    assert_eq!(until_next_midnight(now), Duration::days(1));
+}
+
+/// Make sure that the scheduler waits for the midnight after the clock's current time
+#[test]
+fn nightly_tick_follows_clock() {
+    use chrono::NaiveDate;
+    use clock::MockClock;
+
+    let clock = MockClock::new(NaiveDate::from_ymd(2017, 12, 12).and_hms(22, 0, 0));
+    assert_eq!(until_next_midnight(clock.now()), Duration::hours(2));
+    clock.advance(StdDuration::from_secs(2 * 60 * 60 + 1));
+    assert_eq!(until_next_midnight(clock.now()), Duration::days(1) - Duration::seconds(1));
+    assert_eq!(clock.now().date(), NaiveDate::from_ymd(2017, 12, 13));
+}
@@ -8,48 +8,313 @@
 extern crate chrono;
 #[macro_use]
 extern crate diesel;
+extern crate flate2;
 #[macro_use]
-extern crate diesel_infer_schema;
 extern crate log;
+extern crate osutrack_types;
 extern crate r2d2;
 extern crate r2d2_diesel;
 extern crate reqwest;
 extern crate rocket;
 // #[macro_use]
 extern crate rocket_contrib;
+extern crate serde;
 extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Instant;
+
 use diesel::mysql::MysqlConnection;
 use r2d2::{ Pool, PooledConnection };
 use r2d2_diesel::ConnectionManager;
+use rocket::Route;
 
 mod secret;
+mod admin;
+mod api_version;
+mod cache;
+mod clock;
+mod compression;
+mod error;
 mod routes;
+mod safe_json;
+mod sig;
 mod schema;
 mod models;
 mod osu_api;
+mod export;
+mod jobs;
 use osu_api::ApiClient;
+use clock::{Clock, SystemClock};
+use routes::{EndpointInfo, Stability};
+use cache::CachePolicy::{MaxAge, NoStore};
+use secret::{PENDING_BEATMAP_CACHE_MAX_AGE_SECS, SIG_CACHE_MAX_AGE_SECS, STATS_CACHE_MAX_AGE_SECS};
+mod params;
 mod helpers;
+#[cfg(test)]
+mod test_support;
 use helpers::create_db_pool;
+use helpers::db_metrics::{DbMetrics, DbTimer, PoolState};
 
-pub struct DbPool(Pool<ConnectionManager<MysqlConnection>>);
+pub struct DbPool(Pool<ConnectionManager<MysqlConnection>>, DbMetrics);
 
 impl DbPool {
+    pub fn new(pool: Pool<ConnectionManager<MysqlConnection>>, clock: Arc<Clock>) -> DbPool {
+        DbPool(pool, DbMetrics::new(clock))
+    }
+
+    /// Checks a connection out of the pool, recording how long it took to get one
     pub fn get_conn(&self) -> PooledConnection<ConnectionManager<MysqlConnection>> {
-        return self.0.get().unwrap()
+        let start = self.1.instant();
+        let conn = self.0.get().unwrap();
+        self.1.record_checkout(self.1.instant().duration_since(start), self.state());
+        conn
+    }
+
+    pub fn state(&self) -> PoolState {
+        let state = self.0.state();
+        PoolState {
+            max_size: self.0.max_size(),
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+            in_use_connections: state.connections - state.idle_connections,
+        }
+    }
+
+    pub fn metrics(&self) -> &DbMetrics {
+        &self.1
+    }
+
+    /// Starts timing the database work of a request to `route`; see `DbTimer`
+    pub fn time_route(&self, route: &'static str) -> DbTimer {
+        self.1.time_route(route)
+    }
+
+    /// Checks a connection out of the pool like `get_conn`, adding the time until it's returned to `timer`
+    pub fn get_timed_conn<'a>(&self, timer: &'a DbTimer) -> TimedConn<'a> {
+        let conn = self.get_conn();
+        TimedConn { conn: conn, timer: timer, start: timer.start() }
     }
 }
 
+/// A connection checked out of the pool by `DbPool::get_timed_conn`
+pub struct TimedConn<'a> {
+    conn: PooledConnection<ConnectionManager<MysqlConnection>>,
+    timer: &'a DbTimer,
+    start: Instant,
+}
+
+impl<'a> Deref for TimedConn<'a> {
+    type Target = MysqlConnection;
+
+    fn deref(&self) -> &MysqlConnection {
+        &*self.conn
+    }
+}
+
+impl<'a> Drop for TimedConn<'a> {
+    fn drop(&mut self) {
+        self.timer.finish(self.start);
+    }
+}
+
+/// Returns every route served by the backend.  Each of them must also be described in `ENDPOINTS`.
+fn mounted_routes() -> Vec<Route> {
+    routes![
+        routes::update, routes::get_stats, routes::get_last_pp_diff, routes::live_stats, routes::get_updates,
+        routes::get_hiscores, routes::get_beatmaps, routes::get_beatmap, routes::preview,
+        routes::export, routes::get_graph, routes::get_efficiency, admin::update_sources,
+        routes::get_hiscore_difficulty, routes::compare_history, routes::rank_to_pp,
+        admin::dedup_updates, routes::export_csv, routes::get_beatmap_stats, routes::online_users,
+        routes::live_diff, routes::featured_beatmaps, routes::mod_breakdown, routes::version,
+        routes::stats_batch, routes::api_usage, routes::cache_stats, routes::stats_lite,
+        routes::hiscore_history, admin::api_usage, routes::pp_gain_rank, routes::endpoints,
+        admin::raw_snapshot, routes::recent_hiscores, routes::hiscores_all, admin::set_tracking,
+        admin::orphans, admin::repair_orphaned_users, routes::get_summary,
+        routes::live_stats_v2, routes::get_updates_around, admin::merge, routes::get_daily_stats,
+        admin::backfill_daily_stats, routes::get_user_info, admin::list_flags, admin::add_flag, admin::remove_flag,
+        routes::diff_between, admin::metrics, admin::status, admin::cached_beatmaps, routes::signature,
+    ]
+}
+
+macro_rules! endpoint {
+    ($method:expr, $path:expr, $stability:ident, $description:expr) => {
+        EndpointInfo {
+            method: $method, path: $path, description: $description, stability: Stability::$stability,
+            cache_policy: None,
+        }
+    };
+    ($method:expr, $path:expr, $stability:ident, $description:expr, cache: $cache_policy:expr) => {
+        EndpointInfo {
+            method: $method, path: $path, description: $description, stability: Stability::$stability,
+            cache_policy: Some($cache_policy),
+        }
+    };
+}
+
+/// Descriptions of every route in `mounted_routes`, served by `/endpoints`, along with the caching policies that the
+/// `CacheControl` fairing applies to them.  Routes under `/admin/` and routes with methods other than GET are never
+/// cached, and routes without a policy don't get a `Cache-Control` header unless they set one themselves.
+pub const ENDPOINTS: &'static [EndpointInfo] = &[
+    endpoint!("GET", "/update/<username>/<mode>", Stable,
+        "Fetches a user's current stats, records them, and returns the changes since their last update",
+        cache: NoStore),
+    endpoint!("GET", "/preview/<username>/<mode>", Stable,
+        "Returns the changes since a user's last update without recording their current stats", cache: NoStore),
+    endpoint!("GET", "/stats/<username>/<mode>", Stable, "Returns a user's most recently recorded stats",
+        cache: MaxAge(STATS_CACHE_MAX_AGE_SECS)),
+    endpoint!("GET", "/stats_lite/<username>/<mode>", Experimental,
+        "Returns a small subset of a user's most recently recorded stats"),
+    endpoint!("POST", "/stats_batch", Stable, "Returns the most recently recorded stats of up to 100 users"),
+    endpoint!("GET", "/livestats/<username>/<mode>", Stable,
+        "Fetches a user's current stats and recent events from the osu! API", cache: NoStore),
+    endpoint!("GET", "/v2/livestats/<username>/<mode>", Experimental,
+        "Returns a user's current stats from the osu! API without recording them unless asked to", cache: NoStore),
+    endpoint!("GET", "/efficiency/<username>/<mode>", Stable,
+        "Returns pp per play and other efficiency ratios computed from a user's recent updates",
+        cache: MaxAge(STATS_CACHE_MAX_AGE_SECS)),
+    endpoint!("GET", "/user/<username>", Stable, "Returns a user's row along with the flags set on them"),
+    endpoint!("GET", "/summary/<username>/<mode>", Stable, "Returns how many updates a user has and how often",
+        cache: MaxAge(STATS_CACHE_MAX_AGE_SECS)),
+    endpoint!("GET", "/hiscore_difficulty/<username>/<mode>", Stable,
+        "Returns the number and average pp of a user's hiscores in half-star buckets",
+        cache: MaxAge(STATS_CACHE_MAX_AGE_SECS)),
+    endpoint!("GET", "/mod_breakdown/<username>/<mode>", Stable,
+        "Returns a user's hiscores grouped by mod combination", cache: MaxAge(STATS_CACHE_MAX_AGE_SECS)),
+    endpoint!("GET", "/updates/<username>/<mode>", Stable, "Returns all of a user's recorded updates",
+        cache: MaxAge(STATS_CACHE_MAX_AGE_SECS)),
+    endpoint!("GET", "/updates/<username>/<mode>/around/<timestamp>/<n>", Stable,
+        "Returns the updates of a user recorded just before and after a time"),
+    endpoint!("GET", "/daily/<username>/<mode>", Stable, "Returns a user's stats at the end of each day with updates",
+        cache: MaxAge(STATS_CACHE_MAX_AGE_SECS)),
+    endpoint!("GET", "/graph/<username>/<mode>", Stable, "Returns a user's pp and rank history for graphing"),
+    endpoint!("GET", "/hiscore_history/<username>/<mode>", Experimental,
+        "Returns the pp of a user's 1st, 50th, and 100th best plays over time"),
+    endpoint!("GET", "/online_users", Stable, "Returns the recorded number of users in the osu! IRC channel over time"),
+    endpoint!("GET", "/compare_history/<a>/<b>/<mode>", Stable, "Returns the rank histories of two users"),
+    endpoint!("GET", "/version", Stable, "Returns the version of the backend and its schema"),
+    endpoint!("GET", "/api_usage", Internal, "Returns the number of requests recently made to the osu! API"),
+    endpoint!("GET", "/cache_stats", Internal, "Returns the hit rates of the caches of osu! API responses"),
+    endpoint!("GET", "/rank_to_pp/<mode>", Stable,
+        "Estimates the pp needed for a rank or the rank for an amount of pp"),
+    endpoint!("GET", "/leaderboard/pp_gain/<mode>/rank/<username>", Experimental,
+        "Returns a user's position on the leaderboard of recent pp gain"),
+    endpoint!("GET", "/hiscores/<username>/<mode>", Stable, "Returns a user's stored hiscores",
+        cache: MaxAge(STATS_CACHE_MAX_AGE_SECS)),
+    endpoint!("GET", "/hiscores/recent", Experimental, "Returns the most recently recorded hiscores of all users"),
+    endpoint!("GET", "/hiscores_all/<username>", Experimental, "Returns a user's best hiscores in each mode",
+        cache: MaxAge(STATS_CACHE_MAX_AGE_SECS)),
+    endpoint!("GET", "/export/<username>", Stable, "Streams all of the data recorded for a user as JSON"),
+    endpoint!("GET", "/export/<username>/<mode>/csv", Stable, "Streams a user's recorded updates as CSV"),
+    endpoint!("GET", "/lastpp/<username>/<mode>", Stable,
+        "Returns the changes since the last update in which a user's pp was different", cache: NoStore),
+    endpoint!("GET", "/livediff/<username>/<mode>/<update_id>", Stable,
+        "Returns the changes between a recorded update and a user's current stats", cache: NoStore),
+    endpoint!("GET", "/diff/<username>/<mode>/between/<from>/<to>", Stable,
+        "Returns the changes between the recorded updates of a user closest to two times"),
+    endpoint!("GET", "/beatmaps/<ids>/<mode>", Stable, "Returns several beatmaps, fetching uncached ones",
+        cache: MaxAge(PENDING_BEATMAP_CACHE_MAX_AGE_SECS)),
+    endpoint!("GET", "/featured_beatmaps", Stable, "Returns the beatmaps featured on the osu!track website"),
+    endpoint!("GET", "/beatmap/<id>/<mode>/stats", Stable, "Returns statistics of the hiscores set on a beatmap"),
+    endpoint!("GET", "/beatmap/<id>/<mode>", Stable, "Returns a beatmap, fetching it if it isn't cached",
+        cache: MaxAge(PENDING_BEATMAP_CACHE_MAX_AGE_SECS)),
+    endpoint!("GET", "/sig/<username>/<file>", Experimental,
+        "Returns a PNG signature image of a user's stats, requested as /sig/<username>/<mode>.png",
+        cache: MaxAge(SIG_CACHE_MAX_AGE_SECS)),
+    endpoint!("GET", "/endpoints", Stable, "Lists every endpoint served by the backend"),
+    endpoint!("GET", "/admin/update_sources", Internal, "Returns the number of updates recorded from each source"),
+    endpoint!("POST", "/admin/dedup_updates/<username>/<mode>", Internal,
+        "Deletes a user's redundant updates left behind by past bugs"),
+    endpoint!("GET", "/admin/api_usage", Internal, "Returns the number of requests recently made to the osu! API"),
+    endpoint!("GET", "/admin/status", Internal,
+        "Returns the state of the database pool and the routes that spent the most time on the database"),
+    endpoint!("GET", "/admin/metrics", Internal,
+        "Returns database pool and query time metrics in the Prometheus format"),
+    endpoint!("GET", "/admin/raw/<user_id>/<mode>", Internal,
+        "Returns the most recent raw get_user response received for a user"),
+    endpoint!("POST", "/admin/user/<id>/tracking", Internal, "Enables or disables the tracking of a user"),
+    endpoint!("GET", "/admin/orphans", Internal, "Counts the updates and hiscores of users without a user row"),
+    endpoint!("POST", "/admin/orphans/repair", Internal, "Creates placeholder rows for users without a user row"),
+    endpoint!("POST", "/admin/merge_users/<from_id>/<into_id>", Internal,
+        "Moves the updates and hiscores of one user to another and deletes the first"),
+    endpoint!("POST", "/admin/daily_stats/backfill", Internal,
+        "Rebuilds every user's daily aggregates from their updates"),
+    endpoint!("GET", "/admin/user/<id>/flags", Internal, "Lists the flags set on a user"),
+    endpoint!("POST", "/admin/user/<id>/flags/<flag>", Internal, "Sets a flag on a user with a note"),
+    endpoint!("DELETE", "/admin/user/<id>/flags/<flag>", Internal, "Removes a flag from a user"),
+    endpoint!("GET", "/admin/beatmaps/cached", Internal, "Lists the cached beatmaps last updated within a time range"),
+];
+
 pub fn main() {
+    let api_client = match ApiClient::new() {
+        Ok(api_client) => api_client,
+        Err(err) => {
+            eprintln!("Unable to set up the osu! API client: {}", err);
+            ::std::process::exit(1);
+        },
+    };
+    let pool = create_db_pool();
+    // route handlers read the time from the managed clock as `State<Arc<Clock>>`
+    let clock: Arc<Clock> = Arc::new(SystemClock);
+    jobs::start(pool.clone(), clock.clone());
+
     // initialize the Rocket webserver
     rocket::ignite()
-        .mount("/", routes![
-            routes::update, routes::get_stats, routes::get_last_pp_diff, routes::live_stats, routes::get_updates,
-            routes::get_hiscores, routes::get_beatmaps, routes::get_beatmap,
-        ])
-        .manage(ApiClient::new())
-        .manage(DbPool(create_db_pool()))
+        .mount("/", mounted_routes())
+        .mount(api_version::V1_PREFIX, api_version::v1_routes(mounted_routes()))
+        .attach(cache::CacheControl)
+        .attach(api_version::DeprecateLegacyPaths)
+        .manage(api_client)
+        .manage(DbPool::new(pool, clock.clone()))
+        .manage(clock)
+        .manage(routes::VersionInfo::current())
         .launch();
 }
+
+/// Make sure that every mounted route is described in `ENDPOINTS` and that every description is of a mounted route
+#[test]
+fn endpoint_registry_completeness() {
+    use std::collections::HashSet;
+
+    let mounted: HashSet<(String, String)> = mounted_routes().iter()
+        .map(|route| (route.method.to_string(), route.uri.path().to_owned()))
+        .collect();
+    let registered: HashSet<(String, String)> = ENDPOINTS.iter()
+        .map(|endpoint| (endpoint.method.to_owned(), endpoint.path.to_owned()))
+        .collect();
+
+    assert_eq!(registered.len(), ENDPOINTS.len(), "`ENDPOINTS` describes a route more than once");
+    assert_eq!(mounted.difference(&registered).collect::<Vec<_>>(), Vec::<&(String, String)>::new());
+    assert_eq!(registered.difference(&mounted).collect::<Vec<_>>(), Vec::<&(String, String)>::new());
+}
+
+/// Make sure that running a handler moves the checkout counters and records the handler's database time
+#[test]
+fn db_metrics_track_handlers() {
+    use rocket::http::{Header, Status};
+    use rocket::local::Client;
+    use secret::ADMIN_TOKEN;
+    use test_support::test_pool;
+
+    let db_pool = DbPool::new(test_pool(), Arc::new(SystemClock));
+    let metrics = db_pool.metrics().clone();
+    let server = rocket::ignite().mount("/", routes![routes::get_updates, admin::metrics]).manage(db_pool);
+    let client = Client::new(server).unwrap();
+    assert_eq!(metrics.checkout_stats().checkouts, 0);
+
+    let res = client.get("/updates/__untracked__/0").dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+    assert_eq!(metrics.checkout_stats().checkouts, 1);
+    let slowest = metrics.slowest_routes();
+    assert_eq!(slowest.iter().map(|route| (route.route, route.requests)).collect::<Vec<_>>(), vec![("get_updates", 1)]);
+
+    assert_eq!(client.get("/admin/metrics").dispatch().status(), Status::Unauthorized);
+    let mut res = client.get("/admin/metrics").header(Header::new("X-Admin-Token", ADMIN_TOKEN)).dispatch();
+    let body = res.body_string().unwrap();
+    assert!(body.contains("osutrack_db_checkouts_total 1\n"), "{}", body);
+    assert!(body.contains("osutrack_db_route_requests_total{route=\"get_updates\"} 1\n"), "{}", body);
+    assert!(body.contains("osutrack_db_pool_max_size 1\n"), "{}", body);
+}
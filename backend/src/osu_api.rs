@@ -1,26 +1,128 @@
 //! Functions or interfacing with the osu! API
 
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use chrono::NaiveDateTime;
 use diesel;
 use diesel::prelude::*;
-use diesel::result::Error;
 use diesel::mysql::MysqlConnection;
 use r2d2::Pool;
 use r2d2_diesel::ConnectionManager;
+use reqwest;
+use reqwest::header::{Headers, UserAgent};
 use serde_json;
 
-use secret::API_KEY;
-use models::{Beatmap, NewUpdate, NewHiscore, User, NewUser};
-use schema::users::dsl as users_dsl;
-use schema::updates::dsl as updates_dsl;
+use clock::{Clock, SystemClock};
+use error::ApiError;
+use osutrack_types::grade::normalize_grade;
+use secret::{
+    API_EXTRA_ROOT_CERTIFICATES, API_KEY, API_RATE_LIMIT_PER_MINUTE, API_REQUIRE_HTTPS, API_USER_AGENT_CONTACT,
+    INSERT_RETRY_ATTEMPTS, INSERT_RETRY_QUEUE_DEPTH, LIVE_STATS_CACHE_MAX_AGE_SECS, MAX_RAW_SNAPSHOT_BYTES,
+    PARSE_ALERT_COOLDOWN_SECS, PARSE_ALERT_FAILURE_RATE, PARSE_ALERT_MIN_FAILURES, PARSE_ALERT_WEBHOOK_URL,
+    PARSE_ALERT_WINDOW_SECS, SLOW_API_CALL_THRESHOLD_MS,
+};
+use models::{total_hits, Beatmap, NewUpdate, NewHiscore, NewRawSnapshot};
 use schema::beatmaps::dsl as beatmaps_dsl;
-use helpers::{debug, parse_pair, MYSQL_DATE_FORMAT, create_db_pool, get_url};
+use helpers::{debug, parse_osu_date, parse_pair, create_db_pool, get_url, INVALID_BEATMAP_ERR};
+use helpers::api_usage::{api_endpoint, ApiUsage};
+use helpers::lru::{get_or_fetch, CacheStats, LruCache};
+use helpers::parse_alerts::{ParseAlert, ParseFailureTracker};
+use helpers::raw_snapshots::{raw_user_id, store_raw_snapshot, truncate_payload};
+use helpers::retry_queue::RetryQueue;
 
 const API_URL: &'static str = "https://osu.ppy.sh/api";
-const DATE_PARSE_ERROR: &'static str = "Unable to parse supplied datetime string into `NaiveDateTime`";
+/// The number of days of events requested for users by default, matching the osu! API's own default
+pub const DEFAULT_EVENT_DAYS: u8 = 1;
+/// The maximum number of days of events that the osu! API will return for a user
+pub const MAX_EVENT_DAYS: u8 = 31;
+/// The maximum number of users' stats that are cached at once
+const STATS_CACHE_CAPACITY: usize = 1000;
+/// The maximum number of top plays that the osu! API returns for a user.  `get_user_best` has no offset parameter, so
+/// plays beyond these can't be fetched by paging either.
+pub const API_USER_BEST_LIMIT: u16 = 100;
+/// How long to wait before retrying a failed beatmap insert for the first time, in milliseconds
+const INSERT_RETRY_BACKOFF_MS: u64 = 500;
+
+/// Returns `url` with the value of its `k` (API key) query parameter replaced so that it can be logged safely
+fn redact_api_key(url: &str) -> String {
+    let (base, query) = match url.find('?') {
+        Some(i) => (&url[..i + 1], &url[i + 1..]),
+        None => return String::from(url),
+    };
+    let params: Vec<&str> = query.split('&')
+        .map(|param| if param.starts_with("k=") { "k=REDACTED" } else { param })
+        .collect();
+    format!("{}{}", base, params.join("&"))
+}
+
+/// Returns the `User-Agent` sent with requests to the osu! API, which identifies the backend and how to contact us
+fn user_agent() -> String {
+    format!("osutrack2/{} (+{})", env!("CARGO_PKG_VERSION"), API_USER_AGENT_CONTACT)
+}
+
+/// Builds the HTTP client used to make requests to the osu! API at `api_url`, trusting the DER-encoded certificates at
+/// the `extra_root_certificates` paths along with the system's root certificates.  All requests made with the client
+/// carry the `User-Agent` returned by `user_agent`.  Returns an error if `require_https`
+/// is set and `api_url` isn't an HTTPS URL, if any of the certificates can't be loaded, or if TLS can't be set up.
+fn build_http_client(
+    api_url: &str, require_https: bool, extra_root_certificates: &[&str]
+) -> Result<reqwest::Client, String> {
+    if require_https && !api_url.starts_with("https://") {
+        return Err(format!("The osu! API URL {} doesn't use HTTPS, which is required by `API_REQUIRE_HTTPS`", api_url));
+    }
+
+    let mut builder = reqwest::Client::builder();
+    let mut headers = Headers::new();
+    headers.set(UserAgent::new(user_agent()));
+    builder.default_headers(headers);
+    for path in extra_root_certificates {
+        let mut der = Vec::new();
+        File::open(path).and_then(|mut file| file.read_to_end(&mut der))
+            .map_err(|err| format!("Unable to read the root certificate at {}: {}", path, err))?;
+        let certificate = reqwest::Certificate::from_der(&der)
+            .map_err(|err| format!("Unable to parse the root certificate at {}: {}", path, err))?;
+        builder.add_root_certificate(certificate);
+    }
+
+    builder.build().map_err(|err| format!("Unable to set up TLS for requests to the osu! API: {}", err))
+}
+
+/// Requests `url` from the osu! API, recording the request to its endpoint in `usage` and logging a warning if it takes
+/// longer than `SLOW_API_CALL_THRESHOLD_MS`
+fn get_api_url(http: &reqwest::Client, usage: &ApiUsage, url: &str) -> Result<String, String> {
+    usage.record(api_endpoint(url));
+    let start = Instant::now();
+    let res = get_url(http, url);
+    let elapsed = start.elapsed();
+    if elapsed >= Duration::from_millis(SLOW_API_CALL_THRESHOLD_MS) {
+        warn!(
+            "Slow osu! API request to {} took {}ms", redact_api_key(url),
+            elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000
+        );
+    }
+
+    res
+}
+
+/// A user's top plays as returned by `ApiClient::get_user_best`
+pub struct UserBest {
+    pub hiscores: Vec<NewHiscore>,
+    /// Set if more plays were requested than the osu! API could provide and the user may have more of them
+    pub truncated: bool,
+}
+
+/// Determines whether a request for `requested` top plays that returned `returned` of them was cut short by the osu!
+/// API's limit.  Users with fewer plays than the limit have simply returned all of them.
+fn is_user_best_truncated(requested: u16, returned: usize) -> bool {
+    requested > API_USER_BEST_LIMIT && returned >= API_USER_BEST_LIMIT as usize
+}
 
 /// An event returned in a user stats response from the osu! API.  Since the API returns all its values as quoted by
 /// default and really don't need to use these values right now, they stay as `String`s.
@@ -52,7 +154,9 @@ struct RawUpdate {
     pub count_rank_s: Option<String>,
     pub count_rank_a: Option<String>,
     pub pp_country_rank: Option<String>,
-    pub events: Vec<UpdateEvent>,
+    /// Missing or `null` for accounts that have never had any events
+    #[serde(default)]
+    pub events: Option<Vec<UpdateEvent>>,
 }
 
 impl RawUpdate {
@@ -60,12 +164,16 @@ impl RawUpdate {
     /// available for the user in the mode, will return `Ok(NewUpdate)`.  If the user exists but has no stats for the mode,
     /// returns `Err(None)`.  If some error occured during parsing/conversion, returns `Err(Some(String))`.
     pub fn to_update(self, mode: u8) -> Result<NewUpdate, Option<String>> {
+        let count300: i32 = self.count300.ok_or(None)?.parse().map_err(|err| Some(debug(err)) )?;
+        let count100: i32 = self.count100.ok_or(None)?.parse().map_err(|err| Some(debug(err)) )?;
+        let count50: i32 = self.count50.ok_or(None)?.parse().map_err(|err| Some(debug(err)) )?;
+
         Ok(NewUpdate {
             user_id: self.user_id.parse().map_err(|err| Some(debug(err)) )?,
             mode: mode as i16,
-            count300: self.count300.ok_or(None)?.parse().map_err(|err| Some(debug(err)) )?,
-            count100: self.count100.ok_or(None)?.parse().map_err(|err| Some(debug(err)) )?,
-            count50: self.count50.ok_or(None)?.parse().map_err(|err| Some(debug(err)) )?,
+            count300: count300,
+            count100: count100,
+            count50: count50,
             playcount: self.playcount.ok_or(None)?.parse().map_err(|err| Some(debug(err)) )?,
             ranked_score: self.ranked_score.ok_or(None)?.parse().map_err(|err| Some(debug(err)) )?,
             total_score: self.total_score.ok_or(None)?.parse().map_err(|err| Some(debug(err)) )?,
@@ -76,7 +184,12 @@ impl RawUpdate {
             count_rank_ss: self.count_rank_ss.ok_or(None)?.parse().map_err(|err| Some(debug(err)) )?,
             count_rank_s: self.count_rank_s.ok_or(None)?.parse().map_err(|err| Some(debug(err)) )?,
             count_rank_a: self.count_rank_a.ok_or(None)?.parse().map_err(|err| Some(debug(err)) )?,
-            pp_country_rank: self.pp_country_rank.ok_or(None)?.parse().map_err(|err| Some(debug(err)) )?,
+            // users without a country ranking have a rank of 0 or `null` rather than no stats for the mode
+            pp_country_rank: parse_opt::<i32>(self.pp_country_rank).map_err(Some)?.and_then(|rank| {
+                if rank > 0 { Some(rank) } else { None }
+            }),
+            source: None,
+            total_hits: total_hits(count300, count100, count50),
         })
     }
 }
@@ -90,6 +203,22 @@ struct RawHiscore {
     pub enabled_mods: String,
     pub rank: String,
     pub date: String,
+    pub count300: Option<String>,
+    pub count100: Option<String>,
+    pub count50: Option<String>,
+    pub countmiss: Option<String>,
+    pub countkatu: Option<String>,
+    pub countgeki: Option<String>,
+    pub maxcombo: Option<String>,
+    pub perfect: Option<String>,
+}
+
+/// Parses an optional quoted number from the osu! API, passing through `None` if the field wasn't supplied.
+fn parse_opt<T>(val: Option<String>) -> Result<Option<T>, String> where T: FromStr, T::Err: Debug {
+    match val {
+        Some(s) => s.parse().map(Some).map_err(debug),
+        None => Ok(None),
+    }
 }
 
 impl RawHiscore {
@@ -102,210 +231,1064 @@ impl RawHiscore {
             score: self.score.parse().map_err(debug)?,
             pp: self.pp.parse().map_err(debug)?,
             enabled_mods: self.enabled_mods.parse().map_err(debug)?,
-            rank: self.rank,
-            score_time: NaiveDateTime::parse_from_str(&self.date, MYSQL_DATE_FORMAT).map_err(debug)?,
+            rank: normalize_grade(self.rank),
+            score_time: parse_osu_date(&self.date)?,
+            count300: parse_opt(self.count300)?,
+            count100: parse_opt(self.count100)?,
+            count50: parse_opt(self.count50)?,
+            countmiss: parse_opt(self.countmiss)?,
+            countkatu: parse_opt(self.countkatu)?,
+            countgeki: parse_opt(self.countgeki)?,
+            maxcombo: parse_opt(self.maxcombo)?,
+            // the API sends `perfect` as "0" or "1"
+            perfect: parse_opt::<u8>(self.perfect)?.map(|p| p == 1),
         })
     }
 }
 
+/// A score on a beatmap's global leaderboard as returned by the osu! API's `get_scores` endpoint.  Only the fields that
+/// are needed to determine a user's position on the leaderboard are parsed.
+#[derive(Clone, Deserialize)]
+struct RawScore {
+    pub user_id: String,
+    pub score: String,
+}
+
+/// A user's current stats as returned by `ApiClient::get_stats`
+pub struct UserStats {
+    /// The user's username as reported by the osu! API, with its proper capitalization
+    pub username: String,
+    pub stats: NewUpdate,
+}
+
+/// The key of a cached stats response: the lowercased username, mode, and number of event days
+type StatsCacheKey = (String, u8, u8);
+
+/// The set of ids and modes of the beatmaps stored in the beatmap cache, kept in memory so that beatmaps that are
+/// already cached aren't inserted again.  Cloning it produces a handle to the same set.
+#[derive(Clone, Default)]
+pub struct KnownBeatmaps(Arc<RwLock<HashSet<(i32, u8)>>>);
+
+impl KnownBeatmaps {
+    /// Loads the ids and modes of all beatmaps currently in the beatmap cache
+    pub fn load(conn: &MysqlConnection) -> Result<KnownBeatmaps, String> {
+        let keys: Vec<(i32, i16)> = beatmaps_dsl::beatmaps
+            .select((beatmaps_dsl::beatmap_id, beatmaps_dsl::mode))
+            .load(conn)
+            .map_err(debug)?;
+
+        let keys = keys.into_iter().map(|(beatmap_id, mode)| (beatmap_id, mode as u8)).collect();
+        Ok(KnownBeatmaps(Arc::new(RwLock::new(keys))))
+    }
+
+    pub fn contains(&self, beatmap_id: i32, mode: u8) -> bool {
+        self.0.read().unwrap().contains(&(beatmap_id, mode))
+    }
+
+    pub fn insert(&self, beatmap_id: i32, mode: u8) {
+        self.0.write().unwrap().insert((beatmap_id, mode));
+    }
+
+    /// Inserts `beatmap` into the beatmap cache with `insert` unless it's already known to be cached, recording it as
+    /// cached if the insert succeeds.  Returns `true` if an insert was attempted.
+    pub fn cache_beatmap<F>(&self, beatmap: &Beatmap, insert: F) -> bool
+        where F: FnOnce(&Beatmap) -> Result<(), String>
+    {
+        if self.contains(beatmap.beatmap_id, beatmap.mode as u8) {
+            return false;
+        }
+
+        match insert(beatmap) {
+            Ok(()) => self.insert(beatmap.beatmap_id, beatmap.mode as u8),
+            Err(err) => println!("Error while attempting to insert beatmap into beatmap cache: {}", err),
+        }
+        true
+    }
+}
+
 /// A client used to interface with the osu! API.
 pub struct ApiClient {
+    /// The base URL of the osu! API, without a trailing slash
+    api_url: String,
+    http: reqwest::Client,
     pool: Pool<ConnectionManager<MysqlConnection>>,
+    stats_cache: Mutex<LruCache<StatsCacheKey, Option<(RawUpdate, NewUpdate)>>>,
+    known_beatmaps: KnownBeatmaps,
+    usage: ApiUsage,
+    /// Failed beatmap cache inserts that are waiting to be retried
+    retries: RetryQueue,
+    /// The outcomes of parsing recent `get_user` and `get_user_best` responses
+    parse_failures: Mutex<ParseFailureTracker>,
+}
+
+/// Parses a beatmap from the osu! API's `get_beatmaps` response.  The values are provided as strings by the API so
+/// they're converted manually.  `mode` is set to the mode that the beatmap was requested in, which is what its
+/// difficulty values are calculated for, while `native_mode` is the mode that the beatmap was made for.  Fails with
+/// `INVALID_BEATMAP_ERR` if any of the fields are missing or `null`, as `approved_date` is for unranked beatmaps, or
+/// if its dates can't be parsed.
+fn parse_beatmap(raw: &HashMap<String, Option<String>>, requested_mode: u8) -> Result<Beatmap, String> {
+    let field = |name: &str| beatmap_field(raw, name);
+    let date = |name: &str| beatmap_field(raw, name).and_then(|val| {
+        parse_osu_date(val).map_err(|err| {
+            warn!("Unable to parse the {} of a beatmap from the osu! API: {}", name, err);
+            String::from(INVALID_BEATMAP_ERR)
+        })
+    });
+
+    Ok(Beatmap {
+        mode: requested_mode as i16,
+        native_mode: parse_pair(field("mode")?),
+        beatmapset_id: parse_pair(field("beatmapset_id")?),
+        beatmap_id: parse_pair(field("beatmap_id")?),
+        approved: parse_pair(field("approved")?),
+        approved_date: date("approved_date")?,
+        last_update: date("last_update")?,
+        total_length: parse_pair(field("total_length")?),
+        hit_length: parse_pair(field("hit_length")?),
+        version: String::from(field("version")?),
+        artist: String::from(field("artist")?),
+        title: String::from(field("title")?),
+        creator: String::from(field("creator")?),
+        bpm: parse_pair(field("bpm")?),
+        source: String::from(field("source")?),
+        difficulty: parse_pair(field("difficultyrating")?),
+        diff_size: parse_pair(field("diff_size")?),
+        diff_overall: parse_pair(field("diff_overall")?),
+        diff_approach: parse_pair(field("diff_approach")?),
+        diff_drain: parse_pair(field("diff_drain")?),
+    })
+}
+
+/// Returns the value of one of the fields of a beatmap from the osu! API's `get_beatmaps` response, failing with
+/// `INVALID_BEATMAP_ERR` if it's missing or `null`
+fn beatmap_field<'a>(raw: &'a HashMap<String, Option<String>>, name: &str) -> Result<&'a str, String> {
+    match raw.get(name) {
+        Some(&Some(ref val)) => Ok(val),
+        _ => {
+            warn!("The osu! API returned a beatmap without a value for {}", name);
+            Err(String::from(INVALID_BEATMAP_ERR))
+        },
+    }
+}
+
+/// The beatmaps to request from the osu! API's `get_beatmaps` endpoint
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BeatmapQuery {
+    Beatmap(i32),
+    Beatmapset(i32),
+}
+
+/// The difficulties of a beatmapset are usually uploaded together, so their ids are close to each other.  Requested
+/// beatmaps within this distance of each other are guessed to belong to the same beatmapset.
+const BEATMAPSET_ID_WINDOW: i32 = 50;
+
+/// Requests and parses the beatmaps matching `query` from the osu! API
+fn request_beatmaps(
+    api_url: &str, http: &reqwest::Client, usage: &ApiUsage, query: BeatmapQuery, mode: u8
+) -> Result<Vec<Beatmap>, String> {
+    let url = match query {
+        BeatmapQuery::Beatmap(id) => format!("{}/get_beatmaps?k={}&m={}&b={}", api_url, API_KEY, mode, id),
+        BeatmapQuery::Beatmapset(id) => format!("{}/get_beatmaps?k={}&m={}&s={}", api_url, API_KEY, mode, id),
+    };
+    let raw: Vec<HashMap<String, Option<String>>> =
+        serde_json::from_str(&get_api_url(http, usage, &url)?).map_err(debug)?;
+    raw.iter().map(|raw| parse_beatmap(raw, mode)).collect()
+}
+
+/// Fetches the beatmaps with the given ids using `fetch`.  Each beatmap is fetched by id, since the beatmapset that it
+/// belongs to isn't known in advance.  Once one has been fetched, its whole beatmapset is fetched as well if at least
+/// two of the remaining ids might belong to it (see `BEATMAPSET_ID_WINDOW`); with fewer, fetching the beatmapset can't
+/// take fewer requests than fetching them by id.  Returns every beatmap that was fetched, including the difficulties of
+/// fetched beatmapsets that weren't requested, along with the requested ids that the osu! API doesn't know about.
+fn fetch_beatmaps_grouped<F>(ids: &[i32], mut fetch: F) -> Result<(Vec<Beatmap>, Vec<i32>), String>
+    where F: FnMut(BeatmapQuery) -> Result<Vec<Beatmap>, String>
+{
+    let mut remaining: Vec<i32> = ids.to_vec();
+    remaining.sort();
+    remaining.dedup();
+    let (mut fetched, mut unknown) = (Vec::new(), Vec::new());
+
+    while !remaining.is_empty() {
+        let id = remaining.remove(0);
+        let beatmap = match fetch(BeatmapQuery::Beatmap(id))?.into_iter().find(|beatmap| beatmap.beatmap_id == id) {
+            Some(beatmap) => beatmap,
+            None => {
+                unknown.push(id);
+                continue;
+            },
+        };
+
+        let nearby = remaining.iter().filter(|&&other| other - id <= BEATMAPSET_ID_WINDOW).count();
+        if nearby >= 2 {
+            for sibling in fetch(BeatmapQuery::Beatmapset(beatmap.beatmapset_id))? {
+                remaining.retain(|&other| other != sibling.beatmap_id);
+                if sibling.beatmap_id != id {
+                    fetched.push(sibling);
+                }
+            }
+        }
+        fetched.push(beatmap);
+    }
+
+    Ok((fetched, unknown))
+}
+
+/// Inserts a beatmap into the beatmap cache.  If another request fetched and cached the same beatmap in the meantime,
+/// the existing row is kept and this does nothing.
+fn store_beatmap(conn: &MysqlConnection, beatmap: &Beatmap) -> Result<(), String> {
+    diesel::insert_or_ignore_into(beatmaps_dsl::beatmaps)
+        .values(beatmap)
+        .execute(conn)
+        .map(|_| ())
+        .map_err(debug)
+}
+
+/// Queues a beatmap whose insert into the beatmap cache failed to be inserted again later
+fn retry_beatmap_insert(
+    retries: &RetryQueue, pool: &Pool<ConnectionManager<MysqlConnection>>, known_beatmaps: &KnownBeatmaps,
+    beatmap: &Beatmap,
+) {
+    let (pool, known_beatmaps, beatmap) = (pool.clone(), known_beatmaps.clone(), beatmap.clone());
+    let description = format!("insert of beatmap {} into the beatmap cache", beatmap.beatmap_id);
+    retries.push(description, Box::new(move || {
+        let conn = pool.get().map_err(debug)?;
+        store_beatmap(&*conn, &beatmap)?;
+        known_beatmaps.insert(beatmap.beatmap_id, beatmap.mode as u8);
+        Ok(())
+    }));
+}
+
+/// Inserts the given beatmaps into the beatmap cache, skipping those that are already cached.  Failed inserts are
+/// queued to be retried, as are all of the inserts if no connection can be acquired from the pool.
+fn insert_beatmaps(
+    pool: &Pool<ConnectionManager<MysqlConnection>>, known_beatmaps: &KnownBeatmaps, retries: &RetryQueue,
+    beatmaps: &[Beatmap],
+) {
+    let conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(err) => {
+            error!("Unable to get a connection to cache {} beatmaps; queueing them for retry: {}", beatmaps.len(), err);
+            let uncached = beatmaps.iter()
+                .filter(|beatmap| !known_beatmaps.contains(beatmap.beatmap_id, beatmap.mode as u8));
+            for beatmap in uncached {
+                retry_beatmap_insert(retries, pool, known_beatmaps, beatmap);
+            }
+            return;
+        },
+    };
+    let conn: &MysqlConnection = &*conn;
+    for beatmap in beatmaps {
+        known_beatmaps.cache_beatmap(beatmap, |beatmap| store_beatmap(conn, beatmap).map_err(|err| {
+            retry_beatmap_insert(retries, pool, known_beatmaps, beatmap);
+            err
+        }));
+    }
+}
+
+/// Stores the raw `get_user` response `payload` as the latest one for its user in `mode` in a separate thread, cutting
+/// it short if it's larger than `MAX_RAW_SNAPSHOT_BYTES`.  Responses without a user id, such as those for unknown
+/// users, aren't stored.
+fn snapshot_raw_stats(pool: Pool<ConnectionManager<MysqlConnection>>, payload: &str, mode: u8) {
+    let user_id = match raw_user_id(payload) {
+        Some(user_id) => user_id,
+        None => { return; },
+    };
+    let (payload, truncated) = truncate_payload(payload, MAX_RAW_SNAPSHOT_BYTES);
+    let snapshot = NewRawSnapshot {
+        user_id: user_id,
+        mode: mode as i16,
+        payload: payload,
+        truncated: truncated,
+        fetched_at: SystemClock.now(),
+    };
+
+    thread::spawn(move || {
+        let res = pool.get().map_err(debug).and_then(|conn| store_raw_snapshot(&*conn, &snapshot));
+        if let Err(err) = res {
+            error!("Unable to store the raw stats of user {} in mode {}: {}", snapshot.user_id, snapshot.mode, err);
+        }
+    });
+}
+
+/// Logs a parse failure alert and POSTs it to `PARSE_ALERT_WEBHOOK_URL` in a separate thread if one is configured
+fn send_parse_alert(http: reqwest::Client, alert: ParseAlert) {
+    error!(
+        "{} of {} osu! API responses in the last {} seconds failed to parse; the latest error was: {}",
+        alert.failures, alert.attempts, alert.window_secs, alert.last_error
+    );
+    let url = match PARSE_ALERT_WEBHOOK_URL {
+        Some(url) => url,
+        None => { return; },
+    };
+
+    thread::spawn(move || {
+        let res = http.post(url).json(&alert).send().map_err(debug).and_then(|res| if res.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("Received {}", res.status()))
+        });
+        if let Err(err) = res {
+            error!("Unable to send parse failure alert to the ops webhook: {}", err);
+        }
+    });
+}
+
+/// Inserts the given beatmaps into the beatmap cache in a separate thread
+fn cache_beatmaps(
+    pool: Pool<ConnectionManager<MysqlConnection>>, known_beatmaps: KnownBeatmaps, retries: RetryQueue,
+    beatmaps: Vec<Beatmap>,
+) {
+    thread::spawn(move || insert_beatmaps(&pool, &known_beatmaps, &retries, &beatmaps));
 }
 
 impl ApiClient {
-    pub fn new() -> ApiClient {
-        ApiClient { pool: create_db_pool() }
+    /// Sets up the client's connections to the osu! API and the database, returning an error describing the problem if
+    /// either of them can't be set up
+    pub fn new() -> Result<ApiClient, String> {
+        ApiClient::connect(API_URL, API_REQUIRE_HTTPS, create_db_pool())
     }
 
-    /// Fetches beatmap metadata from the osu! API, automatically updating the internal betamap cache with the data.
-    pub fn get_beatmap(&self, beatmap_id: usize, mode: u8) -> Result<Option<Beatmap>, String> {
-        let res = get_url(&format!("{}/get_beatmaps?k={}&m={}&b={}", API_URL, API_KEY, mode, beatmap_id))?;
+    /// Sets up a client that makes its requests to the mock osu! API at `api_url` and stores data using `pool`
+    #[cfg(test)]
+    pub fn with_mock_api(
+        api_url: &str, pool: Pool<ConnectionManager<MysqlConnection>>
+    ) -> Result<ApiClient, String> {
+        ApiClient::connect(api_url, false, pool)
+    }
 
-        // try to parse the response into a vector of `String`:`String` `HashMap`s
-        let raw: Vec<HashMap<String, String>> = serde_json::from_str(&res).map_err(debug)?;
-        // make sure that we actually got a response
-        if raw.len() == 0 {
-            return Ok(None);
+    /// Sets up a client for the osu! API at `api_url`, loading the ids of the cached beatmaps using `pool`
+    fn connect(
+        api_url: &str, require_https: bool, pool: Pool<ConnectionManager<MysqlConnection>>
+    ) -> Result<ApiClient, String> {
+        let http = build_http_client(api_url, require_https, API_EXTRA_ROOT_CERTIFICATES)?;
+        let known_beatmaps = {
+            let conn = pool.get().map_err(|err| format!("Unable to get connection from pool: {}", err))?;
+            KnownBeatmaps::load(&*conn).map_err(|err| format!("Unable to load the ids of cached beatmaps: {}", err))?
+        };
+
+        Ok(ApiClient {
+            api_url: String::from(api_url),
+            http: http,
+            pool: pool,
+            stats_cache: Mutex::new(
+                LruCache::new(STATS_CACHE_CAPACITY, Duration::from_secs(LIVE_STATS_CACHE_MAX_AGE_SECS))
+            ),
+            known_beatmaps: known_beatmaps,
+            usage: ApiUsage::new(Arc::new(SystemClock), API_RATE_LIMIT_PER_MINUTE),
+            retries: RetryQueue::start(
+                INSERT_RETRY_QUEUE_DEPTH, INSERT_RETRY_ATTEMPTS, Duration::from_millis(INSERT_RETRY_BACKOFF_MS)
+            ),
+            parse_failures: Mutex::new(ParseFailureTracker::new(
+                PARSE_ALERT_MIN_FAILURES,
+                PARSE_ALERT_FAILURE_RATE,
+                ::chrono::Duration::seconds(PARSE_ALERT_WINDOW_SECS),
+                ::chrono::Duration::seconds(PARSE_ALERT_COOLDOWN_SECS),
+            )),
+        })
+    }
+
+    /// Records that an osu! API response was parsed successfully
+    fn parse_succeeded(&self) {
+        self.parse_failures.lock().unwrap().record_success(SystemClock.now());
+    }
+
+    /// Records that the osu! API response `payload` failed to parse with `err`, sending an alert if parse failures have
+    /// spiked.  Returns `err` so that it can be passed on to the caller.
+    fn parse_failed(&self, payload: &str, err: String) -> String {
+        let alert = self.parse_failures.lock().unwrap().record_failure(SystemClock.now(), &err, payload);
+        if let Some(alert) = alert {
+            send_parse_alert(self.http.clone(), alert);
         }
-        let first = &raw[0];
+        err
+    }
 
-        // parse the `HashMap` into a `NewBeatmap` manually since the values are provided as strings from the osu! API
-        let beatmap = Beatmap {
-            mode: mode as i16,
-            beatmapset_id: parse_pair(&first.get("beatmapset_id").unwrap()),
-            beatmap_id: parse_pair(&first.get("beatmap_id").unwrap()),
-            approved: parse_pair(&first.get("approved").unwrap()),
-            approved_date: NaiveDateTime::parse_from_str(&first.get("approved_date").unwrap(), MYSQL_DATE_FORMAT)
-                .expect(DATE_PARSE_ERROR),
-            last_update: NaiveDateTime::parse_from_str(&first.get("approved_date").unwrap(), MYSQL_DATE_FORMAT)
-                .expect(DATE_PARSE_ERROR),
-            total_length: parse_pair(&first.get("total_length").unwrap()),
-            hit_length: parse_pair(&first.get("hit_length").unwrap()),
-            version: first.get("version").unwrap().clone(),
-            artist: first.get("artist").unwrap().clone(),
-            title: first.get("title").unwrap().clone(),
-            creator: first.get("creator").unwrap().clone(),
-            bpm: parse_pair(&first.get("bpm").unwrap()),
-            source: first.get("source").unwrap().clone(),
-            difficulty: parse_pair(&first.get("difficultyrating").unwrap()),
-            diff_size: parse_pair(&first.get("diff_size").unwrap()),
-            diff_overall: parse_pair(&first.get("diff_overall").unwrap()),
-            diff_approach: parse_pair(&first.get("diff_approach").unwrap()),
-            diff_drain: parse_pair(&first.get("diff_drain").unwrap()),
+    /// The counts of the requests that have been made to the osu! API recently
+    pub fn usage(&self) -> &ApiUsage {
+        &self.usage
+    }
+
+    /// The hit and miss counts of the client's caches, keyed by the osu! API endpoint whose responses they hold
+    pub fn cache_stats(&self) -> HashMap<&'static str, CacheStats> {
+        let mut stats = HashMap::new();
+        stats.insert("get_user", self.stats_cache.lock().unwrap().stats());
+        stats
+    }
+
+    /// The ids of the beatmaps that are stored in the beatmap cache
+    pub fn known_beatmaps(&self) -> &KnownBeatmaps {
+        &self.known_beatmaps
+    }
+
+    /// Returns a beatmap in `mode` from the beatmap cache, fetching it from the osu! API and inserting it into the
+    /// cache if it isn't cached in that mode yet.  Returns `None` if the osu! API doesn't know about the beatmap.  Any
+    /// code that needs the data of a single beatmap should go through this.
+    pub fn ensure_beatmap(&self, beatmap_id: i32, mode: u8) -> Result<Option<Beatmap>, ApiError> {
+        let conn: &MysqlConnection = &*self.pool.get().map_err(debug)?;
+        if self.known_beatmaps.contains(beatmap_id, mode) {
+            let cached: Option<Beatmap> = beatmaps_dsl::beatmaps
+                .find((beatmap_id, mode as i16))
+                .first(conn)
+                .optional()
+                .map_err(debug)?;
+            if cached.is_some() {
+                return Ok(cached);
+            }
+        }
+
+        let query = BeatmapQuery::Beatmap(beatmap_id);
+        let beatmap = match request_beatmaps(&self.api_url, &self.http, &self.usage, query, mode)?.into_iter().next() {
+            Some(beatmap) => beatmap,
+            None => { return Ok(None); },
         };
+        self.known_beatmaps.cache_beatmap(&beatmap, |beatmap| store_beatmap(conn, beatmap).map_err(|err| {
+            retry_beatmap_insert(&self.retries, &self.pool, &self.known_beatmaps, beatmap);
+            err
+        }));
 
-        // insert the beatmap into the database in a separate thread
+        Ok(Some(beatmap))
+    }
+
+    /// Fetches the beatmaps with the given ids that aren't in the beatmap cache from the osu! API, grouping them by
+    /// beatmapset where possible to reduce the number of requests made.  Returns the fetched beatmaps along with the
+    /// ids of those that the osu! API doesn't know about.  The fetched beatmaps are inserted into the beatmap cache in
+    /// a separate thread along with any other difficulties of their beatmapsets that were fetched with them.
+    pub fn get_beatmaps_bulk(&self, ids: &[i32], mode: u8) -> Result<(Vec<Beatmap>, Vec<i32>), String> {
+        let missing: Vec<i32> = ids.iter().cloned().filter(|&id| !self.known_beatmaps.contains(id, mode)).collect();
+        let (fetched, unknown) = fetch_beatmaps_grouped(&missing, |query| {
+            request_beatmaps(&self.api_url, &self.http, &self.usage, query, mode)
+        })?;
+        let found = fetched.iter().filter(|beatmap| missing.contains(&beatmap.beatmap_id)).cloned().collect();
+        if !fetched.is_empty() {
+            cache_beatmaps(self.pool.clone(), self.known_beatmaps.clone(), self.retries.clone(), fetched);
+        }
+
+        Ok((found, unknown))
+    }
+
+    /// Fetches the beatmaps with the given ids that aren't already cached and inserts them into the beatmap cache, all
+    /// in a separate thread so that the caller doesn't have to wait on the osu! API.
+    pub fn prefetch_beatmaps(&self, ids: Vec<i32>, mode: u8) {
         let pool = self.pool.clone();
-        let beatmap_clone = beatmap.clone();
+        let known_beatmaps = self.known_beatmaps.clone();
+        let api_url = self.api_url.clone();
+        let http = self.http.clone();
+        let usage = self.usage.clone();
+        let retries = self.retries.clone();
         thread::spawn(move || {
-            let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
-            match diesel::insert_into(beatmaps_dsl::beatmaps)
-                .values(&beatmap_clone)
-                .execute(conn)
-            {
-                Ok(_) => (),
-                Err(err) => println!("Error while attempting to insert beatmap into beatmap cache: {:?}", err),
+            let missing: Vec<i32> = ids.into_iter().filter(|&id| !known_beatmaps.contains(id, mode)).collect();
+            match fetch_beatmaps_grouped(&missing, |query| request_beatmaps(&api_url, &http, &usage, query, mode)) {
+                Ok((fetched, _)) => insert_beatmaps(&pool, &known_beatmaps, &retries, &fetched),
+                Err(err) => println!("Error while prefetching beatmaps: {}", err),
             }
         });
+    }
 
-        Ok(Some(beatmap))
+    /// Returns the raw representation of a user's current stats for a given gamemode, including their events from the
+    /// last `event_days` days (1-31).  Responses are cached for a short time; if `force` is set, the cache is bypassed
+    /// and the stats are always fetched from the osu! API.  Responses fetched from the osu! API are stored as raw
+    /// snapshots if `snapshot` is set.
+    fn fetch_raw_stats(
+        &self, username: &str, mode: u8, event_days: u8, force: bool, snapshot: bool
+    ) -> Result<Option<(RawUpdate, NewUpdate)>, String> {
+        if event_days < 1 || event_days > MAX_EVENT_DAYS {
+            return Err(format!("`event_days` must be between 1 and {}; got {}", MAX_EVENT_DAYS, event_days));
+        }
+
+        let key = (username.to_lowercase(), mode, event_days);
+        get_or_fetch(&self.stats_cache, key, force, || self.request_raw_stats(username, mode, event_days, snapshot))
     }
 
-    /// Returns a user's current stats for a given gamemode.
-    pub fn get_stats(&self, username: &str, mode: u8) -> Result<Option<NewUpdate>, String> {
-        let res = get_url(&format!("{}/get_user?k={}&u={}&m={}", API_URL, API_KEY, username, mode))?;
+    /// Requests a user's stats from the osu! API, bypassing the cache.  The response is stored as the user's latest raw
+    /// snapshot if `snapshot` is set.
+    fn request_raw_stats(
+        &self, username: &str, mode: u8, event_days: u8, snapshot: bool
+    ) -> Result<Option<(RawUpdate, NewUpdate)>, String> {
 
-        let raw_updates: Vec<RawUpdate> = serde_json::from_str(&res).map_err(debug)?;
+        let res = get_api_url(&self.http, &self.usage, &format!(
+            "{}/get_user?k={}&u={}&m={}&event_days={}", self.api_url, API_KEY, username, mode, event_days
+        ))?;
+        // stored before parsing so that payloads which fail to parse can be inspected
+        if snapshot {
+            snapshot_raw_stats(self.pool.clone(), &res, mode);
+        }
+
+        let raw_updates: Vec<RawUpdate> = serde_json::from_str(&res)
+            .map_err(|err| self.parse_failed(&res, debug(err)))?;
         if raw_updates.len() == 0 {
+            self.parse_succeeded();
             return Ok(None);
         }
         let raw_update = raw_updates[0].clone();
-        let raw_clone = raw_update.clone();
-        let parsed_update = raw_update.to_update(mode).map_err(|err_opt| -> String {
-            match err_opt {
-                Some(s) => s,
-                None => format!("No stats available for user {} in that mode.", username),
-            }
-        })?;
+        let parsed_update = match raw_update.clone().to_update(mode) {
+            Ok(parsed_update) => parsed_update,
+            Err(Some(err)) => { return Err(self.parse_failed(&res, err)); },
+            Err(None) => {
+                self.parse_succeeded();
+                return Err(format!("No stats available for user {} in that mode.", username));
+            },
+        };
+        self.parse_succeeded();
 
-        // in another thread, check if the user is in the database already.  If they are, make sure that their userid
-        // and username match, updating them if they aren't.  If they're not in the db, add them.
-        let pool = self.pool.clone();
-        let parsed_clone = parsed_update.clone();
-        thread::spawn(move || {
-            let conn: &MysqlConnection = &*pool.get().map_err(debug).expect("Unable to get connection from pool in thread!");
-            let user_id: i32 = raw_clone.user_id.parse().expect("Unable to parse user_id from string to i32");
-            match users_dsl::users.find(user_id).first(conn) {
-                Ok(usr) => {
-                    // a user row exists for this user id, so check that the usernames match
-                    let usr: User = usr;
-                    diesel::update(users_dsl::users.find(usr.id))
-                        .set(users_dsl::username.eq(&raw_clone.username))
-                        .execute(conn)
-                        .expect("Error while updating username");
-                },
-                Err(err) => {
-                    // no user row exists, so insert one.
-                    match err {
-                        Error::NotFound => {
-                            let usr = NewUser {
-                                id: user_id,
-                                username: raw_clone.username,
-                            };
-
-                            diesel::insert_into(users_dsl::users)
-                                .values(&usr)
-                                .execute(conn)
-                                .expect("Unable to insert new user row into database.");
-                        },
-                        _ => println!("Unexpected error occured when searching database for username: {:?}", err),
-                    }
-
-                    // This is the first update for that user, so store this one
-                    diesel::insert_into(updates_dsl::updates)
-                        .values(&parsed_clone)
-                        .execute(conn)
-                        .map_err(debug)
-                        .expect("Error while inserting first update into database");
-                },
-            }
-        });
+        Ok(Some((raw_update, parsed_update)))
+    }
+
+    /// Returns a user's current stats for a given gamemode without writing anything to the database.  If `force` is
+    /// set, the stats are always fetched from the osu! API rather than the cache.
+    pub fn fetch_stats(&self, username: &str, mode: u8, force: bool) -> Result<Option<NewUpdate>, String> {
+        let stats = self.fetch_raw_stats(username, mode, DEFAULT_EVENT_DAYS, force, false)?;
+        Ok(stats.map(|(_, parsed_update)| parsed_update))
+    }
 
-        Ok(Some(parsed_update))
+    /// Returns a user's current stats for a given gamemode along with their username as reported by the osu! API.
+    /// Callers that store the stats must make sure that the user's row exists with `helpers::ensure_user` first.
+    /// `event_days` controls how many days of the user's recent events are requested from the osu! API and must be
+    /// between 1 and 31.  If `force` is set, the stats are always fetched from the osu! API rather than the cache.  The
+    /// raw response is stored as the user's latest raw snapshot if `snapshot` is set; nothing else is written to the
+    /// database, and nothing at all if it isn't set.
+    pub fn get_stats(
+        &self, username: &str, mode: u8, event_days: u8, force: bool, snapshot: bool
+    ) -> Result<Option<UserStats>, String> {
+        Ok(self.fetch_raw_stats(username, mode, event_days, force, snapshot)?
+            .map(|(raw_update, parsed_update)| UserStats { username: raw_update.username, stats: parsed_update }))
     }
 
-    pub fn get_user_best(&self, user_id: i32, mode: u8, count: u8) -> Result<Option<Vec<NewHiscore>>, String> {
-        let res = get_url(&format!("{}/get_user_best?k={}&u={}&m={}&limit={}", API_URL, API_KEY, user_id, mode, count))?;
+    /// Looks up a user's current username by their id, returning `None` if the osu! API doesn't know of them.  This
+    /// bypasses the stats cache, which is keyed by username, and doesn't store a raw snapshot of the response.
+    pub fn get_username(&self, user_id: i32) -> Result<Option<String>, String> {
+        let res = get_api_url(&self.http, &self.usage, &format!(
+            "{}/get_user?k={}&u={}&type=id&event_days=1", self.api_url, API_KEY, user_id
+        ))?;
 
-        let raw_hiscores: Vec<RawHiscore> = serde_json::from_str(&res).map_err(debug)?;
+        let raw_updates: Vec<RawUpdate> = serde_json::from_str(&res).map_err(debug)?;
+        Ok(raw_updates.into_iter().next().map(|raw_update| raw_update.username))
+    }
+
+    /// Fetches up to `count` of a user's top plays.  Counts above `API_USER_BEST_LIMIT` are clamped to it since the
+    /// osu! API can't provide more; `UserBest::truncated` is set if that caused plays to be left out.
+    pub fn get_user_best(&self, user_id: i32, mode: u8, count: u16) -> Result<Option<UserBest>, String> {
+        let limit = cmp::min(count, API_USER_BEST_LIMIT);
+        let res = get_api_url(&self.http, &self.usage, &format!(
+            "{}/get_user_best?k={}&u={}&m={}&limit={}", self.api_url, API_KEY, user_id, mode, limit
+        ))?;
+
+        let raw_hiscores: Vec<RawHiscore> = serde_json::from_str(&res)
+            .map_err(|err| self.parse_failed(&res, debug(err)))?;
         if raw_hiscores.len() == 0 {
+            self.parse_succeeded();
             return Ok(None)
         }
 
         // map all of the `RawHiscore`s into `NewHiscore`s
         let mut results = Vec::with_capacity(raw_hiscores.len());
         for raw_hiscore in raw_hiscores {
-            let new_hiscore = raw_hiscore.to_new_hiscore(user_id, mode)?;
+            let new_hiscore = raw_hiscore.to_new_hiscore(user_id, mode).map_err(|err| self.parse_failed(&res, err))?;
             results.push(new_hiscore);
         }
+        self.parse_succeeded();
+
+        let truncated = is_user_best_truncated(count, results.len());
+        Ok(Some(UserBest { hiscores: results, truncated: truncated }))
+    }
+
+    /// Finds a user's position on a beatmap's global leaderboard using the `get_scores` endpoint.  The osu! API only
+    /// returns the top 100 scores for a beatmap, so `None` is returned if the user's score isn't among them.
+    pub fn get_scores(&self, beatmap_id: i32, user_id: i32, mode: u8) -> Result<Option<u32>, String> {
+        let res = get_api_url(
+            &self.http, &self.usage,
+            &format!("{}/get_scores?k={}&b={}&m={}&limit=100", self.api_url, API_KEY, beatmap_id, mode)
+        )?;
+
+        let raw_scores: Vec<RawScore> = serde_json::from_str(&res).map_err(debug)?;
+        let user_id = user_id.to_string();
+        let position = raw_scores.iter().position(|score| score.user_id == user_id);
 
-        Ok(Some(results))
+        Ok(position.map(|i| i as u32 + 1))
     }
 }
 
 /// Make sure we can run basic queries on the database using a connection pool
 #[test]
 fn basic_queries() {
-    let client = ApiClient::new();
-    let conn = &*client.pool.get().unwrap();
+    use test_support::test_pool;
+
+    let pool = test_pool();
+    let conn = &*pool.get().unwrap();
     diesel::expression::dsl::sql::<::diesel::types::Bool>("SELECT 1")
         .get_result::<bool>(conn)
         .unwrap();
 }
 
-/// Try fetching a beatmap from the osu! API and make sure that it's parsed correctly.  Then insert it into the beatmap cache.
+/// Fetch a beatmap from the mock osu! API and make sure that it's parsed correctly and inserted into the beatmap cache.
+/// Requesting it again is served from the cache without another request.
 #[test]
 fn test_beatmap_fetch_store() {
     use helpers::modes::STANDARD;
-    use schema;
+    use test_support::{fixture, test_pool, MockApi};
 
-    let client = ApiClient::new();
-    let beatmap = client.get_beatmap(1031604, STANDARD).unwrap().unwrap();
+    let api = MockApi::start(vec![("get_beatmaps", fixture("get_beatmaps.json"))]);
+    let client = ApiClient::with_mock_api(&api.url, test_pool()).unwrap();
 
-    let query = diesel::insert_into(schema::beatmaps::dsl::beatmaps)
-        .values(&beatmap);
-    let conn: &MysqlConnection = &*client.pool.get().expect("Unable to get connection from pool");
-    query.execute(conn).unwrap();
+    let beatmap = client.ensure_beatmap(1031604, STANDARD).unwrap().unwrap();
+    assert_eq!((beatmap.beatmap_id, beatmap.beatmapset_id, beatmap.mode), (1031604, 486535, 0));
+    assert_eq!((beatmap.version.as_str(), beatmap.creator.as_str()), ("Extra", "Ekoro"));
+    assert!((beatmap.difficulty - 6.0634).abs() < 1e-3);
+    assert!(client.known_beatmaps.contains(1031604, STANDARD));
+
+    let cached = client.ensure_beatmap(1031604, STANDARD).unwrap().unwrap();
+    assert_eq!((cached.beatmap_id, cached.version), (beatmap.beatmap_id, beatmap.version));
+    assert_eq!(api.requests().len(), 1);
+    assert!(api.requests()[0].starts_with("/get_beatmaps?"));
+}
+
+/// A beatmap that's cached in one mode is fetched again when it's requested in another, and both are then cached
+#[test]
+fn beatmaps_cached_per_mode() {
+    use helpers::modes::{MANIA, STANDARD};
+    use test_support::{fixture, test_pool, MockApi};
+
+    let api = MockApi::start(vec![("get_beatmaps", fixture("get_beatmaps.json"))]);
+    let client = ApiClient::with_mock_api(&api.url, test_pool()).unwrap();
+
+    assert_eq!(client.ensure_beatmap(1031604, STANDARD).unwrap().unwrap().mode, STANDARD as i16);
+    assert_eq!(client.ensure_beatmap(1031604, MANIA).unwrap().unwrap().mode, MANIA as i16);
+    assert_eq!(api.requests().len(), 2);
+    assert!(api.requests()[1].contains("m=3"));
+
+    assert_eq!(client.ensure_beatmap(1031604, STANDARD).unwrap().unwrap().mode, STANDARD as i16);
+    assert_eq!(client.ensure_beatmap(1031604, MANIA).unwrap().unwrap().mode, MANIA as i16);
+    assert_eq!(api.requests().len(), 2);
 }
 
 /// Make sure that we're able to read values back out of the database
 #[test]
 fn test_beatmap_retrieve() {
-    use schema::beatmaps::dsl::*;
-    use models::Beatmap;
+    use test_support::{fixture, test_pool};
 
-    let client = ApiClient::new();
-    let conn: &MysqlConnection = &*client.pool.get().expect("Unable to get connection from pool");
-    beatmaps.filter(beatmap_id.eq(1031604))
-        .load::<Beatmap>(conn)
+    let pool = test_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    let raw: Vec<HashMap<String, Option<String>>> = serde_json::from_str(&fixture("get_beatmaps.json")).unwrap();
+    let beatmap = parse_beatmap(&raw[0], 0).unwrap();
+    store_beatmap(conn, &beatmap).unwrap();
+
+    let stored: Vec<Beatmap> = beatmaps_dsl::beatmaps
+        .filter(beatmaps_dsl::beatmap_id.eq(1031604))
+        .load(conn)
         .unwrap();
+    let stored: Vec<(i32, String, f32)> = stored.into_iter()
+        .map(|stored| (stored.beatmapset_id, stored.title, stored.bpm))
+        .collect();
+    assert_eq!(stored, vec![(beatmap.beatmapset_id, beatmap.title, beatmap.bpm)]);
 }
 
-/// Make sure that we're able to retrieve user stats from the osu! API and parse them into a `NewUpdate`
+/// Make sure that we're able to retrieve user stats from the mock osu! API, parse them into a `NewUpdate`, and store it
 #[test]
 fn test_user_stats_fetch_store() {
+    use helpers::{ensure_user, get_last_update};
     use helpers::modes::STANDARD;
-    use schema;
+    use schema::updates::dsl as updates_dsl;
+    use test_support::{fixture, test_pool, MockApi};
 
-    // get most recent user stats from the osu! API
-    let client = ApiClient::new();
-    let update = client.get_stats("ameo", STANDARD).unwrap().unwrap();
+    let api = MockApi::start(vec![("get_user", fixture("get_user.json"))]);
+    let client = ApiClient::with_mock_api(&api.url, test_pool()).unwrap();
+    let UserStats { username, stats: update } = client.get_stats("ameo", STANDARD, DEFAULT_EVENT_DAYS, true, true)
+        .unwrap()
+        .unwrap();
+    assert_eq!((username.as_str(), update.user_id, update.pp_rank), ("Ameo", 4704931, 48217));
+    assert!(api.requests()[0].contains("u=ameo"));
 
     // store the update into the database
     let conn: &MysqlConnection = &*client.pool.get().expect("Unable to get connection from pool");
-    diesel::insert_into(schema::updates::dsl::updates)
+    ensure_user(conn, update.user_id, &username).unwrap();
+    diesel::insert_into(updates_dsl::updates)
         .values(&update)
         .execute(conn)
         .unwrap();
+    let stored = get_last_update(update.user_id, STANDARD, conn).unwrap().unwrap();
+    assert_eq!((stored.playcount, stored.pp_raw), (update.playcount, update.pp_raw));
+}
+
+/// Users and beatmaps that the osu! API doesn't know about are reported as missing rather than as errors
+#[test]
+fn empty_api_responses() {
+    use helpers::modes::STANDARD;
+    use test_support::{test_pool, MockApi};
+
+    let api = MockApi::start(vec![
+        ("get_user", String::from("[]")), ("get_beatmaps", String::from("[]")), ("get_user_best", String::from("[]")),
+    ]);
+    let client = ApiClient::with_mock_api(&api.url, test_pool()).unwrap();
+
+    assert!(client.get_stats("nobody", STANDARD, DEFAULT_EVENT_DAYS, true, true).unwrap().is_none());
+    assert_eq!(client.get_username(-1), Ok(None));
+    assert!(client.ensure_beatmap(-1, STANDARD).unwrap().is_none());
+    assert!(client.get_user_best(-1, STANDARD, 100).unwrap().is_none());
+}
+
+/// Responses that can't be parsed and requests that fail are reported as errors
+#[test]
+fn unparseable_api_responses() {
+    use helpers::modes::STANDARD;
+    use test_support::{test_pool, MockApi};
+
+    let api = MockApi::start(vec![
+        ("get_user", String::from("[{\"user_id\":\"2\",\"username\":\"Ameo\",\"count300\":\"many\",\"count100\":\"1\",\
+            \"count50\":\"1\",\"playcount\":\"1\",\"ranked_score\":\"1\",\"total_score\":\"1\",\"pp_rank\":\"1\",\
+            \"level\":\"1\",\"pp_raw\":\"1\",\"accuracy\":\"1\",\"count_rank_ss\":\"1\",\"count_rank_s\":\"1\",\
+            \"count_rank_a\":\"1\",\"pp_country_rank\":\"1\"}]")),
+        ("get_user_best", String::from("{\"error\":\"unexpected\"}")),
+    ]);
+    let client = ApiClient::with_mock_api(&api.url, test_pool()).unwrap();
+
+    assert!(client.get_stats("Ameo", STANDARD, DEFAULT_EVENT_DAYS, true, true).is_err());
+    assert!(client.get_user_best(2, STANDARD, 100).is_err());
+
+    // the mock doesn't serve `get_scores`, so the request fails
+    assert!(client.get_scores(1031604, 2, STANDARD).is_err());
+}
+
+/// Inserting a beatmap that's already cached fails, while storing it through the cache is a no-op
+#[test]
+fn duplicate_beatmap_insert() {
+    use test_support::{fixture, test_pool};
+
+    let pool = test_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    let raw: Vec<HashMap<String, Option<String>>> = serde_json::from_str(&fixture("get_beatmaps.json")).unwrap();
+    let beatmap = parse_beatmap(&raw[0], 0).unwrap();
+
+    store_beatmap(conn, &beatmap).unwrap();
+    assert!(diesel::insert_into(beatmaps_dsl::beatmaps).values(&beatmap).execute(conn).is_err());
+    store_beatmap(conn, &beatmap).unwrap();
+}
+
+/// Make sure that a beatmap's last update is read separately from its approval date, since
+/// `/admin/beatmaps/cached` filters on it
+#[test]
+fn beatmap_last_update() {
+    use chrono::NaiveDateTime;
+    use helpers::load_cached_beatmaps;
+    use test_support::{fixture, test_pool};
+
+    let pool = test_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    let raw: Vec<HashMap<String, Option<String>>> = serde_json::from_str(&fixture("get_beatmaps.json")).unwrap();
+    let beatmap = parse_beatmap(&raw[0], 0).unwrap();
+    assert_eq!(beatmap.approved_date, parse_osu_date("2016-08-21 19:48:12").unwrap());
+    assert_eq!(beatmap.last_update, parse_osu_date("2016-08-16 17:19:05").unwrap());
+    store_beatmap(conn, &beatmap).unwrap();
+
+    let cached_ids = |at: NaiveDateTime| -> Vec<i32> {
+        load_cached_beatmaps(conn, Some(at), Some(at), 100, 0).unwrap()
+            .into_iter()
+            .map(|row| row.beatmap_id)
+            .collect()
+    };
+    assert!(cached_ids(beatmap.last_update).contains(&beatmap.beatmap_id));
+    assert!(!cached_ids(beatmap.approved_date).contains(&beatmap.beatmap_id));
+}
+
+/// Make sure that beatmaps that are already known to be cached aren't inserted again
+#[test]
+fn known_beatmaps_skip_insert() {
+    use test_support::test_beatmap;
+
+    let beatmap = test_beatmap(486535, 1031604);
+    let known_beatmaps = KnownBeatmaps::default();
+    let mut inserts = 0;
+
+    assert!(known_beatmaps.cache_beatmap(&beatmap, |_| { inserts += 1; Ok(()) }));
+    assert!(known_beatmaps.contains(1031604, 0));
+    assert!(!known_beatmaps.cache_beatmap(&beatmap, |_| { inserts += 1; Ok(()) }));
+    assert_eq!(inserts, 1);
+
+    // failed inserts aren't recorded, so they're attempted again next time
+    let mut other = beatmap.clone();
+    other.beatmap_id = 129891;
+    assert!(known_beatmaps.cache_beatmap(&other, |_| Err(String::from("duplicate key"))));
+    assert!(!known_beatmaps.contains(129891, 0));
+    assert!(known_beatmaps.cache_beatmap(&other, |_| { inserts += 1; Ok(()) }));
+    assert_eq!(inserts, 2);
+}
+
+/// Beatmaps made for a mode other than standard keep their native mode even when requested in a different mode
+#[test]
+fn mania_native_beatmap_parsing() {
+    let fixture: HashMap<String, Option<String>> = [
+        ("beatmapset_id", "2837"), ("beatmap_id", "22538"), ("approved", "1"), ("approved_date", "2008-05-25 17:46:21"),
+        ("last_update", "2008-05-25 17:46:21"), ("total_length", "181"), ("hit_length", "170"), ("version", "7K Hard"),
+        ("artist", "Artist"), ("title", "Title"), ("creator", "Mapper"), ("bpm", "150"), ("source", ""),
+        ("difficultyrating", "3.25"), ("diff_size", "7"), ("diff_overall", "8"), ("diff_approach", "5"),
+        ("diff_drain", "8"), ("mode", "3"),
+    ].iter().map(|&(key, val)| (String::from(key), Some(String::from(val)))).collect();
+
+    let beatmap = parse_beatmap(&fixture, 0).unwrap();
+    assert_eq!(beatmap.mode, 0);
+    assert_eq!(beatmap.native_mode, 3);
+    assert_eq!(beatmap.beatmap_id, 22538);
+    assert_eq!(beatmap.difficulty, 3.25);
+
+    let serialized = serde_json::to_value(&beatmap).unwrap();
+    assert_eq!(serialized["mode"], 0);
+    assert_eq!(serialized["native_mode"], 3);
+}
+
+/// Unranked beatmaps have a `null` approval date, which fails the request as a bad response from the osu! API rather
+/// than panicking
+#[test]
+fn unranked_beatmap_parsing() {
+    use rocket::http::Status;
+    use test_support::fixture;
+
+    let mut raw: Vec<HashMap<String, Option<String>>> = serde_json::from_str(&fixture("get_beatmaps.json")).unwrap();
+    raw[0].insert(String::from("approved_date"), None);
+    let err = parse_beatmap(&raw[0], 0).unwrap_err();
+    assert_eq!(err, INVALID_BEATMAP_ERR);
+    assert_eq!(ApiError::from(err).status(), Status::BadGateway);
+
+    raw[0].insert(String::from("approved_date"), Some(String::from("yesterday")));
+    assert_eq!(parse_beatmap(&raw[0], 0).unwrap_err(), INVALID_BEATMAP_ERR);
+}
+
+/// Beatmapsets are only fetched when at least two of the other requested ids might belong to them, and every
+/// difficulty of a fetched beatmapset is returned
+#[test]
+fn grouped_beatmap_fetches() {
+    use test_support::test_beatmap;
+
+    // a beatmapset with five difficulties, one with two, and two nearby beatmaps in different beatmapsets
+    let fixtures = vec![
+        test_beatmap(100, 1000), test_beatmap(100, 1001), test_beatmap(100, 1002), test_beatmap(100, 1003),
+        test_beatmap(100, 1004), test_beatmap(200, 2000), test_beatmap(200, 2001), test_beatmap(300, 3000),
+        test_beatmap(400, 3010),
+    ];
+
+    let mut queries = Vec::new();
+    let ids = [1003, 1000, 1001, 1002, 2000, 2001, 3000, 3010, 9999];
+    let (fetched, unknown) = fetch_beatmaps_grouped(&ids, |query| {
+        queries.push(query);
+        Ok(fixtures.iter().filter(|beatmap| match query {
+            BeatmapQuery::Beatmap(id) => beatmap.beatmap_id == id,
+            BeatmapQuery::Beatmapset(id) => beatmap.beatmapset_id == id,
+        }).cloned().collect())
+    }).unwrap();
+
+    let mut fetched_ids: Vec<i32> = fetched.iter().map(|beatmap| beatmap.beatmap_id).collect();
+    fetched_ids.sort();
+    assert_eq!(fetched_ids, vec![1000, 1001, 1002, 1003, 1004, 2000, 2001, 3000, 3010]);
+    assert_eq!(unknown, vec![9999]);
+    // fetching the beatmapset of 2000 or 3000 couldn't have saved a request
+    assert_eq!(queries, vec![
+        BeatmapQuery::Beatmap(1000), BeatmapQuery::Beatmapset(100), BeatmapQuery::Beatmap(2000),
+        BeatmapQuery::Beatmap(2001), BeatmapQuery::Beatmap(3000), BeatmapQuery::Beatmap(3010),
+        BeatmapQuery::Beatmap(9999),
+    ]);
+}
+
+/// Fetching beatmaps in bulk from the mock osu! API takes fewer requests than there are ids when they share a
+/// beatmapset, returns only the requested beatmaps, and caches the rest of the beatmapset too
+#[test]
+fn bulk_beatmap_fetch_requests() {
+    use test_support::{fixture, test_pool, MockApi};
+
+    let template: Vec<HashMap<String, String>> = serde_json::from_str(&fixture("get_beatmaps.json")).unwrap();
+    let template = template[0].clone();
+    // beatmapset -100 has six difficulties, five of which are requested
+    let api = MockApi::with_handler(move |target| {
+        let param = |name: &str| target.split(|c| c == '?' || c == '&')
+            .find(|param| param.starts_with(name))
+            .map(|param| String::from(&param[name.len()..]));
+        let ids: Vec<i32> = match (param("b="), param("s=")) {
+            (Some(ref id), _) if id.parse::<i32>().map(|id| id <= -1000 && id >= -1005).unwrap_or(false) => {
+                vec![id.parse().unwrap()]
+            },
+            (_, Some(ref id)) if id == "-100" => (-1005..-999).collect(),
+            _ => Vec::new(),
+        };
+        let beatmaps: Vec<HashMap<String, String>> = ids.into_iter()
+            .map(|id| {
+                let mut raw = template.clone();
+                raw.insert(String::from("beatmapset_id"), String::from("-100"));
+                raw.insert(String::from("beatmap_id"), id.to_string());
+                raw
+            })
+            .collect();
+        Some(serde_json::to_string(&beatmaps).unwrap())
+    });
+    let client = ApiClient::with_mock_api(&api.url, test_pool()).unwrap();
+
+    let ids = [-1000, -1004, -1001, -1002, -1003, -9999];
+    let (found, unknown) = client.get_beatmaps_bulk(&ids, 0).unwrap();
+    let mut found_ids: Vec<i32> = found.iter().map(|beatmap| beatmap.beatmap_id).collect();
+    found_ids.sort();
+    assert_eq!(found_ids, vec![-1004, -1003, -1002, -1001, -1000]);
+    assert_eq!(unknown, vec![-9999]);
+    // the unknown beatmap, the first of the beatmapset, and then the rest of the beatmapset
+    let requests = api.requests();
+    assert!(requests.len() < ids.len());
+    assert_eq!(requests.len(), 3);
+    assert!(requests[2].ends_with("&s=-100"));
+
+    // the beatmaps are cached in a separate thread
+    let start = Instant::now();
+    while !(-1005..-999).all(|id| client.known_beatmaps().contains(id, 0)) {
+        assert!(start.elapsed() < Duration::from_secs(5), "The fetched beatmapset wasn't cached");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Two requests that both fetched the same uncached beatmap store it once without either of them failing
+#[test]
+fn concurrent_beatmap_stores() {
+    use diesel::dsl::count_star;
+    use test_support::test_beatmap;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    let beatmap = test_beatmap(-1, -1);
+    // each request has checked the known beatmaps before either of them stored the beatmap
+    let (first_request, second_request) = (KnownBeatmaps::default(), KnownBeatmaps::default());
+    assert!(first_request.cache_beatmap(&beatmap, |beatmap| store_beatmap(conn, beatmap)));
+    assert!(second_request.cache_beatmap(&beatmap, |beatmap| store_beatmap(conn, beatmap)));
+    assert!(first_request.contains(-1, 0) && second_request.contains(-1, 0));
+
+    let rows: i64 = beatmaps_dsl::beatmaps
+        .filter(beatmaps_dsl::beatmap_id.eq(-1))
+        .select(count_star())
+        .first(conn)
+        .unwrap();
+    assert_eq!(rows, 1);
+}
+
+#[test]
+fn api_key_redaction() {
+    let url = format!("{}/get_user?k={}&u=Ameo&m=0", API_URL, API_KEY);
+    let redacted = redact_api_key(&url);
+    assert_eq!(redacted, format!("{}/get_user?k=REDACTED&u=Ameo&m=0", API_URL));
+    assert!(!redacted.contains(API_KEY));
+
+    // parameters that merely end in `k` are left alone
+    assert_eq!(redact_api_key("https://example.com/a?m=0&k=secret&pk=1"), "https://example.com/a?m=0&k=REDACTED&pk=1");
+    assert_eq!(redact_api_key("https://example.com/a"), "https://example.com/a");
+}
+
+#[test]
+fn user_best_truncation() {
+    // 150 plays were requested but the API could only provide its limit of 100
+    assert!(is_user_best_truncated(150, 100));
+    // the user only has 80 plays, so all of them were returned
+    assert!(!is_user_best_truncated(150, 80));
+    // requests within the limit are never truncated
+    assert!(!is_user_best_truncated(100, 100));
+    assert!(!is_user_best_truncated(50, 50));
+}
+
+#[cfg(test)]
+fn parse_raw_update(json: &str) -> RawUpdate {
+    let mut raw_updates: Vec<RawUpdate> = serde_json::from_str(json).unwrap();
+    raw_updates.remove(0)
+}
+
+/// A brand-new account that has no events at all
+#[test]
+fn fresh_account_parsing() {
+    let raw = parse_raw_update(
+        "[{\"user_id\":\"1\",\"username\":\"New Player\",\"count300\":\"0\",\"count100\":\"0\",\"count50\":\"0\",\
+        \"playcount\":\"0\",\"ranked_score\":\"0\",\"total_score\":\"0\",\"pp_rank\":\"0\",\"level\":\"1\",\
+        \"pp_raw\":\"0\",\"accuracy\":\"0\",\"count_rank_ss\":\"0\",\"count_rank_s\":\"0\",\"count_rank_a\":\"0\",\
+        \"pp_country_rank\":\"0\"}]"
+    );
+    assert!(raw.events.is_none());
+    let update = raw.to_update(0).ok().unwrap();
+    assert_eq!((update.user_id, update.playcount, update.level), (1, 0, 1.));
+
+    let raw = parse_raw_update("[{\"user_id\":\"1\",\"username\":\"New Player\",\"events\":null}]");
+    assert!(raw.events.is_none());
+}
+
+/// Fields that the osu! API adds in the future are ignored
+#[test]
+fn unknown_fields_parsing() {
+    let raw = parse_raw_update(
+        "[{\"user_id\":\"2\",\"username\":\"Ameo\",\"count300\":\"1000\",\"count100\":\"100\",\"count50\":\"10\",\
+        \"playcount\":\"50\",\"ranked_score\":\"100000\",\"total_score\":\"200000\",\"pp_rank\":\"5000\",\
+        \"level\":\"30.5\",\"pp_raw\":\"1234.5\",\"accuracy\":\"98.5\",\"count_rank_ss\":\"1\",\"count_rank_s\":\"2\",\
+        \"count_rank_a\":\"3\",\"pp_country_rank\":\"100\",\"country\":\"US\",\"total_seconds_played\":\"12345\",\
+        \"events\":[{\"display_html\":\"\",\"beatmap_id\":\"1\",\"beatmapset_id\":\"1\",\
+        \"date\":\"2017-12-01 00:00:00\",\"epicfactor\":\"1\",\"new_field\":true}]}]"
+    );
+    assert_eq!(raw.events.as_ref().map(|events| events.len()), Some(1));
+    let update = raw.to_update(0).ok().unwrap();
+    assert_eq!((update.pp_raw, update.total_hits), (1234.5, 1110));
+}
+
+/// Users that exist but have never played a mode have `null` stats, which is reported as having no stats rather than as
+/// a parse error
+#[test]
+fn null_stats_parsing() {
+    let raw = parse_raw_update(
+        "[{\"user_id\":\"3\",\"username\":\"Taiko Only\",\"count300\":null,\"count100\":null,\"count50\":null,\
+        \"playcount\":null,\"ranked_score\":null,\"total_score\":null,\"pp_rank\":null,\"level\":null,\"pp_raw\":null,\
+        \"accuracy\":null,\"count_rank_ss\":null,\"count_rank_s\":null,\"count_rank_a\":null,\"pp_country_rank\":null,\
+        \"events\":[]}]"
+    );
+    match raw.to_update(2) {
+        Err(None) => (),
+        Err(Some(err)) => panic!("Expected no stats, got a parse error: {}", err),
+        Ok(_) => panic!("Expected no stats, got an update"),
+    }
+
+    // malformed values are still parse errors
+    let raw = parse_raw_update("[{\"user_id\":\"3\",\"username\":\"Broken\",\"count300\":\"many\"}]");
+    match raw.to_update(0) {
+        Err(Some(_)) => (),
+        _ => panic!("Expected a parse error"),
+    }
+}
+
+/// Make sure that beatmaps are queued to be inserted later rather than panicking when the pool has no connections left
+#[test]
+fn exhausted_pool_beatmap_insert() {
+    use helpers::database_url;
+    use test_support::test_beatmap;
+
+    let manager = ConnectionManager::<MysqlConnection>::new(database_url());
+    let pool = Pool::builder().max_size(1).connection_timeout(Duration::from_millis(100)).build(manager).unwrap();
+    let _held = pool.get().unwrap();
+
+    let known_beatmaps = KnownBeatmaps::default();
+    known_beatmaps.insert(-2, 0);
+    let (retries, queued) = RetryQueue::capture();
+
+    insert_beatmaps(&pool, &known_beatmaps, &retries, &[test_beatmap(-1, -1), test_beatmap(-1, -2)]);
+    // only the beatmap that isn't cached yet is queued, and nothing is recorded as cached
+    let descriptions: Vec<String> = queued.try_iter().map(|(description, _)| description).collect();
+    assert_eq!(descriptions, vec![String::from("insert of beatmap -1 into the beatmap cache")]);
+    assert!(!known_beatmaps.contains(-1, 0));
+}
+
+/// Make sure that plain HTTP is refused when HTTPS is required and that unreadable certificates are reported
+#[test]
+fn http_client_tls_settings() {
+    let err = build_http_client("http://osu.ppy.sh/api", true, &[]).unwrap_err();
+    assert!(err.contains("doesn't use HTTPS"));
+    assert!(build_http_client("http://osu.ppy.sh/api", false, &[]).is_ok());
+
+    let err = build_http_client(API_URL, true, &["/nonexistent/osu-api-root.der"]).unwrap_err();
+    assert!(err.contains("/nonexistent/osu-api-root.der"));
+}
+
+/// Make sure that requests to the osu! API identify the backend through their `User-Agent`
+#[test]
+fn api_user_agent() {
+    use helpers::modes::STANDARD;
+    use test_support::{test_pool, MockApi};
+
+    assert!(user_agent().starts_with(&format!("osutrack2/{} (+", env!("CARGO_PKG_VERSION"))));
+
+    let api = MockApi::start(vec![("get_user", String::from("[]"))]);
+    let client = ApiClient::with_mock_api(&api.url, test_pool()).unwrap();
+    client.get_stats("Ameo", STANDARD, DEFAULT_EVENT_DAYS, true, true).unwrap();
+    assert_eq!(api.user_agents(), vec![Some(user_agent())]);
 }
@@ -0,0 +1,883 @@
+//! Request guards and parameter types used to parse and validate the inputs of the API routes
+
+use std::cmp;
+use std::io::Read;
+use std::ops::Deref;
+
+use chrono::{NaiveDate, NaiveDateTime};
+use rocket::Outcome;
+use rocket::data::{self, Data, FromData};
+use rocket::http::{RawStr, Status};
+use rocket::request::{self, FormItems, FromForm, FromFormValue, FromParam, FromRequest, Request};
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use helpers::{parse_osu_date, UpdateBias};
+use helpers::leaderboard::{DEFAULT_PP_GAIN_DAYS, MAX_PP_GAIN_DAYS};
+use helpers::modes::check_mode;
+use helpers::user_flags::UserFlagKind;
+use osu_api::{DEFAULT_EVENT_DAYS, MAX_EVENT_DAYS};
+use osutrack_types::Grade;
+use osutrack_types::diff::DiffOptions;
+use secret::{ENABLED_MODES, FIRST_UPDATE_NEWHS_LIMIT, MAX_REQUEST_BODY_BYTES};
+
+/// An optional form value.  Unlike `Option<T>`, which treats invalid values the same as missing ones, values that are
+/// supplied but fail to parse cause the whole form to be rejected.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OptionalParam<T>(pub Option<T>);
+
+impl<'v, T: FromFormValue<'v>> FromFormValue<'v> for OptionalParam<T> {
+    type Error = T::Error;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<Self, Self::Error> {
+        T::from_form_value(form_value).map(|val| OptionalParam(Some(val)))
+    }
+
+    fn default() -> Option<Self> {
+        Some(OptionalParam(None))
+    }
+}
+
+/// A point in time supplied as a unix timestamp in seconds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UnixTime(pub NaiveDateTime);
+
+impl<'v> FromFormValue<'v> for UnixTime {
+    type Error = &'v RawStr;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<Self, Self::Error> {
+        form_value.parse::<i64>().ok()
+            .and_then(|secs| NaiveDateTime::from_timestamp_opt(secs, 0))
+            .map(UnixTime)
+            .ok_or(form_value)
+    }
+}
+
+impl<'a> FromParam<'a> for UnixTime {
+    type Error = String;
+
+    fn from_param(param: &'a RawStr) -> Result<Self, String> {
+        UnixTime::from_form_value(param)
+            .map_err(|_| format!("Timestamps must be a unix timestamp in seconds; got {}", param))
+    }
+}
+
+/// A game mode supplied as a path segment.  Only the modes in `ENABLED_MODES` are accepted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mode(pub u8);
+
+impl<'a> FromParam<'a> for Mode {
+    type Error = String;
+
+    fn from_param(param: &'a RawStr) -> Result<Self, String> {
+        let mode: u8 = param.parse().map_err(|_| format!("Invalid mode: {}", param))?;
+        check_mode(mode, ENABLED_MODES).map(Mode)
+    }
+}
+
+/// The file name of a signature image, which is the mode that it's for followed by `.png`, such as `0.png`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SigFile(pub Mode);
+
+impl<'a> FromParam<'a> for SigFile {
+    type Error = String;
+
+    fn from_param(param: &'a RawStr) -> Result<Self, String> {
+        if !param.ends_with(".png") {
+            return Err(format!("Signature images must be requested as <mode>.png; got {}", param));
+        }
+        Mode::from_param(RawStr::from_str(&param[..param.len() - 4])).map(SigFile)
+    }
+}
+
+/// A point in time supplied as an ISO 8601 timestamp, such as `2017-12-01T12:00:00Z`, or as a date, which stands for
+/// midnight (UTC) at its start.  Timestamps with offsets are converted to UTC and ones without are taken to be in UTC.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IsoTime(pub NaiveDateTime);
+
+impl<'a> FromParam<'a> for IsoTime {
+    type Error = String;
+
+    fn from_param(param: &'a RawStr) -> Result<Self, String> {
+        let decoded = param.url_decode().map_err(|_| format!("Invalid timestamp: {}", param))?;
+        NaiveDate::parse_from_str(&decoded, "%Y-%m-%d")
+            .map(|date| date.and_hms(0, 0, 0))
+            .or_else(|_| parse_osu_date(&decoded))
+            .map(IsoTime)
+            .map_err(|_| format!("Timestamps must be ISO 8601 timestamps or dates; got {}", decoded))
+    }
+}
+
+/// Query parameters for routes that return data from a range of time.  Both bounds are inclusive unix timestamps.
+#[derive(FromForm)]
+pub struct DateRangeParams {
+    pub from: OptionalParam<UnixTime>,
+    pub to: OptionalParam<UnixTime>,
+}
+
+/// The maximum number of top plays that the osu! API will return for a user in a single request
+pub const MAX_HISCORE_LIMIT: u8 = 100;
+/// The maximum number of top plays that can be requested for a user with `?deep=true`
+pub const MAX_DEEP_HISCORE_LIMIT: u16 = 1000;
+/// The number of hiscores returned by `/hiscores/recent` when no limit is supplied
+pub const DEFAULT_RECENT_HISCORES: u32 = 50;
+/// The maximum number of hiscores returned by a single request to `/hiscores/recent`
+pub const MAX_RECENT_HISCORES: u32 = 100;
+/// The number of beatmaps returned by `/admin/beatmaps/cached` when no limit is supplied
+pub const DEFAULT_CACHED_BEATMAPS: u32 = 100;
+/// The maximum number of beatmaps returned by a single request to `/admin/beatmaps/cached`
+pub const MAX_CACHED_BEATMAPS: u32 = 500;
+/// The number of hiscores returned per mode by `/hiscores_all` when no limit is supplied
+pub const DEFAULT_TOP_HISCORES: u32 = 10;
+/// The maximum number of hiscores returned per mode by `/hiscores_all`
+pub const MAX_TOP_HISCORES: u32 = 50;
+
+/// Request guard that parses the request's query string into `T`, failing the request with a 400 if it can't be parsed.
+/// Unlike a `?<params>` route segment, this also matches requests that don't have a query string at all; in that case
+/// all of the fields of `T` take their default values.
+pub struct Query<T>(pub T);
+
+impl<T> Deref for Query<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<'a, 'r, T: FromForm<'a>> FromRequest<'a, 'r> for Query<T> {
+    type Error = String;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, String> {
+        let query = request.uri().query().unwrap_or("");
+        match T::from_form(&mut FormItems::from(query), false) {
+            Ok(parsed) => Outcome::Success(Query(parsed)),
+            Err(_) => Outcome::Failure((Status::BadRequest, format!("Invalid query string: {}", query))),
+        }
+    }
+}
+
+/// Reads at most `limit` bytes from `body` and parses them as JSON, returning a message describing the problem if the
+/// body is too large or isn't valid JSON of the expected shape.
+fn parse_json_body<T: DeserializeOwned, R: Read>(body: R, limit: usize) -> Result<T, String> {
+    let mut buf = Vec::new();
+    body.take(limit as u64 + 1).read_to_end(&mut buf)
+        .map_err(|err| format!("Error while reading request body: {}", err))?;
+    if buf.len() > limit {
+        return Err(format!("Request body exceeds the maximum size of {} bytes", limit));
+    }
+
+    serde_json::from_slice(&buf).map_err(|err| format!("Invalid JSON body: {}", err))
+}
+
+/// Data guard that parses the request's body as JSON into `T`.  Bodies larger than `MAX_REQUEST_BODY_BYTES` are
+/// rejected without being read into memory in full.  All routes that accept JSON bodies should take a
+/// `Result<JsonBody<T>, String>` and map the error to `ApiError::BadInput` so that clients are told what was wrong
+/// with their body rather than receiving Rocket's generic error page.
+pub struct JsonBody<T>(pub T);
+
+impl<T> Deref for JsonBody<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned> FromData for JsonBody<T> {
+    type Error = String;
+
+    fn from_data(_request: &Request, data: Data) -> data::Outcome<Self, String> {
+        match parse_json_body(data.open(), MAX_REQUEST_BODY_BYTES) {
+            Ok(parsed) => Outcome::Success(JsonBody(parsed)),
+            Err(err) => Outcome::Failure((Status::BadRequest, err)),
+        }
+    }
+}
+
+/// The body of a `/stats_batch` request
+#[derive(Deserialize)]
+pub struct StatsBatchRequest {
+    pub usernames: Vec<String>,
+    pub mode: u8,
+}
+
+/// The number of top plays to fetch for a user from the osu! API.  Values above `MAX_DEEP_HISCORE_LIMIT` are clamped to
+/// it and zero or non-numeric values are rejected.  Defaults to `MAX_HISCORE_LIMIT` when not supplied.  Unless the
+/// client opts in with `?deep=true`, the limit is further clamped to `MAX_HISCORE_LIMIT` by `HiscoreParams::hs_limit`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HsLimit(pub u16);
+
+impl<'v> FromFormValue<'v> for HsLimit {
+    type Error = &'v RawStr;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<Self, Self::Error> {
+        match form_value.parse::<u32>() {
+            Ok(0) | Err(_) => Err(form_value),
+            Ok(n) => Ok(HsLimit(cmp::min(n, MAX_DEEP_HISCORE_LIMIT as u32) as u16)),
+        }
+    }
+
+    fn default() -> Option<Self> {
+        Some(HsLimit(MAX_HISCORE_LIMIT as u16))
+    }
+}
+
+/// A validated osu! username.  osu! usernames are 2-15 characters long and consist only of alphanumerics, spaces,
+/// underscores, hyphens, and square brackets.  Surrounding whitespace is trimmed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Username {
+    original: String,
+    normalized: String,
+}
+
+impl Username {
+    pub fn parse(raw: &str) -> Result<Username, String> {
+        let trimmed = raw.trim();
+        let len = trimmed.chars().count();
+        if len < 2 || len > 15 {
+            return Err(format!("Usernames must be between 2 and 15 characters long: {:?}", trimmed));
+        }
+
+        let is_valid_char = |c: char| c.is_ascii_alphanumeric() || " _-[]".contains(c);
+        if !trimmed.chars().all(is_valid_char) {
+            return Err(format!("Username contains invalid characters: {:?}", trimmed));
+        }
+
+        Ok(Username {
+            original: String::from(trimmed),
+            normalized: trimmed.to_lowercase(),
+        })
+    }
+
+    /// The username as supplied by the client, to be used for osu! API requests and responses
+    pub fn as_str(&self) -> &str {
+        &self.original
+    }
+
+    /// The lowercased username, to be used for database lookups
+    pub fn normalized(&self) -> &str {
+        &self.normalized
+    }
+}
+
+impl<'a> FromParam<'a> for Username {
+    type Error = String;
+
+    fn from_param(param: &'a RawStr) -> Result<Self, String> {
+        let decoded = param.percent_decode().map_err(|_| format!("Username isn't valid UTF-8: {}", param))?;
+        Username::parse(&decoded)
+    }
+}
+
+/// The maximum number of beatmaps that can be requested at once from the `/beatmaps` route
+pub const MAX_BEATMAP_IDS: usize = 50;
+
+/// Parses a JSON-encoded array of beatmap ids as supplied to the `/beatmaps` route.  All ids must be positive and no
+/// more than `MAX_BEATMAP_IDS` of them may be supplied.
+pub fn parse_beatmap_ids(raw: &str) -> Result<Vec<i32>, String> {
+    let ids: Vec<i32> = serde_json::from_str(raw)
+        .map_err(|err| format!("Beatmap ids must be a JSON array of integers: {}", err))?;
+
+    if ids.len() > MAX_BEATMAP_IDS {
+        return Err(format!("No more than {} beatmaps can be requested at once; got {}", MAX_BEATMAP_IDS, ids.len()));
+    }
+    if let Some(id) = ids.iter().find(|&&id| id <= 0) {
+        return Err(format!("Beatmap ids must be positive: {}", id));
+    }
+
+    Ok(ids)
+}
+
+/// Query parameters for routes that fetch a user's top plays from the osu! API
+#[derive(FromForm)]
+pub struct HiscoreParams {
+    pub hs_limit: HsLimit,
+    /// If set, only hiscores set since the previous update are reported as new
+    pub recent_only: bool,
+    /// If set, all of the new hiscores are reported on a user's first update rather than only the best
+    /// `FIRST_UPDATE_NEWHS_LIMIT` of them
+    pub full_newhs: bool,
+    /// If set, `hs_limit` may exceed `MAX_HISCORE_LIMIT`
+    pub deep: bool,
+    /// If set, stats that didn't change are left out of the returned diff
+    pub only_changed: bool,
+}
+
+impl HiscoreParams {
+    /// The number of top plays that should be fetched for the user
+    pub fn hs_limit(&self) -> u16 {
+        if self.deep { self.hs_limit.0 } else { cmp::min(self.hs_limit.0, MAX_HISCORE_LIMIT as u16) }
+    }
+
+    pub fn diff_options(&self) -> DiffOptions {
+        DiffOptions {
+            recent_only: self.recent_only,
+            first_update_limit: if self.full_newhs { None } else { Some(FIRST_UPDATE_NEWHS_LIMIT) },
+            // dropped hiscores can only be found if all of the user's top plays are fetched
+            track_dropped: self.hs_limit() >= MAX_HISCORE_LIMIT as u16,
+        }
+    }
+}
+
+/// A grade supplied as a query parameter using the osu! API's names, such as `S` or `XH`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GradeParam(pub Grade);
+
+impl<'v> FromFormValue<'v> for GradeParam {
+    type Error = &'v RawStr;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<Self, Self::Error> {
+        form_value.parse().map(GradeParam).map_err(|_| form_value)
+    }
+}
+
+/// Query parameters for the `/hiscores` route
+#[derive(FromForm)]
+pub struct HiscoreListParams {
+    /// If set, the position of the user's best plays on their beatmaps' global leaderboards are looked up
+    pub global_rank: bool,
+    /// If set, only hiscores that are still among the user's top plays are returned
+    pub current_only: bool,
+    /// If set, only hiscores with this grade are returned.  Grades match their silver variants unless `exact_grade` is
+    /// set as well.
+    pub grade: OptionalParam<GradeParam>,
+    pub exact_grade: bool,
+    /// If set, each hiscore's age, position among the user's current top plays, and weighted pp are included
+    pub enrich: bool,
+}
+
+/// Query parameters for the `/hiscores/recent` route other than its `PageParams`
+#[derive(FromForm)]
+pub struct RecentHiscoresParams {
+    /// If set, only hiscores set in this mode are returned
+    pub mode: OptionalParam<u8>,
+    /// If set, the beatmap of each hiscore is included if it's in the beatmap cache
+    pub beatmaps: bool,
+}
+
+/// Returns the `?offset=` of the page after one that was requested with `limit` and `offset` and had `returned` items,
+/// or `None` if it was the last page
+pub fn next_page_offset(limit: u32, offset: u32, returned: usize) -> Option<u32> {
+    if limit > 0 && returned == limit as usize { Some(offset + limit) } else { None }
+}
+
+/// Query parameters for routes that return a list one page at a time with `?limit=` and `?offset=`
+#[derive(FromForm)]
+pub struct PageParams {
+    /// The number of items to return; see `PageParams::limit`
+    pub limit: OptionalParam<u32>,
+    /// The number of items to skip, used to fetch later pages
+    pub offset: OptionalParam<u32>,
+}
+
+impl PageParams {
+    /// The number of items to return, defaulting to `default` and clamped to `max`
+    pub fn limit(&self, default: u32, max: u32) -> u32 {
+        cmp::min(self.limit.0.unwrap_or(default), max)
+    }
+
+    pub fn offset(&self) -> u32 {
+        self.offset.0.unwrap_or(0)
+    }
+}
+
+/// Query parameters for the `/hiscores_all` route
+#[derive(FromForm)]
+pub struct HiscoresAllParams {
+    /// The number of hiscores to return per mode; see `HiscoresAllParams::limit`
+    pub limit: OptionalParam<u32>,
+    /// If set, the beatmap of each hiscore is included if it's in the beatmap cache
+    pub include_beatmaps: bool,
+}
+
+impl HiscoresAllParams {
+    /// The number of hiscores to return per mode, defaulting to `DEFAULT_TOP_HISCORES` and clamped to
+    /// `MAX_TOP_HISCORES`
+    pub fn limit(&self) -> u32 {
+        cmp::min(self.limit.0.unwrap_or(DEFAULT_TOP_HISCORES), MAX_TOP_HISCORES)
+    }
+}
+
+/// The order that a list is returned in, supplied as `asc` or `desc`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl<'v> FromFormValue<'v> for SortOrder {
+    type Error = &'v RawStr;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<Self, Self::Error> {
+        match form_value.as_str() {
+            "asc" => Ok(SortOrder::Asc),
+            "desc" => Ok(SortOrder::Desc),
+            _ => Err(form_value),
+        }
+    }
+}
+
+/// Query parameters for the `/updates` route
+#[derive(FromForm)]
+pub struct UpdateListParams {
+    /// The order of the updates by time.  Defaults to oldest first, which is what graphs want.
+    pub order: OptionalParam<SortOrder>,
+    /// If supplied, only this many updates from the start of the ordering are returned, so `?order=desc&limit=<n>`
+    /// returns the `n` most recent updates, newest first
+    pub limit: OptionalParam<u32>,
+}
+
+impl UpdateListParams {
+    pub fn descending(&self) -> bool {
+        self.order.0 == Some(SortOrder::Desc)
+    }
+}
+
+/// Query parameters for the `/stats` route
+#[derive(FromForm)]
+pub struct StatsParams {
+    /// If set, the percentile of tracked users that the user's rank puts them in is included in the response
+    pub percentile: bool,
+}
+
+/// Query parameters for the `/rank_to_pp` route.  Exactly one of them must be supplied.
+#[derive(FromForm)]
+pub struct RankToPpParams {
+    pub rank: OptionalParam<u32>,
+    pub pp: OptionalParam<f32>,
+}
+
+/// Query parameters for routes that fetch a user's stats from the osu! API
+#[derive(FromForm)]
+pub struct ForceParams {
+    /// If set, the user's stats are always fetched from the osu! API rather than served from the cache
+    pub force: bool,
+}
+
+/// Query parameters for routes that can include the beatmaps of the hiscores that they return
+#[derive(FromForm)]
+pub struct BeatmapParams {
+    /// If set, the beatmap of each hiscore is included, fetching it from the osu! API if it isn't cached
+    pub include_beatmaps: bool,
+}
+
+/// The maximum number of decimal places that values can be rounded to with `?precision=<n>`
+pub const MAX_PRECISION: u8 = 8;
+
+/// A number of decimal places between 0 and `MAX_PRECISION`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Precision(pub u8);
+
+impl<'v> FromFormValue<'v> for Precision {
+    type Error = &'v RawStr;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<Self, Self::Error> {
+        match form_value.parse::<u8>() {
+            Ok(n) if n <= MAX_PRECISION => Ok(Precision(n)),
+            _ => Err(form_value),
+        }
+    }
+}
+
+/// Query parameters for routes that return pp and accuracy values
+#[derive(FromForm)]
+pub struct PrecisionParams {
+    /// If supplied, pp and accuracy values are rounded to this many decimal places.  They're returned with full
+    /// precision otherwise.
+    pub precision: OptionalParam<Precision>,
+}
+
+impl PrecisionParams {
+    pub fn decimals(&self) -> Option<u8> {
+        self.precision.0.map(|precision| precision.0)
+    }
+}
+
+/// Query parameters for routes that return `ranked_score` and `total_score`
+#[derive(FromForm)]
+pub struct SafeIntegerParams {
+    /// If set, `ranked_score` and `total_score` are serialized as strings so they survive being parsed as doubles
+    pub safe_integers: bool,
+}
+
+/// The number of days of recent events to request for a user from the osu! API.  Must be between 1 and 31; defaults
+/// to 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EventDays(pub u8);
+
+impl<'v> FromFormValue<'v> for EventDays {
+    type Error = &'v RawStr;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<Self, Self::Error> {
+        match form_value.parse::<u8>() {
+            Ok(n) if n >= 1 && n <= MAX_EVENT_DAYS => Ok(EventDays(n)),
+            _ => Err(form_value),
+        }
+    }
+
+    fn default() -> Option<Self> {
+        Some(EventDays(DEFAULT_EVENT_DAYS))
+    }
+}
+
+/// Query parameters for the `/livestats` routes
+#[derive(FromForm)]
+pub struct LiveStatsParams {
+    pub event_days: EventDays,
+    /// Whether the fetched stats are recorded as an update; see `LiveStatsParams::record`
+    pub record: OptionalParam<bool>,
+}
+
+impl LiveStatsParams {
+    /// Whether the fetched stats should be recorded as an update, falling back to `default` if `?record=` wasn't
+    /// supplied
+    pub fn record(&self, default: bool) -> bool {
+        self.record.0.unwrap_or(default)
+    }
+}
+
+/// The number of days of recent updates to measure pp gain over.  Must be between 1 and `MAX_PP_GAIN_DAYS`; defaults
+/// to `DEFAULT_PP_GAIN_DAYS`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PpGainDays(pub u16);
+
+impl<'v> FromFormValue<'v> for PpGainDays {
+    type Error = &'v RawStr;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<Self, Self::Error> {
+        match form_value.parse::<u16>() {
+            Ok(n) if n >= 1 && n <= MAX_PP_GAIN_DAYS => Ok(PpGainDays(n)),
+            _ => Err(form_value),
+        }
+    }
+
+    fn default() -> Option<Self> {
+        Some(PpGainDays(DEFAULT_PP_GAIN_DAYS))
+    }
+}
+
+/// Query parameters for the pp gain leaderboard routes
+#[derive(FromForm)]
+pub struct PpGainParams {
+    pub days: PpGainDays,
+}
+
+/// Where a request to record an update came from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UpdateSource {
+    Web,
+    Bot,
+    Scheduler,
+    Other,
+}
+
+impl UpdateSource {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            UpdateSource::Web => "web",
+            UpdateSource::Bot => "bot",
+            UpdateSource::Scheduler => "scheduler",
+            UpdateSource::Other => "other",
+        }
+    }
+}
+
+impl<'v> FromFormValue<'v> for UpdateSource {
+    type Error = &'v RawStr;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<Self, Self::Error> {
+        match form_value.as_str() {
+            "web" => Ok(UpdateSource::Web),
+            "bot" => Ok(UpdateSource::Bot),
+            "scheduler" => Ok(UpdateSource::Scheduler),
+            "other" => Ok(UpdateSource::Other),
+            _ => Err(form_value),
+        }
+    }
+
+    fn default() -> Option<Self> {
+        Some(UpdateSource::Other)
+    }
+}
+
+impl<'a> FromParam<'a> for UserFlagKind {
+    type Error = String;
+
+    fn from_param(param: &'a RawStr) -> Result<Self, String> {
+        param.as_str().parse()
+    }
+}
+
+impl<'v> FromFormValue<'v> for UpdateBias {
+    type Error = &'v RawStr;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<Self, Self::Error> {
+        match form_value.as_str() {
+            "before" => Ok(UpdateBias::Before),
+            "after" => Ok(UpdateBias::After),
+            "nearest" => Ok(UpdateBias::Nearest),
+            _ => Err(form_value),
+        }
+    }
+
+    fn default() -> Option<Self> {
+        Some(UpdateBias::Nearest)
+    }
+}
+
+/// Query parameters for the `/diff/.../between` route
+#[derive(FromForm)]
+pub struct DiffBetweenParams {
+    /// Which update each timestamp resolves to: `before`, `after`, or `nearest` (the default)
+    pub bias: UpdateBias,
+}
+
+/// Query parameters for routes that record updates
+#[derive(FromForm)]
+pub struct SourceParams {
+    pub source: UpdateSource,
+}
+
+#[test]
+fn hs_limit_clamping() {
+    assert_eq!(HsLimit::from_form_value(RawStr::from_str("10")), Ok(HsLimit(10)));
+    assert_eq!(HsLimit::from_form_value(RawStr::from_str("100")), Ok(HsLimit(100)));
+    assert_eq!(HsLimit::from_form_value(RawStr::from_str("101")), Ok(HsLimit(101)));
+    assert_eq!(HsLimit::from_form_value(RawStr::from_str("99999")), Ok(HsLimit(MAX_DEEP_HISCORE_LIMIT)));
+    assert!(HsLimit::from_form_value(RawStr::from_str("0")).is_err());
+    assert!(HsLimit::from_form_value(RawStr::from_str("-5")).is_err());
+    assert!(HsLimit::from_form_value(RawStr::from_str("ten")).is_err());
+}
+
+#[test]
+fn hs_limit_default() {
+    let params = HiscoreParams::from_form(&mut FormItems::from(""), false).ok().unwrap();
+    assert_eq!(params.hs_limit(), MAX_HISCORE_LIMIT as u16);
+
+    // limits above `MAX_HISCORE_LIMIT` are only honored with `?deep=true`
+    let params = HiscoreParams::from_form(&mut FormItems::from("hs_limit=500"), false).ok().unwrap();
+    assert_eq!(params.hs_limit(), MAX_HISCORE_LIMIT as u16);
+    let params = HiscoreParams::from_form(&mut FormItems::from("hs_limit=150&deep=true"), false).ok().unwrap();
+    assert_eq!(params.hs_limit(), 150);
+    assert!(params.diff_options().track_dropped);
+
+    assert!(HiscoreParams::from_form(&mut FormItems::from("hs_limit=0"), false).is_err());
+}
+
+#[test]
+fn username_validation() {
+    let username = Username::from_param(RawStr::from_str("%5B%20Frost%20%5D")).unwrap();
+    assert_eq!(username.as_str(), "[ Frost ]");
+    assert_eq!(username.normalized(), "[ frost ]");
+
+    assert_eq!(Username::parse("o_o").unwrap().as_str(), "o_o");
+    assert_eq!(Username::parse("  Ameo ").unwrap().as_str(), "Ameo");
+    assert_eq!(Username::parse("Some-Guy").unwrap().normalized(), "some-guy");
+
+    assert!(Username::parse("a").is_err());
+    assert!(Username::parse("   a   ").is_err());
+    assert!(Username::parse("abcdefghijklmnop").is_err());
+    assert!(Username::parse("ameo'; DROP TABLE").is_err());
+    assert!(Username::parse("ameo&k=123").is_err());
+    assert!(Username::parse("日本語ユーザー").is_err());
+}
+
+#[test]
+fn update_source_parsing() {
+    let parse = |query: &str| SourceParams::from_form(&mut FormItems::from(query), false).ok().map(|p| p.source);
+    assert_eq!(parse(""), Some(UpdateSource::Other));
+    assert_eq!(parse("source=bot"), Some(UpdateSource::Bot));
+    assert_eq!(parse("hs_limit=10&source=scheduler"), Some(UpdateSource::Scheduler));
+    assert_eq!(parse("source=discord"), None);
+}
+
+#[test]
+fn event_days_validation() {
+    assert_eq!(EventDays::from_form_value(RawStr::from_str("1")), Ok(EventDays(1)));
+    assert_eq!(EventDays::from_form_value(RawStr::from_str("31")), Ok(EventDays(31)));
+    assert!(EventDays::from_form_value(RawStr::from_str("0")).is_err());
+    assert!(EventDays::from_form_value(RawStr::from_str("32")).is_err());
+    assert!(EventDays::from_form_value(RawStr::from_str("week")).is_err());
+    assert_eq!(PpGainDays::from_form_value(RawStr::from_str("30")), Ok(PpGainDays(30)));
+    assert_eq!(PpGainDays::default(), Some(PpGainDays(DEFAULT_PP_GAIN_DAYS)));
+    assert!(PpGainDays::from_form_value(RawStr::from_str("0")).is_err());
+    assert!(PpGainDays::from_form_value(RawStr::from_str("366")).is_err());
+}
+
+#[test]
+fn live_stats_record_param() {
+    let parse = |query: &str| LiveStatsParams::from_form(&mut FormItems::from(query), false).ok().unwrap();
+    assert_eq!((parse("").record(true), parse("").record(false)), (true, false));
+    assert!(!parse("record=false").record(true));
+    assert!(parse("event_days=5&record=true").record(false));
+    assert!(LiveStatsParams::from_form(&mut FormItems::from("record=maybe"), false).is_err());
+}
+
+#[test]
+fn unix_time_param() {
+    let parse = |param: &str| UnixTime::from_param(RawStr::from_str(param)).map(|time| time.0.timestamp());
+    assert_eq!(parse("1500000000"), Ok(1500000000));
+    assert!(parse("2017-07-14").is_err());
+    assert!(parse("1.5").is_err());
+    assert!(parse("").is_err());
+}
+
+#[test]
+fn mode_param() {
+    let parse = |param: &str| Mode::from_param(RawStr::from_str(param)).map(|mode| mode.0);
+    for &mode in ENABLED_MODES {
+        assert_eq!(parse(&mode.to_string()), Ok(mode));
+    }
+    assert!(parse("4").unwrap_err().starts_with("Invalid mode"));
+    assert_eq!(parse("taiko"), Err(String::from("Invalid mode: taiko")));
+    assert!(parse("-1").is_err());
+}
+
+#[test]
+fn sig_file_param() {
+    let parse = |param: &str| SigFile::from_param(RawStr::from_str(param)).map(|file| (file.0).0);
+    assert_eq!(parse("0.png"), Ok(0));
+    assert_eq!(parse("3.png"), Ok(3));
+    assert!(parse("0").is_err());
+    assert!(parse("4.png").unwrap_err().starts_with("Invalid mode"));
+    assert!(parse(".png").is_err());
+}
+
+#[test]
+fn date_range_parsing() {
+    let parse = |query: &str| DateRangeParams::from_form(&mut FormItems::from(query), false).ok()
+        .map(|p| (p.from.0.map(|t| t.0.timestamp()), p.to.0.map(|t| t.0.timestamp())));
+    assert_eq!(parse(""), Some((None, None)));
+    assert_eq!(parse("from=1500000000"), Some((Some(1500000000), None)));
+    assert_eq!(parse("from=1500000000&to=1600000000"), Some((Some(1500000000), Some(1600000000))));
+    assert_eq!(parse("to=yesterday"), None);
+}
+
+#[test]
+fn beatmap_id_validation() {
+    assert_eq!(parse_beatmap_ids("[1031604, 129891]"), Ok(vec![1031604, 129891]));
+    assert_eq!(parse_beatmap_ids("[]"), Ok(Vec::new()));
+    assert!(parse_beatmap_ids("[1031604, -5]").is_err());
+    assert!(parse_beatmap_ids("[0]").is_err());
+    assert!(parse_beatmap_ids("[99999999999]").is_err());
+    assert!(parse_beatmap_ids("1031604").is_err());
+
+    let too_many: Vec<i32> = (1..(MAX_BEATMAP_IDS as i32 + 2)).collect();
+    assert!(parse_beatmap_ids(&serde_json::to_string(&too_many).unwrap()).is_err());
+    let max: Vec<i32> = (1..(MAX_BEATMAP_IDS as i32 + 1)).collect();
+    assert_eq!(parse_beatmap_ids(&serde_json::to_string(&max).unwrap()), Ok(max));
+}
+
+#[test]
+fn full_newhs_disables_first_update_limit() {
+    let params = HiscoreParams::from_form(&mut FormItems::from(""), false).ok().unwrap();
+    assert_eq!(params.diff_options().first_update_limit, Some(FIRST_UPDATE_NEWHS_LIMIT));
+
+    let params = HiscoreParams::from_form(&mut FormItems::from("full_newhs=true"), false).ok().unwrap();
+    assert_eq!(params.diff_options().first_update_limit, None);
+}
+
+#[test]
+fn json_body_parsing() {
+    use std::io::Cursor;
+
+    let parse = |body: &str, limit: usize| parse_json_body::<Vec<i32>, _>(Cursor::new(body.as_bytes()), limit);
+    assert_eq!(parse("[1, 2, 3]", 64), Ok(vec![1, 2, 3]));
+    // bodies of exactly the limit are accepted
+    assert_eq!(parse("[1,2]", 5), Ok(vec![1, 2]));
+
+    let oversized = parse("[1,2,3]", 5).unwrap_err();
+    assert_eq!(oversized, "Request body exceeds the maximum size of 5 bytes");
+
+    let malformed = parse("[1, 2", 64).unwrap_err();
+    assert!(malformed.starts_with("Invalid JSON body: "));
+    let wrong_shape = parse("{\"a\": 1}", 64).unwrap_err();
+    assert!(wrong_shape.starts_with("Invalid JSON body: "));
+}
+
+#[test]
+fn stats_batch_body_parsing() {
+    use std::io::Cursor;
+
+    let parse = |body: &str| parse_json_body::<StatsBatchRequest, _>(Cursor::new(body.as_bytes()), 1024);
+    let request = parse("{\"usernames\": [\"Ameo\", \"Cookiezi\"], \"mode\": 3}").ok().unwrap();
+    assert_eq!((request.usernames, request.mode), (vec![String::from("Ameo"), String::from("Cookiezi")], 3));
+    // the mode is required
+    assert!(parse("{\"usernames\": [\"Ameo\"]}").is_err());
+}
+
+#[test]
+fn grade_filter_parsing() {
+    let parse = |query: &str| HiscoreListParams::from_form(&mut FormItems::from(query), false).ok();
+    assert_eq!(parse("").unwrap().grade, OptionalParam(None));
+    assert_eq!(parse("grade=SH").unwrap().grade, OptionalParam(Some(GradeParam(Grade::SH))));
+    assert_eq!(parse("grade=ss&exact_grade=true").unwrap().grade, OptionalParam(Some(GradeParam(Grade::X))));
+    assert!(parse("grade=Z").is_none());
+}
+
+#[test]
+fn precision_parsing() {
+    let parse = |query: &str| PrecisionParams::from_form(&mut FormItems::from(query), false).ok().map(|p| p.decimals());
+    assert_eq!(parse(""), Some(None));
+    assert_eq!(parse("precision=0"), Some(Some(0)));
+    assert_eq!(parse("precision=2"), Some(Some(2)));
+    assert_eq!(parse("precision=9"), None);
+    assert_eq!(parse("precision=-1"), None);
+    assert_eq!(parse("precision=two"), None);
+}
+
+#[test]
+fn recent_hiscores_limit() {
+    // `/hiscores/recent` parses the same query string into both its own params and its `PageParams`
+    let parse = |query: &str| {
+        let params = RecentHiscoresParams::from_form(&mut FormItems::from(query), false).ok();
+        let page = PageParams::from_form(&mut FormItems::from(query), false).ok();
+        params.and_then(|params| page.map(|page| {
+            (params.mode.0, page.limit(DEFAULT_RECENT_HISCORES, MAX_RECENT_HISCORES), page.offset())
+        }))
+    };
+    assert_eq!(parse(""), Some((None, DEFAULT_RECENT_HISCORES, 0)));
+    assert_eq!(parse("mode=1&limit=10&offset=20"), Some((Some(1), 10, 20)));
+    assert_eq!(parse("limit=5000&beatmaps=true"), Some((None, MAX_RECENT_HISCORES, 0)));
+}
+
+#[test]
+fn page_params() {
+    let parse = |query: &str| PageParams::from_form(&mut FormItems::from(query), false).ok()
+        .map(|p| (p.limit(DEFAULT_CACHED_BEATMAPS, MAX_CACHED_BEATMAPS), p.offset()));
+    assert_eq!(parse(""), Some((DEFAULT_CACHED_BEATMAPS, 0)));
+    assert_eq!(parse("limit=20&offset=40"), Some((20, 40)));
+    assert_eq!(parse("limit=100000"), Some((MAX_CACHED_BEATMAPS, 0)));
+    assert!(parse("offset=-1").is_none());
+
+    assert_eq!(next_page_offset(20, 40, 20), Some(60));
+    assert_eq!(next_page_offset(20, 40, 7), None);
+    assert_eq!(next_page_offset(0, 0, 0), None);
+}
+
+#[test]
+fn update_list_ordering() {
+    let parse = |query: &str| UpdateListParams::from_form(&mut FormItems::from(query), false).ok();
+    assert_eq!(parse("").map(|p| (p.descending(), p.limit.0)), Some((false, None)));
+    assert_eq!(parse("order=asc").map(|p| p.descending()), Some(false));
+    assert_eq!(parse("order=desc&limit=5").map(|p| (p.descending(), p.limit.0)), Some((true, Some(5))));
+    assert!(parse("order=newest").is_none());
+}
+
+#[test]
+fn iso_time_param() {
+    let parse = |raw: &str| IsoTime::from_param(RawStr::from_str(raw)).map(|time| time.0);
+    let expected = NaiveDate::from_ymd(2017, 12, 1).and_hms(12, 30, 0);
+    assert_eq!(parse("2017-12-01T12:30:00Z"), Ok(expected));
+    assert_eq!(parse("2017-12-01T14:30:00%2B02:00"), Ok(expected));
+    assert_eq!(parse("2017-12-01T12:30:00"), Ok(expected));
+    assert_eq!(parse("2017-12-01"), Ok(NaiveDate::from_ymd(2017, 12, 1).and_hms(0, 0, 0)));
+    assert!(parse("1512131400").is_err());
+    assert!(parse("yesterday").is_err());
+
+    let bias = |query: &str| DiffBetweenParams::from_form(&mut FormItems::from(query), false).ok().map(|p| p.bias);
+    assert_eq!(bias(""), Some(UpdateBias::Nearest));
+    assert_eq!(bias("bias=before"), Some(UpdateBias::Before));
+    assert!(bias("bias=closest").is_none());
+}
@@ -1,321 +1,1422 @@
 //! Maps the API endpoints to functions
 
-use std::collections::HashMap;
+use std::cmp::{self, Ordering};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 
-use chrono::NaiveDateTime;
+use chrono::{Duration, NaiveDate, NaiveDateTime};
 use diesel;
 use diesel::prelude::*;
 use diesel::BelongingToDsl;
+use diesel::mysql::MysqlConnection;
 use rocket::State;
+use rocket::http::{ContentType, RawStr};
+use rocket::response::Stream;
+use rocket::response::content::Content;
 use rocket_contrib::Json;
-use serde_json;
 
 use super::DbPool;
-use helpers::{debug, get_user_from_username, get_last_update};
-use models::{Beatmap, Update, NewUpdate, Hiscore, NewHiscore, User};
-use osu_api::ApiClient;
+use cache::{CachePolicy, CachedJson, WithCachePolicy};
+use clock::Clock;
+use compression::Compressed;
+use error::ApiError;
+use export::{CsvExportStream, ExportStream};
+use helpers::{
+    debug, ensure_user, get_cached_beatmaps, get_user, get_user_from_username, get_last_update, get_latest_updates,
+    get_recent_hiscores, get_top_hiscores_by_mode, get_tracked_modes, is_tracking_enabled, record_update,
+    find_first_update_after, find_update_near, load_hiscores_as_of, load_updates, load_updates_around,
+    find_last_update_with_different_pp, get_difficulty_buckets, get_uncached_hiscore_beatmaps, load_rank_history,
+    mark_dropped_hiscores, DifficultyBucket, UpdateBias,
+};
+use helpers::accuracy;
+use helpers::api_usage::ApiUsageSummary;
+use helpers::daily_stats::load_daily_stats;
+use helpers::db_metrics::DbTimer;
+use helpers::hiscore_history::{load_hiscore_history_data, reconstruct_hiscore_history, HiscoreHistoryPoint};
+use helpers::leaderboard::{get_pp_gain_rank, window_start, PpGainRank};
+use helpers::lru::CacheStats;
+use helpers::modes::{check_mode, STANDARD};
+use helpers::online_users::load_online_users;
+use helpers::pp_weighting::{current_positions, enrich_hiscore, pp_weight, HiscoreEnrichment};
+use helpers::rank_pp;
+use helpers::sampling::downsample;
+use helpers::stats_math;
+use helpers::user_flags::{has_user_flag, load_user_flags, UserFlagKind};
+use models::{Beatmap, DailyStats, Update, NewUpdate, Hiscore, NewHiscore, OnlineUsers, StatsLite, User, UserFlag};
+use osutrack_types::{Mods, SCHEMA_VERSION};
+use osu_api::{ApiClient, UserStats, DEFAULT_EVENT_DAYS};
+use params::{
+    next_page_offset, parse_beatmap_ids, BeatmapParams, DateRangeParams, DiffBetweenParams, ForceParams, GradeParam,
+    HiscoreListParams, HiscoreParams, HiscoresAllParams, IsoTime, JsonBody, LiveStatsParams, Mode, PageParams,
+    PpGainParams, PrecisionParams, Query, RankToPpParams, RecentHiscoresParams, SafeIntegerParams, SigFile,
+    SourceParams, StatsBatchRequest, StatsParams, UnixTime, UpdateListParams, UpdateSource, Username,
+    DEFAULT_RECENT_HISCORES, MAX_RECENT_HISCORES,
+};
+use safe_json::SafeJson;
+use secret::{
+    BEATMAP_CACHE_MAX_AGE_SECS, ENABLED_MODES, FEATURED_BEATMAPS, PENDING_BEATMAP_CACHE_MAX_AGE_SECS,
+    SIG_CACHE_MAX_AGE_SECS, SIG_PLACEHOLDER_MAX_AGE_SECS,
+};
+use sig::{render_placeholder, render_sig, SigStats};
 use schema::updates::dsl as updates_dsl;
 use schema::hiscores::dsl as hiscores_dsl;
+#[cfg(test)]
+use test_support::{test_stored_update, test_update};
+pub use osutrack_types::diff::{DiffHiscore, DiffOptions, PreviousScore, UpdateDiff};
 
-/// Holds the changes between two updates
+/// A stored hiscore along with the play's position on the beatmap's global leaderboard.  `global_rank` is omitted from
+/// the serialized output if it wasn't looked up and is `null` if the play isn't on the leaderboard.
 #[derive(Serialize)]
-pub struct UpdateDiff {
-    pub first_update: bool,
-    pub count300: i32,
-    pub count100: i32,
-    pub count50: i32,
-    pub playcount: i32,
-    pub ranked_score: i64,
-    pub total_score: i64,
-    pub pp_rank: i32,
-    pub level: f32,
-    pub pp_raw: f32,
-    pub accuracy: f32,
-    pub count_rank_ss: i32,
-    pub count_rank_s: i32,
-    pub count_rank_a: i32,
-    pub pp_country_rank: i32,
-    pub newhs: Vec<NewHiscore>,
-}
-
-impl UpdateDiff {
-    /// Given two different updates, returns a new `UpdateDiff` representing the difference between them.  If the first
-    /// update doesn't exist, then the first update will be treated as containing all zeros.
-    pub fn diff(prev: Option<&Update>, cur: &NewUpdate, old_hs: Vec<Hiscore>, new_hs: Vec<NewHiscore>) -> UpdateDiff {
-        match prev {
-            Some(prev) => {
-                // find hiscores that are in the new hiscores but not the old hiscores
-                let hs_diff: Vec<NewHiscore> = new_hs.into_iter().filter_map(|cur_hs| -> Option<NewHiscore> {
-                    let mut is_duplicate = false;
-                    for old_hs in &old_hs {
-                        if old_hs.beatmap_id == cur_hs.beatmap_id && old_hs.score == cur_hs.score {
-                            is_duplicate = true;
-                            break;
-                        }
-                    }
-
-                    if is_duplicate { None } else { Some(cur_hs) }
-                }).collect();
-
-                UpdateDiff {
-                    first_update: false,
-                    count300: cur.count300 - prev.count300,
-                    count100: cur.count100 - prev.count100,
-                    count50: cur.count50 - prev.count50,
-                    playcount: cur.playcount - prev.playcount,
-                    ranked_score: cur.ranked_score - prev.ranked_score,
-                    total_score: cur.total_score - prev.total_score,
-                    pp_rank: cur.pp_rank - prev.pp_rank,
-                    level: cur.level - prev.level,
-                    pp_raw: cur.pp_raw - prev.pp_raw,
-                    accuracy: cur.accuracy - prev.accuracy,
-                    count_rank_ss: cur.count_rank_ss - prev.count_rank_ss,
-                    count_rank_s: cur.count_rank_s - prev.count_rank_s,
-                    count_rank_a: cur.count_rank_a - prev.count_rank_a,
-                    pp_country_rank: cur.pp_country_rank - prev.pp_country_rank,
-                    newhs: hs_diff,
-                }
-            },
-            None => UpdateDiff {
-                first_update: true,
-                count300: cur.count300,
-                count100: cur.count100,
-                count50: cur.count50,
-                playcount: cur.playcount,
-                ranked_score: cur.ranked_score,
-                total_score: cur.total_score,
-                pp_rank: cur.pp_rank,
-                level: cur.level,
-                pp_raw: cur.pp_raw,
-                accuracy: cur.accuracy,
-                count_rank_ss: cur.count_rank_ss,
-                count_rank_s: cur.count_rank_s,
-                count_rank_a: cur.count_rank_a,
-                pp_country_rank: cur.pp_country_rank,
-                newhs: new_hs
-            }
-        }
+pub struct DetailedHiscore {
+    #[serde(flatten)]
+    pub hiscore: Hiscore,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub global_rank: Option<Option<u32>>,
+    /// The accuracy of the play computed from its hit counts, or `null` if they weren't recorded
+    pub accuracy: Option<f64>,
+    /// Only included if `?enrich=true` was supplied
+    #[serde(flatten)]
+    pub enrichment: Option<HiscoreEnrichment>,
+}
+
+/// The maximum number of global leaderboard ranks that will be looked up in a single request to `/hiscores`, since each
+/// one requires a request to the osu! API.
+const MAX_GLOBAL_RANK_LOOKUPS: usize = 10;
+
+/// Fetches a user's current hiscores from the osu! API along with whether there were more of them than were fetched
+fn fetch_user_best(
+    client: &ApiClient, user_id: i32, mode: u8, params: &HiscoreParams
+) -> Result<(Vec<NewHiscore>, bool), String> {
+    Ok(match client.get_user_best(user_id, mode, params.hs_limit())? {
+        Some(best) => (best.hiscores, best.truncated),
+        None => (Vec::new(), false),
+    })
+}
+
+/// Computes the diff between a user's current stats and `prev`, treating any of their current hiscores (as returned by
+/// `fetch_user_best`) that aren't stored in the database as new.
+fn diff_against_update(
+    db_conn: &MysqlConnection, stats: &NewUpdate, prev: Option<&Update>, mode: u8, params: &HiscoreParams,
+    cur_best: (Vec<NewHiscore>, bool),
+) -> Result<UpdateDiff, String> {
+    let (cur_hiscores, truncated) = cur_best;
+    // look up the user's previous hiscores
+    let old_hiscores: Vec<Hiscore> = hiscores_dsl::hiscores
+        .filter(hiscores_dsl::user_id.eq(stats.user_id))
+        .filter(hiscores_dsl::mode.eq(mode as i16))
+        .load::<Hiscore>(db_conn)
+        .map_err(debug)?;
+
+    let mut diff = UpdateDiff::diff(prev, stats, old_hiscores, cur_hiscores, &params.diff_options());
+    diff.hiscores_truncated = truncated;
+    allow_flagged_resets(db_conn, stats.user_id, &mut diff)?;
+    Ok(diff)
+}
+
+/// Clears the diff's `unexpected_decreases` if the user is flagged `allow_resets`, since their stats are expected to go
+/// backwards and shouldn't be reported as a possible score reset.
+fn allow_flagged_resets(db_conn: &MysqlConnection, user_id: i32, diff: &mut UpdateDiff) -> Result<(), String> {
+    if !diff.unexpected_decreases.is_empty() && has_user_flag(db_conn, user_id, UserFlagKind::AllowResets)? {
+        diff.unexpected_decreases.clear();
     }
+    Ok(())
+}
+
+/// Computes the diff between a user's current stats and one of their stored updates.  Only hiscores recorded at or
+/// before the time of the stored update are treated as old, so everything recorded since then is reported as new.
+fn diff_against_stored_update(
+    db_conn: &MysqlConnection, stats: &NewUpdate, target: &Update, cur_hiscores: Vec<NewHiscore>, opts: &DiffOptions,
+) -> Result<UpdateDiff, String> {
+    let old_hiscores: Vec<Hiscore> = hiscores_dsl::hiscores
+        .filter(hiscores_dsl::user_id.eq(target.user_id))
+        .filter(hiscores_dsl::mode.eq(target.mode))
+        .filter(hiscores_dsl::time_recorded.le(target.update_time))
+        .load(db_conn)
+        .map_err(debug)?;
+
+    Ok(UpdateDiff::diff(Some(target), stats, old_hiscores, cur_hiscores, opts))
 }
 
-/// Updates a user's stats using the osu! API and returns the changes since the last recorded update.
+/// Sets the beatmaps of the diff's new hiscores, fetching the ones that aren't in the beatmap cache from the osu! API
+/// and inserting them into it.  Hiscores on beatmaps that the osu! API doesn't know about are given a `null` beatmap.
+/// This runs after the update has been recorded, so failures are only logged: if the beatmaps can't be fetched, the
+/// hiscores on them are given a `null` beatmap as well and the beatmaps are prefetched in the background instead.
+fn attach_beatmaps(client: &ApiClient, db_pool: &DbPool, db_timer: &DbTimer, diff: &mut UpdateDiff, mode: u8) {
+    let ids: Vec<i32> = diff.newhs.iter().map(|hs| hs.hiscore.beatmap_id).collect();
+    let cached = get_cached_beatmaps(&*db_pool.get_timed_conn(db_timer), &ids, mode);
+    let mut beatmaps = cached.unwrap_or_else(|err| {
+        error!("Unable to load the cached beatmaps of new hiscores: {}", err);
+        HashMap::new()
+    });
+    let mut uncached_ids: Vec<i32> = ids.into_iter().filter(|id| !beatmaps.contains_key(id)).collect();
+    uncached_ids.sort();
+    uncached_ids.dedup();
+
+    match client.get_beatmaps_bulk(&uncached_ids, mode) {
+        Ok((fetched, _)) => beatmaps.extend(fetched.into_iter().map(|beatmap| (beatmap.beatmap_id, beatmap))),
+        Err(err) => {
+            warn!("Unable to fetch the beatmaps of new hiscores; prefetching them instead: {}", err);
+            client.prefetch_beatmaps(uncached_ids, mode);
+        },
+    }
+    diff.set_beatmaps(&beatmaps);
+}
+
+/// Makes sure that `update` is one of a user's updates in `mode`.  Updates that don't exist or belong to other users
+/// are reported as not found, while updates of the user in a different mode are rejected as bad input.
+fn check_update_target(update: Option<Update>, update_id: i32, user_id: i32, mode: u8) -> Result<Update, ApiError> {
+    match update {
+        Some(ref update) if update.user_id == user_id && update.mode != mode as i16 => Err(ApiError::BadInput(
+            format!("Update {} is from mode {} rather than mode {}", update_id, update.mode, mode)
+        )),
+        Some(update) if update.user_id == user_id => Ok(update),
+        _ => Err(ApiError::NotFound(format!("No update with id {} found for the user", update_id))),
+    }
+}
+
+/// Updates a user's stats using the osu! API and returns the changes since the last recorded update.  Accepts an
+/// optional `?hs_limit=<n>` query parameter controlling how many of the user's top plays are checked for new hiscores
+/// and an optional `?recent_only=true` parameter that only reports hiscores set since the last update as new.  Clients
+/// should identify themselves with `?source=web|bot|scheduler|other`, which is stored along with the update.  Passing
+/// `?safe_integers=true` serializes `ranked_score` and `total_score` as strings.  `hs_limit` values above 100 are only
+/// accepted with `?deep=true`; the osu! API can't return more than 100 plays, so `hiscores_truncated` is set in the
+/// diff if the user has at least that many.  `?only_changed=true` leaves the stats that didn't change out of the diff.
+/// Stats that went down aren't reported in `unexpected_decreases` for users flagged `allow_resets`.  The user's row as
+/// it is after the update is included in the diff as `user`.  Outside of standard, the hit counts are also included
+/// under the mode's names for them, such as `fruits` in catch; see `COUNT_ALIASES`.  `?include_beatmaps=true` includes
+/// the beatmap of each new hiscore as `beatmap`, which is `null` for beatmaps that the osu! API doesn't know about or
+/// that couldn't be fetched, so that clients can display new plays without looking them up.  Responds with a 403 for
+/// users who have opted out of tracking and for requests from the scheduler for users flagged `do_not_autoupdate`.
 #[get("/update/<username>/<mode>")]
 pub fn update(
-    api_client: State<ApiClient>, db_pool: State<DbPool>, username: String, mode: u8
-) -> Result<Option<Json<UpdateDiff>>, String> {
+    api_client: State<ApiClient>, db_pool: State<DbPool>, clock: State<Arc<Clock>>, username: Result<Username, String>,
+    mode: Result<Mode, String>, params: Query<HiscoreParams>, source_params: Query<SourceParams>,
+    safe_params: Query<SafeIntegerParams>, beatmap_params: Query<BeatmapParams>,
+) -> Result<Option<SafeJson<UpdateDiff>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let mode = mode.map_err(ApiError::BadInput)?.0;
     let client = api_client.inner();
-    let db_conn = &*db_pool.get_conn();
+    // connections are only held between requests to the osu! API so that waiting on it isn't timed
+    let db_timer = db_pool.time_route("update");
 
-    let stats = client.get_stats(&username, mode)?;
+    // checked before fetching the user's stats so that skipped updates don't use up requests to the osu! API
+    if skips_scheduled_update(&*db_pool.get_timed_conn(&db_timer), &username, source_params.source)? {
+        return Err(ApiError::Forbidden(format!(
+            "{} is flagged `do_not_autoupdate`, so updates from the scheduler are skipped", username.as_str()
+        )));
+    }
+
+    // updates are always made against fresh stats
+    let stats = client.get_stats(username.as_str(), mode, DEFAULT_EVENT_DAYS, true, true)?;
     match stats {
         None => { return Ok(None); },
-        Some(s) => {
-            let last_update: Option<Update> = get_last_update(s.user_id, mode, db_conn)?;
-
-            // if there was a change worth recording between the two updates, write it to the database
-            let needs_insert = if last_update.is_some() {
-                let first = last_update.as_ref().unwrap();
-                first.pp_rank != s.pp_rank ||
-                    s.playcount != s.playcount ||
-                    s.pp_country_rank != s.pp_country_rank
-            } else {
-                true
+        Some(UserStats { username, stats: mut s }) => {
+            s.source = Some(String::from(source_params.source.as_str()));
+            let (last_update, stored_update_id) = {
+                let db_conn = &*db_pool.get_timed_conn(&db_timer);
+                if !is_tracking_enabled(db_conn, s.user_id)? {
+                    return Err(ApiError::Forbidden(format!(
+                        "Tracking has been disabled for {}; their stored data can still be viewed", username
+                    )));
+                }
+                ensure_user(db_conn, s.user_id, &username)?;
+                let last_update: Option<Update> = get_last_update(s.user_id, mode, db_conn)?;
+
+                // if there was a change worth recording between the two updates, write it to the database
+                let stored_update_id = record_update(db_conn, &**clock, &s, last_update.as_ref())?;
+                (last_update, stored_update_id)
             };
 
-            if needs_insert {
-                diesel::insert_into(updates_dsl::updates)
-                    .values(&s)
+            let cur_best = fetch_user_best(client, s.user_id, mode, &params)?;
+            let (mut diff, new_hiscores) = {
+                let db_conn = &*db_pool.get_timed_conn(&db_timer);
+                // calculate the diff between the last and current updates
+                let mut diff = diff_against_update(db_conn, &s, last_update.as_ref(), mode, &params, cur_best)?;
+                diff.stored_update_id = stored_update_id;
+                diff.user = Some(get_user(db_conn, s.user_id)?);
+
+                // insert all new hiscores into the database, including the ones that weren't reported as new
+                let new_hiscores: Vec<NewHiscore> = diff.newhs.iter()
+                    .map(|hs| hs.hiscore.clone())
+                    .chain(diff.stale_hs.iter().cloned())
+                    .collect();
+                diesel::insert_into(hiscores_dsl::hiscores)
+                    .values(&new_hiscores)
                     .execute(db_conn)
                     .map_err(debug)?;
+
+                // flag the hiscores that were pushed out of the user's top plays and unflag any that are back in them
+                let dropped_ids: Vec<i32> = diff.dropped_hs.iter().map(|hs| hs.id).collect();
+                mark_dropped_hiscores(db_conn, &**clock, &dropped_ids, &diff.reappeared_hs)?;
+                (diff, new_hiscores)
+            };
+
+            // fetch the beatmaps of the new hiscores into the beatmap cache in the background, waiting on the ones
+            // reported in the diff if they were requested
+            if beatmap_params.include_beatmaps {
+                attach_beatmaps(client, &*db_pool, &db_timer, &mut diff, mode);
+                client.prefetch_beatmaps(diff.stale_hs.iter().map(|hs| hs.beatmap_id).collect(), mode);
+            } else {
+                client.prefetch_beatmaps(new_hiscores.iter().map(|hs| hs.beatmap_id).collect(), mode);
             }
 
-            // look up the user's previous hiscores
-            let old_hiscores: Vec<Hiscore> = hiscores_dsl::hiscores
-                .filter(hiscores_dsl::user_id.eq(s.user_id))
+            // calculate the difference between the current stats and the last update (if it exists) and return them
+            Ok(Some(SafeJson::new(diff, safe_params.safe_integers).with_only_changed(params.only_changed)
+                .with_count_aliases(mode)))
+        }
+    }
+}
+
+/// Returns `true` if the update is requested by the scheduler for a tracked user flagged `do_not_autoupdate`
+fn skips_scheduled_update(
+    db_conn: &MysqlConnection, username: &Username, source: UpdateSource
+) -> Result<bool, String> {
+    if source != UpdateSource::Scheduler {
+        return Ok(false);
+    }
+
+    match get_user_from_username(db_conn, username.normalized())? {
+        Some(usr) => has_user_flag(db_conn, usr.id, UserFlagKind::DoNotAutoupdate),
+        None => Ok(false),
+    }
+}
+
+/// Returns the changes that `/update` would report for a user without recording anything in the database, letting
+/// clients show what would change before committing an update.  This still makes the same requests to the osu! API as
+/// `/update` does, so it counts against the API rate limit in the same way.  Accepts the same parameters as `/update`
+/// along with `?force=true` to bypass the stats cache.
+#[get("/preview/<username>/<mode>")]
+pub fn preview(
+    api_client: State<ApiClient>, db_pool: State<DbPool>, username: Result<Username, String>,
+    mode: Result<Mode, String>, params: Query<HiscoreParams>, force_params: Query<ForceParams>,
+    safe_params: Query<SafeIntegerParams>,
+) -> Result<Option<SafeJson<UpdateDiff>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    let client = api_client.inner();
+    let db_timer = db_pool.time_route("preview");
+
+    let stats = match client.fetch_stats(username.as_str(), mode, force_params.force)? {
+        Some(s) => s,
+        None => { return Ok(None); },
+    };
+    let cur_best = fetch_user_best(client, stats.user_id, mode, &params)?;
+    let db_conn = &*db_pool.get_timed_conn(&db_timer);
+    let last_update: Option<Update> = get_last_update(stats.user_id, mode, db_conn)?;
+    let diff = diff_against_update(db_conn, &stats, last_update.as_ref(), mode, &params, cur_best)?;
+
+    Ok(Some(SafeJson::new(diff, safe_params.safe_integers).with_only_changed(params.only_changed)
+        .with_count_aliases(mode)))
+}
+
+/// A user's most recently stored update along with its id, which clients can compare against the `stored_update_id`
+/// returned by `/update` to tell whether an update produced a new row.
+#[derive(Serialize)]
+pub struct StatsResponse {
+    pub update_id: i32,
+    #[serde(flatten)]
+    pub update: Update,
+    /// The percentage of tracked users that the user is ranked at or above according to the latest rank/pp snapshot.
+    /// Only included with `?percentile=true`; `null` if there's no snapshot for the mode yet.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rank_percentile: Option<Option<f32>>,
+}
+
+/// The response of the `/stats` route.  Serializes as a `StatsResponse` if the user has any updates in the mode.
+/// Otherwise, it serializes as `{"tracked": true, "modes_available": [...]}` listing the modes that the user does have
+/// updates in so that clients can tell them apart from untracked users.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum StatsResult {
+    Stats(StatsResponse),
+    NotInMode { tracked: bool, modes_available: Vec<u8> },
+}
+
+/// Returns current static statistics for a user as stored in the osu!track database.  Designed to be extrememly fast and
+/// avoid the osu! server round-trip involved with getting live stats.  Returns a 404 if the user isn't tracked; see
+/// `StatsResult` for the format of the response for tracked users without any updates in the mode.  Accepts
+/// `?safe_integers=true` like `/update`.  `?percentile=true` adds the percentile of
+/// tracked users that the user's rank puts them in.  `?precision=<n>` rounds pp and accuracy to `n` decimal places.
+/// Outside of standard, the hit counts are also included under the mode's names for them; see `COUNT_ALIASES`.
+#[get("/stats/<username>/<mode>")]
+pub fn get_stats(
+    db_pool: State<DbPool>, username: Result<Username, String>, mode: Result<Mode, String>, params: Query<StatsParams>,
+    safe_params: Query<SafeIntegerParams>, precision_params: Query<PrecisionParams>,
+) -> Result<Option<SafeJson<StatsResult>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    let db_timer = db_pool.time_route("get_stats");
+    let db_conn = &*db_pool.get_timed_conn(&db_timer);
+
+    let usr: User = match get_user_from_username(db_conn, username.normalized())? {
+        Some(usr) => usr,
+        None => { return Ok(None); },
+    };
+
+    let update: Update = match Update::belonging_to(&usr)
+        .order(updates_dsl::id.desc())
+        .filter(updates_dsl::mode.eq(mode as i16))
+        .first(db_conn)
+        .optional()
+        .map_err(debug)?
+    {
+        Some(update) => update,
+        None => {
+            let res = StatsResult::NotInMode { tracked: true, modes_available: get_tracked_modes(db_conn, usr.id)? };
+            return Ok(Some(SafeJson::new(res, false)));
+        },
+    };
+
+    let rank_percentile = if params.percentile {
+        let points = rank_pp::load_latest_snapshot(db_conn, mode)?.map(|(_, points)| points).unwrap_or_else(Vec::new);
+        Some(rank_pp::rank_percentile(&points, update.pp_rank))
+    } else {
+        None
+    };
+
+    let res = StatsResponse { update_id: update.id, update: update, rank_percentile: rank_percentile };
+    let res = StatsResult::Stats(res);
+    Ok(Some(
+        SafeJson::new(res, safe_params.safe_integers)
+            .with_precision(precision_params.decimals())
+            .with_count_aliases(mode)
+    ))
+}
+
+/// How long clients may cache `/stats_lite` responses for, in seconds
+const STATS_LITE_MAX_AGE_SECS: u32 = 60;
+
+/// Loads the fields of the user's latest stored update in `mode` that are needed for profile headers
+fn load_stats_lite(db_conn: &MysqlConnection, user_id: i32, mode: u8) -> Result<Option<StatsLite>, String> {
+    updates_dsl::updates
+        .filter(updates_dsl::user_id.eq(user_id))
+        .filter(updates_dsl::mode.eq(mode as i16))
+        .order(updates_dsl::id.desc())
+        .select((
+            updates_dsl::pp_raw, updates_dsl::pp_rank, updates_dsl::pp_country_rank, updates_dsl::accuracy,
+            updates_dsl::playcount, updates_dsl::update_time,
+        ))
+        .first::<StatsLite>(db_conn)
+        .optional()
+        .map_err(debug)
+}
+
+/// Returns only the stats shown in profile headers from a user's latest stored update, which is much smaller than the
+/// full update returned by `/stats`.  Returns a 404 if the user isn't tracked or has no updates in the mode.
+#[get("/stats_lite/<username>/<mode>")]
+pub fn stats_lite(
+    db_pool: State<DbPool>, username: Result<Username, String>, mode: Result<Mode, String>,
+) -> Result<Option<CachedJson<StatsLite>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    let db_timer = db_pool.time_route("stats_lite");
+    let db_conn = &*db_pool.get_timed_conn(&db_timer);
+
+    let usr: User = match get_user_from_username(db_conn, username.normalized())? {
+        Some(usr) => usr,
+        None => { return Ok(None); },
+    };
+
+    Ok(load_stats_lite(db_conn, usr.id, mode)?.map(|stats| CachedJson::new(stats, STATS_LITE_MAX_AGE_SECS)))
+}
+
+/// The maximum number of users whose stats can be requested at once from `/stats_batch`
+const MAX_STATS_BATCH_SIZE: usize = 100;
+
+/// Loads the latest stored update in `mode` of each of the given users, keyed by the usernames as they were requested.
+/// Users that aren't tracked or have no updates in the mode are mapped to `None`.  Makes the same number of queries
+/// regardless of the number of users.
+fn load_stats_batch(
+    db_conn: &MysqlConnection, usernames: &[Username], mode: u8
+) -> Result<HashMap<String, Option<Update>>, String> {
+    use schema::users::dsl as users_dsl;
+
+    let normalized: Vec<&str> = usernames.iter().map(|username| username.normalized()).collect();
+    let users: Vec<User> = users_dsl::users
+        .filter(users_dsl::username.eq_any(&normalized))
+        .load(db_conn)
+        .map_err(debug)?;
+    let user_ids: Vec<i32> = users.iter().map(|usr| usr.id).collect();
+    let updates_by_user: HashMap<i32, Update> = get_latest_updates(db_conn, &user_ids, mode)?
+        .into_iter()
+        .map(|update| (update.user_id, update))
+        .collect();
+
+    let mut stats = HashMap::new();
+    for username in usernames {
+        // usernames are compared case-insensitively by the database
+        let usr = users.iter().find(|usr| usr.username.to_lowercase() == username.normalized());
+        let update = usr.and_then(|usr| updates_by_user.get(&usr.id)).cloned();
+        stats.insert(String::from(username.as_str()), update);
+    }
+
+    Ok(stats)
+}
+
+/// Returns the latest stored stats of several users in a mode at once, like `/stats` does for a single user.  Takes a
+/// JSON body of the form `{"usernames": [...], "mode": <n>}` with up to `MAX_STATS_BATCH_SIZE` usernames and returns an
+/// object mapping each of them to their stats.  Users that aren't tracked or have no updates in the mode are mapped to
+/// `null`.  Accepts `?safe_integers=true` and `?precision=<n>` like `/stats`.
+#[post("/stats_batch", data = "<request>")]
+pub fn stats_batch(
+    db_pool: State<DbPool>, request: Result<JsonBody<StatsBatchRequest>, String>,
+    safe_params: Query<SafeIntegerParams>, precision_params: Query<PrecisionParams>,
+) -> Result<SafeJson<HashMap<String, Option<Update>>>, ApiError> {
+    let request = request.map_err(ApiError::BadInput)?;
+    if request.usernames.len() > MAX_STATS_BATCH_SIZE {
+        return Err(ApiError::BadInput(format!(
+            "No more than {} users can be requested at once; got {}", MAX_STATS_BATCH_SIZE, request.usernames.len()
+        )));
+    }
+    let usernames = request.usernames.iter()
+        .map(|username| Username::parse(username))
+        .collect::<Result<Vec<Username>, String>>()
+        .map_err(ApiError::BadInput)?;
+
+    let mode = check_mode(request.mode, ENABLED_MODES).map_err(ApiError::BadInput)?;
+
+    let db_timer = db_pool.time_route("stats_batch");
+    let stats = load_stats_batch(&*db_pool.get_timed_conn(&db_timer), &usernames, mode)?;
+    Ok(SafeJson::new(stats, safe_params.safe_integers).with_precision(precision_params.decimals()))
+}
+
+/// Records stats fetched for `/livestats` through the same path as `/update` if `record` is set, returning the id of
+/// the new update if one was recorded.  Nothing is written to the database at all if `record` isn't set.
+fn record_live_stats(
+    db_conn: &MysqlConnection, clock: &Clock, username: &str, stats: &NewUpdate, record: bool
+) -> Result<Option<i32>, String> {
+    if !record {
+        return Ok(None);
+    }
+
+    ensure_user(db_conn, stats.user_id, username)?;
+    let last_update = get_last_update(stats.user_id, stats.mode as u8, db_conn)?;
+    // if there was a change worth recording between the two updates, write it to the database
+    record_update(db_conn, clock, stats, last_update.as_ref())
+}
+
+/// Fetches a user's live stats for the `/livestats` routes, recording them if `?record=true` was passed or if
+/// `?record=` wasn't passed at all and `record_by_default` is set.  The time spent recording them is timed as `route`.
+fn live_stats_response(
+    client: &ApiClient, db_pool: &DbPool, route: &'static str, clock: &Clock, username: Username, mode: u8,
+    params: &LiveStatsParams, force: bool, record_by_default: bool,
+) -> Result<Option<NewUpdate>, ApiError> {
+    let db_timer = db_pool.time_route(route);
+    // read-only requests don't store a raw snapshot of the response either
+    let record = params.record(record_by_default);
+    let stats = client.get_stats(username.as_str(), mode, params.event_days.0, force, record)?;
+    let UserStats { username, stats } = match stats {
+        Some(u) => u,
+        None => { return Ok(None); },
+    };
+
+    record_live_stats(&*db_pool.get_timed_conn(&db_timer), clock, &username, &stats, record)?;
+    Ok(Some(stats))
+}
+
+/// Returns the live view of a user's stats as reported by the osu! API.  Functions the same way as the `/update/` endpoint
+/// but returns the current statistics rather than the change since the last update.  Accepts an optional
+/// `?event_days=<n>` parameter (1-31) controlling how many days of recent events are requested from the osu! API and
+/// `?force=true` to bypass the stats cache.  Accepts `?safe_integers=true` and `?precision=<n>` like `/stats`.  The
+/// live stats of users who have opted out of tracking are still returned, but nothing is recorded for them.  Passing
+/// `?record=false` makes the route read-only, so it works for users that aren't tracked without starting to track them.
+#[get("/livestats/<username>/<mode>")]
+pub fn live_stats(
+    api_client: State<ApiClient>, db_pool: State<DbPool>, clock: State<Arc<Clock>>, username: Result<Username, String>,
+    mode: Result<Mode, String>, params: Query<LiveStatsParams>, force_params: Query<ForceParams>,
+    safe_params: Query<SafeIntegerParams>, precision_params: Query<PrecisionParams>,
+) -> Result<Option<SafeJson<NewUpdate>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    let stats = live_stats_response(
+        api_client.inner(), &*db_pool, "live_stats", &**clock, username, mode, &params, force_params.force, true
+    )?;
+
+    Ok(stats.map(|stats| {
+        SafeJson::new(stats, safe_params.safe_integers).with_precision(precision_params.decimals())
+    }))
+}
+
+/// The same as `/livestats` except that nothing is recorded unless `?record=true` is passed
+#[get("/v2/livestats/<username>/<mode>")]
+pub fn live_stats_v2(
+    api_client: State<ApiClient>, db_pool: State<DbPool>, clock: State<Arc<Clock>>, username: Result<Username, String>,
+    mode: Result<Mode, String>, params: Query<LiveStatsParams>, force_params: Query<ForceParams>,
+    safe_params: Query<SafeIntegerParams>, precision_params: Query<PrecisionParams>,
+) -> Result<Option<SafeJson<NewUpdate>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    let stats = live_stats_response(
+        api_client.inner(), &*db_pool, "live_stats_v2", &**clock, username, mode, &params, force_params.force, false
+    )?;
+
+    Ok(stats.map(|stats| {
+        SafeJson::new(stats, safe_params.safe_integers).with_precision(precision_params.decimals())
+    }))
+}
+
+/// The number of a user's most recent updates that `/efficiency` fits the accuracy trend to
+const ACCURACY_TREND_UPDATES: i64 = 30;
+
+/// Ratios derived from a user's most recently stored update and their recent history.  Each ratio is `null` if the user
+/// has no plays to compute it over.
+#[derive(Serialize)]
+pub struct Efficiency {
+    pub update_id: i32,
+    pub pp_per_play: Option<f64>,
+    pub ranked_score_per_play: Option<f64>,
+    pub hits_per_play: Option<f64>,
+    /// The pp gained per play in the 30 days before the latest update, measured from the last update recorded at least
+    /// 30 days before it, or `null` if there is none.  The window ends at the latest update rather than now, so it
+    /// covers the user's last 30 days of tracked activity even if they haven't been updated recently.
+    pub pp_per_play_30d: Option<f64>,
+    /// Like `pp_per_play_30d` but over the 90 days before the latest update
+    pub pp_per_play_90d: Option<f64>,
+    /// The change in accuracy per day over the user's last `ACCURACY_TREND_UPDATES` updates, or `null` if there are
+    /// fewer than two of them
+    pub accuracy_trend: Option<f64>,
+}
+
+/// Returns the pp gained per play in the `days` days before `latest`: between the last of the user's updates recorded
+/// at least `days` days before `latest` and `latest` itself
+fn pp_per_play_before_update(db_conn: &MysqlConnection, latest: &Update, days: i64) -> Result<Option<f64>, String> {
+    let start = find_update_near(
+        db_conn, latest.user_id, latest.mode as u8, latest.update_time - Duration::days(days), UpdateBias::Before
+    )?;
+    Ok(start.and_then(|start| stats_math::pp_per_play_between(&start, latest)))
+}
+
+/// Returns "pp per play" and other efficiency ratios computed from the user's most recently stored update, along with
+/// the pp gained per play in the 30 and 90 days before that update and the trend of their accuracy over their recent
+/// updates.
+#[get("/efficiency/<username>/<mode>")]
+pub fn get_efficiency(
+    db_pool: State<DbPool>, username: Result<Username, String>, mode: Result<Mode, String>
+) -> Result<Option<Json<Efficiency>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    let db_timer = db_pool.time_route("get_efficiency");
+    let db_conn = &*db_pool.get_timed_conn(&db_timer);
+
+    let usr: User = match get_user_from_username(db_conn, username.normalized())? {
+        Some(user) => user,
+        None => { return Ok(None); },
+    };
+    let recent = load_updates(db_conn, usr.id, mode, true, Some(ACCURACY_TREND_UPDATES))?;
+    let update = match recent.first() {
+        Some(update) => update,
+        None => { return Ok(None); },
+    };
+
+    Ok(Some(Json(Efficiency {
+        update_id: update.id,
+        pp_per_play: stats_math::per_play(update.pp_raw as f64, update.playcount),
+        ranked_score_per_play: stats_math::per_play(update.ranked_score as f64, update.playcount),
+        hits_per_play: stats_math::per_play(update.total_hits as f64, update.playcount),
+        pp_per_play_30d: pp_per_play_before_update(db_conn, update, 30)?,
+        pp_per_play_90d: pp_per_play_before_update(db_conn, update, 90)?,
+        accuracy_trend: stats_math::accuracy_trend(&recent),
+    })))
+}
+
+/// Aggregates of all of a user's stored updates in a mode.  `days_tracked` and `updates_per_day` are only included if
+/// the user has more than one update.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct UpdateSummary {
+    pub update_count: i64,
+    pub first_update: NaiveDateTime,
+    pub last_update: NaiveDateTime,
+    /// The number of whole days between the first and last updates
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub days_tracked: Option<i64>,
+    /// The number of updates divided by `days_tracked`, or by one if all of the updates are from the same day
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updates_per_day: Option<f64>,
+}
+
+impl UpdateSummary {
+    pub fn new(update_count: i64, first_update: NaiveDateTime, last_update: NaiveDateTime) -> UpdateSummary {
+        let (days_tracked, updates_per_day) = if update_count > 1 {
+            let days = (last_update - first_update).num_days();
+            (Some(days), Some(update_count as f64 / cmp::max(days, 1) as f64))
+        } else {
+            (None, None)
+        };
+
+        UpdateSummary {
+            update_count: update_count,
+            first_update: first_update,
+            last_update: last_update,
+            days_tracked: days_tracked,
+            updates_per_day: updates_per_day,
+        }
+    }
+}
+
+/// A user's row along with the flags that have been set on them
+#[derive(Serialize)]
+pub struct UserInfo {
+    #[serde(flatten)]
+    pub user: User,
+    pub flags: Vec<UserFlag>,
+}
+
+/// Returns a tracked user's row along with the flags that the operators have set on them, or a 404 if they aren't
+/// tracked
+#[get("/user/<username>")]
+pub fn get_user_info(
+    db_pool: State<DbPool>, username: Result<Username, String>
+) -> Result<Option<Json<UserInfo>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let db_timer = db_pool.time_route("get_user_info");
+    let db_conn = &*db_pool.get_timed_conn(&db_timer);
+
+    let usr: User = match get_user_from_username(db_conn, username.normalized())? {
+        Some(user) => user,
+        None => { return Ok(None); },
+    };
+    let flags = load_user_flags(db_conn, usr.id)?;
+
+    Ok(Some(Json(UserInfo { user: usr, flags: flags })))
+}
+
+/// Returns the number of updates that a user has in a mode along with the times of the first and last of them and how
+/// often they've been updated.  Returns a 404 if the user isn't tracked or has no updates in the mode.
+#[get("/summary/<username>/<mode>")]
+pub fn get_summary(
+    db_pool: State<DbPool>, username: Result<Username, String>, mode: Result<Mode, String>
+) -> Result<Option<Json<UpdateSummary>>, ApiError> {
+    use diesel::dsl::{count_star, max, min};
+
+    let username = username.map_err(ApiError::BadInput)?;
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    let db_timer = db_pool.time_route("get_summary");
+    let db_conn = &*db_pool.get_timed_conn(&db_timer);
+
+    let usr: User = match get_user_from_username(db_conn, username.normalized())? {
+        Some(user) => user,
+        None => { return Ok(None); },
+    };
+    let (count, first, last): (i64, Option<NaiveDateTime>, Option<NaiveDateTime>) = updates_dsl::updates
+        .filter(updates_dsl::user_id.eq(usr.id))
+        .filter(updates_dsl::mode.eq(mode as i16))
+        .select((count_star(), min(updates_dsl::update_time), max(updates_dsl::update_time)))
+        .first(db_conn)
+        .map_err(debug)?;
+
+    Ok(match (first, last) {
+        (Some(first), Some(last)) => Some(Json(UpdateSummary::new(count, first, last))),
+        _ => None,
+    })
+}
+
+/// Returns a user's daily aggregates in a mode, oldest first.  Accepts optional `?from=` and `?to=` unix timestamps;
+/// the days that they fall on are included.  Returns a 404 if the user isn't tracked.
+#[get("/daily/<username>/<mode>")]
+pub fn get_daily_stats(
+    db_pool: State<DbPool>, username: Result<Username, String>, mode: Result<Mode, String>,
+    range: Query<DateRangeParams>,
+) -> Result<Option<Json<Vec<DailyStats>>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    let db_timer = db_pool.time_route("get_daily_stats");
+    let db_conn = &*db_pool.get_timed_conn(&db_timer);
+
+    let usr: User = match get_user_from_username(db_conn, username.normalized())? {
+        Some(user) => user,
+        None => { return Ok(None); },
+    };
+    let (from, to) = (range.from.0.map(|t| t.0.date()), range.to.0.map(|t| t.0.date()));
+
+    Ok(Some(Json(load_daily_stats(db_conn, usr.id, mode, from, to)?)))
+}
+
+/// A histogram of a user's hiscores by star rating
+#[derive(Serialize)]
+pub struct HiscoreDifficulty {
+    pub buckets: Vec<DifficultyBucket>,
+    /// The ids of beatmaps that the user has hiscores on that aren't in the beatmap cache.  Hiscores on these beatmaps
+    /// aren't included in the buckets, so clients may want to fetch them through `/beatmaps` and try again.
+    pub missing_beatmap_ids: Vec<i32>,
+}
+
+/// Returns a histogram of the number of stored hiscores a user has and the average pp of them in half-star buckets
+/// based on the star ratings of their beatmaps.
+#[get("/hiscore_difficulty/<username>/<mode>")]
+pub fn get_hiscore_difficulty(
+    db_pool: State<DbPool>, username: Result<Username, String>, mode: Result<Mode, String>
+) -> Result<Option<Json<HiscoreDifficulty>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    let db_timer = db_pool.time_route("get_hiscore_difficulty");
+    let db_conn = &*db_pool.get_timed_conn(&db_timer);
+
+    let usr: User = match get_user_from_username(db_conn, username.normalized())? {
+        Some(user) => user,
+        None => { return Ok(None); },
+    };
+
+    Ok(Some(Json(HiscoreDifficulty {
+        buckets: get_difficulty_buckets(db_conn, usr.id, mode)?,
+        missing_beatmap_ids: get_uncached_hiscore_beatmaps(db_conn, usr.id, mode)?,
+    })))
+}
+
+/// A user's current hiscores with one normalized mod combination
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ModBreakdown {
+    pub mods: Mods,
+    /// The mods as their acronyms, such as "HDDT" or "NM" for nomod
+    pub acronym: String,
+    pub plays: usize,
+    /// The sum of the raw (unweighted) pp of the plays
+    pub total_pp: f32,
+    /// The sum of the pp that the plays contribute to the user's total after weighting
+    pub weighted_pp: f32,
+    /// The average accuracy of the plays whose hit counts were recorded, or `null` if none of them were
+    pub avg_accuracy: Option<f64>,
+}
+
+/// Groups a user's current hiscores by their normalized mod combination, ordered by the total pp of each group
+fn group_by_mods(mode: u8, hiscores: &[Hiscore]) -> Vec<ModBreakdown> {
+    let pps: Vec<(i32, f32)> = hiscores.iter().map(|hs| (hs.id, hs.pp)).collect();
+    let positions = current_positions(&pps);
+    let weighted = |hs: &Hiscore| positions.get(&hs.id).map(|&position| hs.pp * pp_weight(position) as f32);
+
+    let mut groups: HashMap<Mods, Vec<&Hiscore>> = HashMap::new();
+    for hs in hiscores {
+        groups.entry(Mods::from_enabled_mods(hs.enabled_mods).normalized()).or_insert_with(Vec::new).push(hs);
+    }
+
+    let mut breakdown: Vec<ModBreakdown> = groups.into_iter().map(|(mods, plays)| {
+        let accuracies: Vec<f64> = plays.iter().filter_map(|hs| accuracy::compute(mode, hs.hit_counts())).collect();
+        ModBreakdown {
+            mods: mods,
+            acronym: mods.acronym(),
+            plays: plays.len(),
+            total_pp: plays.iter().map(|hs| hs.pp).sum(),
+            weighted_pp: plays.iter().filter_map(|&hs| weighted(hs)).sum(),
+            avg_accuracy: if accuracies.is_empty() {
+                None
+            } else {
+                Some(accuracies.iter().sum::<f64>() / accuracies.len() as f64)
+            },
+        }
+    }).collect();
+    breakdown.sort_by(|a, b| b.total_pp.partial_cmp(&a.total_pp).unwrap_or(Ordering::Equal));
+
+    breakdown
+}
+
+/// Returns a user's current hiscores grouped by mod combination with the number of plays, their total and weighted pp,
+/// and their average accuracy for each.  Variants of mods are grouped with the mods that they're variants of, so
+/// nightcore plays are counted as double time and perfect plays as sudden death.
+#[get("/mod_breakdown/<username>/<mode>")]
+pub fn mod_breakdown(
+    db_pool: State<DbPool>, username: Result<Username, String>, mode: Result<Mode, String>
+) -> Result<Option<Json<Vec<ModBreakdown>>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    let db_timer = db_pool.time_route("mod_breakdown");
+    let db_conn = &*db_pool.get_timed_conn(&db_timer);
+
+    let usr: User = match get_user_from_username(db_conn, username.normalized())? {
+        Some(user) => user,
+        None => { return Ok(None); },
+    };
+
+    let hiscores: Vec<Hiscore> = hiscores_dsl::hiscores
+        .filter(hiscores_dsl::user_id.eq(usr.id))
+        .filter(hiscores_dsl::mode.eq(mode as i16))
+        .filter(hiscores_dsl::dropped_at.is_null())
+        .load(db_conn)
+        .map_err(debug)?;
+
+    Ok(Some(Json(group_by_mods(mode, &hiscores))))
+}
+
+/// The response of the `/updates` route.  Serializes as a bare array of updates if the user has any in the mode, or as
+/// `{"user_id": <id>, "updates": []}` for a tracked user without any so that clients can tell that the user exists.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum UpdatesResponse {
+    Updates(Vec<Update>),
+    NoUpdates { user_id: i32, updates: Vec<Update> },
+}
+
+impl UpdatesResponse {
+    pub fn new(user_id: i32, updates: Vec<Update>) -> UpdatesResponse {
+        if updates.is_empty() {
+            UpdatesResponse::NoUpdates { user_id: user_id, updates: updates }
+        } else {
+            UpdatesResponse::Updates(updates)
+        }
+    }
+}
+
+/// Returns all of a user's stored updates for a given gamemode, oldest first.  Accepts `?order=desc` to return them
+/// newest first and `?limit=<n>` to return only the first `n` of them in that order.  Returns a 404 if the user isn't
+/// tracked; see `UpdatesResponse` for the format of the response for tracked users without any updates in the mode.
+/// Accepts `?safe_integers=true` like `/update`.
+#[get("/updates/<username>/<mode>")]
+pub fn get_updates(
+    db_pool: State<DbPool>, username: Result<Username, String>, mode: Result<Mode, String>,
+    params: Query<UpdateListParams>, safe_params: Query<SafeIntegerParams>,
+) -> Result<Option<Compressed<SafeJson<UpdatesResponse>>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    let db_timer = db_pool.time_route("get_updates");
+    let db_conn = &*db_pool.get_timed_conn(&db_timer);
+
+    let usr: User = match get_user_from_username(db_conn, username.normalized())? {
+        Some(user) => user,
+        None => { return Ok(None); },
+    };
+
+    // pull the updates belonging to the selected user from the database for the provided gamemode
+    let updates = load_updates(db_conn, usr.id, mode, params.descending(), params.limit.0.map(|limit| limit as i64))?;
+
+    Ok(Some(Compressed(SafeJson::new(UpdatesResponse::new(usr.id, updates), safe_params.safe_integers))))
+}
+
+/// The maximum number of updates on each side of the timestamp that can be requested from `/updates/.../around`
+const MAX_SURROUNDING_UPDATES: u32 = 50;
+
+/// Returns up to `n` of a user's updates in a mode recorded at or before `timestamp`, a unix timestamp in seconds, and
+/// up to `n` recorded after it, ordered by time.  Meant for showing the context around a point on a graph without
+/// loading the user's whole history.  `n` can be at most `MAX_SURROUNDING_UPDATES`.  Returns a 404 if the user isn't
+/// tracked.  Accepts `?safe_integers=true` like `/update`.
+#[get("/updates/<username>/<mode>/around/<timestamp>/<n>")]
+pub fn get_updates_around(
+    db_pool: State<DbPool>, username: Result<Username, String>, mode: Result<Mode, String>,
+    timestamp: Result<UnixTime, String>, n: u32, safe_params: Query<SafeIntegerParams>,
+) -> Result<Option<SafeJson<Vec<Update>>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let timestamp = timestamp.map_err(ApiError::BadInput)?;
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    if n == 0 || n > MAX_SURROUNDING_UPDATES {
+        return Err(ApiError::BadInput(format!(
+            "The number of updates on each side must be between 1 and {}; got {}", MAX_SURROUNDING_UPDATES, n
+        )));
+    }
+    let db_timer = db_pool.time_route("get_updates_around");
+    let db_conn = &*db_pool.get_timed_conn(&db_timer);
+
+    let usr: User = match get_user_from_username(db_conn, username.normalized())? {
+        Some(user) => user,
+        None => { return Ok(None); },
+    };
+    let updates = load_updates_around(db_conn, usr.id, mode, timestamp.0, n as i64)?;
+
+    Ok(Some(SafeJson::new(updates, safe_params.safe_integers)))
+}
+
+/// A single point on a user's rank graph: `[unix_timestamp, pp_rank, pp_raw]`
+pub type GraphPoint = (i64, i32, f32);
+
+/// The maximum number of points returned by `/graph`
+const GRAPH_MAX_POINTS: usize = 500;
+/// The number of seconds that clients and CDNs may cache `/graph` responses for
+const GRAPH_MAX_AGE_SECS: u32 = 300;
+
+/// Converts a user's `(update_time, pp_rank, pp_raw)` history into compact graph points, downsampling it to at most
+/// `GRAPH_MAX_POINTS` points.
+fn to_graph_points(history: &[(NaiveDateTime, i32, f32)]) -> Vec<GraphPoint> {
+    downsample(history, GRAPH_MAX_POINTS)
+        .into_iter()
+        .map(|(update_time, pp_rank, pp_raw)| (update_time.timestamp(), pp_rank, pp_raw))
+        .collect()
+}
+
+/// Returns the data needed to draw a user's rank graph as a compact list of `[unix_timestamp, pp_rank, pp_raw]` points,
+/// downsampled to at most `GRAPH_MAX_POINTS` points.  Responses can be cached by clients and CDNs for a few minutes.
+#[get("/graph/<username>/<mode>")]
+pub fn get_graph(
+    db_pool: State<DbPool>, username: Result<Username, String>, mode: Result<Mode, String>
+) -> Result<Option<CachedJson<Vec<GraphPoint>>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    let db_timer = db_pool.time_route("get_graph");
+    let db_conn = &*db_pool.get_timed_conn(&db_timer);
+
+    let usr: User = match get_user_from_username(db_conn, username.normalized())? {
+        Some(user) => user,
+        None => { return Ok(None); },
+    };
+
+    let history: Vec<(NaiveDateTime, i32, f32)> = updates_dsl::updates
+        .filter(updates_dsl::user_id.eq(usr.id))
+        .filter(updates_dsl::mode.eq(mode as i16))
+        .order(updates_dsl::update_time.asc())
+        .select((updates_dsl::update_time, updates_dsl::pp_rank, updates_dsl::pp_raw))
+        .load(db_conn)
+        .map_err(debug)?;
+
+    Ok(Some(CachedJson::new(to_graph_points(&history), GRAPH_MAX_AGE_SECS)))
+}
+
+/// Returns the pp of a user's best, 50th, and 100th best plays at the time of each of their stored updates as a list of
+/// `[unix_timestamp, best_pp, 50th_pp, 100th_pp]` points, downsampled to at most `GRAPH_MAX_POINTS` points.  The plays
+/// are reconstructed from the times that the user's hiscores were recorded, and positions that the user had no play in
+/// yet are `null`.
+#[get("/hiscore_history/<username>/<mode>")]
+pub fn hiscore_history(
+    db_pool: State<DbPool>, username: Result<Username, String>, mode: Result<Mode, String>
+) -> Result<Option<CachedJson<Vec<HiscoreHistoryPoint>>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    let db_timer = db_pool.time_route("hiscore_history");
+    let db_conn = &*db_pool.get_timed_conn(&db_timer);
+
+    let usr: User = match get_user_from_username(db_conn, username.normalized())? {
+        Some(user) => user,
+        None => { return Ok(None); },
+    };
+
+    let (update_times, hiscores) = load_hiscore_history_data(db_conn, usr.id, mode)?;
+    let history = reconstruct_hiscore_history(&update_times, &hiscores);
+    Ok(Some(CachedJson::new(downsample(&history, GRAPH_MAX_POINTS), GRAPH_MAX_AGE_SECS)))
+}
+
+/// Returns the recorded number of users in the osu! IRC channel over time, downsampled to at most `GRAPH_MAX_POINTS`
+/// points.  Accepts optional `?from=` and `?to=` unix timestamps limiting the range of the history.
+#[get("/online_users")]
+pub fn online_users(
+    db_pool: State<DbPool>, range: Query<DateRangeParams>
+) -> Result<CachedJson<Vec<OnlineUsers>>, ApiError> {
+    let db_timer = db_pool.time_route("online_users");
+    let db_conn = &*db_pool.get_timed_conn(&db_timer);
+    let history = load_online_users(db_conn, range.from.0.map(|t| t.0), range.to.0.map(|t| t.0))?;
+
+    Ok(CachedJson::new(downsample(&history, GRAPH_MAX_POINTS), GRAPH_MAX_AGE_SECS))
+}
+
+/// Returns the `(update_time, pp_rank)` histories of two users in one response keyed by username so that they can be
+/// drawn on the same graph.  Accepts optional `?from=` and `?to=` unix timestamps which are applied to both histories.
+/// Responds with a 404 naming the user if either of them has no stored updates in the range.
+#[get("/compare_history/<a>/<b>/<mode>")]
+pub fn compare_history(
+    db_pool: State<DbPool>, a: Result<Username, String>, b: Result<Username, String>, mode: Result<Mode, String>,
+    range: Query<DateRangeParams>,
+) -> Result<Json<HashMap<String, Vec<(NaiveDateTime, i32)>>>, ApiError> {
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    let db_timer = db_pool.time_route("compare_history");
+    let db_conn = &*db_pool.get_timed_conn(&db_timer);
+    let (from, to) = (range.from.0.map(|t| t.0), range.to.0.map(|t| t.0));
+
+    let mut histories = HashMap::new();
+    for username in vec![a, b] {
+        let username = username.map_err(ApiError::BadInput)?;
+        let not_found = || ApiError::NotFound(format!("No rank history found for user {}", username.as_str()));
+
+        let usr: User = get_user_from_username(db_conn, username.normalized())?.ok_or_else(&not_found)?;
+        let history = load_rank_history(db_conn, usr.id, mode, from, to)?;
+        if history.is_empty() {
+            return Err(not_found());
+        }
+
+        histories.insert(String::from(username.as_str()), history);
+    }
+
+    Ok(Json(histories))
+}
+
+/// An estimate of the pp needed to reach a rank or the rank reached with an amount of pp, based on a snapshot of all
+/// tracked users taken on `snapshot_date`
+#[derive(Serialize)]
+pub struct RankPpEstimate {
+    pub mode: u8,
+    pub rank: i32,
+    pub pp: f32,
+    pub snapshot_date: NaiveDate,
+}
+
+/// Information about the running build of the backend
+#[derive(Clone, Debug, Serialize)]
+pub struct VersionInfo {
+    /// The version of the `osutrack-backend` crate
+    pub version: &'static str,
+    /// The git commit that the backend was built from, if `OSUTRACK_GIT_COMMIT` was set at build time
+    pub commit: Option<&'static str>,
+    pub schema_version: u32,
+}
+
+impl VersionInfo {
+    pub fn current() -> VersionInfo {
+        VersionInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            commit: option_env!("OSUTRACK_GIT_COMMIT"),
+            schema_version: SCHEMA_VERSION,
+        }
+    }
+}
+
+/// Returns the version of the backend, the git commit that it was built from, and the version of its schema
+#[get("/version")]
+pub fn version(version_info: State<VersionInfo>) -> Json<VersionInfo> {
+    Json(version_info.inner().clone())
+}
+
+/// How likely an endpoint is to change in ways that break its consumers
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stability {
+    /// Relied on by the osu!track website; its responses only change in backwards-compatible ways
+    Stable,
+    /// Recently added; its path or responses may still change
+    Experimental,
+    /// Meant for the operators of the backend rather than for third-party applications
+    Internal,
+}
+
+/// A description of a route served by the backend, listed by `/endpoints`
+#[derive(Debug, Serialize)]
+pub struct EndpointInfo {
+    pub method: &'static str,
+    /// The path of the route with its dynamic segments in angle brackets, such as `/stats/<username>/<mode>`
+    pub path: &'static str,
+    pub description: &'static str,
+    pub stability: Stability,
+    /// How the `CacheControl` fairing lets browsers and CDNs cache the route's responses; see `route_cache_policy`
+    #[serde(skip_serializing)]
+    pub cache_policy: Option<CachePolicy>,
+}
+
+/// Lists every endpoint served by the backend along with a short description of it and how stable it is
+#[get("/endpoints")]
+pub fn endpoints() -> Json<&'static [EndpointInfo]> {
+    Json(::ENDPOINTS)
+}
+
+/// Returns the number of requests made to the osu! API in each of the last 60 minutes along with the API's rate limit,
+/// so that operators can see how much headroom there is.  The same summary is available from `/admin/api_usage`.
+#[get("/api_usage")]
+pub fn api_usage(api_client: State<ApiClient>) -> Json<ApiUsageSummary> {
+    Json(api_client.usage().summary())
+}
+
+/// Returns the number of hits and misses of each of the caches of osu! API responses along with their maximum age so
+/// that operators can judge whether the caches are effective.
+#[get("/cache_stats")]
+pub fn cache_stats(api_client: State<ApiClient>) -> Json<HashMap<&'static str, CacheStats>> {
+    Json(api_client.cache_stats())
+}
+
+/// Estimates the pp needed to reach a rank with `?rank=<n>` or the rank that an amount of pp corresponds to with
+/// `?pp=<n>` using the latest nightly snapshot of tracked users' stats.  Returns a 404 if no snapshot has been recorded
+/// for the mode yet.
+#[get("/rank_to_pp/<mode>")]
+pub fn rank_to_pp(
+    db_pool: State<DbPool>, mode: Result<Mode, String>, params: Query<RankToPpParams>
+) -> Result<Json<RankPpEstimate>, ApiError> {
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    let db_timer = db_pool.time_route("rank_to_pp");
+    let db_conn = &*db_pool.get_timed_conn(&db_timer);
+    let (snapshot_date, points) = rank_pp::load_latest_snapshot(db_conn, mode)?
+        .ok_or_else(|| ApiError::NotFound(format!("No rank/pp snapshot has been recorded for mode {}", mode)))?;
+    let no_points = || ApiError::NotFound(format!("The latest rank/pp snapshot for mode {} is empty", mode));
+
+    let (rank, pp) = match (params.rank.0, params.pp.0) {
+        (Some(0), None) => { return Err(ApiError::BadInput(String::from("`rank` must be at least 1"))); },
+        (Some(rank), None) => (rank as i32, rank_pp::pp_for_rank(&points, rank as i32).ok_or_else(&no_points)?),
+        (None, Some(pp)) => (rank_pp::rank_for_pp(&points, pp).ok_or_else(&no_points)?, pp),
+        _ => { return Err(ApiError::BadInput(String::from("Exactly one of `rank` or `pp` must be supplied"))); },
+    };
+
+    Ok(Json(RankPpEstimate { mode: mode, rank: rank, pp: pp, snapshot_date: snapshot_date }))
+}
+
+/// Returns a user's position on the leaderboard of pp gained by tracked users in a mode over the last `?days=` days
+/// (7 by default) as `{ rank, total_users, gain }`.  A user's gain is the difference between the pp of their first and
+/// last updates in the window, so users with fewer than two updates in it aren't on the leaderboard and get a 404.
+#[get("/leaderboard/pp_gain/<mode>/rank/<username>")]
+pub fn pp_gain_rank(
+    db_pool: State<DbPool>, clock: State<Arc<Clock>>, mode: Result<Mode, String>, username: Result<Username, String>,
+    params: Query<PpGainParams>,
+) -> Result<Option<Json<PpGainRank>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    let db_timer = db_pool.time_route("pp_gain_rank");
+    let db_conn = &*db_pool.get_timed_conn(&db_timer);
+
+    let usr: User = match get_user_from_username(db_conn, username.normalized())? {
+        Some(user) => user,
+        None => { return Ok(None); },
+    };
+
+    let since = window_start(clock.now(), params.days.0);
+    Ok(get_pp_gain_rank(db_conn, usr.id, mode, since)?.map(Json))
+}
+
+/// Returns all of a user's stored hsicores for a given gamemode.  If `?global_rank=true` is supplied, the positions of
+/// the user's best `MAX_GLOBAL_RANK_LOOKUPS` plays on their beatmaps' global leaderboards are looked up as well.
+/// `?current_only=true` leaves out hiscores that have been pushed out of the user's top plays.  `?grade=<grade>` only
+/// returns hiscores with that grade, including its silver variant unless `?exact_grade=true` is supplied as well.
+/// `?precision=<n>` rounds pp and accuracy to `n` decimal places.  `?enrich=true` adds the number of days since each
+/// play was set as `age_days` along with its `position` among the user's current top plays and the `weighted_pp` that
+/// it contributes to their total, both of which are `null` for plays that have been pushed out of them.
+#[get("/hiscores/<username>/<mode>")]
+pub fn get_hiscores(
+    api_client: State<ApiClient>, db_pool: State<DbPool>, clock: State<Arc<Clock>>, username: Result<Username, String>,
+    mode: Result<Mode, String>, params: Query<HiscoreListParams>, precision_params: Query<PrecisionParams>,
+) -> Result<Option<Compressed<SafeJson<Vec<DetailedHiscore>>>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    let client = api_client.inner();
+    let db_timer = db_pool.time_route("get_hiscores");
+
+    // the connection is returned before any global ranks are looked up so that the osu! API isn't timed
+    let (usr, hiscores, positions) = {
+        let db_conn = &*db_pool.get_timed_conn(&db_timer);
+        let usr: User = match get_user_from_username(db_conn, username.normalized())? {
+            Some(user) => user,
+            None => { return Ok(None); },
+        };
+
+        // pull all hiscores belonging to the selected user from the database for the provided gamemode
+        let mut query = hiscores_dsl::hiscores
+            .filter(hiscores_dsl::user_id.eq(usr.id))
+            .filter(hiscores_dsl::mode.eq(mode as i16))
+            .into_boxed();
+        if params.current_only {
+            query = query.filter(hiscores_dsl::dropped_at.is_null());
+        }
+        if let Some(GradeParam(grade)) = params.grade.0 {
+            let grades: Vec<&str> = grade.matching(!params.exact_grade).iter().map(|grade| grade.as_str()).collect();
+            query = query.filter(hiscores_dsl::rank.eq_any(grades));
+        }
+        let hiscores = query.order(hiscores_dsl::score_time.asc())
+            .load::<Hiscore>(db_conn)
+            .map_err(debug)?;
+
+        // positions are among all of the user's current plays, not just the ones that passed the filters
+        let positions = if params.enrich {
+            let current: Vec<(i32, f32)> = hiscores_dsl::hiscores
+                .filter(hiscores_dsl::user_id.eq(usr.id))
                 .filter(hiscores_dsl::mode.eq(mode as i16))
-                .load::<Hiscore>(db_conn)
+                .filter(hiscores_dsl::dropped_at.is_null())
+                .select((hiscores_dsl::id, hiscores_dsl::pp))
+                .load(db_conn)
                 .map_err(debug)?;
+            Some(current_positions(&current))
+        } else {
+            None
+        };
+        (usr, hiscores, positions)
+    };
+    let now = clock.now();
 
-            // get the user's current hiscores
-            let cur_hiscores = match api_client.get_user_best(s.user_id, mode, 100)? {
-                Some(hs) => hs,
-                None => Vec::new(),
-            };
+    let mut hiscores: Vec<DetailedHiscore> = hiscores.into_iter()
+        .map(|hs| {
+            let accuracy = accuracy::compute(mode, hs.hit_counts());
+            let enrichment = positions.as_ref().map(|positions| enrich_hiscore(&hs, positions, now));
+            DetailedHiscore { hiscore: hs, global_rank: None, accuracy: accuracy, enrichment: enrichment }
+        })
+        .collect();
+
+    if params.global_rank {
+        // each lookup is a separate osu! API request, so only look up ranks for the user's best plays
+        let mut by_pp: Vec<&mut DetailedHiscore> = hiscores.iter_mut().collect();
+        by_pp.sort_by(|a, b| b.hiscore.pp.partial_cmp(&a.hiscore.pp).unwrap_or(Ordering::Equal));
+        for hs in by_pp.into_iter().take(MAX_GLOBAL_RANK_LOOKUPS) {
+            hs.global_rank = Some(client.get_scores(hs.hiscore.beatmap_id, usr.id, mode)?);
+        }
+    }
 
-            // calculate the diff between the last and current updates
-            let diff = UpdateDiff::diff(last_update.as_ref(), &s, old_hiscores, cur_hiscores);
+    Ok(Some(Compressed(SafeJson::new(hiscores, false).with_precision(precision_params.decimals()))))
+}
 
-            // insert all new hiscores into the database
-            diesel::insert_into(hiscores_dsl::hiscores)
-                .values(&diff.newhs)
-                .execute(db_conn)
-                .map_err(debug)?;
+/// A hiscore from the site-wide feed of recently recorded plays along with the name of the user who set it.  `beatmap`
+/// is omitted unless it was requested and is `null` if the beatmap isn't in the beatmap cache.
+#[derive(Serialize)]
+pub struct RecentHiscore {
+    #[serde(flatten)]
+    pub hiscore: Hiscore,
+    pub username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub beatmap: Option<Option<Beatmap>>,
+}
 
-            // TODO: Prefetch all of the beatmaps and update them into the cache
+/// A page of the site-wide feed of recently recorded hiscores
+#[derive(Serialize)]
+pub struct RecentHiscoresPage {
+    pub hiscores: Vec<RecentHiscore>,
+    /// The `?offset=` to request the next page with, or `null` if this is the last page
+    pub next_offset: Option<u32>,
+}
+
+/// Returns the most recently recorded hiscores of all tracked users, newest first, along with the usernames of the
+/// users who set them.  `?mode=` only returns hiscores set in a mode.  `?limit=` sets the size of the page (50 by
+/// default, at most 100) and `?offset=` skips that many hiscores to fetch later pages.  `?beatmaps=true` includes the
+/// beatmap of each hiscore if it's in the beatmap cache.
+#[get("/hiscores/recent")]
+pub fn recent_hiscores(
+    db_pool: State<DbPool>, params: Query<RecentHiscoresParams>, page: Query<PageParams>
+) -> Result<Json<RecentHiscoresPage>, ApiError> {
+    let db_timer = db_pool.time_route("recent_hiscores");
+    let db_conn = &*db_pool.get_timed_conn(&db_timer);
+    let (limit, offset) = (page.limit(DEFAULT_RECENT_HISCORES, MAX_RECENT_HISCORES), page.offset());
+    if let Some(mode) = params.mode.0 {
+        check_mode(mode, ENABLED_MODES).map_err(ApiError::BadInput)?;
+    }
+    let recent = get_recent_hiscores(db_conn, params.mode.0, limit as i64, offset as i64)?;
 
-            // calculate the difference between the current stats and the last update (if it exists) and return them
-            Ok(Some(Json(diff)))
+    // keyed by beatmap id and mode, since the hiscores can be from different modes
+    let mut beatmaps = HashMap::new();
+    if params.beatmaps {
+        let mut modes: Vec<i16> = recent.iter().map(|&(ref hs, _)| hs.mode).collect();
+        modes.sort();
+        modes.dedup();
+        for mode in modes {
+            let ids: Vec<i32> = recent.iter()
+                .filter(|&&(ref hs, _)| hs.mode == mode)
+                .map(|&(ref hs, _)| hs.beatmap_id)
+                .collect();
+            let cached = get_cached_beatmaps(db_conn, &ids, mode as u8)?;
+            beatmaps.extend(cached.into_iter().map(|(id, beatmap)| ((id, mode), beatmap)));
         }
     }
-}
 
-/// Returns current static statistics for a user as stored in the osu!track database.  Designed to be extrememly fast and
-/// avoid the osu! server round-trip involved with getting live stats.  Returns a 404 if there is no stored updates for the
-/// user in the selected mode.
-#[get("/stats/<username>/<mode>")]
-pub fn get_stats(db_pool: State<DbPool>, username: String, mode: u8) -> Result<Option<Json<Update>>, String> {
-    let db_conn = &*db_pool.get_conn();
+    let next_offset = next_page_offset(limit, offset, recent.len());
+    let hiscores = recent.into_iter()
+        .map(|(hiscore, username)| {
+            let key = (hiscore.beatmap_id, hiscore.mode);
+            let beatmap = if params.beatmaps { Some(beatmaps.get(&key).cloned()) } else { None };
+            RecentHiscore { hiscore: hiscore, username: username, beatmap: beatmap }
+        })
+        .collect();
 
-    let usr: User = match get_user_from_username(db_conn, &username)? {
-        Some(usr) => usr,
-        None => { return Ok(None); },
-    };
+    Ok(Json(RecentHiscoresPage { hiscores: hiscores, next_offset: next_offset }))
+}
 
-    Update::belonging_to(&usr)
-        .order(updates_dsl::id.desc())
-        .filter(updates_dsl::mode.eq(mode as i16))
-        .first(db_conn)
-        .map(|x| Some(Json(x)))
-        .map_err(debug)
+/// A stored hiscore along with its beatmap.  `beatmap` is omitted unless it was requested and is `null` if the beatmap
+/// isn't in the beatmap cache.
+#[derive(Serialize)]
+pub struct HiscoreWithBeatmap {
+    #[serde(flatten)]
+    pub hiscore: Hiscore,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub beatmap: Option<Option<Beatmap>>,
 }
 
-/// Returns the live view of a user's stats as reported by the osu! API.  Functions the same way as the `/update/` endpoint
-/// but returns the current statistics rather than the change since the last update
-#[get("/livestats/<username>/<mode>")]
-pub fn live_stats(
-    api_client: State<ApiClient>, db_pool: State<DbPool>, username: String, mode: u8
-) -> Result<Option<Json<NewUpdate>>, String> {
-    let client = api_client.inner();
-    let db_conn = &*db_pool.get_conn();
+/// Returns a user's best current hiscores by pp in every mode that they have any in, keyed by mode.  `?limit=` sets
+/// the number of hiscores returned per mode (10 by default, at most 50) and `?include_beatmaps=true` includes the
+/// beatmap of each hiscore if it's in the beatmap cache.
+#[get("/hiscores_all/<username>")]
+pub fn hiscores_all(
+    db_pool: State<DbPool>, username: Result<Username, String>, params: Query<HiscoresAllParams>,
+) -> Result<Option<Json<BTreeMap<u8, Vec<HiscoreWithBeatmap>>>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let db_timer = db_pool.time_route("hiscores_all");
+    let db_conn = &*db_pool.get_timed_conn(&db_timer);
 
-    let stats: NewUpdate = match client.get_stats(&username, mode)? {
-        Some(u) => u,
+    let usr: User = match get_user_from_username(db_conn, username.normalized())? {
+        Some(user) => user,
         None => { return Ok(None); },
     };
 
-    // check to see if the user exists in our database yet.  If it doesn't, it will soon because the `get_stats()`
-    // function inserts it on another thread.
-    let usr: User = match get_user_from_username(db_conn, &username)? {
-        Some(usr) => usr,
-        None => {
-            // this means that the DB is currently in the process of inserting the user and update, so we don't need to bother
-            return Ok(Some(Json(stats)));
-        },
-    };
-
-    // find the last stored update for the user and, if there has been a change, insert a new update
-    let last_update = get_last_update(usr.id, mode, db_conn)?;
-
-    // if there was a change worth recording between the two updates, write it to the database
-    let needs_insert = if last_update.is_some() {
-        let first = last_update.unwrap();
-        first.pp_rank != stats.pp_rank ||
-            stats.playcount != stats.playcount ||
-            stats.pp_country_rank != stats.pp_country_rank
-    } else {
-        true
-    };
+    let top_hiscores = get_top_hiscores_by_mode(db_conn, usr.id, params.limit() as i64)?;
+    let mut res = BTreeMap::new();
+    for (mode, hiscores) in top_hiscores {
+        // beatmaps are cached separately for each mode, so each mode's are loaded on their own
+        let beatmaps = if params.include_beatmaps {
+            let ids: Vec<i32> = hiscores.iter().map(|hs| hs.beatmap_id).collect();
+            get_cached_beatmaps(db_conn, &ids, mode)?
+        } else {
+            HashMap::new()
+        };
 
-    if needs_insert {
-        diesel::insert_into(updates_dsl::updates)
-            .values(&stats)
-            .execute(db_conn)
-            .map_err(debug)?;
+        let hiscores = hiscores.into_iter()
+            .map(|hiscore| {
+                let beatmap = if params.include_beatmaps {
+                    Some(beatmaps.get(&hiscore.beatmap_id).cloned())
+                } else {
+                    None
+                };
+                HiscoreWithBeatmap { hiscore: hiscore, beatmap: beatmap }
+            })
+            .collect();
+        res.insert(mode, hiscores);
     }
 
-    Ok(Some(Json(stats)))
+    Ok(Some(Json(res)))
 }
 
-/// Returns all of a user's stored updates for a given gamemode.
-#[get("/updates/<username>/<mode>")]
-pub fn get_updates(db_pool: State<DbPool>, username: String, mode: u8) -> Result<Option<Json<Vec<Update>>>, String> {
-    let db_conn = &*db_pool.get_conn();
+/// Returns a single JSON document containing the user's profile along with all of their stored updates and hiscores
+/// across all modes.  The response is streamed from the database as it's written and is aborted if it grows larger
+/// than `MAX_EXPORT_BYTES`.
+#[get("/export/<username>")]
+pub fn export(
+    db_pool: State<DbPool>, username: Result<Username, String>
+) -> Result<Option<Compressed<Content<Stream<ExportStream>>>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let db_conn = db_pool.get_conn();
 
-    let usr: User = match get_user_from_username(db_conn, &username)? {
+    let usr: User = match get_user_from_username(&*db_conn, username.normalized())? {
         Some(user) => user,
         None => { return Ok(None); },
     };
 
-    // pull all updates belonging to the selected user from the database for the provided gamemode
-    let updates = updates_dsl::updates
-        .filter(updates_dsl::user_id.eq(usr.id))
-        .filter(updates_dsl::mode.eq(mode as i16))
-        .order(updates_dsl::update_time.asc())
-        .load::<Update>(db_conn)
-        .map_err(debug)?;
-
-    Ok(Some(Json(updates)))
+    let export_stream = ExportStream::new(db_conn, &usr)?;
+    Ok(Some(Compressed(Content(ContentType::JSON, Stream::from(export_stream)))))
 }
 
-/// Returns all of a user's stored hsicores for a given gamemode.
-#[get("/hiscores/<username>/<mode>")]
-pub fn get_hiscores(db_pool: State<DbPool>, username: String, mode: u8) -> Result<Option<Json<Vec<Hiscore>>>, String> {
-    let db_conn = &*db_pool.get_conn();
+/// Returns all of a user's stored updates in a mode as CSV with a header row.  Like `/export`, the response is streamed
+/// from the database as it's written and is aborted if it grows larger than `MAX_EXPORT_BYTES`.
+#[get("/export/<username>/<mode>/csv")]
+pub fn export_csv(
+    db_pool: State<DbPool>, username: Result<Username, String>, mode: Result<Mode, String>
+) -> Result<Option<Compressed<Content<Stream<CsvExportStream>>>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    let db_conn = db_pool.get_conn();
 
-    let usr: User = match get_user_from_username(db_conn, &username)? {
+    let usr: User = match get_user_from_username(&*db_conn, username.normalized())? {
         Some(user) => user,
         None => { return Ok(None); },
     };
 
-    // pull all hiscores belonging to the selected user from the database for the provided gamemode
-    let hiscores = hiscores_dsl::hiscores
-        .filter(hiscores_dsl::user_id.eq(usr.id))
-        .filter(hiscores_dsl::mode.eq(mode as i16))
-        .order(hiscores_dsl::score_time.asc())
-        .load::<Hiscore>(db_conn)
-        .map_err(debug)?;
-
-    Ok(Some(Json(hiscores)))
+    let export_stream = CsvExportStream::new(db_conn, usr.id, mode);
+    Ok(Some(Compressed(Content(ContentType::CSV, Stream::from(export_stream)))))
 }
 
 /// Returns the difference between a user's current stats and the last time their total PP score was different than its
-/// current value.
+/// current value.  Accepts the same query parameters as `/update` along with `?force=true` to bypass the stats cache.
 #[get("/lastpp/<username>/<mode>")]
 pub fn get_last_pp_diff(
-    api_client: State<ApiClient>, db_pool: State<DbPool>, username: String, mode: u8
-) -> Result<Option<Json<UpdateDiff>>, String> {
+    api_client: State<ApiClient>, db_pool: State<DbPool>, clock: State<Arc<Clock>>, username: Result<Username, String>,
+    mode: Result<Mode, String>, params: Query<HiscoreParams>, force_params: Query<ForceParams>,
+    safe_params: Query<SafeIntegerParams>,
+) -> Result<Option<SafeJson<UpdateDiff>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let mode = mode.map_err(ApiError::BadInput)?.0;
     let client = api_client.inner();
     let db_conn = &*db_pool.get_conn();
 
-    let stats = client.get_stats(&username, mode)?;
+    let stats = client.get_stats(username.as_str(), mode, DEFAULT_EVENT_DAYS, force_params.force, true)?;
     match stats {
         None => { return Ok(None); },
-        Some(s) => {
+        Some(UserStats { username, stats: s }) => {
+            // store the user's stats if this is the first time that they've been seen
+            ensure_user(db_conn, s.user_id, &username)?;
+            if get_last_update(s.user_id, mode, db_conn)?.is_none() {
+                record_update(db_conn, &**clock, &s, None)?;
+            }
+
             // find the most recent update in the same game mode where `pp_raw` is different than current.
-            let last_different_update: Vec<Update> = updates_dsl::updates
-                .filter(updates_dsl::user_id.eq(s.user_id))
-                .filter(updates_dsl::mode.eq(mode as i16))
-                .filter(updates_dsl::pp_raw.ne(s.pp_raw))
-                .order(updates_dsl::id.desc())
-                .limit(1)
-                .load::<Update>(db_conn)
-                .map_err(debug)?;
-            let last_different_update = if last_different_update.len() > 0 { Some(&last_different_update[0]) } else { None };
+            let last_different_update = find_last_update_with_different_pp(db_conn, s.user_id, mode, s.pp_raw)?;
+            let last_different_update = last_different_update.as_ref();
 
             // find the first recorded update that has the same pp as the user currently does
-            let first_same_update_time: Option<NaiveDateTime> = if last_different_update.is_some() {
-                let same_updates = updates_dsl::updates
-                    .filter(updates_dsl::id.gt(last_different_update.unwrap().id))
-                    .filter(updates_dsl::user_id.eq(s.user_id))
-                    .filter(updates_dsl::mode.eq(mode as i16))
-                    .order(updates_dsl::id.asc())
-                    .select(updates_dsl::update_time)
-                    .limit(1)
-                    .load::<NaiveDateTime>(db_conn)
-                    .map_err(debug)?;
-                if same_updates.len() > 0 {
-                    Some(same_updates[0].clone())
-                } else {
-                    None
-                }
-            } else {
-                None
+            let first_same_update_time: Option<NaiveDateTime> = match last_different_update {
+                Some(update) => find_first_update_after(db_conn, s.user_id, mode, update.id)?.map(|u| u.update_time),
+                None => None,
             };
 
             // get the user's current hiscores
-            let cur_hiscores: Vec<NewHiscore> = match api_client.get_user_best(s.user_id, mode, 100)? {
-                Some(hs) => hs,
-                None => Vec::new(),
+            let (cur_hiscores, truncated) = match api_client.get_user_best(s.user_id, mode, params.hs_limit())? {
+                Some(best) => (best.hiscores, best.truncated),
+                None => (Vec::new(), false),
             };
 
             let old_hiscores: Vec<Hiscore> = if last_different_update.is_some() {
@@ -342,31 +1443,919 @@ pub fn get_last_pp_diff(
             };
 
             // calculate the diff between the current and last significant update and return it
-            Ok(Some(Json(UpdateDiff::diff(last_different_update, &s, old_hiscores, cur_hiscores))))
+            let opts = params.diff_options();
+            let mut diff = UpdateDiff::diff(last_different_update, &s, old_hiscores, cur_hiscores, &opts);
+            diff.hiscores_truncated = truncated;
+            Ok(Some(SafeJson::new(diff, safe_params.safe_integers).with_only_changed(params.only_changed)
+                .with_count_aliases(mode)))
         }
     }
 }
 
+/// Returns the difference between a user's current stats and one of their stored updates, such as the one from their
+/// peak, treating hiscores recorded since that update as new.  Nothing is stored.  Invalid update ids and updates from
+/// a different mode are rejected with a 400 while updates belonging to other users return a 404.  Accepts the same
+/// query parameters as `/lastpp`.
+#[get("/livediff/<username>/<mode>/<update_id>")]
+pub fn live_diff(
+    api_client: State<ApiClient>, db_pool: State<DbPool>, username: Result<Username, String>,
+    mode: Result<Mode, String>, update_id: Result<i32, &RawStr>, params: Query<HiscoreParams>,
+    force_params: Query<ForceParams>, safe_params: Query<SafeIntegerParams>,
+) -> Result<Option<SafeJson<UpdateDiff>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    let update_id = match update_id {
+        Ok(id) if id > 0 => id,
+        Ok(id) => { return Err(ApiError::BadInput(format!("Invalid update id: {}", id))); },
+        Err(raw) => { return Err(ApiError::BadInput(format!("Invalid update id: {}", raw))); },
+    };
+    let client = api_client.inner();
+    let db_conn = &*db_pool.get_conn();
+
+    let stats = match client.fetch_stats(username.as_str(), mode, force_params.force)? {
+        Some(s) => s,
+        None => { return Ok(None); },
+    };
+    let target: Option<Update> = updates_dsl::updates
+        .find(update_id)
+        .first(db_conn)
+        .optional()
+        .map_err(debug)?;
+    let target = check_update_target(target, update_id, stats.user_id, mode)?;
+
+    let (cur_hiscores, truncated) = match client.get_user_best(stats.user_id, mode, params.hs_limit())? {
+        Some(best) => (best.hiscores, best.truncated),
+        None => (Vec::new(), false),
+    };
+    let mut diff = diff_against_stored_update(db_conn, &stats, &target, cur_hiscores, &params.diff_options())?;
+    diff.hiscores_truncated = truncated;
+
+    Ok(Some(SafeJson::new(diff, safe_params.safe_integers).with_only_changed(params.only_changed)
+        .with_count_aliases(mode)))
+}
+
+/// Returns the difference between two of a user's stored updates, chosen by time rather than by id so that date pickers
+/// can be used to select them.  `from` and `to` are ISO 8601 timestamps or dates, each resolved to the closest stored
+/// update in the mode; `?bias=before|after` resolves them to the last update at or before the timestamp or the first
+/// one at or after it instead.  The hiscores of each update are the ones that were stored at its time, so plays
+/// recorded between the two are reported as new and ones dropped between them as dropped.  Returns a 400 if no update
+/// can be found for either bound and a 404 if the user isn't tracked.  Accepts `?safe_integers=true` like `/update`.
+#[get("/diff/<username>/<mode>/between/<from>/<to>")]
+pub fn diff_between(
+    db_pool: State<DbPool>, username: Result<Username, String>, mode: Result<Mode, String>,
+    from: Result<IsoTime, String>, to: Result<IsoTime, String>, params: Query<DiffBetweenParams>,
+    safe_params: Query<SafeIntegerParams>,
+) -> Result<Option<SafeJson<UpdateDiff>>, ApiError> {
+    let username = username.map_err(ApiError::BadInput)?;
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    let (from, to) = (from.map_err(ApiError::BadInput)?, to.map_err(ApiError::BadInput)?);
+    if from.0 > to.0 {
+        return Err(ApiError::BadInput(format!("The start of the range ({}) is after its end ({})", from.0, to.0)));
+    }
+    let db_timer = db_pool.time_route("diff_between");
+    let db_conn = &*db_pool.get_timed_conn(&db_timer);
+
+    let usr: User = match get_user_from_username(db_conn, username.normalized())? {
+        Some(user) => user,
+        None => { return Ok(None); },
+    };
+    let find_bound = |time: NaiveDateTime| -> Result<Update, ApiError> {
+        find_update_near(db_conn, usr.id, mode, time, params.bias)?
+            .ok_or_else(|| ApiError::BadInput(format!("No update of the user in mode {} found near {}", mode, time)))
+    };
+    let (start, end) = (find_bound(from.0)?, find_bound(to.0)?);
+
+    let old_hiscores = load_hiscores_as_of(db_conn, usr.id, mode, start.update_time)?;
+    let cur_hiscores: Vec<NewHiscore> = load_hiscores_as_of(db_conn, usr.id, mode, end.update_time)?
+        .iter()
+        .filter(|hs| hs.dropped_at.is_none())
+        .map(Hiscore::to_new_hiscore)
+        .collect();
+    // every stored hiscore is known at both ends, so plays that left the user's top plays in between can be reported
+    let opts = DiffOptions { track_dropped: true, ..DiffOptions::default() };
+    let diff = UpdateDiff::diff(Some(&start), &end.to_new_update(), old_hiscores, cur_hiscores, &opts);
+
+    Ok(Some(SafeJson::new(diff, safe_params.safe_integers).with_count_aliases(mode)))
+}
+
+/// Returns how long responses holding `beatmaps` may be cached for: a long time if all of their approval statuses are
+/// final and only briefly otherwise, since the other beatmaps may still be updated by their mappers
+fn beatmap_cache_policy<'a, I: IntoIterator<Item=&'a Beatmap>>(beatmaps: I) -> CachePolicy {
+    if beatmaps.into_iter().all(|beatmap| beatmap.approval_status().is_final()) {
+        CachePolicy::MaxAge(BEATMAP_CACHE_MAX_AGE_SECS)
+    } else {
+        CachePolicy::MaxAge(PENDING_BEATMAP_CACHE_MAX_AGE_SECS)
+    }
+}
+
 /// Returns data for a set of beatmaps.  It first attempts to retrieve them from the database but if they aren't
-/// stored, they will be retrieved from the osu! API and inserted.  Returns a Json-encoded hap of beatmap_id:beatmap
+/// stored, they will be retrieved from the osu! API and inserted.  Returns a Json-encoded hap of beatmap_id:beatmap.
+/// Responses are cached for `BEATMAP_CACHE_MAX_AGE_SECS` if all of the beatmaps are ranked, approved, or loved and for
+/// `PENDING_BEATMAP_CACHE_MAX_AGE_SECS` otherwise.
 #[get("/beatmaps/<ids>/<mode>")]
 pub fn get_beatmaps(
-    api_client: State<ApiClient>, db_pool: State<DbPool>, ids: String, mode: u8
-) -> Result<Option<Json<HashMap<i32, Beatmap>>>, String> {
-    let ids: Vec<i32> = serde_json::from_str(&ids).map_err(debug)?;
-    // TODO: Search the database and find all beatmaps that have IDs that are included in the parsed vector of ids.
-    // TODO: Retrieve all beatmaps from the API (preferrably asynchronously) that are not contained in the database
-    // TODO: Package up all results and return them
-    unimplemented!();
+    api_client: State<ApiClient>, db_pool: State<DbPool>, ids: String, mode: Result<Mode, String>
+) -> Result<Option<WithCachePolicy<Json<HashMap<i32, Beatmap>>>>, ApiError> {
+    use schema::beatmaps::dsl as beatmaps_dsl;
+
+    let ids: Vec<i32> = parse_beatmap_ids(&ids).map_err(ApiError::BadInput)?;
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    let client = api_client.inner();
+    let db_conn = &*db_pool.get_conn();
+
+    // load the beatmaps that are known to be cached from the database and fetch the rest from the osu! API
+    let (cached_ids, uncached_ids): (Vec<i32>, Vec<i32>) = ids.into_iter()
+        .partition(|&id| client.known_beatmaps().contains(id, mode));
+    let cached: Vec<Beatmap> = beatmaps_dsl::beatmaps
+        .filter(beatmaps_dsl::beatmap_id.eq_any(cached_ids))
+        .filter(beatmaps_dsl::mode.eq(mode as i16))
+        .load(db_conn)
+        .map_err(debug)?;
+
+    let (fetched, _) = client.get_beatmaps_bulk(&uncached_ids, mode)?;
+
+    let beatmaps: HashMap<i32, Beatmap> = cached.into_iter()
+        .chain(fetched.into_iter())
+        .map(|beatmap| (beatmap.beatmap_id, beatmap))
+        .collect();
+
+    let policy = beatmap_cache_policy(beatmaps.values());
+    Ok(Some(WithCachePolicy(Json(beatmaps), policy)))
+}
+
+/// The number of seconds that clients and CDNs may cache `/featured_beatmaps` responses for
+const FEATURED_MAX_AGE_SECS: u32 = 600;
+
+/// Orders `beatmaps` by the position of their ids in `ids`, logging a warning for each id that's missing
+fn order_beatmaps(ids: &[i32], beatmaps: Vec<Beatmap>) -> Vec<Beatmap> {
+    let mut by_id: HashMap<i32, Beatmap> = beatmaps.into_iter()
+        .map(|beatmap| (beatmap.beatmap_id, beatmap))
+        .collect();
+
+    ids.iter().filter_map(|id| {
+        let beatmap = by_id.remove(id);
+        if beatmap.is_none() {
+            warn!("Featured beatmap {} couldn't be found in the cache or the osu! API", id);
+        }
+        beatmap
+    }).collect()
+}
+
+/// Returns the beatmaps configured in `FEATURED_BEATMAPS` in their configured order.  They're served from the beatmap
+/// cache; any that aren't cached yet are fetched from the osu! API in standard mode (beatmaps made for other modes
+/// are returned in their native mode) and inserted.  Featured beatmaps that the osu! API doesn't know about are left
+/// out of the response.
+#[get("/featured_beatmaps")]
+pub fn featured_beatmaps(
+    api_client: State<ApiClient>, db_pool: State<DbPool>
+) -> Result<CachedJson<Vec<Beatmap>>, ApiError> {
+    use schema::beatmaps::dsl as beatmaps_dsl;
+
+    let client = api_client.inner();
+    let db_conn = &*db_pool.get_conn();
+
+    let mut beatmaps: Vec<Beatmap> = beatmaps_dsl::beatmaps
+        .filter(beatmaps_dsl::beatmap_id.eq_any(FEATURED_BEATMAPS))
+        .filter(beatmaps_dsl::mode.eq(STANDARD as i16))
+        .load(db_conn)
+        .map_err(debug)?;
+    let missing: Vec<i32> = FEATURED_BEATMAPS.iter()
+        .cloned()
+        .filter(|&id| !beatmaps.iter().any(|beatmap| beatmap.beatmap_id == id))
+        .collect();
+    if !missing.is_empty() {
+        let (fetched, _) = client.get_beatmaps_bulk(&missing, STANDARD)?;
+        beatmaps.extend(fetched);
+    }
+
+    Ok(CachedJson::new(order_beatmaps(FEATURED_BEATMAPS, beatmaps), FEATURED_MAX_AGE_SECS))
+}
+
+/// Aggregate statistics about the plays that tracked users have set on a beatmap.  Each user is represented by their
+/// best play on the map by pp.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct BeatmapStats {
+    pub beatmap_id: i32,
+    pub mode: u8,
+    /// The number of tracked users that have a hiscore on the map
+    pub users: usize,
+    pub avg_pp: f32,
+    pub median_pp: f32,
+    /// The highest score set on the map by a tracked user, or 0 if there aren't any
+    pub best_score: i32,
+}
+
+/// Computes the statistics of a beatmap from the `(user_id, pp, score)` of each of the hiscores set on it
+fn aggregate_beatmap_plays(beatmap_id: i32, mode: u8, plays: &[(i32, f32, i32)]) -> BeatmapStats {
+    let mut best_pp_by_user: HashMap<i32, f32> = HashMap::new();
+    for &(user_id, pp, _) in plays {
+        let best_pp = best_pp_by_user.entry(user_id).or_insert(pp);
+        if pp > *best_pp {
+            *best_pp = pp;
+        }
+    }
+
+    let mut pps: Vec<f32> = best_pp_by_user.values().cloned().collect();
+    pps.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    let median_pp = match pps.len() {
+        0 => 0.,
+        len if len % 2 == 0 => (pps[len / 2 - 1] + pps[len / 2]) / 2.,
+        len => pps[len / 2],
+    };
+    let avg_pp = if pps.is_empty() { 0. } else { pps.iter().sum::<f32>() / pps.len() as f32 };
+
+    BeatmapStats {
+        beatmap_id: beatmap_id,
+        mode: mode,
+        users: pps.len(),
+        avg_pp: avg_pp,
+        median_pp: median_pp,
+        best_score: plays.iter().map(|&(_, _, score)| score).max().unwrap_or(0),
+    }
+}
+
+/// Returns statistics about the plays that tracked users have set on a beatmap, computed from the stored hiscores.
+/// Maps that nobody has tracked plays on return zeroed stats rather than a 404.
+#[get("/beatmap/<id>/<mode>/stats")]
+pub fn get_beatmap_stats(
+    db_pool: State<DbPool>, id: i32, mode: Result<Mode, String>
+) -> Result<Json<BeatmapStats>, ApiError> {
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    let db_timer = db_pool.time_route("get_beatmap_stats");
+    let db_conn = &*db_pool.get_timed_conn(&db_timer);
+
+    let plays: Vec<(i32, f32, i32)> = hiscores_dsl::hiscores
+        .filter(hiscores_dsl::beatmap_id.eq(id))
+        .filter(hiscores_dsl::mode.eq(mode as i16))
+        .select((hiscores_dsl::user_id, hiscores_dsl::pp, hiscores_dsl::score))
+        .load(db_conn)
+        .map_err(debug)?;
+
+    Ok(Json(aggregate_beatmap_plays(id, mode, &plays)))
 }
 
 /// Returns data for one beatmap.  It first attempts to retrieve the data from the database if it isn't found there
-/// it is retrieved from the osu! API and inserted.
+/// it is retrieved from the osu! API and inserted.  Beatmaps are cached separately for each mode, so the response's
+/// `mode` is always the requested one; it also includes the beatmap's `native_mode`.  Responses are cached like those
+/// of `/beatmaps`.
 #[get("/beatmap/<id>/<mode>")]
 pub fn get_beatmap(
-    api_client: State<ApiClient>, db_pool: State<DbPool>, id: i32, mode: u8
-) -> Result<Option<Json<Beatmap>>, String> {
-    // TODO: Search the database for the beatmap with the supplied id
-    // TODO: if not found in the database, return it from the API.
-    unimplemented!();
+    api_client: State<ApiClient>, id: i32, mode: Result<Mode, String>
+) -> Result<Option<WithCachePolicy<Json<Beatmap>>>, ApiError> {
+    let mode = mode.map_err(ApiError::BadInput)?.0;
+    Ok(api_client.ensure_beatmap(id, mode)?.map(|beatmap| {
+        let policy = beatmap_cache_policy(Some(&beatmap));
+        WithCachePolicy(Json(beatmap), policy)
+    }))
+}
+
+/// How far before the start of the 7 days that signatures show the rank change over the update compared against may
+/// be.  Users without an update that close to then have no rank change shown rather than one over a longer time.
+const SIG_RANK_DELTA_MAX_GAP_HOURS: i64 = 24;
+
+/// Loads the stats shown on a user's signature from their stored updates, or `None` if they have no updates in the mode
+fn load_sig_stats(db_conn: &MysqlConnection, username: &Username, mode: u8) -> Result<Option<SigStats>, String> {
+    let usr: User = match get_user_from_username(db_conn, username.normalized())? {
+        Some(user) => user,
+        None => { return Ok(None); },
+    };
+    let latest = match get_last_update(usr.id, mode, db_conn)? {
+        Some(update) => update,
+        None => { return Ok(None); },
+    };
+    let week_ago = latest.update_time - Duration::days(7);
+    let earliest = week_ago - Duration::hours(SIG_RANK_DELTA_MAX_GAP_HOURS);
+    let prev = find_update_near(db_conn, usr.id, mode, week_ago, UpdateBias::Before)?
+        .and_then(|prev| if prev.update_time >= earliest { Some(prev) } else { None });
+
+    Ok(Some(SigStats {
+        username: usr.username,
+        mode: mode,
+        pp_raw: latest.pp_raw,
+        pp_rank: latest.pp_rank,
+        accuracy: latest.accuracy,
+        rank_delta_7d: prev.map(|prev| prev.pp_rank - latest.pp_rank),
+    }))
+}
+
+/// Returns a PNG signature image of a user showing their pp, rank, and accuracy along with how many ranks they gained
+/// over the last 7 days, requested as `/sig/<username>/<mode>.png`.  It's rendered entirely from stored updates without
+/// any requests to the osu! API.  Users without stored stats in the mode get a placeholder image instead of a 404 so
+/// that embedded images don't break, as do invalid usernames and modes and requests that fail.  Placeholders are only
+/// cached for `SIG_PLACEHOLDER_MAX_AGE_SECS` so that signatures show up soon after a user's first update, and those
+/// served because loading the stats failed aren't cached at all.
+#[get("/sig/<username>/<file>")]
+pub fn signature(
+    db_pool: State<DbPool>, username: Result<Username, String>, file: Result<SigFile, String>
+) -> WithCachePolicy<Content<Vec<u8>>> {
+    let stats = match (username.as_ref(), file) {
+        (Ok(username), Ok(SigFile(Mode(mode)))) => {
+            let db_timer = db_pool.time_route("signature");
+            load_sig_stats(&*db_pool.get_timed_conn(&db_timer), username, mode)
+                .map_err(|err| error!("Error while loading the stats of a signature: {}", err))
+        },
+        _ => Ok(None),
+    };
+
+    let placeholder = || render_placeholder(username.as_ref().map(Username::as_str).unwrap_or("osu!track"));
+    let (png, policy) = match stats {
+        Ok(Some(stats)) => (render_sig(&stats), CachePolicy::MaxAge(SIG_CACHE_MAX_AGE_SECS)),
+        Ok(None) => (placeholder(), CachePolicy::MaxAge(SIG_PLACEHOLDER_MAX_AGE_SECS)),
+        Err(()) => (placeholder(), CachePolicy::NoStore),
+    };
+    WithCachePolicy(Content(ContentType::PNG, png), policy)
+}
+
+#[cfg(test)]
+fn test_new_hiscore(beatmap_id: i32, score: i32, pp: f32) -> NewHiscore {
+    NewHiscore {
+        user_id: 1, mode: 0, beatmap_id: beatmap_id, score: score, pp: pp, enabled_mods: 0, rank: String::from("A"),
+        score_time: NaiveDateTime::from_timestamp(1500000000, 0), count300: None, count100: None, count50: None,
+        countmiss: None, countkatu: None, countgeki: None, maxcombo: None, perfect: None,
+    }
+}
+
+#[cfg(test)]
+fn test_hiscore(beatmap_id: i32, score: i32, pp: f32) -> Hiscore {
+    let hs = test_new_hiscore(beatmap_id, score, pp);
+    Hiscore {
+        id: 1, user_id: hs.user_id, mode: hs.mode, beatmap_id: hs.beatmap_id, score: hs.score, pp: hs.pp,
+        enabled_mods: hs.enabled_mods, rank: hs.rank, score_time: hs.score_time, time_recorded: hs.score_time,
+        count300: None, count100: None, count50: None, countmiss: None, countkatu: None, countgeki: None,
+        maxcombo: None, perfect: None, dropped_at: None,
+    }
+}
+
+/// Make sure that improving a score on an already-recorded beatmap reports the score that it replaced
+#[test]
+fn diff_hiscore_improvement() {
+    let prev = test_stored_update(1000.);
+    let old_hs = vec![test_hiscore(1, 500000, 345.), test_hiscore(1, 400000, 300.)];
+    let new_hs = vec![test_new_hiscore(1, 600000, 402.)];
+    let diff = UpdateDiff::diff(Some(&prev), &test_update(1050.), old_hs, new_hs, &DiffOptions::default());
+
+    assert_eq!(diff.newhs.len(), 1);
+    assert_eq!(diff.newhs[0].replaces, Some(PreviousScore { score: 500000, pp: 345., enabled_mods: 0 }));
+}
+
+/// Make sure that a score on a beatmap the user has never played before doesn't replace anything
+#[test]
+fn diff_hiscore_first_play() {
+    let prev = test_stored_update(1000.);
+    let old_hs = vec![test_hiscore(1, 500000, 345.)];
+    let new_hs = vec![test_new_hiscore(1, 500000, 345.), test_new_hiscore(2, 700000, 410.)];
+    let diff = UpdateDiff::diff(Some(&prev), &test_update(1050.), old_hs, new_hs, &DiffOptions::default());
+
+    assert_eq!(diff.newhs.len(), 1);
+    assert_eq!(diff.newhs[0].hiscore.beatmap_id, 2);
+    assert_eq!(diff.newhs[0].replaces, None);
+}
+
+/// Make sure that a score equal to an already-recorded one isn't reported as new
+#[test]
+fn diff_hiscore_equal_score() {
+    let prev = test_stored_update(1000.);
+    let old_hs = vec![test_hiscore(1, 500000, 345.)];
+    let new_hs = vec![test_new_hiscore(1, 500000, 345.)];
+    let diff = UpdateDiff::diff(Some(&prev), &test_update(1000.), old_hs, new_hs, &DiffOptions::default());
+
+    assert!(diff.newhs.is_empty());
+}
+
+/// Make sure that the graph points for a long history are a small fraction of the size of the full `/updates` response
+#[test]
+fn graph_points_size() {
+    let updates: Vec<Update> = (0..5000).map(|i| {
+        let mut update = test_stored_update(1000. + i as f32 * 0.1);
+        update.id = i;
+        update.pp_rank -= i;
+        update.update_time = NaiveDateTime::from_timestamp(1500000000 + i as i64 * 3600, 0);
+        update
+    }).collect();
+    let history: Vec<(NaiveDateTime, i32, f32)> = updates.iter()
+        .map(|update| (update.update_time, update.pp_rank, update.pp_raw))
+        .collect();
+
+    let points = to_graph_points(&history);
+    assert_eq!(points.len(), GRAPH_MAX_POINTS);
+    assert_eq!(points[0], (1500000000, 50000, 1000.));
+    assert_eq!(points[GRAPH_MAX_POINTS - 1].0, updates[4999].update_time.timestamp());
+
+    let full_size = ::serde_json::to_string(&updates).unwrap().len();
+    let graph_size = ::serde_json::to_string(&points).unwrap().len();
+    assert!(graph_size * 20 < full_size, "graph: {} bytes, full: {} bytes", graph_size, full_size);
+}
+
+/// Make sure that read-only live stats don't write anything, neither an update nor a raw snapshot of the response, even
+/// for users that aren't tracked
+#[test]
+fn live_stats_read_only() {
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use clock::SystemClock;
+    use helpers::raw_snapshots::load_raw_snapshot;
+    use params::{EventDays, OptionalParam};
+    use test_support::{fixture, test_pool, MockApi};
+
+    let get_user = fixture("get_user.json").replace("\"4704931\"", "\"-9\"").replace("\"Ameo\"", "\"LiveOnly\"");
+    let api = MockApi::start(vec![("get_user", get_user)]);
+    let pool = test_pool();
+    let client = ApiClient::with_mock_api(&api.url, pool.clone()).unwrap();
+    let db_pool = DbPool::new(pool.clone(), Arc::new(SystemClock));
+    let clock = SystemClock;
+
+    // every check uses a connection of its own so that the pool is free for writes made in other threads
+    let live_stats = |record: bool| -> NewUpdate {
+        let params = LiveStatsParams { event_days: EventDays(DEFAULT_EVENT_DAYS), record: OptionalParam(Some(record)) };
+        let username = Username::parse("LiveOnly").unwrap();
+        live_stats_response(&client, &db_pool, "live_stats", &clock, username, 0, &params, true, false)
+            .unwrap()
+            .unwrap()
+    };
+    let count_updates = || -> i64 {
+        let conn = pool.get().expect("Unable to get connection from pool");
+        updates_dsl::updates.filter(updates_dsl::user_id.eq(-9)).count().get_result(&*conn).unwrap()
+    };
+    let has_snapshot = || -> bool {
+        let conn = pool.get().expect("Unable to get connection from pool");
+        load_raw_snapshot(&*conn, -9, 0).unwrap().is_some()
+    };
+
+    assert_eq!(live_stats(false).user_id, -9);
+    assert_eq!(count_updates(), 0);
+    assert!(get_user(&*pool.get().unwrap(), -9).is_err());
+    assert!(!has_snapshot());
+
+    live_stats(true);
+    assert_eq!(count_updates(), 1);
+    // the snapshot is stored in a separate thread
+    let start = Instant::now();
+    while !has_snapshot() {
+        assert!(start.elapsed() < Duration::from_secs(5), "The raw snapshot of the recorded stats wasn't stored");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn update_summary_rates() {
+    let time = |secs: i64| NaiveDateTime::from_timestamp(1500000000 + secs, 0);
+
+    let single = UpdateSummary::new(1, time(0), time(0));
+    assert_eq!((single.days_tracked, single.updates_per_day), (None, None));
+    assert!(!::serde_json::to_string(&single).unwrap().contains("days_tracked"));
+
+    // updates that are all from the same day count as one day
+    let same_day = UpdateSummary::new(3, time(0), time(3600));
+    assert_eq!((same_day.days_tracked, same_day.updates_per_day), (Some(0), Some(3.)));
+
+    let month = UpdateSummary::new(60, time(0), time(30 * 86400 + 3600));
+    assert_eq!((month.days_tracked, month.updates_per_day), (Some(30), Some(2.)));
+}
+
+/// Make sure that only the best few new hiscores are reported on a user's first update unless the limit is disabled,
+/// and that the rest of them are still stored
+#[test]
+fn diff_first_update_limit() {
+    let new_hs = || (1..21).map(|i| test_new_hiscore(i, 500000, i as f32 * 10.)).collect::<Vec<_>>();
+    let opts = DiffOptions { first_update_limit: Some(5), ..DiffOptions::default() };
+
+    let diff = UpdateDiff::diff(None, &test_update(1050.), Vec::new(), new_hs(), &opts);
+    let reported: Vec<i32> = diff.newhs.iter().map(|hs| hs.hiscore.beatmap_id).collect();
+    assert_eq!(reported, vec![20, 19, 18, 17, 16]);
+    assert!(diff.newhs_truncated);
+    assert_eq!(diff.total_new, Some(20));
+    assert_eq!(diff.stale_hs.len(), 15);
+    let json = ::serde_json::to_string(&diff).unwrap();
+    assert!(json.contains("\"newhs_truncated\":true,\"total_new\":20"));
+
+    // disabling the limit reports everything
+    let diff = UpdateDiff::diff(None, &test_update(1050.), Vec::new(), new_hs(), &DiffOptions::default());
+    assert_eq!(diff.newhs.len(), 20);
+    assert!(!diff.newhs_truncated);
+    assert!(diff.stale_hs.is_empty());
+    let json = ::serde_json::to_string(&diff).unwrap();
+    assert!(!json.contains("newhs_truncated") && !json.contains("total_new"));
+
+    // updates after the first one aren't limited
+    let diff = UpdateDiff::diff(Some(&test_stored_update(1000.)), &test_update(1050.), Vec::new(), new_hs(), &opts);
+    assert_eq!(diff.newhs.len(), 20);
+    assert!(!diff.newhs_truncated);
+    assert_eq!(diff.total_new, None);
+}
+
+/// Make sure that plays set before the previous update aren't reported as new when `recent_only` is set
+#[test]
+fn diff_recent_only() {
+    let prev = test_stored_update(1000.);
+    let mut old_play = test_new_hiscore(1, 500000, 345.);
+    old_play.score_time = NaiveDateTime::from_timestamp(1400000000, 0);
+    let mut recent_play = test_new_hiscore(2, 600000, 402.);
+    recent_play.score_time = NaiveDateTime::from_timestamp(1600000000, 0);
+    let new_hs = || vec![old_play.clone(), recent_play.clone()];
+
+    let diff = UpdateDiff::diff(Some(&prev), &test_update(1050.), Vec::new(), new_hs(), &DiffOptions::default());
+    assert_eq!(diff.newhs.len(), 2);
+    assert!(diff.stale_hs.is_empty());
+
+    let opts = DiffOptions { recent_only: true, ..DiffOptions::default() };
+    let diff = UpdateDiff::diff(Some(&prev), &test_update(1050.), Vec::new(), new_hs(), &opts);
+    assert_eq!(diff.newhs.len(), 1);
+    assert_eq!(diff.newhs[0].hiscore.beatmap_id, 2);
+    assert_eq!(diff.stale_hs.len(), 1);
+    assert_eq!(diff.stale_hs[0].beatmap_id, 1);
+
+    // the first update for a user still reports everything
+    let diff = UpdateDiff::diff(None, &test_update(1050.), Vec::new(), new_hs(), &opts);
+    assert_eq!(diff.newhs.len(), 2);
+}
+
+/// Make sure that tracked users without updates in a mode are distinguishable from users with updates
+#[test]
+fn updates_response_format() {
+    let empty = UpdatesResponse::new(1, Vec::new());
+    assert_eq!(::serde_json::to_string(&empty).unwrap(), "{\"user_id\":1,\"updates\":[]}");
+
+    let update = test_stored_update(1000.);
+    let update_json = ::serde_json::to_string(&update).unwrap();
+    let updates = UpdatesResponse::new(1, vec![update]);
+    assert_eq!(::serde_json::to_string(&updates).unwrap(), format!("[{}]", update_json));
+}
+
+#[test]
+fn beatmap_play_aggregation() {
+    // user 1 improved their play on the map, so only their best one counts
+    let plays = vec![(1, 200., 500000), (1, 250., 600000), (2, 300., 700000), (3, 100., 400000), (4, 150., 450000)];
+    let stats = aggregate_beatmap_plays(1031604, 0, &plays);
+    assert_eq!(stats.users, 4);
+    assert_eq!(stats.avg_pp, 200.);
+    assert_eq!(stats.median_pp, 200.);
+    assert_eq!(stats.best_score, 700000);
+
+    let stats = aggregate_beatmap_plays(1031604, 0, &plays[1..4]);
+    assert_eq!(stats.median_pp, 250.);
+
+    assert_eq!(aggregate_beatmap_plays(1, 0, &[]), BeatmapStats {
+        beatmap_id: 1, mode: 0, users: 0, avg_pp: 0., median_pp: 0., best_score: 0,
+    });
+}
+
+/// Only updates of the requesting user in the requested mode can be diffed against
+#[test]
+fn update_target_validation() {
+    let update = test_stored_update(1000.);
+    assert!(check_update_target(Some(update.clone()), 1, 1, 0).is_ok());
+    match check_update_target(Some(update.clone()), 1, 2, 0) {
+        Err(ApiError::NotFound(_)) => (),
+        other => panic!("Expected a 404 for another user's update, got {:?}", other.map(|update| update.id)),
+    }
+    match check_update_target(Some(update), 1, 1, 3) {
+        Err(ApiError::BadInput(_)) => (),
+        other => panic!("Expected a 400 for an update from another mode, got {:?}", other.map(|update| update.id)),
+    }
+    match check_update_target(None, 1, 1, 0) {
+        Err(ApiError::NotFound(_)) => (),
+        other => panic!("Expected a 404 for a missing update, got {:?}", other.map(|update| update.id)),
+    }
+}
+
+/// Comparing against a stored peak reports the pp lost since then and the hiscores recorded after it as new
+#[test]
+fn live_diff_against_peak() {
+    use chrono::{Duration, Utc};
+    use helpers::create_db_pool;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    let stored_hiscore = NewHiscore { user_id: -1, ..test_new_hiscore(1, 500000, 345.) };
+    diesel::insert_into(hiscores_dsl::hiscores).values(&stored_hiscore).execute(conn).unwrap();
+
+    // the peak was an hour from now, so the stored hiscore was recorded before it
+    let mut peak = test_stored_update(1100.);
+    peak.user_id = -1;
+    peak.update_time = Utc::now().naive_utc() + Duration::hours(1);
+    let cur = NewUpdate { user_id: -1, ..test_update(1050.) };
+    let cur_hiscores = vec![
+        NewHiscore { user_id: -1, ..test_new_hiscore(1, 500000, 345.) },
+        NewHiscore { user_id: -1, ..test_new_hiscore(2, 700000, 410.) },
+    ];
+    let diff = diff_against_stored_update(conn, &cur, &peak, cur_hiscores.clone(), &DiffOptions::default()).unwrap();
+    assert_eq!(diff.pp_raw, -50.);
+    assert_eq!(diff.newhs.iter().map(|hs| hs.hiscore.beatmap_id).collect::<Vec<_>>(), vec![2]);
+
+    // against an older update, the stored hiscore was recorded since then and is new as well
+    peak.update_time = Utc::now().naive_utc() - Duration::days(1);
+    let diff = diff_against_stored_update(conn, &cur, &peak, cur_hiscores, &DiffOptions::default()).unwrap();
+    assert_eq!(diff.newhs.len(), 2);
+}
+
+/// Featured beatmaps are returned in their configured order, leaving out any that couldn't be found
+#[test]
+fn featured_beatmap_order() {
+    use test_support::test_beatmap;
+
+    let beatmaps = vec![test_beatmap(1, 10), test_beatmap(1, 20), test_beatmap(1, 30)];
+    let ordered = order_beatmaps(&[30, 10, 40, 20], beatmaps);
+    assert_eq!(ordered.iter().map(|beatmap| beatmap.beatmap_id).collect::<Vec<_>>(), vec![30, 10, 20]);
+}
+
+/// A stored play that was pushed out of the user's top plays is reported as dropped, and a dropped play that made it
+/// back in is reported as reappeared
+#[test]
+fn diff_dropped_hiscores() {
+    let prev = test_stored_update(1000.);
+    let mut old_hs: Vec<Hiscore> = (1..4)
+        .map(|i| Hiscore { id: i, ..test_hiscore(i, 500000, 300. + i as f32) })
+        .collect();
+    let mut reappearing = Hiscore { id: 4, ..test_hiscore(4, 500000, 310.) };
+    reappearing.dropped_at = Some(NaiveDateTime::from_timestamp(1400000000, 0));
+    old_hs.push(reappearing);
+    // a new play on beatmap 5 pushes the play on beatmap 1 out of the top plays
+    let new_hs = vec![
+        test_new_hiscore(2, 500000, 302.), test_new_hiscore(3, 500000, 303.), test_new_hiscore(4, 500000, 310.),
+        test_new_hiscore(5, 600000, 400.),
+    ];
+    let opts = DiffOptions { track_dropped: true, ..DiffOptions::default() };
+    let diff = UpdateDiff::diff(Some(&prev), &test_update(1050.), old_hs.clone(), new_hs.clone(), &opts);
+
+    assert_eq!(diff.newhs.iter().map(|hs| hs.hiscore.beatmap_id).collect::<Vec<_>>(), vec![5]);
+    assert_eq!(diff.dropped_hs.iter().map(|hs| hs.id).collect::<Vec<_>>(), vec![1]);
+    assert_eq!(diff.reappeared_hs, vec![4]);
+
+    // without all of the top plays, nothing can be said about which were dropped
+    let diff = UpdateDiff::diff(Some(&prev), &test_update(1050.), old_hs, new_hs, &DiffOptions::default());
+    assert!(diff.dropped_hs.is_empty());
+    assert!(diff.reappeared_hs.is_empty());
+}
+
+/// Plays are grouped by their normalized mods and ordered by total pp
+#[test]
+fn mod_breakdown_grouping() {
+    let hiscore = |enabled_mods: i32, pp: f32, count300: Option<i32>| Hiscore {
+        enabled_mods: enabled_mods, count300: count300, count100: Some(0), count50: Some(0), countmiss: Some(0),
+        countkatu: Some(0), countgeki: Some(0), ..test_hiscore(1, 500000, pp)
+    };
+    let hiscores = vec![
+        // HDDT and HDNC
+        hiscore(72, 300., Some(100)), hiscore(584, 200., None),
+        // nomod
+        hiscore(0, 150., Some(100)), hiscore(0, 100., Some(100)),
+        // HRPF
+        hiscore(16432, 50., None),
+    ];
+    let hiscores: Vec<Hiscore> = hiscores.into_iter()
+        .enumerate()
+        .map(|(i, hs)| Hiscore { id: i as i32 + 1, ..hs })
+        .collect();
+
+    let breakdown = group_by_mods(0, &hiscores);
+    let summary: Vec<(String, usize, f32)> = breakdown.iter()
+        .map(|group| (group.acronym.clone(), group.plays, group.total_pp))
+        .collect();
+    assert_eq!(summary, vec![
+        (String::from("HDDT"), 2, 500.), (String::from("NM"), 2, 250.), (String::from("HRSD"), 1, 50.),
+    ]);
+    assert_eq!(breakdown[0].avg_accuracy, Some(100.));
+    assert_eq!(breakdown[2].avg_accuracy, None);
+    // the plays are weighted by their positions among all of the current plays, not just the ones in their group
+    assert!((breakdown[0].weighted_pp - 490.).abs() < 1e-3);
+    assert!((breakdown[1].weighted_pp - (150. * 0.9025 + 100. * 0.857375)).abs() < 1e-3);
+}
+
+#[test]
+fn version_info_format() {
+    let serialized = ::serde_json::to_value(&VersionInfo::current()).unwrap();
+    assert_eq!(serialized["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(serialized["schema_version"], SCHEMA_VERSION);
+    assert!(serialized.get("commit").is_some());
+}
+
+/// Make sure that only the latest update of each requested user is returned and that unknown users are left out
+#[test]
+fn stats_batch_latest_updates() {
+    use helpers::create_db_pool;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    ensure_user(conn, -1, "Batch One").unwrap();
+    ensure_user(conn, -2, "Batch Two").unwrap();
+    let updates = vec![
+        NewUpdate { user_id: -1, ..test_update(1000.) },
+        NewUpdate { user_id: -1, ..test_update(1100.) },
+        NewUpdate { user_id: -2, ..test_update(2000.) },
+        // updates in other modes are ignored
+        NewUpdate { user_id: -2, mode: 1, ..test_update(3000.) },
+    ];
+    diesel::insert_into(updates_dsl::updates).values(&updates).execute(conn).unwrap();
+
+    let usernames: Vec<Username> = ["Batch One", "batch two", "Not Tracked"].iter()
+        .map(|username| Username::parse(username).unwrap())
+        .collect();
+    let stats = load_stats_batch(conn, &usernames, 0).unwrap();
+    assert_eq!(stats.len(), 3);
+    assert_eq!(stats["Batch One"].as_ref().unwrap().pp_raw, 1100.);
+    assert_eq!(stats["batch two"].as_ref().unwrap().pp_raw, 2000.);
+    assert!(stats["Not Tracked"].is_none());
+
+    // users without updates in the mode are mapped to `None` as well
+    let stats = load_stats_batch(conn, &usernames[..1], 1).unwrap();
+    assert!(stats["Batch One"].is_none());
+}
+
+/// Make sure that the lite stats match the full update while being much smaller once serialized
+#[test]
+fn stats_lite_payload() {
+    use helpers::create_db_pool;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    ensure_user(conn, -1, "Lite User").unwrap();
+    let updates = vec![
+        NewUpdate { user_id: -1, ..test_update(1000.) },
+        NewUpdate { user_id: -1, ..test_update(1100.) },
+    ];
+    diesel::insert_into(updates_dsl::updates).values(&updates).execute(conn).unwrap();
+
+    let full = get_last_update(-1, 0, conn).unwrap().unwrap();
+    let lite = load_stats_lite(conn, -1, 0).unwrap().unwrap();
+    assert_eq!(lite, StatsLite {
+        pp_raw: full.pp_raw, pp_rank: full.pp_rank, pp_country_rank: full.pp_country_rank, accuracy: full.accuracy,
+        playcount: full.playcount, update_time: full.update_time,
+    });
+    assert_eq!(lite.pp_raw, 1100.);
+
+    let full_len = ::serde_json::to_string(&StatsResponse { update_id: full.id, update: full, rank_percentile: None })
+        .unwrap()
+        .len();
+    let lite_len = ::serde_json::to_string(&lite).unwrap().len();
+    assert!(lite_len * 2 < full_len, "lite payload is {} bytes vs {} for the full one", lite_len, full_len);
+
+    assert!(load_stats_lite(conn, -1, 1).unwrap().is_none());
+}
+
+/// Make sure that the scheduler skips users flagged `do_not_autoupdate` while other sources can still update them
+#[test]
+fn scheduled_updates_skip_flagged_users() {
+    use helpers::create_db_pool;
+    use helpers::user_flags::set_user_flag;
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    ensure_user(conn, -1, "Flagged User").unwrap();
+    ensure_user(conn, -2, "Unflagged User").unwrap();
+    set_user_flag(conn, -1, UserFlagKind::DoNotAutoupdate, "known multi-account").unwrap();
+    set_user_flag(conn, -2, UserFlagKind::AllowResets, "data reset by user request").unwrap();
+    let skips = |username: &str, source: UpdateSource| {
+        skips_scheduled_update(conn, &Username::parse(username).unwrap(), source).unwrap()
+    };
+
+    assert!(skips("Flagged User", UpdateSource::Scheduler));
+    assert!(skips("flagged user", UpdateSource::Scheduler));
+    assert!(!skips("Flagged User", UpdateSource::Web));
+    assert!(!skips("Flagged User", UpdateSource::Other));
+    // other flags don't affect the scheduler
+    assert!(!skips("Unflagged User", UpdateSource::Scheduler));
+    // users that aren't tracked yet can't have flags
+    assert!(!skips("Not Tracked", UpdateSource::Scheduler));
+}
+
+/// Beatmap responses are only cached for long if every beatmap in them has a final approval status
+#[test]
+fn beatmap_caching() {
+    use test_support::test_beatmap;
+
+    let ranked = test_beatmap(1, 1);
+    let qualified = Beatmap { approved: 3, ..test_beatmap(1, 2) };
+    assert_eq!(beatmap_cache_policy(Some(&ranked)), CachePolicy::MaxAge(BEATMAP_CACHE_MAX_AGE_SECS));
+    let pending = CachePolicy::MaxAge(PENDING_BEATMAP_CACHE_MAX_AGE_SECS);
+    assert_eq!(beatmap_cache_policy(vec![&ranked, &qualified]), pending);
+    assert_eq!(beatmap_cache_policy(Vec::new()), CachePolicy::MaxAge(BEATMAP_CACHE_MAX_AGE_SECS));
+}
+
+/// Decreasing stats aren't reported as a possible score reset for users flagged `allow_resets`
+#[test]
+fn flagged_resets_allowed() {
+    use helpers::create_db_pool;
+    use helpers::user_flags::set_user_flag;
+    use test_support::{test_stored_update, test_update};
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    ensure_user(conn, -1, "Reset User").unwrap();
+    ensure_user(conn, -2, "Other User").unwrap();
+    set_user_flag(conn, -1, UserFlagKind::AllowResets, "data reset by user request").unwrap();
+    set_user_flag(conn, -2, UserFlagKind::DoNotAutoupdate, "known multi-account").unwrap();
+    let reset_diff = || {
+        let prev = test_stored_update(1000.);
+        let cur = NewUpdate { ranked_score: prev.ranked_score - 1, ..test_update(1000.) };
+        UpdateDiff::diff(Some(&prev), &cur, Vec::new(), Vec::new(), &DiffOptions::default())
+    };
+
+    let mut diff = reset_diff();
+    allow_flagged_resets(conn, -1, &mut diff).unwrap();
+    assert!(diff.unexpected_decreases.is_empty());
+    // other flags don't allow resets
+    let mut diff = reset_diff();
+    allow_flagged_resets(conn, -2, &mut diff).unwrap();
+    assert_eq!(diff.unexpected_decreases, vec![String::from("ranked_score")]);
+}
+
+/// A failure to fetch the beatmaps of new hiscores doesn't fail the update; the hiscores are still recorded and are
+/// reported with `null` beatmaps
+#[test]
+fn update_beatmap_fetch_failure() {
+    use rocket::http::Status;
+    use rocket::local::Client;
+    use clock::SystemClock;
+    use test_support::{fixture, test_pool, MockApi};
+
+    let get_user = fixture("get_user.json").replace("\"4704931\"", "\"-11\"").replace("\"Ameo\"", "\"MockUser\"");
+    let get_user_best = String::from(
+        "[{\"beatmap_id\":\"-31\",\"score\":\"1000000\",\"pp\":\"345.5\",\"enabled_mods\":\"0\",\"rank\":\"S\",\
+        \"date\":\"2017-12-10 12:00:00\"}]"
+    );
+    // `get_beatmaps` isn't served, so every request for beatmaps fails
+    let api = MockApi::start(vec![("get_user", get_user), ("get_user_best", get_user_best)]);
+    let pool = test_pool();
+    let clock: Arc<Clock> = Arc::new(SystemClock);
+    let server = ::rocket::ignite()
+        .mount("/", routes![update])
+        .manage(ApiClient::with_mock_api(&api.url, pool.clone()).unwrap())
+        .manage(DbPool::new(pool.clone(), clock.clone()))
+        .manage(clock);
+    let client = Client::new(server).unwrap();
+
+    let mut res = client.get("/update/MockUser/0?include_beatmaps=true").dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let diff: ::serde_json::Value = ::serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(diff["newhs"][0]["beatmap_id"], -31);
+    assert!(diff["newhs"][0]["beatmap"].is_null());
+    assert!(api.requests().iter().any(|target| target.starts_with("/get_beatmaps")));
+
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    let stored: i64 = hiscores_dsl::hiscores.filter(hiscores_dsl::user_id.eq(-11)).count().get_result(conn).unwrap();
+    assert_eq!(stored, 1);
+}
+
+/// Signatures only show a rank change if there's an update from around the start of the last 7 days to compare against
+#[test]
+fn sig_rank_delta_window() {
+    use helpers::{create_db_pool, last_insert_id};
+
+    let pool = create_db_pool();
+    let conn: &MysqlConnection = &*pool.get().expect("Unable to get connection from pool");
+    conn.begin_test_transaction().unwrap();
+
+    ensure_user(conn, -12, "SigUser").unwrap();
+    let latest_time = NaiveDateTime::from_timestamp(1500000000, 0);
+    let insert_update = |days_before: i64, hours_before: i64, pp_rank: i32| {
+        let update = NewUpdate { user_id: -12, pp_rank: pp_rank, ..test_update(1000.) };
+        diesel::insert_into(updates_dsl::updates).values(&update).execute(conn).unwrap();
+        diesel::update(updates_dsl::updates.find(last_insert_id(conn).unwrap()))
+            .set(updates_dsl::update_time.eq(latest_time - Duration::days(days_before) - Duration::hours(hours_before)))
+            .execute(conn)
+            .unwrap();
+    };
+    let username = Username::parse("SigUser").unwrap();
+    let rank_delta = || load_sig_stats(conn, &username, 0).unwrap().unwrap().rank_delta_7d;
+
+    // the only earlier update is months old
+    insert_update(90, 0, 60000);
+    insert_update(0, 0, 50000);
+    assert_eq!(rank_delta(), None);
+
+    insert_update(7, 2, 50500);
+    assert_eq!(rank_delta(), Some(500));
+}
+
+/// Placeholder signatures are cached for much less time than real ones, and invalid requests get them as well
+#[test]
+fn placeholder_sig_caching() {
+    use rocket::http::Status;
+    use rocket::local::Client;
+    use cache::CacheControl;
+    use clock::SystemClock;
+    use test_support::test_pool;
+
+    let server = ::rocket::ignite()
+        .mount("/", routes![signature])
+        .attach(CacheControl)
+        .manage(DbPool::new(test_pool(), Arc::new(SystemClock)));
+    let client = Client::new(server).unwrap();
+    let placeholder_cache_control = format!("public, max-age={}", SIG_PLACEHOLDER_MAX_AGE_SECS);
+
+    for path in &["/sig/__untracked__/0.png", "/sig/__untracked__/9.png"] {
+        let res = client.get(*path).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(res.headers().get_one("Cache-Control"), Some(placeholder_cache_control.as_str()), "{}", path);
+    }
 }
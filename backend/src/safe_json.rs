@@ -0,0 +1,225 @@
+//! A JSON responder that can serialize large integer fields as strings.  `ranked_score` and `total_score` can exceed
+//! 2^53 for some accounts, past which JavaScript numbers lose precision, so clients that pass `?safe_integers=true`
+//! receive those fields (and the diffs of them) as decimal strings instead.  It can also round pp and accuracy values,
+//! which are stored as floats and otherwise serialize with noisy trailing decimals, for clients that pass
+//! `?precision=<n>`.  Stats that didn't change can also be left out of diffs for clients that pass
+//! `?only_changed=true`.  Stats responses can include mode-appropriate aliases of the hit count fields as well.
+
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder};
+use rocket_contrib::Json;
+use serde::Serialize;
+use serde_json::{self, Number, Value};
+
+use helpers::modes::count_aliases;
+use osutrack_types::diff::DELTA_FIELDS;
+
+/// The fields that are serialized as strings when safe integers are requested
+const SAFE_INTEGER_FIELDS: &'static [&'static str] = &["ranked_score", "total_score"];
+/// The fields that are rounded when a precision is requested
+const PRECISION_FIELDS: &'static [&'static str] = &["pp", "pp_raw", "accuracy"];
+
+/// Recursively replaces the numeric values of all `SAFE_INTEGER_FIELDS` in `value` with their decimal string form
+pub fn stringify_large_integers(value: &mut Value) {
+    match *value {
+        Value::Object(ref mut map) => for (key, val) in map.iter_mut() {
+            let stringified = match *val {
+                Value::Number(ref num) if SAFE_INTEGER_FIELDS.contains(&key.as_str()) => Some(num.to_string()),
+                _ => None,
+            };
+            match stringified {
+                Some(s) => { *val = Value::String(s); },
+                None => stringify_large_integers(val),
+            }
+        },
+        Value::Array(ref mut vals) => for val in vals.iter_mut() {
+            stringify_large_integers(val);
+        },
+        _ => (),
+    }
+}
+
+/// Recursively rounds the numeric values of all `PRECISION_FIELDS` in `value` to `decimals` decimal places
+pub fn round_decimals(value: &mut Value, decimals: u8) {
+    match *value {
+        Value::Object(ref mut map) => for (key, val) in map.iter_mut() {
+            let rounded = match *val {
+                Value::Number(ref num) if PRECISION_FIELDS.contains(&key.as_str()) => num.as_f64().map(|f| {
+                    let factor = 10f64.powi(decimals as i32);
+                    (f * factor).round() / factor
+                }),
+                _ => None,
+            };
+            match rounded.and_then(Number::from_f64) {
+                Some(num) => { *val = Value::Number(num); },
+                None => round_decimals(val, decimals),
+            }
+        },
+        Value::Array(ref mut vals) => for val in vals.iter_mut() {
+            round_decimals(val, decimals);
+        },
+        _ => (),
+    }
+}
+
+/// Removes the `DELTA_FIELDS` of a serialized `UpdateDiff` that are zero.  Fields that are `null` are kept.
+pub fn remove_zero_deltas(value: &mut Value) {
+    if let Value::Object(ref mut map) = *value {
+        for field in DELTA_FIELDS {
+            let is_zero = map.get(*field).and_then(Value::as_f64).map(|delta| delta == 0.).unwrap_or(false);
+            if is_zero {
+                map.remove(*field);
+            }
+        }
+    }
+}
+
+/// Copies each of the raw fields in `aliases` that `value` has into its alias, leaving the raw field in place
+pub fn add_count_aliases(value: &mut Value, aliases: &[(&str, &str)]) {
+    if let Value::Object(ref mut map) = *value {
+        for &(raw, alias) in aliases {
+            if let Some(val) = map.get(raw).cloned() {
+                map.insert(String::from(alias), val);
+            }
+        }
+    }
+}
+
+/// Serializes the wrapped value as JSON like `Json` does, converting the large integer fields to strings if
+/// `safe_integers` is set and rounding pp and accuracy values if `precision` is set.  If `only_changed` is set, the
+/// value must be an `UpdateDiff` and its zero deltas are left out.  The hit counts of stats and diffs are duplicated
+/// under the names in `count_aliases`.
+pub struct SafeJson<T> {
+    pub value: T,
+    pub safe_integers: bool,
+    pub precision: Option<u8>,
+    pub only_changed: bool,
+    pub count_aliases: &'static [(&'static str, &'static str)],
+}
+
+impl<T> SafeJson<T> {
+    pub fn new(value: T, safe_integers: bool) -> SafeJson<T> {
+        SafeJson {
+            value: value, safe_integers: safe_integers, precision: None, only_changed: false, count_aliases: &[],
+        }
+    }
+
+    /// Rounds pp and accuracy values in the response to `precision` decimal places, if supplied
+    pub fn with_precision(self, precision: Option<u8>) -> SafeJson<T> {
+        SafeJson { precision: precision, ..self }
+    }
+
+    /// Leaves out the stats of the wrapped `UpdateDiff` that didn't change if `only_changed` is set
+    pub fn with_only_changed(self, only_changed: bool) -> SafeJson<T> {
+        SafeJson { only_changed: only_changed, ..self }
+    }
+
+    /// Adds the aliases of the hit count fields of the wrapped stats or `UpdateDiff` in `mode`; see `COUNT_ALIASES`
+    pub fn with_count_aliases(self, mode: u8) -> SafeJson<T> {
+        SafeJson { count_aliases: count_aliases(mode), ..self }
+    }
+}
+
+impl<'r, T: Serialize> Responder<'r> for SafeJson<T> {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        if !self.safe_integers && self.precision.is_none() && !self.only_changed && self.count_aliases.is_empty() {
+            return Json(self.value).respond_to(req);
+        }
+
+        let mut value = serde_json::to_value(&self.value).map_err(|err| {
+            println!("Error while serializing JSON response: {:?}", err);
+            Status::InternalServerError
+        })?;
+        // this has to happen before the large integers are stringified so that their zero values are recognized
+        if self.only_changed {
+            remove_zero_deltas(&mut value);
+        }
+        add_count_aliases(&mut value, self.count_aliases);
+        if self.safe_integers {
+            stringify_large_integers(&mut value);
+        }
+        if let Some(precision) = self.precision {
+            round_decimals(&mut value, precision);
+        }
+        Json(value).respond_to(req)
+    }
+}
+
+#[test]
+fn large_integers_round_trip() {
+    use models::Update;
+    use test_support::test_stored_update;
+
+    // 2^53 + 1 is the smallest positive integer that can't be represented exactly as a double
+    let ranked_score: i64 = (1 << 53) + 1;
+    let total_score: i64 = 9_007_199_254_740_993_123;
+    let update = Update { ranked_score: ranked_score, total_score: total_score, ..test_stored_update(1000.25) };
+
+    let mut value = serde_json::to_value(&vec![&update]).unwrap();
+    stringify_large_integers(&mut value);
+    let serialized = serde_json::to_string(&value).unwrap();
+    assert!(serialized.contains("\"ranked_score\":\"9007199254740993\""));
+
+    let parsed: Value = serde_json::from_str(&serialized).unwrap();
+    assert_eq!(parsed[0]["ranked_score"].as_str().unwrap().parse::<i64>().unwrap(), ranked_score);
+    assert_eq!(parsed[0]["total_score"].as_str().unwrap().parse::<i64>().unwrap(), total_score);
+    // other fields are left as numbers
+    assert_eq!(parsed[0]["playcount"], Value::from(50));
+}
+
+#[test]
+fn decimal_rounding() {
+    use std::collections::HashMap;
+
+    let mut value: Value = serde_json::from_str(
+        "{\"pp_raw\":1234.56789,\"accuracy\":98.7654321,\"hiscores\":[{\"pp\":345.678,\"score\":1234567}],\
+        \"level\":30.56789}"
+    ).unwrap();
+    round_decimals(&mut value, 2);
+    assert_eq!(value["pp_raw"], Value::from(1234.57));
+    assert_eq!(value["accuracy"], Value::from(98.77));
+    assert_eq!(value["hiscores"][0]["pp"], Value::from(345.68));
+    assert_eq!(value["hiscores"][0]["score"], Value::from(1234567));
+    // other fields keep their full precision
+    assert_eq!(value["level"], Value::from(30.56789));
+
+    // values serialized from `f32`s lose the noise from being widened
+    let mut value = serde_json::to_value(&vec![("accuracy", 98.12f32)].into_iter().collect::<HashMap<_, _>>()).unwrap();
+    round_decimals(&mut value, 2);
+    assert_eq!(serde_json::to_string(&value).unwrap(), "{\"accuracy\":98.12}");
+
+    round_decimals(&mut value, 0);
+    assert_eq!(value["accuracy"], Value::from(98.));
+}
+
+#[test]
+fn zero_delta_removal() {
+    let mut value: Value = serde_json::from_str(
+        "{\"first_update\":false,\"count300\":0,\"playcount\":3,\"ranked_score\":0,\"pp_raw\":0.0,\
+        \"accuracy\":-0.25,\"pp_country_rank\":null,\"newhs\":[],\"stored_update_id\":0}"
+    ).unwrap();
+    remove_zero_deltas(&mut value);
+
+    let expected: Value = serde_json::from_str(
+        "{\"first_update\":false,\"playcount\":3,\"accuracy\":-0.25,\"pp_country_rank\":null,\"newhs\":[],\
+        \"stored_update_id\":0}"
+    ).unwrap();
+    assert_eq!(value, expected);
+}
+
+#[test]
+fn count_alias_fields() {
+    use helpers::modes::{CTB, STANDARD};
+
+    let mut value: Value = serde_json::from_str("{\"count300\":500,\"count100\":40,\"playcount\":3}").unwrap();
+    add_count_aliases(&mut value, count_aliases(STANDARD));
+    assert_eq!(value.as_object().unwrap().len(), 3);
+
+    // fields missing from the value, such as zero deltas that were left out, don't get aliases either
+    add_count_aliases(&mut value, count_aliases(CTB));
+    let expected: Value = serde_json::from_str(
+        "{\"count300\":500,\"count100\":40,\"playcount\":3,\"fruits\":500,\"drops\":40}"
+    ).unwrap();
+    assert_eq!(value, expected);
+}
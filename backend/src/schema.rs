@@ -0,0 +1,4 @@
+//! Definitions of the database tables.  These live in the `osutrack-types` crate behind its `diesel` feature; see
+//! `types/src/schema.rs`.
+
+pub use osutrack_types::schema::*;
@@ -0,0 +1,357 @@
+//! Renders the profile signature images served by `/sig`: small fixed-size PNG cards showing a user's stats that can be
+//! embedded in forum signatures.  Text is drawn with a built-in 5x7 bitmap font and the PNG is encoded by hand, so the
+//! output for a given input is always byte-for-byte the same.
+
+use std::io::Write;
+
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+
+use helpers::modes::GameMode;
+
+/// The width of signature images in pixels
+pub const SIG_WIDTH: u32 = 360;
+/// The height of signature images in pixels
+pub const SIG_HEIGHT: u32 = 80;
+
+/// The width of glyphs in the built-in font before scaling.  They're all 7 pixels tall.
+const GLYPH_WIDTH: u32 = 5;
+/// The factor that glyphs are scaled up by when they're drawn
+const TEXT_SCALE: u32 = 2;
+
+type Rgb = [u8; 3];
+
+const BACKGROUND: Rgb = [0x2b, 0x2b, 0x3b];
+const BORDER: Rgb = [0x44, 0x44, 0x5c];
+const ACCENT: Rgb = [0xff, 0x66, 0xaa];
+const TEXT: Rgb = [0xff, 0xff, 0xff];
+const MUTED_TEXT: Rgb = [0xa0, 0xa0, 0xb4];
+const GAIN: Rgb = [0x88, 0xdd, 0x88];
+const LOSS: Rgb = [0xee, 0x77, 0x77];
+
+/// The stats shown on a user's signature, all taken from their stored updates
+#[derive(Clone, Debug, PartialEq)]
+pub struct SigStats {
+    pub username: String,
+    pub mode: u8,
+    pub pp_raw: f32,
+    pub pp_rank: i32,
+    pub accuracy: f32,
+    /// The number of ranks gained over the last 7 days, negative if ranks were lost, or `None` if the user has no
+    /// update from before then to compare against
+    pub rank_delta_7d: Option<i32>,
+}
+
+/// Returns the rows of the built-in font's glyph for `c`, with the leftmost column of each row in the fifth bit.
+/// Lowercase letters are drawn as uppercase ones and characters that the font doesn't have are drawn as `?`.
+fn glyph(c: char) -> [u8; 7] {
+    match c.to_ascii_uppercase() {
+        '0' => [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e],
+        '1' => [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e],
+        '2' => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f],
+        '3' => [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e],
+        '4' => [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02],
+        '5' => [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e],
+        '6' => [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e],
+        '7' => [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e],
+        '9' => [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c],
+        'A' => [0x0e, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11],
+        'B' => [0x1e, 0x11, 0x11, 0x1e, 0x11, 0x11, 0x1e],
+        'C' => [0x0e, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0e],
+        'D' => [0x1c, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1c],
+        'E' => [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x1f],
+        'F' => [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x10],
+        'G' => [0x0e, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0f],
+        'H' => [0x11, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11],
+        'I' => [0x0e, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0e],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0c],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1f],
+        'M' => [0x11, 0x1b, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0e, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e],
+        'P' => [0x1e, 0x11, 0x11, 0x1e, 0x10, 0x10, 0x10],
+        'Q' => [0x0e, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0d],
+        'R' => [0x1e, 0x11, 0x11, 0x1e, 0x14, 0x12, 0x11],
+        'S' => [0x0f, 0x10, 0x10, 0x0e, 0x01, 0x01, 0x1e],
+        'T' => [0x1f, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0a, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0a],
+        'X' => [0x11, 0x11, 0x0a, 0x04, 0x0a, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0a, 0x04, 0x04, 0x04],
+        'Z' => [0x1f, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1f],
+        ' ' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0c, 0x04, 0x08],
+        ':' => [0x00, 0x0c, 0x0c, 0x00, 0x0c, 0x0c, 0x00],
+        '#' => [0x0a, 0x0a, 0x1f, 0x0a, 0x1f, 0x0a, 0x0a],
+        '%' => [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03],
+        '+' => [0x00, 0x04, 0x04, 0x1f, 0x04, 0x04, 0x00],
+        '-' => [0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x00],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1f],
+        '[' => [0x0e, 0x08, 0x08, 0x08, 0x08, 0x08, 0x0e],
+        ']' => [0x0e, 0x02, 0x02, 0x02, 0x02, 0x02, 0x0e],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '!' => [0x04, 0x04, 0x04, 0x04, 0x04, 0x00, 0x04],
+        _ => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+    }
+}
+
+/// An RGB image that signatures are drawn onto.  Anything drawn outside of its bounds is clipped.
+struct Canvas {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    fn new(width: u32, height: u32, background: Rgb) -> Canvas {
+        let pixels = (0..width * height).flat_map(|_| background.iter().cloned()).collect();
+        Canvas { width: width, height: height, pixels: pixels }
+    }
+
+    fn set_pixel(&mut self, x: u32, y: u32, color: Rgb) {
+        if x < self.width && y < self.height {
+            let offset = ((y * self.width + x) * 3) as usize;
+            self.pixels[offset..offset + 3].copy_from_slice(&color);
+        }
+    }
+
+    fn fill_rect(&mut self, x: u32, y: u32, width: u32, height: u32, color: Rgb) {
+        for py in y..y + height {
+            for px in x..x + width {
+                self.set_pixel(px, py, color);
+            }
+        }
+    }
+
+    /// Draws `text` with its top left corner at `(x, y)`, returning the x coordinate just past its end
+    fn draw_text(&mut self, x: u32, y: u32, text: &str, color: Rgb) -> u32 {
+        let mut cursor = x;
+        for c in text.chars() {
+            for (row, bits) in glyph(c).iter().enumerate() {
+                for col in 0..GLYPH_WIDTH {
+                    if bits & (1 << (GLYPH_WIDTH - 1 - col)) != 0 {
+                        let (px, py) = (cursor + col * TEXT_SCALE, y + row as u32 * TEXT_SCALE);
+                        self.fill_rect(px, py, TEXT_SCALE, TEXT_SCALE, color);
+                    }
+                }
+            }
+            cursor += (GLYPH_WIDTH + 1) * TEXT_SCALE;
+        }
+        cursor
+    }
+
+    /// Returns the width in pixels that `text` takes up when drawn
+    fn text_width(text: &str) -> u32 {
+        text.chars().count() as u32 * (GLYPH_WIDTH + 1) * TEXT_SCALE
+    }
+
+    /// Draws the background, border, and accent bar shared by all cards along with the username and mode
+    fn draw_frame(&mut self, username: &str, mode: Option<GameMode>) {
+        let (width, height) = (self.width, self.height);
+        self.fill_rect(0, 0, width, 1, BORDER);
+        self.fill_rect(0, height - 1, width, 1, BORDER);
+        self.fill_rect(width - 1, 0, 1, height, BORDER);
+        self.fill_rect(0, 0, 6, height, ACCENT);
+
+        self.draw_text(16, 10, username, TEXT);
+        if let Some(mode) = mode {
+            let name = mode.to_string();
+            let x = width.saturating_sub(Canvas::text_width(&name) + 12);
+            self.draw_text(x, 10, &name, MUTED_TEXT);
+        }
+    }
+}
+
+/// Computes the CRC-32 that PNG chunks are checksummed with
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Appends a PNG chunk with the given type and data to `png`
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    let mut checksummed = Vec::with_capacity(kind.len() + data.len());
+    checksummed.extend_from_slice(kind);
+    checksummed.extend_from_slice(data);
+
+    png.extend_from_slice(&be_bytes(data.len() as u32));
+    png.extend_from_slice(&checksummed);
+    png.extend_from_slice(&be_bytes(crc32(&checksummed)));
+}
+
+fn be_bytes(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+/// Encodes the canvas as an 8-bit RGB PNG
+fn encode_png(canvas: &Canvas) -> Vec<u8> {
+    let row_len = (canvas.width * 3) as usize;
+    // every scanline starts with its filter type, which is always 0 (none)
+    let mut raw = Vec::with_capacity((row_len + 1) * canvas.height as usize);
+    for row in canvas.pixels.chunks(row_len) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&raw).expect("Writing to a `Vec` can't fail");
+    let compressed = encoder.finish().expect("Writing to a `Vec` can't fail");
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&be_bytes(canvas.width));
+    header.extend_from_slice(&be_bytes(canvas.height));
+    // 8 bits per channel, RGB, default compression and filtering, not interlaced
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut png = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &compressed);
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+/// Returns the text describing the change in a user's rank over the last 7 days along with its color
+fn rank_delta_text(rank_delta: i32) -> (String, Rgb) {
+    match rank_delta {
+        0 => (String::from("NO CHANGE IN 7 DAYS"), MUTED_TEXT),
+        delta if delta > 0 => (format!("+{} RANKS IN 7 DAYS", delta), GAIN),
+        delta => (format!("{} RANKS IN 7 DAYS", delta), LOSS),
+    }
+}
+
+/// Draws the signature of a user with stored stats
+fn draw_sig(stats: &SigStats) -> Canvas {
+    let mut canvas = Canvas::new(SIG_WIDTH, SIG_HEIGHT, BACKGROUND);
+    canvas.draw_frame(&stats.username, GameMode::from_u8(stats.mode));
+
+    let x = canvas.draw_text(16, 32, &format!("{:.0}PP", stats.pp_raw), TEXT);
+    let x = canvas.draw_text(x + 12, 32, &format!("#{}", stats.pp_rank), TEXT);
+    canvas.draw_text(x + 12, 32, &format!("{:.2}%", stats.accuracy), TEXT);
+    if let Some(rank_delta) = stats.rank_delta_7d {
+        let (text, color) = rank_delta_text(rank_delta);
+        canvas.draw_text(16, 54, &text, color);
+    }
+
+    canvas
+}
+
+/// Draws the card shown in place of a signature for users without stored stats in the mode
+fn draw_placeholder(username: &str) -> Canvas {
+    let mut canvas = Canvas::new(SIG_WIDTH, SIG_HEIGHT, BACKGROUND);
+    canvas.draw_frame(username, None);
+    canvas.draw_text(16, 32, "NO DATA YET", MUTED_TEXT);
+    canvas.draw_text(16, 54, "UPDATE ON OSU!TRACK", MUTED_TEXT);
+    canvas
+}
+
+/// Renders the signature image of a user as a PNG
+pub fn render_sig(stats: &SigStats) -> Vec<u8> {
+    encode_png(&draw_sig(stats))
+}
+
+/// Renders the placeholder image served for users without any stored stats in the mode, or for requests that can't be
+/// served at all, as a PNG.  `username` is shown as supplied.
+pub fn render_placeholder(username: &str) -> Vec<u8> {
+    encode_png(&draw_placeholder(username))
+}
+
+#[cfg(test)]
+fn test_stats() -> SigStats {
+    SigStats {
+        username: String::from("Ameo"), mode: 0, pp_raw: 4321.5, pp_rank: 1234, accuracy: 98.25,
+        rank_delta_7d: Some(56),
+    }
+}
+
+/// Checksums the canvas' pixels with CRC-32 rather than the standard library's hasher, whose output isn't guaranteed to
+/// stay the same between Rust releases
+#[cfg(test)]
+fn pixel_hash(canvas: &Canvas) -> u32 {
+    crc32(&canvas.pixels)
+}
+
+/// The checksum that PNG chunks use is the standard CRC-32
+#[test]
+fn png_crc() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    assert_eq!(crc32(b"IEND"), 0xae42_6082);
+}
+
+/// Rendered signatures are well-formed PNGs of the signature's size whose pixels decompress back out of them
+#[test]
+fn sig_png_structure() {
+    use std::io::Read;
+    use flate2::read::ZlibDecoder;
+
+    let canvas = draw_sig(&test_stats());
+    let png = encode_png(&canvas);
+    assert_eq!(&png[..8], &[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n']);
+    assert_eq!(&png[12..16], b"IHDR");
+    assert_eq!(&png[16..24], &[0, 0, 1, 104, 0, 0, 0, 80]);
+    assert_eq!(&png[png.len() - 12..], &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]);
+
+    // every chunk's checksum covers its type and data
+    let mut offset = 8;
+    let mut idat = Vec::new();
+    while offset < png.len() {
+        let len = ((png[offset] as usize) << 24) | ((png[offset + 1] as usize) << 16) |
+            ((png[offset + 2] as usize) << 8) | png[offset + 3] as usize;
+        let checksummed = &png[offset + 4..offset + 8 + len];
+        assert_eq!(&be_bytes(crc32(checksummed)), &png[offset + 8 + len..offset + 12 + len]);
+        if &checksummed[..4] == b"IDAT" {
+            idat.extend_from_slice(&checksummed[4..]);
+        }
+        offset += 12 + len;
+    }
+
+    let mut raw = Vec::new();
+    ZlibDecoder::new(&idat[..]).read_to_end(&mut raw).unwrap();
+    assert_eq!(raw.len(), (SIG_WIDTH as usize * 3 + 1) * SIG_HEIGHT as usize);
+    let first_row = &raw[1..SIG_WIDTH as usize * 3 + 1];
+    assert_eq!(first_row, &canvas.pixels[..SIG_WIDTH as usize * 3]);
+}
+
+/// Rendering is deterministic, so the same stats always produce the same image, while any change to the stats shows
+#[test]
+fn sig_rendering() {
+    let stats = test_stats();
+    assert_eq!(pixel_hash(&draw_sig(&stats)), 0x2567_4070);
+    assert_eq!(render_sig(&stats), render_sig(&stats));
+
+    let changed = vec![
+        SigStats { pp_raw: 4400.25, ..test_stats() },
+        SigStats { pp_rank: 1233, ..test_stats() },
+        SigStats { accuracy: 98.5, ..test_stats() },
+        SigStats { rank_delta_7d: Some(-56), ..test_stats() },
+        SigStats { rank_delta_7d: None, ..test_stats() },
+        SigStats { mode: 3, ..test_stats() },
+    ];
+    for stats in changed {
+        assert_ne!(pixel_hash(&draw_sig(&stats)), pixel_hash(&draw_sig(&test_stats())), "{:?}", stats);
+    }
+
+    // the placeholder is the same size and differs from any real signature
+    let placeholder = draw_placeholder("Ameo");
+    assert_eq!((placeholder.width, placeholder.height), (SIG_WIDTH, SIG_HEIGHT));
+    assert_ne!(pixel_hash(&placeholder), pixel_hash(&draw_sig(&test_stats())));
+    assert_eq!(pixel_hash(&placeholder), pixel_hash(&draw_placeholder("ameo")));
+}
+
+#[test]
+fn rank_delta_descriptions() {
+    assert_eq!(rank_delta_text(56), (String::from("+56 RANKS IN 7 DAYS"), GAIN));
+    assert_eq!(rank_delta_text(-3), (String::from("-3 RANKS IN 7 DAYS"), LOSS));
+    assert_eq!(rank_delta_text(0).0, "NO CHANGE IN 7 DAYS");
+}
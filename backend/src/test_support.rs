@@ -0,0 +1,183 @@
+//! Shared setup for tests: a database pool whose writes are never committed, a mock of the osu! API that serves
+//! fixture responses, loading of those fixtures, and models for tests to store.  Nothing here touches the network
+//! beyond the loopback interface.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+use diesel::Connection;
+use diesel::mysql::MysqlConnection;
+use r2d2::{CustomizeConnection, Pool};
+use r2d2_diesel::{self, ConnectionManager};
+
+use helpers::database_url;
+use models::{NewUpdate, Update};
+
+pub use osutrack_types::test_support::test_beatmap;
+
+/// Starts a test transaction on every connection as it's established so that nothing written through it is committed
+#[derive(Debug)]
+struct TestTransaction;
+
+impl CustomizeConnection<MysqlConnection, r2d2_diesel::Error> for TestTransaction {
+    fn on_acquire(&self, conn: &mut MysqlConnection) -> Result<(), r2d2_diesel::Error> {
+        conn.begin_test_transaction().map_err(r2d2_diesel::Error::QueryError)
+    }
+}
+
+/// Creates a pool with a single connection to the test database (see `helpers::database_url`) that is inside of a test
+/// transaction.  Everything written through the pool, including by threads that the code under test spawns, is rolled
+/// back when the pool is dropped, and everything written is visible to later reads through the pool.
+pub fn test_pool() -> Pool<ConnectionManager<MysqlConnection>> {
+    let manager = ConnectionManager::<MysqlConnection>::new(database_url());
+    Pool::builder()
+        .max_size(1)
+        .connection_timeout(Duration::from_secs(5))
+        .connection_customizer(Box::new(TestTransaction))
+        .build(manager)
+        .expect("Unable to connect to the test database")
+}
+
+/// Returns the contents of the file `name` in the `fixtures` directory
+pub fn fixture(name: &str) -> String {
+    let path = format!("{}/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+    let mut contents = String::new();
+    File::open(&path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .unwrap_or_else(|err| panic!("Unable to read fixture {}: {}", path, err));
+    contents
+}
+
+/// Returns the stats of user 1 in standard with `pp_raw` pp.  Tests that care about the user or their other stats
+/// override them.
+pub fn test_update(pp_raw: f32) -> NewUpdate {
+    NewUpdate {
+        user_id: 1, mode: 0, count300: 1000, count100: 100, count50: 10, playcount: 50, ranked_score: 100000,
+        total_score: 200000, pp_rank: 50000, level: 30.5, pp_raw: pp_raw, accuracy: 97.5, count_rank_ss: 1,
+        count_rank_s: 5, count_rank_a: 10, pp_country_rank: Some(1000), source: None, total_hits: 1110,
+    }
+}
+
+/// Returns the stats of `test_update` as they'd be loaded from the database after being recorded as update 1
+pub fn test_stored_update(pp_raw: f32) -> Update {
+    let cur = test_update(pp_raw);
+    Update {
+        id: 1, user_id: cur.user_id, mode: cur.mode, count300: cur.count300, count100: cur.count100,
+        count50: cur.count50, playcount: cur.playcount, ranked_score: cur.ranked_score, total_score: cur.total_score,
+        pp_rank: cur.pp_rank, level: cur.level, pp_raw: cur.pp_raw, accuracy: cur.accuracy,
+        count_rank_ss: cur.count_rank_ss, count_rank_s: cur.count_rank_s, count_rank_a: cur.count_rank_a,
+        pp_country_rank: cur.pp_country_rank, update_time: NaiveDateTime::from_timestamp(1500000000, 0),
+        source: None, total_hits: cur.total_hits,
+    }
+}
+
+pub fn mock_response(status_line: &str, extra_headers: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status_line, extra_headers, body.len(), body
+    )
+}
+
+/// Starts a server on a random local port that responds to a single request with `response`, returning its URL
+pub fn serve_once(response: String) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0u8; 4096];
+        let _ = stream.read(&mut buf);
+        let _ = stream.write_all(response.as_bytes());
+    });
+
+    url
+}
+
+/// A mock of the osu! API running on a random local port.  Requests are answered by the name of the endpoint that they
+/// were made to, such as `get_user`; requests to endpoints without a response get a 404.
+pub struct MockApi {
+    /// The base URL of the mock, to be passed to `ApiClient::with_mock_api`
+    pub url: String,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+}
+
+/// A request received by a `MockApi`
+#[derive(Clone)]
+struct MockRequest {
+    /// The path and query of the request
+    target: String,
+    user_agent: Option<String>,
+}
+
+/// Reads the head of a request from `stream`
+fn read_request(stream: &mut TcpStream) -> MockRequest {
+    let mut buf = [0u8; 8192];
+    let len = stream.read(&mut buf).unwrap_or(0);
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let mut lines = request.lines();
+
+    let target = lines.next().and_then(|line| line.split_whitespace().nth(1)).map(String::from).unwrap_or_default();
+    let user_agent = lines
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name.eq_ignore_ascii_case("user-agent") => Some(value.trim()),
+                _ => None,
+            }
+        })
+        .next()
+        .map(String::from);
+
+    MockRequest { target: target, user_agent: user_agent }
+}
+
+impl MockApi {
+    /// Starts the mock, responding to requests to each of the endpoints in `responses` with its body
+    pub fn start(responses: Vec<(&'static str, String)>) -> MockApi {
+        MockApi::with_handler(move |target| {
+            let endpoint = target.split('?').next().unwrap_or("").trim_left_matches('/');
+            responses.iter().find(|&&(name, _)| name == endpoint).map(|&(_, ref body)| body.clone())
+        })
+    }
+
+    /// Starts the mock, responding to each request with the body that `handler` returns for its path and query or
+    /// with a 404 if it returns `None`
+    pub fn with_handler<F>(handler: F) -> MockApi
+        where F: Fn(&str) -> Option<String> + Send + 'static
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = requests.clone();
+        thread::spawn(move || for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => { continue; },
+            };
+            let request = read_request(&mut stream);
+            let response = match handler(&request.target) {
+                Some(body) => mock_response("200 OK", "", &body),
+                None => mock_response("404 Not Found", "", ""),
+            };
+            recorded.lock().unwrap().push(request);
+            let _ = stream.write_all(response.as_bytes());
+        });
+
+        MockApi { url: url, requests: requests }
+    }
+
+    /// The paths and queries of the requests that the mock has received so far, oldest first
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().iter().map(|request| request.target.clone()).collect()
+    }
+
+    /// The `User-Agent` headers of the requests that the mock has received so far, oldest first
+    pub fn user_agents(&self) -> Vec<Option<String>> {
+        self.requests.lock().unwrap().iter().map(|request| request.user_agent.clone()).collect()
+    }
+}
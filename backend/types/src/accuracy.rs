@@ -0,0 +1,109 @@
+//! Functions for computing the accuracy of individual plays from their hit counts.  Each mode weights its judgements
+//! differently, so the formulas are mode-specific.
+
+use mode::{STANDARD, TAIKO, CTB, MANIA};
+
+/// The judgement counts of a single play.  The meaning of each count depends on the mode:
+///
+///  - standard: 300s, 100s, 50s, and misses (`countkatu`/`countgeki` are unused)
+///  - taiko: greats (`count300`), goods (`count100`), and misses
+///  - ctb: fruits (`count300`), drops (`count100`), caught droplets (`count50`), missed droplets (`countkatu`), and
+///    misses
+///  - mania: MAXes (`countgeki`), 300s, 200s (`countkatu`), 100s, 50s, and misses
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HitCounts {
+    pub count300: i32,
+    pub count100: i32,
+    pub count50: i32,
+    pub countmiss: i32,
+    pub countkatu: i32,
+    pub countgeki: i32,
+}
+
+impl HitCounts {
+    /// Builds a `HitCounts` from individually optional counts, returning `None` if any of them are missing.
+    pub fn from_parts(
+        count300: Option<i32>, count100: Option<i32>, count50: Option<i32>, countmiss: Option<i32>,
+        countkatu: Option<i32>, countgeki: Option<i32>
+    ) -> Option<HitCounts> {
+        Some(HitCounts {
+            count300: count300?,
+            count100: count100?,
+            count50: count50?,
+            countmiss: countmiss?,
+            countkatu: countkatu?,
+            countgeki: countgeki?,
+        })
+    }
+}
+
+/// Computes the accuracy of a play as a percentage from 0 to 100 in the same format that the osu! API reports user
+/// accuracy.  Returns `None` if the mode is unknown, the counts weren't recorded, or the play has no judgements at all.
+pub fn compute(mode: u8, counts: Option<HitCounts>) -> Option<f64> {
+    let c = counts?;
+    let (count300, count100, count50) = (c.count300 as f64, c.count100 as f64, c.count50 as f64);
+    let (countmiss, countkatu, countgeki) = (c.countmiss as f64, c.countkatu as f64, c.countgeki as f64);
+
+    let (hit_value, max_value) = match mode {
+        STANDARD => (
+            300. * count300 + 100. * count100 + 50. * count50,
+            300. * (count300 + count100 + count50 + countmiss),
+        ),
+        TAIKO => (
+            count300 + 0.5 * count100,
+            count300 + count100 + countmiss,
+        ),
+        CTB => (
+            count300 + count100 + count50,
+            count300 + count100 + count50 + countkatu + countmiss,
+        ),
+        MANIA => (
+            300. * (countgeki + count300) + 200. * countkatu + 100. * count100 + 50. * count50,
+            300. * (countgeki + count300 + countkatu + count100 + count50 + countmiss),
+        ),
+        _ => { return None; },
+    };
+
+    if max_value <= 0. {
+        return None;
+    }
+
+    Some(100. * hit_value / max_value)
+}
+
+#[cfg(test)]
+fn counts(
+    count300: i32, count100: i32, count50: i32, countmiss: i32, countkatu: i32, countgeki: i32
+) -> Option<HitCounts> {
+    Some(HitCounts { count300, count100, count50, countmiss, countkatu, countgeki })
+}
+
+/// Check each mode's formula against plays with known accuracies
+#[test]
+fn accuracy_per_mode() {
+    let cases: Vec<(u8, Option<HitCounts>, f64)> = vec![
+        (STANDARD, counts(950, 40, 5, 5, 12, 180), 96.416_667),
+        (STANDARD, counts(1000, 0, 0, 0, 0, 0), 100.),
+        (STANDARD, counts(0, 0, 0, 10, 0, 0), 0.),
+        (TAIKO, counts(900, 80, 0, 20, 0, 0), 94.),
+        (TAIKO, counts(500, 0, 0, 0, 0, 0), 100.),
+        (CTB, counts(500, 100, 380, 5, 15, 0), 98.),
+        (CTB, counts(200, 20, 75, 0, 5, 0), 98.333_333),
+        (MANIA, counts(300, 30, 10, 10, 50, 600), 94.5),
+        (MANIA, counts(0, 0, 0, 0, 0, 1000), 100.),
+    ];
+
+    for (mode, hit_counts, expected) in cases {
+        let accuracy = compute(mode, hit_counts).unwrap();
+        assert!((accuracy - expected).abs() < 0.0001, "mode {}: expected {}, got {}", mode, expected, accuracy);
+    }
+}
+
+/// Make sure that missing counts, empty plays, and unknown modes don't produce a number
+#[test]
+fn accuracy_unknown() {
+    assert_eq!(compute(STANDARD, None), None);
+    assert_eq!(compute(STANDARD, counts(0, 0, 0, 0, 0, 0)), None);
+    assert_eq!(compute(4, counts(100, 0, 0, 0, 0, 0)), None);
+    assert_eq!(HitCounts::from_parts(Some(1), Some(2), Some(3), Some(4), None, Some(6)), None);
+}
@@ -0,0 +1,118 @@
+//! The ranking statuses of beatmaps, which the osu! API reports as numeric codes in the `approved` field
+
+use std::fmt;
+
+use serde::{Serialize, Serializer};
+
+pub const LOVED: i16 = 4;
+pub const QUALIFIED: i16 = 3;
+pub const APPROVED: i16 = 2;
+pub const RANKED: i16 = 1;
+pub const PENDING: i16 = 0;
+pub const WIP: i16 = -1;
+pub const GRAVEYARD: i16 = -2;
+
+/// A beatmap's approval status.  Serializes as its lowercase name, or as `"unknown(<code>)"` for codes that aren't
+/// known so that new statuses added to the osu! API don't cause errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ApprovalStatus {
+    Loved,
+    Qualified,
+    Approved,
+    Ranked,
+    Pending,
+    Wip,
+    Graveyard,
+    Unknown(i16),
+}
+
+impl ApprovalStatus {
+    pub fn from_code(code: i16) -> ApprovalStatus {
+        match code {
+            LOVED => ApprovalStatus::Loved,
+            QUALIFIED => ApprovalStatus::Qualified,
+            APPROVED => ApprovalStatus::Approved,
+            RANKED => ApprovalStatus::Ranked,
+            PENDING => ApprovalStatus::Pending,
+            WIP => ApprovalStatus::Wip,
+            GRAVEYARD => ApprovalStatus::Graveyard,
+            code => ApprovalStatus::Unknown(code),
+        }
+    }
+
+    /// Returns the numeric code used for the status by the osu! API
+    pub fn code(&self) -> i16 {
+        match *self {
+            ApprovalStatus::Loved => LOVED,
+            ApprovalStatus::Qualified => QUALIFIED,
+            ApprovalStatus::Approved => APPROVED,
+            ApprovalStatus::Ranked => RANKED,
+            ApprovalStatus::Pending => PENDING,
+            ApprovalStatus::Wip => WIP,
+            ApprovalStatus::Graveyard => GRAVEYARD,
+            ApprovalStatus::Unknown(code) => code,
+        }
+    }
+
+    /// Returns `true` if beatmaps with this status can no longer be changed by their mappers.  Beatmaps with any other
+    /// status may still be updated, so cached copies of them go stale.
+    pub fn is_final(&self) -> bool {
+        match *self {
+            ApprovalStatus::Ranked | ApprovalStatus::Approved | ApprovalStatus::Loved => true,
+            _ => false,
+        }
+    }
+}
+
+impl From<i16> for ApprovalStatus {
+    fn from(code: i16) -> ApprovalStatus {
+        ApprovalStatus::from_code(code)
+    }
+}
+
+impl fmt::Display for ApprovalStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            ApprovalStatus::Loved => "loved",
+            ApprovalStatus::Qualified => "qualified",
+            ApprovalStatus::Approved => "approved",
+            ApprovalStatus::Ranked => "ranked",
+            ApprovalStatus::Pending => "pending",
+            ApprovalStatus::Wip => "wip",
+            ApprovalStatus::Graveyard => "graveyard",
+            ApprovalStatus::Unknown(code) => { return write!(f, "unknown({})", code); },
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Serialize for ApprovalStatus {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[test]
+fn approval_status_codes() {
+    let statuses = [
+        (4, ApprovalStatus::Loved, "loved", true),
+        (3, ApprovalStatus::Qualified, "qualified", false),
+        (2, ApprovalStatus::Approved, "approved", true),
+        (1, ApprovalStatus::Ranked, "ranked", true),
+        (0, ApprovalStatus::Pending, "pending", false),
+        (-1, ApprovalStatus::Wip, "wip", false),
+        (-2, ApprovalStatus::Graveyard, "graveyard", false),
+    ];
+    for &(code, status, name, is_final) in statuses.iter() {
+        assert_eq!(ApprovalStatus::from(code), status);
+        assert_eq!(status.code(), code);
+        assert_eq!(::serde_json::to_string(&status).unwrap(), format!("\"{}\"", name));
+        assert_eq!(status.is_final(), is_final);
+    }
+
+    let unknown = ApprovalStatus::from(7);
+    assert_eq!(unknown, ApprovalStatus::Unknown(7));
+    assert_eq!(unknown.code(), 7);
+    assert_eq!(::serde_json::to_string(&unknown).unwrap(), "\"unknown(7)\"");
+    assert!(!unknown.is_final());
+}
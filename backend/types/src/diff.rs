@@ -0,0 +1,465 @@
+//! The difference between two snapshots of a user's stats, as returned by the update routes
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+use accuracy;
+use pp::weighted_pp;
+use models::{Beatmap, Update, NewUpdate, Hiscore, NewHiscore, User};
+
+/// The score that a new hiscore replaced on the same beatmap
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PreviousScore {
+    pub score: i32,
+    pub pp: f32,
+    pub enabled_mods: i32,
+}
+
+/// A new hiscore as included in an `UpdateDiff`, along with the previously recorded score on the same beatmap if there
+/// was one.  Serializes as the flattened `NewHiscore` with an extra `replaces` field.
+#[derive(Deserialize, Serialize)]
+pub struct DiffHiscore {
+    #[serde(flatten)]
+    pub hiscore: NewHiscore,
+    pub replaces: Option<PreviousScore>,
+    /// The accuracy of the play computed from its hit counts, or `null` if they weren't available
+    pub accuracy: Option<f64>,
+    /// The beatmap that the play was set on.  Omitted unless it was requested and `null` if it couldn't be found.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub beatmap: Option<Option<Beatmap>>,
+}
+
+impl DiffHiscore {
+    pub fn new(hiscore: NewHiscore, replaces: Option<PreviousScore>) -> DiffHiscore {
+        let accuracy = accuracy::compute(hiscore.mode as u8, hiscore.hit_counts());
+        DiffHiscore { hiscore: hiscore, replaces: replaces, accuracy: accuracy, beatmap: None }
+    }
+}
+
+/// The numeric fields of an `UpdateDiff` that hold the changes in the user's stats
+pub const DELTA_FIELDS: &'static [&'static str] = &[
+    "count300", "count100", "count50", "total_hits", "playcount", "ranked_score", "total_score", "pp_rank", "level",
+    "pp_raw", "accuracy", "count_rank_ss", "count_rank_s", "count_rank_a", "pp_country_rank",
+];
+
+/// Holds the changes between two updates
+#[derive(Deserialize, Serialize)]
+pub struct UpdateDiff {
+    pub first_update: bool,
+    pub count300: i32,
+    pub count100: i32,
+    pub count50: i32,
+    #[serde(default)]
+    pub total_hits: i64,
+    pub playcount: i32,
+    pub ranked_score: i64,
+    pub total_score: i64,
+    pub pp_rank: i32,
+    pub level: f32,
+    pub pp_raw: f32,
+    pub accuracy: f32,
+    pub count_rank_ss: i32,
+    pub count_rank_s: i32,
+    pub count_rank_a: i32,
+    /// `null` if the user didn't have a country ranking in either of the updates
+    pub pp_country_rank: Option<i32>,
+    pub newhs: Vec<DiffHiscore>,
+    /// The number of new hiscores in `newhs`
+    #[serde(default)]
+    pub new_hiscore_count: usize,
+    /// How much the new hiscores added to the weighted pp of the user's top plays, taking into account the plays that
+    /// they pushed down and the scores that they replaced.
+    #[serde(default)]
+    pub new_weighted_pp_gain: f32,
+    /// Set if this is the first update and `newhs` only contains the user's best few new hiscores.  Omitted otherwise.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub newhs_truncated: bool,
+    /// The total number of new hiscores if `newhs` was truncated.  Omitted otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_new: Option<usize>,
+    /// Set if more top plays were requested with `?deep=true` than the osu! API could provide, so hiscores beyond the
+    /// user's top 100 weren't checked.  Omitted otherwise.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub hiscores_truncated: bool,
+    /// Hiscores that aren't stored yet but weren't reported in `newhs`, either because they were set before the
+    /// previous update or because `newhs` was truncated.  They still need to be stored.
+    #[serde(skip)]
+    pub stale_hs: Vec<NewHiscore>,
+    /// Stored hiscores that were among the user's top plays but have been pushed out of them.  Only determined if
+    /// `DiffOptions::track_dropped` is set; omitted if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dropped_hs: Vec<Hiscore>,
+    /// The ids of stored hiscores that had been dropped from the user's top plays but are among them again
+    #[serde(skip)]
+    pub reappeared_hs: Vec<i32>,
+    /// The id of the update row that was inserted while computing this diff, or `null` if none was
+    pub stored_update_id: Option<i32>,
+    /// The user's row as it is after the update, included by `/update` so that clients don't need to fetch it
+    /// separately.  Omitted otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<User>,
+    /// The names of the stats that only ever grow for an account in good standing but decreased between the two
+    /// updates, which they only do if the account was reset or the osu! API returned inconsistent stats.  See
+    /// `find_unexpected_decreases` for which stats are checked.  Omitted if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unexpected_decreases: Vec<String>,
+}
+
+/// Options controlling how an `UpdateDiff` is computed
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DiffOptions {
+    /// If set, only hiscores set after the previous update are reported as new.  Unstored hiscores that are older than
+    /// that are placed in `stale_hs` instead.  Has no effect if there is no previous update.
+    pub recent_only: bool,
+    /// If set, at most this many of the new hiscores (those with the most pp) are reported in `newhs` on a user's first
+    /// update, with the rest placed in `stale_hs`.  Has no effect if there is a previous update.
+    pub first_update_limit: Option<usize>,
+    /// Set if the new hiscores are all of the user's top plays, in which case stored hiscores that aren't among them
+    /// anymore are reported in `dropped_hs`.  Has no effect if there is no previous update.
+    pub track_dropped: bool,
+}
+
+fn is_false(val: &bool) -> bool {
+    !*val
+}
+
+/// The change in country rank between two updates.  A rank that appears or disappears isn't a change that can be
+/// expressed as a number, so the change is only known if both updates have one.
+fn country_rank_diff(prev: Option<i32>, cur: Option<i32>) -> Option<i32> {
+    match (prev, cur) {
+        (Some(prev), Some(cur)) => Some(delta_i32(prev, cur)),
+        _ => None,
+    }
+}
+
+/// The change between two values of a stat, saturating instead of overflowing if the osu! API returned nonsensical
+/// values
+fn delta_i32(prev: i32, cur: i32) -> i32 {
+    cur.saturating_sub(prev)
+}
+
+fn delta_i64(prev: i64, cur: i64) -> i64 {
+    cur.saturating_sub(prev)
+}
+
+/// Returns the names of the `DELTA_FIELDS` that only ever grow for an account in good standing but are lower in `cur`
+/// than in `prev`
+fn find_unexpected_decreases(prev: &Update, cur: &NewUpdate) -> Vec<String> {
+    let decreased = [
+        ("count300", cur.count300 < prev.count300),
+        ("count100", cur.count100 < prev.count100),
+        ("count50", cur.count50 < prev.count50),
+        ("total_hits", cur.total_hits < prev.total_hits),
+        ("playcount", cur.playcount < prev.playcount),
+        ("ranked_score", cur.ranked_score < prev.ranked_score),
+        ("total_score", cur.total_score < prev.total_score),
+    ];
+
+    decreased.iter()
+        .filter(|&&(_, decreased)| decreased)
+        .map(|&(field, _)| String::from(field))
+        .collect()
+}
+
+impl UpdateDiff {
+    /// Given two different updates, returns a new `UpdateDiff` representing the difference between them.  If the first
+    /// update doesn't exist, then the first update will be treated as containing all zeros.  `new_hs` are the user's
+    /// current top plays, which are also used to summarize the new hiscores.
+    pub fn diff(
+        prev: Option<&Update>, cur: &NewUpdate, old_hs: Vec<Hiscore>, new_hs: Vec<NewHiscore>, opts: &DiffOptions
+    ) -> UpdateDiff {
+        let cur_hiscores = new_hs.clone();
+        let mut diff = match prev {
+            Some(prev) => {
+                // find hiscores that are in the new hiscores but not the old hiscores.  The new hiscores may only be
+                // the top few plays if a smaller `hs_limit` was requested, so old hiscores are only ever used to rule
+                // out fetched plays and old plays beyond the fetched depth are simply never matched.
+                let old_keys: HashSet<(i32, i32)> = old_hs.iter()
+                    .map(|hs| (hs.beatmap_id, hs.score))
+                    .collect();
+                let (dropped_hs, reappeared_hs) = if opts.track_dropped {
+                    let cur_keys: HashSet<(i32, i32)> = new_hs.iter()
+                        .map(|hs| (hs.beatmap_id, hs.score))
+                        .collect();
+                    let is_current = |hs: &Hiscore| cur_keys.contains(&(hs.beatmap_id, hs.score));
+                    let dropped: Vec<Hiscore> = old_hs.iter()
+                        .filter(|hs| hs.dropped_at.is_none() && !is_current(*hs))
+                        .cloned()
+                        .collect();
+                    let reappeared: Vec<i32> = old_hs.iter()
+                        .filter(|hs| hs.dropped_at.is_some() && is_current(*hs))
+                        .map(|hs| hs.id)
+                        .collect();
+                    (dropped, reappeared)
+                } else {
+                    (Vec::new(), Vec::new())
+                };
+                let (unstored_hs, stale_hs): (Vec<NewHiscore>, Vec<NewHiscore>) = new_hs.into_iter()
+                    .filter(|cur_hs| !old_keys.contains(&(cur_hs.beatmap_id, cur_hs.score)))
+                    .partition(|cur_hs| !opts.recent_only || cur_hs.score_time > prev.update_time);
+                let hs_diff: Vec<DiffHiscore> = unstored_hs.into_iter()
+                    .map(|cur_hs| {
+                        // if the user already had a recorded score on the map, this one replaces the best of them
+                        let replaces = old_hs.iter()
+                            .filter(|hs| hs.beatmap_id == cur_hs.beatmap_id)
+                            .max_by_key(|hs| hs.score)
+                            .map(|hs| PreviousScore { score: hs.score, pp: hs.pp, enabled_mods: hs.enabled_mods });
+
+                        DiffHiscore::new(cur_hs, replaces)
+                    })
+                    .collect();
+
+                UpdateDiff {
+                    first_update: false,
+                    count300: delta_i32(prev.count300, cur.count300),
+                    count100: delta_i32(prev.count100, cur.count100),
+                    count50: delta_i32(prev.count50, cur.count50),
+                    total_hits: delta_i64(prev.total_hits, cur.total_hits),
+                    playcount: delta_i32(prev.playcount, cur.playcount),
+                    ranked_score: delta_i64(prev.ranked_score, cur.ranked_score),
+                    total_score: delta_i64(prev.total_score, cur.total_score),
+                    pp_rank: delta_i32(prev.pp_rank, cur.pp_rank),
+                    level: cur.level - prev.level,
+                    pp_raw: cur.pp_raw - prev.pp_raw,
+                    accuracy: cur.accuracy - prev.accuracy,
+                    count_rank_ss: delta_i32(prev.count_rank_ss, cur.count_rank_ss),
+                    count_rank_s: delta_i32(prev.count_rank_s, cur.count_rank_s),
+                    count_rank_a: delta_i32(prev.count_rank_a, cur.count_rank_a),
+                    pp_country_rank: country_rank_diff(prev.pp_country_rank, cur.pp_country_rank),
+                    newhs: hs_diff,
+                    newhs_truncated: false,
+                    total_new: None,
+                    hiscores_truncated: false,
+                    stale_hs: stale_hs,
+                    dropped_hs: dropped_hs,
+                    reappeared_hs: reappeared_hs,
+                    stored_update_id: None,
+                    user: None,
+                    new_hiscore_count: 0,
+                    new_weighted_pp_gain: 0.,
+                    unexpected_decreases: find_unexpected_decreases(prev, cur),
+                }
+            },
+            None => {
+                // every one of the user's top plays is new on their first update, so only report the best of them
+                let total_new = new_hs.len();
+                let (new_hs, stale_hs) = match opts.first_update_limit {
+                    Some(limit) if total_new > limit => {
+                        let mut sorted_hs = new_hs;
+                        sorted_hs.sort_by(|a, b| b.pp.partial_cmp(&a.pp).unwrap_or(Ordering::Equal));
+                        let rest = sorted_hs.split_off(limit);
+                        (sorted_hs, rest)
+                    },
+                    _ => (new_hs, Vec::new()),
+                };
+                let newhs_truncated = !stale_hs.is_empty();
+
+                UpdateDiff {
+                    first_update: true,
+                    count300: cur.count300,
+                    count100: cur.count100,
+                    count50: cur.count50,
+                    total_hits: cur.total_hits,
+                    playcount: cur.playcount,
+                    ranked_score: cur.ranked_score,
+                    total_score: cur.total_score,
+                    pp_rank: cur.pp_rank,
+                    level: cur.level,
+                    pp_raw: cur.pp_raw,
+                    accuracy: cur.accuracy,
+                    count_rank_ss: cur.count_rank_ss,
+                    count_rank_s: cur.count_rank_s,
+                    count_rank_a: cur.count_rank_a,
+                    pp_country_rank: cur.pp_country_rank,
+                    newhs: new_hs.into_iter().map(|hs| DiffHiscore::new(hs, None)).collect(),
+                    newhs_truncated: newhs_truncated,
+                    total_new: if newhs_truncated { Some(total_new) } else { None },
+                    hiscores_truncated: false,
+                    stale_hs: stale_hs,
+                    dropped_hs: Vec::new(),
+                    reappeared_hs: Vec::new(),
+                    stored_update_id: None,
+                    user: None,
+                    new_hiscore_count: 0,
+                    new_weighted_pp_gain: 0.,
+                    unexpected_decreases: Vec::new(),
+                }
+            },
+        };
+        diff.summarize_new_hiscores(&cur_hiscores);
+        diff
+    }
+
+    /// Sets `new_hiscore_count` and `new_weighted_pp_gain` from `newhs` and the user's current top plays, which must
+    /// include the new hiscores.  The top plays without the new hiscores and with the scores that they replaced are
+    /// treated as the user's previous top plays.
+    fn summarize_new_hiscores(&mut self, cur_hiscores: &[NewHiscore]) {
+        let new_keys: HashSet<(i32, i32)> = self.newhs.iter()
+            .map(|hs| (hs.hiscore.beatmap_id, hs.hiscore.score))
+            .collect();
+        let cur_pps: Vec<f32> = cur_hiscores.iter().map(|hs| hs.pp).collect();
+        let prev_pps: Vec<f32> = cur_hiscores.iter()
+            .filter(|hs| !new_keys.contains(&(hs.beatmap_id, hs.score)))
+            .map(|hs| hs.pp)
+            .chain(self.newhs.iter().filter_map(|hs| hs.replaces.as_ref().map(|prev| prev.pp)))
+            .collect();
+
+        self.new_hiscore_count = self.newhs.len();
+        self.new_weighted_pp_gain = weighted_pp(&cur_pps) - weighted_pp(&prev_pps);
+    }
+
+    /// Sets the `beatmap` of each of the new hiscores from `beatmaps`, which is keyed by beatmap id.  Hiscores whose
+    /// beatmaps are missing from it are given a `null` beatmap.
+    pub fn set_beatmaps(&mut self, beatmaps: &HashMap<i32, Beatmap>) {
+        for hs in self.newhs.iter_mut() {
+            hs.beatmap = Some(beatmaps.get(&hs.hiscore.beatmap_id).cloned());
+        }
+    }
+}
+
+/// The serialized form of `UpdateDiff` is what the `/update` route returns, so make sure that it stays exactly the
+/// same.
+#[test]
+fn update_diff_serialization_snapshot() {
+    use test_support::{test_time, test_update};
+
+    let update = test_update(4321.5);
+    let hiscore = NewHiscore {
+        user_id: 2, mode: 0, beatmap_id: 1031604, score: 1000000, pp: 345.5, enabled_mods: 72,
+        rank: String::from("S"), score_time: test_time(), count300: Some(3), count100: Some(0), count50: Some(0),
+        countmiss: Some(1), countkatu: Some(0), countgeki: Some(0), maxcombo: Some(700), perfect: Some(false),
+    };
+    let diff = UpdateDiff::diff(None, &update, Vec::new(), vec![hiscore], &DiffOptions::default());
+
+    assert_eq!(
+        ::serde_json::to_string(&diff).unwrap(),
+        "{\"first_update\":true,\"count300\":1000,\"count100\":100,\"count50\":10,\"total_hits\":1110,\
+        \"playcount\":50,\"ranked_score\":123456789,\"total_score\":987654321,\"pp_rank\":1234,\"level\":99.5,\
+        \"pp_raw\":4321.5,\"accuracy\":98.25,\"count_rank_ss\":1,\"count_rank_s\":2,\"count_rank_a\":3,\
+        \"pp_country_rank\":56,\
+        \"newhs\":[{\"user_id\":2,\"mode\":0,\"beatmap_id\":1031604,\"score\":1000000,\"pp\":345.5,\
+        \"enabled_mods\":72,\"rank\":\"S\",\"score_time\":\"2017-12-10T12:00:00\",\"count300\":3,\"count100\":0,\
+        \"count50\":0,\"countmiss\":1,\"countkatu\":0,\"countgeki\":0,\"maxcombo\":700,\"perfect\":false,\
+        \"replaces\":null,\"accuracy\":75.0}],\"new_hiscore_count\":1,\"new_weighted_pp_gain\":345.5,\
+        \"stored_update_id\":null}"
+    );
+}
+
+/// A country rank appearing for a user who didn't have one isn't reported as a huge improvement
+#[test]
+fn appearing_country_rank() {
+    use test_support::{test_stored_update, test_update};
+
+    let cur = test_update(4321.5);
+    let mut prev = Update { pp_country_rank: None, ..test_stored_update(4300.) };
+    let diff = |prev: &Update, cur: &NewUpdate| {
+        UpdateDiff::diff(Some(prev), cur, Vec::new(), Vec::new(), &DiffOptions::default())
+    };
+
+    assert_eq!(diff(&prev, &cur).pp_country_rank, None);
+    assert!(::serde_json::to_string(&diff(&prev, &cur)).unwrap().contains("\"pp_country_rank\":null"));
+    prev.pp_country_rank = Some(60);
+    assert_eq!(diff(&prev, &cur).pp_country_rank, Some(-4));
+    // the rank disappearing again is unknown as well
+    let cur = NewUpdate { pp_country_rank: None, ..cur };
+    assert_eq!(diff(&prev, &cur).pp_country_rank, None);
+    // a user's first update reports their current rank, if any
+    let first = UpdateDiff::diff(None, &cur, Vec::new(), Vec::new(), &DiffOptions::default());
+    assert_eq!(first.pp_country_rank, None);
+}
+
+#[test]
+fn new_hiscore_pp_gain() {
+    use test_support::{test_time, test_update};
+
+    let hiscore = |beatmap_id: i32, pp: f32| NewHiscore {
+        user_id: 2, mode: 0, beatmap_id: beatmap_id, score: 1000000, pp: pp, enabled_mods: 0, rank: String::from("A"),
+        score_time: test_time(), count300: None, count100: None, count50: None, countmiss: None, countkatu: None,
+        countgeki: None, maxcombo: None, perfect: None,
+    };
+    let cur_hiscores = vec![hiscore(1, 300.), hiscore(2, 200.), hiscore(3, 100.)];
+    let mut diff = UpdateDiff::diff(None, &test_update(4321.5), Vec::new(), Vec::new(), &DiffOptions::default());
+
+    // a new best play pushes the other two down
+    diff.newhs = vec![DiffHiscore::new(hiscore(1, 300.), None)];
+    diff.summarize_new_hiscores(&cur_hiscores);
+    assert_eq!(diff.new_hiscore_count, 1);
+    let expected = (300. + 200. * 0.95 + 100. * 0.9025) - (200. + 100. * 0.95);
+    assert!((diff.new_weighted_pp_gain - expected).abs() < 1e-3);
+
+    // an improved score only gains the difference over the score that it replaced
+    let replaced = PreviousScore { score: 900000, pp: 250., enabled_mods: 0 };
+    diff.newhs = vec![DiffHiscore::new(hiscore(1, 300.), Some(replaced))];
+    diff.summarize_new_hiscores(&cur_hiscores);
+    assert!((diff.new_weighted_pp_gain - 50.).abs() < 1e-3);
+
+    diff.newhs = Vec::new();
+    diff.summarize_new_hiscores(&cur_hiscores);
+    assert_eq!((diff.new_hiscore_count, diff.new_weighted_pp_gain), (0, 0.));
+}
+
+/// A ranked score that went down, as it does when an account is reset, is flagged rather than silently reported, and
+/// stats far enough apart to overflow saturate instead
+#[test]
+fn decreasing_ranked_score() {
+    use test_support::{test_stored_update, test_update};
+
+    let prev = Update { ranked_score: 100000000, pp_rank: 1300, ..test_stored_update(4300.) };
+    let cur = NewUpdate { ranked_score: 1000, pp_rank: 1234, ..test_update(4321.5) };
+    let diff = |prev: &Update, cur: &NewUpdate| {
+        UpdateDiff::diff(Some(prev), cur, Vec::new(), Vec::new(), &DiffOptions::default())
+    };
+
+    let reset = diff(&prev, &cur);
+    assert_eq!(reset.ranked_score, -99999000);
+    assert_eq!(reset.unexpected_decreases, vec![String::from("ranked_score")]);
+    assert!(::serde_json::to_string(&reset).unwrap().contains("\"unexpected_decreases\":[\"ranked_score\"]"));
+
+    // a rank getting worse is a normal change
+    let normal = diff(&prev, &NewUpdate { ranked_score: 100001000, pp_rank: 1400, ..cur.clone() });
+    assert!(normal.unexpected_decreases.is_empty());
+    assert!(!::serde_json::to_string(&normal).unwrap().contains("unexpected_decreases"));
+
+    let prev = Update { ranked_score: i64::min_value(), count300: i32::max_value(), ..prev };
+    let wild = diff(&prev, &NewUpdate { count300: -1000, ..cur });
+    assert_eq!((wild.ranked_score, wild.count300), (i64::max_value(), i32::min_value()));
+    assert_eq!(wild.unexpected_decreases, vec![String::from("count300")]);
+}
+
+/// The change in total hits is reported along with the counts that it's the sum of
+#[test]
+fn total_hits_diff() {
+    use models::total_hits;
+    use test_support::{test_stored_update, test_update};
+
+    let prev = Update { count300: 900, total_hits: total_hits(900, 100, 10), ..test_stored_update(4300.) };
+    let cur = NewUpdate { count100: 120, count50: 15, total_hits: total_hits(1000, 120, 15), ..test_update(4321.5) };
+
+    let diff = UpdateDiff::diff(Some(&prev), &cur, Vec::new(), Vec::new(), &DiffOptions::default());
+    assert_eq!(diff.total_hits, 125);
+    assert_eq!(diff.total_hits, (diff.count300 + diff.count100 + diff.count50) as i64);
+    let first = UpdateDiff::diff(None, &cur, Vec::new(), Vec::new(), &DiffOptions::default());
+    assert_eq!(first.total_hits, 1135);
+}
+
+/// New hiscores only include their beatmaps once they've been set, and those that couldn't be found are `null`
+#[test]
+fn new_hiscore_beatmaps() {
+    use test_support::{test_beatmap, test_time, test_update};
+
+    let hiscore = |beatmap_id: i32| NewHiscore {
+        user_id: 2, mode: 0, beatmap_id: beatmap_id, score: 1000000, pp: 345.5, enabled_mods: 0,
+        rank: String::from("S"), score_time: test_time(), count300: None, count100: None, count50: None,
+        countmiss: None, countkatu: None, countgeki: None, maxcombo: None, perfect: None,
+    };
+    let beatmap = test_beatmap(1, 10);
+    let opts = DiffOptions::default();
+    let mut diff = UpdateDiff::diff(None, &test_update(4321.5), Vec::new(), vec![hiscore(10), hiscore(20)], &opts);
+    assert!(::serde_json::to_value(&diff.newhs[0]).unwrap().get("beatmap").is_none());
+
+    let beatmaps: HashMap<i32, Beatmap> = vec![(10, beatmap)].into_iter().collect();
+    diff.set_beatmaps(&beatmaps);
+    let serialized = ::serde_json::to_value(&diff.newhs).unwrap();
+    assert_eq!(serialized[0]["beatmap"]["title"], "Title");
+    assert!(serialized[1]["beatmap"].is_null());
+}
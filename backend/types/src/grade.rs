@@ -0,0 +1,142 @@
+//! The letter grades that plays are awarded.  The osu! API reports them in the `rank` field of scores using its own
+//! names for the SS and silver (Hidden/Flashlight) grades: `X` is SS, `XH` is a silver SS, and `SH` is a silver S.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error;
+
+/// A play's grade.  Serializes as the name used by the osu! API.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Grade {
+    XH,
+    X,
+    SH,
+    S,
+    A,
+    B,
+    C,
+    D,
+    F,
+}
+
+impl Grade {
+    /// The name of the grade as used by the osu! API
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Grade::XH => "XH",
+            Grade::X => "X",
+            Grade::SH => "SH",
+            Grade::S => "S",
+            Grade::A => "A",
+            Grade::B => "B",
+            Grade::C => "C",
+            Grade::D => "D",
+            Grade::F => "F",
+        }
+    }
+
+    /// Returns the grade without its silver variant, so `XH` becomes `X` and `SH` becomes `S`
+    pub fn plain(&self) -> Grade {
+        match *self {
+            Grade::XH => Grade::X,
+            Grade::SH => Grade::S,
+            grade => grade,
+        }
+    }
+
+    /// Returns the grades that match this one.  If `loose` is set, a grade and its silver variant match each other.
+    pub fn matching(&self, loose: bool) -> Vec<Grade> {
+        if !loose {
+            return vec![*self];
+        }
+
+        match self.plain() {
+            Grade::X => vec![Grade::X, Grade::XH],
+            Grade::S => vec![Grade::S, Grade::SH],
+            grade => vec![grade],
+        }
+    }
+}
+
+impl FromStr for Grade {
+    type Err = String;
+
+    /// Parses the osu! API's name for a grade.  `SS` and `SSH` are accepted as aliases for `X` and `XH` since that's
+    /// how the grades are displayed in the game.
+    fn from_str(s: &str) -> Result<Grade, String> {
+        match s.trim().to_uppercase().as_str() {
+            "XH" | "SSH" => Ok(Grade::XH),
+            "X" | "SS" => Ok(Grade::X),
+            "SH" => Ok(Grade::SH),
+            "S" => Ok(Grade::S),
+            "A" => Ok(Grade::A),
+            "B" => Ok(Grade::B),
+            "C" => Ok(Grade::C),
+            "D" => Ok(Grade::D),
+            "F" => Ok(Grade::F),
+            _ => Err(format!("Unknown grade: {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Grade {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl Serialize for Grade {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Grade {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Grade, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse().map_err(D::Error::custom)
+    }
+}
+
+/// Normalizes a grade received from the osu! API into its canonical name, keeping unknown values as they are so that
+/// nothing is lost if the API starts sending new ones.
+pub fn normalize_grade(raw: String) -> String {
+    match raw.parse::<Grade>() {
+        Ok(grade) => String::from(grade.as_str()),
+        Err(_) => raw,
+    }
+}
+
+#[test]
+fn grade_parsing() {
+    let grades = [
+        Grade::XH, Grade::X, Grade::SH, Grade::S, Grade::A, Grade::B, Grade::C, Grade::D, Grade::F,
+    ];
+    for &grade in grades.iter() {
+        assert_eq!(grade.as_str().parse::<Grade>(), Ok(grade));
+        assert_eq!(grade.to_string(), grade.as_str());
+        let json = ::serde_json::to_string(&grade).unwrap();
+        assert_eq!(::serde_json::from_str::<Grade>(&json).unwrap(), grade);
+    }
+
+    assert_eq!("ss".parse::<Grade>(), Ok(Grade::X));
+    assert_eq!("SSH".parse::<Grade>(), Ok(Grade::XH));
+    assert!("Z".parse::<Grade>().is_err());
+    assert!(::serde_json::from_str::<Grade>("\"SSS\"").is_err());
+
+    assert_eq!(normalize_grade(String::from("sh")), "SH");
+    assert_eq!(normalize_grade(String::from("SS")), "X");
+    assert_eq!(normalize_grade(String::from("???")), "???");
+}
+
+#[test]
+fn grade_matching() {
+    assert_eq!(Grade::S.matching(true), vec![Grade::S, Grade::SH]);
+    assert_eq!(Grade::SH.matching(true), vec![Grade::S, Grade::SH]);
+    assert_eq!(Grade::XH.matching(true), vec![Grade::X, Grade::XH]);
+    assert_eq!(Grade::A.matching(true), vec![Grade::A]);
+    assert_eq!(Grade::S.matching(false), vec![Grade::S]);
+    assert_eq!(Grade::XH.plain(), Grade::X);
+}
@@ -0,0 +1,42 @@
+//! Plain data types returned by the osu!track API.  Used by the backend itself as well as by external Rust clients that
+//! want to deserialize its responses without copying the definitions.
+//!
+//! The diesel derives and table definitions used by the backend are only enabled with the `diesel` feature.  The
+//! `test-support` feature exposes the model factories in `test_support` to the backend's tests.
+
+#[macro_use]
+extern crate bitflags;
+extern crate chrono;
+#[cfg(feature = "diesel")]
+#[macro_use]
+extern crate diesel;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[cfg(test)]
+extern crate serde_json;
+
+pub mod accuracy;
+pub mod approval;
+pub mod diff;
+pub mod grade;
+pub mod mode;
+pub mod models;
+pub mod mods;
+pub mod pp;
+#[cfg(feature = "diesel")]
+pub mod schema;
+#[cfg(any(test, feature = "test-support"))]
+#[doc(hidden)]
+pub mod test_support;
+
+/// The version of the database schema and the models stored in it.  Bumped whenever a migration is added or a model
+/// changes, which so far has happened once per migration.
+pub const SCHEMA_VERSION: u32 = 17;
+
+pub use approval::ApprovalStatus;
+pub use diff::{DiffHiscore, PreviousScore, UpdateDiff};
+pub use grade::Grade;
+pub use mode::GameMode;
+pub use models::{Beatmap, Hiscore, NewHiscore, NewUpdate, StatsLite, Update, User};
+pub use mods::Mods;
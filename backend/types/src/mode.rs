@@ -0,0 +1,80 @@
+//! Definitions of the different mode types
+
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error;
+
+pub const STANDARD: u8 = 0;
+pub const TAIKO: u8 = 1;
+pub const CTB: u8 = 2;
+pub const MANIA: u8 = 3;
+
+/// One of the four osu! game modes.  Serializes as the mode's numeric id, the same way that modes are represented in
+/// the osu! API and in the `mode` fields of the other types.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum GameMode {
+    Standard,
+    Taiko,
+    Ctb,
+    Mania,
+}
+
+impl GameMode {
+    /// Returns the mode with the given numeric id, or `None` if there is no such mode
+    pub fn from_u8(mode: u8) -> Option<GameMode> {
+        match mode {
+            STANDARD => Some(GameMode::Standard),
+            TAIKO => Some(GameMode::Taiko),
+            CTB => Some(GameMode::Ctb),
+            MANIA => Some(GameMode::Mania),
+            _ => None,
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match *self {
+            GameMode::Standard => STANDARD,
+            GameMode::Taiko => TAIKO,
+            GameMode::Ctb => CTB,
+            GameMode::Mania => MANIA,
+        }
+    }
+}
+
+impl fmt::Display for GameMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            GameMode::Standard => "osu!",
+            GameMode::Taiko => "osu!taiko",
+            GameMode::Ctb => "osu!catch",
+            GameMode::Mania => "osu!mania",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Serialize for GameMode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.as_u8())
+    }
+}
+
+impl<'de> Deserialize<'de> for GameMode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<GameMode, D::Error> {
+        let mode = u8::deserialize(deserializer)?;
+        GameMode::from_u8(mode).ok_or_else(|| D::Error::custom(format!("Invalid game mode: {}", mode)))
+    }
+}
+
+#[test]
+fn game_mode_roundtrip() {
+    for mode in 0..4 {
+        assert_eq!(GameMode::from_u8(mode).unwrap().as_u8(), mode);
+    }
+    assert_eq!(GameMode::from_u8(4), None);
+
+    assert_eq!(::serde_json::to_string(&GameMode::Mania).unwrap(), "3");
+    assert_eq!(::serde_json::from_str::<GameMode>("1").unwrap(), GameMode::Taiko);
+    assert!(::serde_json::from_str::<GameMode>("7").is_err());
+}
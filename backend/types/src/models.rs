@@ -0,0 +1,535 @@
+//! Definitions of data types that are stored in the database or retrieved from the osu! API.  The diesel derives are
+//! only enabled with the `diesel` feature.
+
+use chrono::{NaiveDate, NaiveDateTime};
+use serde::{Serialize, Serializer};
+use serde::ser::SerializeStruct;
+
+use accuracy::HitCounts;
+use approval::ApprovalStatus;
+use mode::MANIA;
+#[cfg(feature = "diesel")]
+use schema::{
+    users, updates, hiscores, beatmaps, online_users, rank_pp_samples, raw_snapshots, daily_stats, user_flags,
+};
+
+/// Represents a user.  Maps our internal id to the osu! id and contains the last time the user was updated.  Users that
+/// have opted out of tracking have `enabled` unset; their stored data is kept but no new updates are recorded for them.
+#[derive(Deserialize, Serialize)]
+#[cfg_attr(feature = "diesel", derive(Associations, Identifiable, Queryable))]
+pub struct User {
+    pub id: i32,
+    pub username: String,
+    pub first_update: NaiveDateTime,
+    pub last_update: NaiveDateTime,
+    pub enabled: bool,
+}
+
+/// A new user, ready to be inserted into the database.  Maps usernames to osu_ids and holds metadata about the first and most
+/// recent times the user was updated.
+#[cfg_attr(feature = "diesel", derive(Insertable))]
+#[cfg_attr(feature = "diesel", table_name="users")]
+pub struct NewUser {
+    pub id: i32,
+    pub username: String,
+}
+
+/// Returns the total number of objects that a user has hit, which is stored along with each update so that hits per
+/// play can be computed without summing the counts of every update
+pub fn total_hits(count300: i32, count100: i32, count50: i32) -> i64 {
+    count300 as i64 + count100 as i64 + count50 as i64
+}
+
+/// Represents an update for a user containing a snapshot of their stats at a certain point in time.
+#[derive(Clone, Deserialize, Serialize)]
+#[cfg_attr(feature = "diesel", derive(Associations, Identifiable, Queryable))]
+#[cfg_attr(feature = "diesel", belongs_to(User))]
+pub struct Update {
+    pub id: i32,
+    pub user_id: i32,
+    pub mode: i16,
+    pub count300: i32,
+    pub count100: i32,
+    pub count50: i32,
+    pub playcount: i32,
+    pub ranked_score: i64,
+    pub total_score: i64,
+    pub pp_rank: i32,
+    pub level: f32,
+    pub pp_raw: f32,
+    pub accuracy: f32,
+    pub count_rank_ss: i32,
+    pub count_rank_s: i32,
+    pub count_rank_a: i32,
+    /// `None` for users without a country ranking, such as those whose country is unset
+    pub pp_country_rank: Option<i32>,
+    pub update_time: NaiveDateTime,
+    /// Where the update was requested from (web, bot, scheduler, or other).  Not recorded for older updates.
+    pub source: Option<String>,
+    /// The sum of `count300`, `count100`, and `count50`; see `total_hits`
+    #[serde(default)]
+    pub total_hits: i64,
+}
+
+impl Update {
+    /// Returns the stats of the update in the form that new updates are recorded in, which is what diffs are computed
+    /// against
+    pub fn to_new_update(&self) -> NewUpdate {
+        NewUpdate {
+            user_id: self.user_id,
+            mode: self.mode,
+            count300: self.count300,
+            count100: self.count100,
+            count50: self.count50,
+            playcount: self.playcount,
+            ranked_score: self.ranked_score,
+            total_score: self.total_score,
+            pp_rank: self.pp_rank,
+            level: self.level,
+            pp_raw: self.pp_raw,
+            accuracy: self.accuracy,
+            count_rank_ss: self.count_rank_ss,
+            count_rank_s: self.count_rank_s,
+            count_rank_a: self.count_rank_a,
+            pp_country_rank: self.pp_country_rank,
+            source: self.source.clone(),
+            total_hits: self.total_hits,
+        }
+    }
+}
+
+/// Represents a current snapshot of a user's statistics ready to be inserted in the database.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "diesel", derive(Associations, Insertable))]
+#[cfg_attr(feature = "diesel", table_name="updates")]
+pub struct NewUpdate {
+    pub user_id: i32,
+    pub mode: i16,
+    pub count300: i32,
+    pub count100: i32,
+    pub count50: i32,
+    pub playcount: i32,
+    pub ranked_score: i64,
+    pub total_score: i64,
+    pub pp_rank: i32,
+    pub level: f32,
+    pub pp_raw: f32,
+    pub accuracy: f32,
+    pub count_rank_ss: i32,
+    pub count_rank_s: i32,
+    pub count_rank_a: i32,
+    /// `None` for users without a country ranking, such as those whose country is unset
+    pub pp_country_rank: Option<i32>,
+    pub source: Option<String>,
+    /// The sum of `count300`, `count100`, and `count50`; see `total_hits`
+    #[serde(default)]
+    pub total_hits: i64,
+}
+
+/// The subset of a stored update shown in profile headers, returned by `/stats_lite`
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[cfg_attr(feature = "diesel", derive(Queryable))]
+pub struct StatsLite {
+    pub pp_raw: f32,
+    pub pp_rank: i32,
+    pub pp_country_rank: Option<i32>,
+    pub accuracy: f32,
+    pub playcount: i32,
+    pub update_time: NaiveDateTime,
+}
+
+/// The subset of an entry in the beatmap cache listed by `/beatmaps/cached`
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[cfg_attr(feature = "diesel", derive(Queryable))]
+pub struct CachedBeatmapRow {
+    pub beatmap_id: i32,
+    pub beatmapset_id: i32,
+    /// The mode that the beatmap was requested in; converted beatmaps are cached once for each mode
+    pub mode: i16,
+    pub title: String,
+    pub last_update: NaiveDateTime,
+}
+
+/// An entry in the beatmap cache.  Holds information about a beatmap in the local database to avoid the delay of querying the osu! API for each one.
+/// Serializes with an extra `approved_status` field holding the name of the `approved` code and a `key_count` field
+/// that's set for beatmaps made for mania.
+#[derive(Clone, Debug, Deserialize)]
+#[cfg_attr(feature = "diesel", derive(Insertable, Queryable))]
+#[cfg_attr(feature = "diesel", table_name = "beatmaps")]
+pub struct Beatmap {
+    /// The mode that the beatmap was requested in.  Converted beatmaps have difficulty values for this mode, and a
+    /// beatmap is cached once for each mode that it's requested in.
+    pub mode: i16,
+    /// The mode that the beatmap was made for
+    pub native_mode: i16,
+    pub beatmapset_id: i32,
+    pub beatmap_id: i32,
+    pub approved: i16,
+    pub approved_date: NaiveDateTime,
+    pub last_update: NaiveDateTime,
+    pub total_length: i32,
+    pub hit_length: i32,
+    pub version: String,
+    pub artist: String,
+    pub title: String,
+    pub creator: String,
+    pub bpm: f32,
+    pub source: String,
+    pub difficulty: f32,
+    pub diff_size: f32,
+    pub diff_overall: f32,
+    pub diff_approach: f32,
+    pub diff_drain: f32,
+}
+
+impl Beatmap {
+    pub fn approval_status(&self) -> ApprovalStatus {
+        ApprovalStatus::from(self.approved)
+    }
+
+    /// Returns the number of keys that the beatmap is played with if it was made for mania, which the osu! API reports
+    /// as its circle size.  Beatmaps converted to mania are played with a key count derived from their objects, which
+    /// the osu! API doesn't report, so this is `None` for them.
+    pub fn key_count(&self) -> Option<u8> {
+        if self.native_mode == MANIA as i16 { Some(self.diff_size.round() as u8) } else { None }
+    }
+}
+
+/// The number of fields that a `Beatmap` is serialized with: each of its own fields plus `approved_status` and
+/// `key_count`
+const BEATMAP_SERIALIZED_FIELDS: usize = 22;
+
+impl Serialize for Beatmap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Beatmap", BEATMAP_SERIALIZED_FIELDS)?;
+        state.serialize_field("mode", &self.mode)?;
+        state.serialize_field("native_mode", &self.native_mode)?;
+        state.serialize_field("beatmapset_id", &self.beatmapset_id)?;
+        state.serialize_field("beatmap_id", &self.beatmap_id)?;
+        state.serialize_field("approved", &self.approved)?;
+        state.serialize_field("approved_status", &self.approval_status())?;
+        state.serialize_field("approved_date", &self.approved_date)?;
+        state.serialize_field("last_update", &self.last_update)?;
+        state.serialize_field("total_length", &self.total_length)?;
+        state.serialize_field("hit_length", &self.hit_length)?;
+        state.serialize_field("version", &self.version)?;
+        state.serialize_field("artist", &self.artist)?;
+        state.serialize_field("title", &self.title)?;
+        state.serialize_field("creator", &self.creator)?;
+        state.serialize_field("bpm", &self.bpm)?;
+        state.serialize_field("source", &self.source)?;
+        state.serialize_field("difficulty", &self.difficulty)?;
+        state.serialize_field("diff_size", &self.diff_size)?;
+        state.serialize_field("diff_overall", &self.diff_overall)?;
+        state.serialize_field("diff_approach", &self.diff_approach)?;
+        state.serialize_field("diff_drain", &self.diff_drain)?;
+        state.serialize_field("key_count", &self.key_count())?;
+        state.end()
+    }
+}
+
+/// A record of the number of online users in the IRC channel at a given point in time.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "diesel", derive(Queryable))]
+pub struct OnlineUsers {
+    pub time_recorded: NaiveDateTime,
+    pub users: i32,
+    pub operators: i32,
+    pub voiced: i32,
+}
+
+/// A new recording of the number of currently online users, ready to be inserted into the database.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "diesel", derive(Insertable))]
+#[cfg_attr(feature = "diesel", table_name="online_users")]
+pub struct NewOnlineUsers {
+    pub users: i32,
+    pub operators: i32,
+    pub voiced: i32,
+}
+
+/// Represents a hiscore achieved by a user.  Records information about the play, the beatmap, and the time the play occured was achieved and recorded.
+/// The hit counts and combo weren't recorded for older hiscores, so they may be missing.
+#[derive(Clone, Deserialize, Serialize)]
+#[cfg_attr(feature = "diesel", derive(Associations, Queryable))]
+#[cfg_attr(feature = "diesel", belongs_to(User))]
+pub struct Hiscore {
+    pub id: i32,
+    pub user_id: i32,
+    pub mode: i16,
+    pub beatmap_id: i32,
+    pub score: i32,
+    pub pp: f32,
+    pub enabled_mods: i32,
+    pub rank: String,
+    pub score_time: NaiveDateTime,
+    pub time_recorded: NaiveDateTime,
+    pub count300: Option<i32>,
+    pub count100: Option<i32>,
+    pub count50: Option<i32>,
+    pub countmiss: Option<i32>,
+    pub countkatu: Option<i32>,
+    pub countgeki: Option<i32>,
+    pub maxcombo: Option<i32>,
+    pub perfect: Option<bool>,
+    /// The time at which the play was found to no longer be among the user's top plays, or `None` if it still is.
+    /// Omitted when serialized if the play is current.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dropped_at: Option<NaiveDateTime>,
+}
+
+impl Hiscore {
+    /// Returns the judgement counts of the play if they were recorded
+    pub fn hit_counts(&self) -> Option<HitCounts> {
+        HitCounts::from_parts(
+            self.count300, self.count100, self.count50, self.countmiss, self.countkatu, self.countgeki
+        )
+    }
+
+    /// Returns the play in the form that it was fetched from the osu! API in, without the details of its storage
+    pub fn to_new_hiscore(&self) -> NewHiscore {
+        NewHiscore {
+            user_id: self.user_id,
+            mode: self.mode,
+            beatmap_id: self.beatmap_id,
+            score: self.score,
+            pp: self.pp,
+            enabled_mods: self.enabled_mods,
+            rank: self.rank.clone(),
+            score_time: self.score_time,
+            count300: self.count300,
+            count100: self.count100,
+            count50: self.count50,
+            countmiss: self.countmiss,
+            countkatu: self.countkatu,
+            countgeki: self.countgeki,
+            maxcombo: self.maxcombo,
+            perfect: self.perfect,
+        }
+    }
+}
+
+/// Represents a new hiscore set by a user, ready to be inserted into the database.
+#[derive(Clone, Deserialize, Serialize)]
+#[cfg_attr(feature = "diesel", derive(Insertable))]
+#[cfg_attr(feature = "diesel", table_name="hiscores")]
+pub struct NewHiscore {
+    pub user_id: i32,
+    pub mode: i16,
+    pub beatmap_id: i32,
+    pub score: i32,
+    pub pp: f32,
+    pub enabled_mods: i32,
+    pub rank: String,
+    pub score_time: NaiveDateTime,
+    pub count300: Option<i32>,
+    pub count100: Option<i32>,
+    pub count50: Option<i32>,
+    pub countmiss: Option<i32>,
+    pub countkatu: Option<i32>,
+    pub countgeki: Option<i32>,
+    pub maxcombo: Option<i32>,
+    pub perfect: Option<bool>,
+}
+
+impl NewHiscore {
+    /// Returns the judgement counts of the play if they were recorded
+    pub fn hit_counts(&self) -> Option<HitCounts> {
+        HitCounts::from_parts(
+            self.count300, self.count100, self.count50, self.countmiss, self.countkatu, self.countgeki
+        )
+    }
+}
+
+/// One bucket of a nightly snapshot of the relationship between rank and pp in a mode.  `rank_bucket` is the median
+/// rank of the users sampled into the bucket and `median_pp` is their median pp.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "diesel", derive(Queryable))]
+pub struct RankPpSample {
+    pub id: i32,
+    pub mode: i16,
+    pub rank_bucket: i32,
+    pub median_pp: f32,
+    pub sample_count: i32,
+    pub snapshot_date: NaiveDate,
+}
+
+/// A bucket of a rank/pp snapshot, ready to be inserted into the database.
+#[cfg_attr(feature = "diesel", derive(Insertable))]
+#[cfg_attr(feature = "diesel", table_name="rank_pp_samples")]
+pub struct NewRankPpSample {
+    pub mode: i16,
+    pub rank_bucket: i32,
+    pub median_pp: f32,
+    pub sample_count: i32,
+    pub snapshot_date: NaiveDate,
+}
+
+/// The raw body of the most recent `get_user` response received from the osu! API for a user in a mode, kept so that
+/// payloads that fail to parse can be inspected.  `payload` is cut short if the response was too large to store, in
+/// which case `truncated` is set.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "diesel", derive(Queryable))]
+pub struct RawSnapshot {
+    pub user_id: i32,
+    pub mode: i16,
+    pub payload: String,
+    pub truncated: bool,
+    pub fetched_at: NaiveDateTime,
+}
+
+/// A raw `get_user` response ready to be stored in the database, replacing the previously stored one for the user and
+/// mode.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "diesel", derive(Insertable))]
+#[cfg_attr(feature = "diesel", table_name="raw_snapshots")]
+pub struct NewRawSnapshot {
+    pub user_id: i32,
+    pub mode: i16,
+    pub payload: String,
+    pub truncated: bool,
+    pub fetched_at: NaiveDateTime,
+}
+
+/// A user's stats at the end of a day in a mode, kept up to date as updates are recorded so that long histories can be
+/// graphed without reading every update.  `playcount` is the playcount of the day's last update and `playcount_delta`
+/// is the number of plays made since the last update of the previous day that the user has stats for.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[cfg_attr(feature = "diesel", derive(Insertable, Queryable))]
+#[cfg_attr(feature = "diesel", table_name="daily_stats")]
+pub struct DailyStats {
+    pub user_id: i32,
+    pub mode: i16,
+    pub date: NaiveDate,
+    pub pp_rank: i32,
+    pub pp_raw: f32,
+    pub accuracy: f32,
+    pub playcount: i32,
+    pub playcount_delta: i32,
+}
+
+/// A flag set on a user by the operators of the instance to have maintenance jobs treat them specially, such as
+/// `allow_resets` or `do_not_autoupdate`, along with a note explaining why it was set
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[cfg_attr(feature = "diesel", derive(Queryable))]
+pub struct UserFlag {
+    pub user_id: i32,
+    pub flag: String,
+    pub note: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// A flag to be set on a user, replacing the note of the flag if it's already set
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "diesel", derive(Insertable))]
+#[cfg_attr(feature = "diesel", table_name="user_flags")]
+pub struct NewUserFlag {
+    pub user_id: i32,
+    pub flag: String,
+    pub note: String,
+}
+
+/// The serialized forms of these types are part of the public API and are relied upon by external clients, so make sure
+/// that they stay exactly the same.
+#[test]
+fn update_serialization_snapshot() {
+    use test_support::test_stored_update;
+
+    let update = Update { source: Some(String::from("web")), ..test_stored_update(4321.5) };
+
+    assert_eq!(
+        ::serde_json::to_string(&update).unwrap(),
+        "{\"id\":1,\"user_id\":2,\"mode\":0,\"count300\":1000,\"count100\":100,\"count50\":10,\"playcount\":50,\
+        \"ranked_score\":123456789,\"total_score\":987654321,\"pp_rank\":1234,\"level\":99.5,\"pp_raw\":4321.5,\
+        \"accuracy\":98.25,\"count_rank_ss\":1,\"count_rank_s\":2,\"count_rank_a\":3,\"pp_country_rank\":56,\
+        \"update_time\":\"2017-12-10T12:00:00\",\"source\":\"web\",\"total_hits\":1110}"
+    );
+}
+
+#[test]
+fn total_hit_count() {
+    assert_eq!(total_hits(1000, 100, 10), 1110);
+    assert_eq!(total_hits(0, 0, 0), 0);
+    // the counts of the most active players add up to more than fits in an `i32`
+    assert_eq!(total_hits(i32::max_value(), i32::max_value(), 1), 2 * i32::max_value() as i64 + 1);
+}
+
+#[test]
+fn hiscore_serialization_snapshot() {
+    use test_support::test_time;
+
+    let hiscore = Hiscore {
+        id: 1, user_id: 2, mode: 0, beatmap_id: 1031604, score: 1000000, pp: 345.5, enabled_mods: 72,
+        rank: String::from("S"), score_time: test_time(), time_recorded: test_time(), count300: Some(500),
+        count100: Some(10), count50: Some(0), countmiss: Some(0), countkatu: None, countgeki: None, maxcombo: Some(700),
+        perfect: Some(false), dropped_at: None,
+    };
+
+    assert_eq!(
+        ::serde_json::to_string(&hiscore).unwrap(),
+        "{\"id\":1,\"user_id\":2,\"mode\":0,\"beatmap_id\":1031604,\"score\":1000000,\"pp\":345.5,\"enabled_mods\":72,\
+        \"rank\":\"S\",\"score_time\":\"2017-12-10T12:00:00\",\"time_recorded\":\"2017-12-10T12:00:00\",\
+        \"count300\":500,\"count100\":10,\"count50\":0,\"countmiss\":0,\"countkatu\":null,\"countgeki\":null,\
+        \"maxcombo\":700,\"perfect\":false}"
+    );
+}
+
+#[test]
+fn user_serialization_snapshot() {
+    use test_support::test_time;
+
+    let user = User {
+        id: 2, username: String::from("Ameo"), first_update: test_time(), last_update: test_time(), enabled: true,
+    };
+
+    assert_eq!(
+        ::serde_json::to_string(&user).unwrap(),
+        "{\"id\":2,\"username\":\"Ameo\",\"first_update\":\"2017-12-10T12:00:00\",\
+        \"last_update\":\"2017-12-10T12:00:00\",\"enabled\":true}"
+    );
+}
+
+#[test]
+fn beatmap_serialization_snapshot() {
+    use test_support::{test_beatmap, test_time};
+
+    let beatmap = Beatmap {
+        approved: 4, approved_date: test_time(), last_update: test_time(), ..test_beatmap(486535, 1031604)
+    };
+
+    let json = ::serde_json::to_string(&beatmap).unwrap();
+    assert_eq!(
+        json,
+        "{\"mode\":0,\"native_mode\":0,\"beatmapset_id\":486535,\"beatmap_id\":1031604,\"approved\":4,\
+        \"approved_status\":\"loved\",\"approved_date\":\"2017-12-10T12:00:00\",\
+        \"last_update\":\"2017-12-10T12:00:00\",\"total_length\":120,\"hit_length\":110,\"version\":\"Insane\",\
+        \"artist\":\"Artist\",\"title\":\"Title\",\"creator\":\"Mapper\",\"bpm\":180.0,\"source\":\"\",\
+        \"difficulty\":5.5,\"diff_size\":4.0,\"diff_overall\":8.0,\"diff_approach\":9.0,\
+        \"diff_drain\":6.0,\"key_count\":null}"
+    );
+    let fields = ::serde_json::from_str::<::serde_json::Map<String, ::serde_json::Value>>(&json).unwrap().len();
+    assert_eq!(fields, BEATMAP_SERIALIZED_FIELDS);
+    // the extra fields are ignored when deserializing
+    assert_eq!(::serde_json::from_str::<Beatmap>(&json).unwrap().approval_status(), ApprovalStatus::Loved);
+}
+
+#[test]
+fn mania_key_counts() {
+    use test_support::test_beatmap;
+
+    let beatmap = |mode: i16, diff_size: f32| Beatmap {
+        mode: mode, native_mode: mode, diff_size: diff_size, ..test_beatmap(2837, 22538)
+    };
+
+    assert_eq!(beatmap(3, 4.).key_count(), Some(4));
+    assert_eq!(beatmap(3, 7.).key_count(), Some(7));
+    // circle size isn't a key count in the other modes
+    assert_eq!(beatmap(0, 4.).key_count(), None);
+    // or for beatmaps converted to mania
+    let converted = Beatmap { mode: 3, ..beatmap(0, 4.) };
+    assert_eq!(converted.key_count(), None);
+    assert!(::serde_json::to_value(&converted).unwrap()["key_count"].is_null());
+
+    let json: ::serde_json::Value = ::serde_json::to_value(&beatmap(3, 7.)).unwrap();
+    assert_eq!(json["key_count"], ::serde_json::Value::from(7));
+}
@@ -0,0 +1,128 @@
+//! The mods that can be enabled for a play.  The osu! API reports them as a bitfield in the `enabled_mods` field of
+//! scores, which is stored as-is in the `enabled_mods` column of hiscores.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+bitflags! {
+    /// A set of mods.  Serializes as the raw bitfield, the same as `enabled_mods`.
+    pub struct Mods: u32 {
+        const NO_FAIL = 1;
+        const EASY = 1 << 1;
+        const TOUCH_DEVICE = 1 << 2;
+        const HIDDEN = 1 << 3;
+        const HARD_ROCK = 1 << 4;
+        const SUDDEN_DEATH = 1 << 5;
+        const DOUBLE_TIME = 1 << 6;
+        const RELAX = 1 << 7;
+        const HALF_TIME = 1 << 8;
+        /// Always set along with `DOUBLE_TIME`
+        const NIGHTCORE = 1 << 9;
+        const FLASHLIGHT = 1 << 10;
+        const AUTOPLAY = 1 << 11;
+        const SPUN_OUT = 1 << 12;
+        const AUTOPILOT = 1 << 13;
+        /// Always set along with `SUDDEN_DEATH`
+        const PERFECT = 1 << 14;
+        const KEY4 = 1 << 15;
+        const KEY5 = 1 << 16;
+        const KEY6 = 1 << 17;
+        const KEY7 = 1 << 18;
+        const KEY8 = 1 << 19;
+        const FADE_IN = 1 << 20;
+        const RANDOM = 1 << 21;
+        const CINEMA = 1 << 22;
+        const TARGET = 1 << 23;
+        const KEY9 = 1 << 24;
+        const KEY_COOP = 1 << 25;
+        const KEY1 = 1 << 26;
+        const KEY3 = 1 << 27;
+        const KEY2 = 1 << 28;
+        const SCORE_V2 = 1 << 29;
+        const MIRROR = 1 << 30;
+    }
+}
+
+/// The acronyms of the mods in the order that the osu! client displays them in
+const ACRONYMS: &'static [(Mods, &'static str)] = &[
+    (Mods::EASY, "EZ"), (Mods::NO_FAIL, "NF"), (Mods::HALF_TIME, "HT"), (Mods::HIDDEN, "HD"), (Mods::FADE_IN, "FI"),
+    (Mods::HARD_ROCK, "HR"), (Mods::SUDDEN_DEATH, "SD"), (Mods::PERFECT, "PF"), (Mods::DOUBLE_TIME, "DT"),
+    (Mods::NIGHTCORE, "NC"), (Mods::FLASHLIGHT, "FL"), (Mods::SPUN_OUT, "SO"), (Mods::TOUCH_DEVICE, "TD"),
+    (Mods::RELAX, "RX"), (Mods::AUTOPILOT, "AP"), (Mods::AUTOPLAY, "AT"), (Mods::CINEMA, "CN"), (Mods::TARGET, "TP"),
+    (Mods::RANDOM, "RD"), (Mods::MIRROR, "MR"), (Mods::KEY_COOP, "CP"), (Mods::KEY1, "1K"), (Mods::KEY2, "2K"),
+    (Mods::KEY3, "3K"), (Mods::KEY4, "4K"), (Mods::KEY5, "5K"), (Mods::KEY6, "6K"), (Mods::KEY7, "7K"),
+    (Mods::KEY8, "8K"), (Mods::KEY9, "9K"), (Mods::SCORE_V2, "V2"),
+];
+
+impl Mods {
+    /// Converts the `enabled_mods` value of a score into a set of mods, ignoring any unknown bits
+    pub fn from_enabled_mods(enabled_mods: i32) -> Mods {
+        Mods::from_bits_truncate(enabled_mods as u32)
+    }
+
+    /// Folds mods that are variants of others into the mods that they're variants of, so that plays with them are
+    /// grouped together: `NIGHTCORE` becomes `DOUBLE_TIME` and `PERFECT` becomes `SUDDEN_DEATH`.
+    pub fn normalized(self) -> Mods {
+        let mut mods = self;
+        if mods.contains(Mods::NIGHTCORE) {
+            mods.remove(Mods::NIGHTCORE);
+            mods.insert(Mods::DOUBLE_TIME);
+        }
+        if mods.contains(Mods::PERFECT) {
+            mods.remove(Mods::PERFECT);
+            mods.insert(Mods::SUDDEN_DEATH);
+        }
+
+        mods
+    }
+
+    /// Returns the mods as their concatenated two-letter acronyms, such as "HDDT", or "NM" if no mods are set
+    pub fn acronym(self) -> String {
+        if self.is_empty() {
+            return String::from("NM");
+        }
+
+        ACRONYMS.iter()
+            .filter(|&&(mods, _)| self.contains(mods))
+            .map(|&(_, acronym)| acronym)
+            .collect()
+    }
+}
+
+impl Serialize for Mods {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(self.bits())
+    }
+}
+
+impl<'de> Deserialize<'de> for Mods {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Mods, D::Error> {
+        u32::deserialize(deserializer).map(Mods::from_bits_truncate)
+    }
+}
+
+#[test]
+fn mods_from_enabled_mods() {
+    assert_eq!(Mods::from_enabled_mods(0), Mods::empty());
+    assert_eq!(Mods::from_enabled_mods(72), Mods::HIDDEN | Mods::DOUBLE_TIME);
+    assert_eq!(Mods::from_enabled_mods(576), Mods::DOUBLE_TIME | Mods::NIGHTCORE);
+    assert_eq!(Mods::from_enabled_mods(16416), Mods::SUDDEN_DEATH | Mods::PERFECT);
+    assert_eq!(::serde_json::to_string(&(Mods::HIDDEN | Mods::HARD_ROCK)).unwrap(), "24");
+}
+
+#[test]
+fn mods_normalization() {
+    let normalized = |enabled_mods: i32| Mods::from_enabled_mods(enabled_mods).normalized();
+    // NC is always sent along with DT and folds into it
+    assert_eq!(normalized(576), Mods::DOUBLE_TIME);
+    assert_eq!(normalized(584), Mods::HIDDEN | Mods::DOUBLE_TIME);
+    // PF is always sent along with SD and folds into it
+    assert_eq!(normalized(16416), Mods::SUDDEN_DEATH);
+    assert_eq!(normalized(16440), Mods::HIDDEN | Mods::HARD_ROCK | Mods::SUDDEN_DEATH);
+    // nomod and plays without variants are unchanged
+    assert_eq!(normalized(0), Mods::empty());
+    assert_eq!(normalized(72), Mods::HIDDEN | Mods::DOUBLE_TIME);
+
+    assert_eq!(Mods::empty().acronym(), "NM");
+    assert_eq!(normalized(584).acronym(), "HDDT");
+    assert_eq!((Mods::HIDDEN | Mods::HARD_ROCK | Mods::FLASHLIGHT).acronym(), "HDHRFL");
+}
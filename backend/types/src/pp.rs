@@ -0,0 +1,29 @@
+//! Functions for combining the pp of individual plays into a user's total pp
+
+/// The factor that the pp of each play is multiplied by relative to the play ranked just above it
+pub const PP_WEIGHT_FACTOR: f32 = 0.95;
+
+/// Returns the weighted sum of the pp of a set of plays in the way that osu! computes a user's total pp: the plays are
+/// ordered by pp and the `n`th best (0-indexed) is weighted by `PP_WEIGHT_FACTOR ^ n`.  The bonus pp that's awarded for
+/// the number of ranked plays isn't included.
+pub fn weighted_pp(pps: &[f32]) -> f32 {
+    let mut sorted = pps.to_vec();
+    sorted.sort_by(|a, b| b.partial_cmp(a).unwrap_or(::std::cmp::Ordering::Equal));
+
+    let mut weight = 1.;
+    let mut total = 0.;
+    for pp in sorted {
+        total += pp * weight;
+        weight *= PP_WEIGHT_FACTOR;
+    }
+    total
+}
+
+#[test]
+fn pp_weighting() {
+    assert_eq!(weighted_pp(&[]), 0.);
+    assert_eq!(weighted_pp(&[100.]), 100.);
+    // the order that the plays are supplied in doesn't matter
+    assert!((weighted_pp(&[100., 200.]) - (200. + 95.)).abs() < 1e-3);
+    assert!((weighted_pp(&[50., 200., 100.]) - (200. + 95. + 45.125)).abs() < 1e-3);
+}
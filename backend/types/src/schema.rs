@@ -0,0 +1,146 @@
+//! Definitions of the database tables.  These mirror the migrations in the backend's `migrations` directory and must
+//! be kept in sync with them; after adding a migration, regenerate this file with `diesel print-schema` or update it
+//! by hand.  Only available with the `diesel` feature.
+
+table! {
+    beatmaps (beatmap_id, mode) {
+        mode -> Smallint,
+        native_mode -> Smallint,
+        beatmapset_id -> Integer,
+        beatmap_id -> Integer,
+        approved -> Smallint,
+        approved_date -> Timestamp,
+        last_update -> Timestamp,
+        total_length -> Integer,
+        hit_length -> Integer,
+        version -> Varchar,
+        artist -> Varchar,
+        title -> Varchar,
+        creator -> Varchar,
+        bpm -> Float,
+        source -> Varchar,
+        difficulty -> Float,
+        diff_size -> Float,
+        diff_overall -> Float,
+        diff_approach -> Float,
+        diff_drain -> Float,
+    }
+}
+
+table! {
+    daily_stats (user_id, mode, date) {
+        user_id -> Integer,
+        mode -> Smallint,
+        date -> Date,
+        pp_rank -> Integer,
+        pp_raw -> Float,
+        accuracy -> Float,
+        playcount -> Integer,
+        playcount_delta -> Integer,
+    }
+}
+
+table! {
+    hiscores (id) {
+        id -> Integer,
+        user_id -> Integer,
+        mode -> Smallint,
+        beatmap_id -> Integer,
+        score -> Integer,
+        pp -> Float,
+        enabled_mods -> Integer,
+        rank -> Varchar,
+        score_time -> Timestamp,
+        time_recorded -> Timestamp,
+        count300 -> Nullable<Integer>,
+        count100 -> Nullable<Integer>,
+        count50 -> Nullable<Integer>,
+        countmiss -> Nullable<Integer>,
+        countkatu -> Nullable<Integer>,
+        countgeki -> Nullable<Integer>,
+        maxcombo -> Nullable<Integer>,
+        perfect -> Nullable<Bool>,
+        dropped_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    online_users (time_recorded) {
+        time_recorded -> Timestamp,
+        users -> Integer,
+        operators -> Integer,
+        voiced -> Integer,
+    }
+}
+
+table! {
+    rank_pp_samples (id) {
+        id -> Integer,
+        mode -> Smallint,
+        rank_bucket -> Integer,
+        median_pp -> Float,
+        sample_count -> Integer,
+        snapshot_date -> Date,
+    }
+}
+
+table! {
+    raw_snapshots (user_id, mode) {
+        user_id -> Integer,
+        mode -> Smallint,
+        payload -> Text,
+        truncated -> Bool,
+        fetched_at -> Timestamp,
+    }
+}
+
+table! {
+    updates (id) {
+        id -> Integer,
+        user_id -> Integer,
+        mode -> Smallint,
+        count300 -> Integer,
+        count100 -> Integer,
+        count50 -> Integer,
+        playcount -> Integer,
+        ranked_score -> Bigint,
+        total_score -> Bigint,
+        pp_rank -> Integer,
+        level -> Float,
+        pp_raw -> Float,
+        accuracy -> Float,
+        count_rank_ss -> Integer,
+        count_rank_s -> Integer,
+        count_rank_a -> Integer,
+        pp_country_rank -> Nullable<Integer>,
+        update_time -> Timestamp,
+        source -> Nullable<Varchar>,
+        total_hits -> Bigint,
+    }
+}
+
+table! {
+    user_flags (user_id, flag) {
+        user_id -> Integer,
+        flag -> Varchar,
+        note -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    users (id) {
+        id -> Integer,
+        username -> Varchar,
+        first_update -> Timestamp,
+        last_update -> Timestamp,
+        enabled -> Bool,
+    }
+}
+
+joinable!(hiscores -> users (user_id));
+joinable!(updates -> users (user_id));
+
+allow_tables_to_appear_in_same_query!(
+    beatmaps, daily_stats, hiscores, online_users, rank_pp_samples, raw_snapshots, updates, user_flags, users
+);
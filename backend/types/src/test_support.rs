@@ -0,0 +1,45 @@
+//! Models shared by the tests of this crate.  Also compiled with the `test-support` feature so that the backend's
+//! tests can build the same models.
+
+use chrono::NaiveDateTime;
+
+use models::{Beatmap, NewUpdate, Update};
+
+/// Returns a ranked standard beatmap with the given ids.  Tests that care about its other fields override them.
+pub fn test_beatmap(beatmapset_id: i32, beatmap_id: i32) -> Beatmap {
+    Beatmap {
+        mode: 0, native_mode: 0, beatmapset_id: beatmapset_id, beatmap_id: beatmap_id, approved: 1,
+        approved_date: NaiveDateTime::from_timestamp(1500000000, 0),
+        last_update: NaiveDateTime::from_timestamp(1500000000, 0), total_length: 120, hit_length: 110,
+        version: String::from("Insane"), artist: String::from("Artist"), title: String::from("Title"),
+        creator: String::from("Mapper"), bpm: 180., source: String::new(), difficulty: 5.5, diff_size: 4.,
+        diff_overall: 8., diff_approach: 9., diff_drain: 6.,
+    }
+}
+
+/// The time that stored test models were recorded at
+pub fn test_time() -> NaiveDateTime {
+    ::chrono::NaiveDate::from_ymd(2017, 12, 10).and_hms(12, 0, 0)
+}
+
+/// Returns the stats of a user with the given pp.  Tests that care about its other fields override them.
+pub fn test_update(pp_raw: f32) -> NewUpdate {
+    NewUpdate {
+        user_id: 2, mode: 0, count300: 1000, count100: 100, count50: 10, playcount: 50, ranked_score: 123456789,
+        total_score: 987654321, pp_rank: 1234, level: 99.5, pp_raw: pp_raw, accuracy: 98.25, count_rank_ss: 1,
+        count_rank_s: 2, count_rank_a: 3, pp_country_rank: Some(56), source: None, total_hits: 1110,
+    }
+}
+
+/// Returns the stats of `test_update` as they'd be loaded from the database after being recorded as update 1 at
+/// `test_time`
+pub fn test_stored_update(pp_raw: f32) -> Update {
+    let cur = test_update(pp_raw);
+    Update {
+        id: 1, user_id: cur.user_id, mode: cur.mode, count300: cur.count300, count100: cur.count100,
+        count50: cur.count50, playcount: cur.playcount, ranked_score: cur.ranked_score, total_score: cur.total_score,
+        pp_rank: cur.pp_rank, level: cur.level, pp_raw: cur.pp_raw, accuracy: cur.accuracy,
+        count_rank_ss: cur.count_rank_ss, count_rank_s: cur.count_rank_s, count_rank_a: cur.count_rank_a,
+        pp_country_rank: cur.pp_country_rank, update_time: test_time(), source: None, total_hits: cur.total_hits,
+    }
+}